@@ -1,13 +1,38 @@
 use acme_redirect::args::DaemonArgs;
 use boxxy::shprintln;
+use std::env;
 
 fn stage1(sh: &mut boxxy::Shell, _args: Vec<String>) -> Result<(), boxxy::Error> {
     shprintln!(sh, "[*] starting stage1");
-    acme_redirect::sandbox::init(&DaemonArgs {
-        bind_addr: "[::]:80".to_string(),
-        chroot: true,
-        user: Some("nobody".to_string()),
-    })
+    acme_redirect::sandbox::init(
+        &DaemonArgs {
+            bind_addr: vec!["[::]:80".parse().unwrap()],
+            bind_unix: None,
+            bind_unix_mode: 0o660,
+            challenge_only: false,
+            bind_challenge: vec![],
+            bind_tls_alpn: None,
+            chroot: true,
+            user: Some("nobody".to_string()),
+            proxy_protocol: false,
+            renew_interval: None,
+            renew_jitter: None,
+            lock_wait: None,
+            maintenance_file: None,
+            redirect_map: None,
+            metrics_addr: None,
+            control_addr: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+            challenge_rate_limit: None,
+            rate_limit_clients: 10000,
+            client_request_timeout: 5,
+            client_disconnect_timeout: 1,
+            keep_alive: 5,
+            max_header_size: 8192,
+        },
+        &env::current_dir().unwrap(),
+    )
     .unwrap();
     shprintln!(sh, "[+] activated!");
     Ok(())
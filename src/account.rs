@@ -0,0 +1,84 @@
+use crate::acme;
+use crate::acme::client::{Account, Client};
+use crate::acme::jws::AccountKey;
+use crate::acme::net::HttpClient;
+use crate::args::AccountCmd;
+use crate::config::Config;
+use crate::errors::*;
+use crate::persist::FilePersist;
+
+pub fn load_account(persist: &FilePersist, acme_url: &str, http: &HttpClient) -> Result<Account> {
+    let key = persist
+        .load_acc_privkey()?
+        .ok_or_else(|| anyhow!("No account key found, run renew to register an account"))?;
+    let key = AccountKey::from_pem(&key)?;
+
+    let client = Client::with_http(acme_url, http)?;
+    Account::existing(client, key).context("Failed to look up acme account")
+}
+
+fn show(config: &Config) -> Result<()> {
+    let persist = FilePersist::new(config);
+    let http = config.acme.http_client()?;
+    let account = load_account(&persist, config.acme.url(config.acme.staging), &http)?;
+
+    println!("url:        {}", account.url());
+    println!("thumbprint: {}", account.key().thumbprint()?);
+    if let Some(contact) = account.contact() {
+        println!("contact:    {}", contact.join(", "));
+    }
+    Ok(())
+}
+
+fn update(config: &Config) -> Result<()> {
+    let persist = FilePersist::new(config);
+    let http = config.acme.http_client()?;
+    let mut account = load_account(&persist, config.acme.url(config.acme.staging), &http)?;
+
+    let contact = acme::contact(config.acme.acme_email.as_deref());
+    if !account.contact_differs(&contact) {
+        info!("Account contact is already up to date");
+        return Ok(());
+    }
+    info!(
+        "Updating account contact from {:?} to {:?}",
+        account.contact().unwrap_or_default(),
+        contact
+    );
+    account.update_contact(&contact)?;
+    Ok(())
+}
+
+fn rollover(config: &Config) -> Result<()> {
+    let persist = FilePersist::new(config);
+    let http = config.acme.http_client()?;
+    let mut account = load_account(&persist, config.acme.url(config.acme.staging), &http)?;
+
+    info!("Generating new account key");
+    let new_key = AccountKey::generate()?;
+    // keep the new key on disk before the server knows about it, so we don't
+    // lose access to the account if we get interrupted after the key change
+    persist.stage_acc_privkey(&new_key.to_pem()?)?;
+
+    info!("Replacing account key for {:?}", account.url());
+    if let Err(err) = account.change_key(new_key) {
+        persist.discard_staged_acc_privkey()?;
+        return Err(err.context("The acme server rejected the key change"));
+    }
+
+    let backup = persist.commit_acc_privkey()?;
+    info!(
+        "Account key has been replaced, old key was saved to {:?}",
+        backup
+    );
+    println!("thumbprint: {}", account.key().thumbprint()?);
+    Ok(())
+}
+
+pub fn run(config: Config, cmd: AccountCmd) -> Result<()> {
+    match cmd {
+        AccountCmd::Show => show(&config),
+        AccountCmd::Rollover => rollover(&config),
+        AccountCmd::Update => update(&config),
+    }
+}
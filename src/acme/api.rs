@@ -0,0 +1,318 @@
+//! JSON objects of the acme protocol, see RFC 8555 section 7.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Directory {
+    pub new_nonce: String,
+    pub new_account: String,
+    pub new_order: String,
+    pub revoke_cert: String,
+    pub key_change: String,
+    /// Only present if the server supports ARI (RFC 9773)
+    #[serde(default)]
+    pub renewal_info: Option<String>,
+    #[serde(default)]
+    pub meta: DirectoryMeta,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryMeta {
+    pub terms_of_service: Option<String>,
+    #[serde(default)]
+    pub external_account_required: bool,
+}
+
+/// The renewal window the CA suggests for a certificate, RFC 9773 section 4.2.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenewalInfo {
+    pub suggested_window: SuggestedWindow,
+    #[serde(default, rename = "explanationURL")]
+    pub explanation_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SuggestedWindow {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewAccount<'a> {
+    pub contact: &'a [String],
+    pub terms_of_service_agreed: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub only_return_existing: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_account_binding: Option<super::jws::Jws>,
+}
+
+/// The parts of the account object we look at, see RFC 8555 section 7.1.2.
+#[derive(Debug, Deserialize)]
+pub struct AccountObject {
+    #[serde(default)]
+    pub contact: Vec<String>,
+}
+
+/// An empty list removes all contacts from the account.
+#[derive(Debug, Serialize)]
+pub struct AccountUpdate<'a> {
+    pub contact: &'a [String],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyChange<'a> {
+    pub account: &'a str,
+    pub old_key: super::jws::Jwk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Deactivated,
+    Expired,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+impl Identifier {
+    pub fn dns(value: &str) -> Identifier {
+        Identifier {
+            kind: "dns".to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewOrder {
+    pub identifiers: Vec<Identifier>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Order {
+    pub status: Status,
+    pub identifiers: Vec<Identifier>,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Problem>,
+}
+
+impl Order {
+    /// The status of the order, with the error if the server sent one.
+    pub fn status_message(&self) -> String {
+        match &self.error {
+            Some(problem) => format!("{:?}: {}", self.status, problem),
+            None => format!("{:?}", self.status),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Authorization {
+    pub identifier: Identifier,
+    pub status: Status,
+    #[serde(default)]
+    pub challenges: Vec<Challenge>,
+    #[serde(default)]
+    pub wildcard: bool,
+}
+
+impl Authorization {
+    pub fn challenge(&self, kind: &str) -> Option<&Challenge> {
+        self.challenges.iter().find(|c| c.kind == kind)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub status: Status,
+    #[serde(default)]
+    pub token: String,
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finalize {
+    pub csr: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revocation {
+    pub certificate: String,
+    pub reason: u8,
+}
+
+/// An error document returned by the acme server, see RFC 7807.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub detail: Option<String>,
+    #[serde(default)]
+    pub subproblems: Vec<Problem>,
+    /// The identifier a subproblem is about
+    pub identifier: Option<Identifier>,
+    /// The Retry-After header of the response, if there was one
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl Problem {
+    pub fn is_bad_nonce(&self) -> bool {
+        self.kind == "urn:ietf:params:acme:error:badNonce"
+    }
+
+    /// Retrying doesn't help until the rate limit window has passed.
+    pub fn is_rate_limited(&self) -> bool {
+        self.kind == "urn:ietf:params:acme:error:rateLimited"
+    }
+
+    pub fn is_already_revoked(&self) -> bool {
+        self.kind == "urn:ietf:params:acme:error:alreadyRevoked"
+    }
+
+    pub fn is_external_account_required(&self) -> bool {
+        self.kind == "urn:ietf:params:acme:error:externalAccountRequired"
+    }
+
+    /// The server couldn't validate an identifier, eg. because its dns name
+    /// doesn't resolve. This keeps failing until the setup is fixed.
+    pub fn is_validation_failure(&self) -> bool {
+        matches!(
+            self.kind.as_str(),
+            "urn:ietf:params:acme:error:caa"
+                | "urn:ietf:params:acme:error:connection"
+                | "urn:ietf:params:acme:error:dns"
+                | "urn:ietf:params:acme:error:incorrectResponse"
+                | "urn:ietf:params:acme:error:rejectedIdentifier"
+                | "urn:ietf:params:acme:error:tls"
+                | "urn:ietf:params:acme:error:unauthorized"
+        )
+    }
+
+    /// The contact isn't a valid mailto: url or the server doesn't accept it.
+    pub fn is_invalid_contact(&self) -> bool {
+        matches!(
+            self.kind.as_str(),
+            "urn:ietf:params:acme:error:invalidContact"
+                | "urn:ietf:params:acme:error:unsupportedContact"
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(identifier) = &self.identifier {
+            write!(f, "{:?}: ", identifier.value)?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        for sub in &self.subproblems {
+            write!(f, " ({})", sub)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Problem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_order() {
+        let order = serde_json::from_str::<Order>(
+            r#"{
+  "status": "pending",
+  "expires": "2016-01-05T14:09:07.99Z",
+  "identifiers": [
+    { "type": "dns", "value": "www.example.org" },
+    { "type": "dns", "value": "example.org" }
+  ],
+  "authorizations": [
+    "https://example.com/acme/authz/PAniVnsZcis",
+    "https://example.com/acme/authz/r4HqLzrSrpI"
+  ],
+  "finalize": "https://example.com/acme/order/TOlocE8rfgo/finalize"
+}"#,
+        )
+        .unwrap();
+        assert_eq!(order.status, Status::Pending);
+        assert_eq!(order.identifiers[1], Identifier::dns("example.org"));
+        assert_eq!(order.authorizations.len(), 2);
+        assert_eq!(order.certificate, None);
+    }
+
+    #[test]
+    fn test_display_problem() {
+        let problem = serde_json::from_str::<Problem>(
+            r#"{
+  "type": "urn:ietf:params:acme:error:malformed",
+  "detail": "Some of the identifiers requested were rejected",
+  "subproblems": [
+    {
+      "type": "urn:ietf:params:acme:error:malformed",
+      "detail": "Invalid underscore in DNS name \"_example.org\"",
+      "identifier": { "type": "dns", "value": "_example.org" }
+    }
+  ]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(problem.to_string(), "urn:ietf:params:acme:error:malformed: Some of the identifiers requested were rejected (\"_example.org\": urn:ietf:params:acme:error:malformed: Invalid underscore in DNS name \"_example.org\")");
+        assert!(!problem.is_bad_nonce());
+        assert!(!problem.is_rate_limited());
+    }
+
+    #[test]
+    fn test_classify_problem() {
+        let problem = serde_json::from_str::<Problem>(
+            r#"{
+  "type": "urn:ietf:params:acme:error:rateLimited",
+  "detail": "too many certificates (5) already issued for this exact set of domains in the last 168h0m0s",
+  "status": 429
+}"#,
+        )
+        .unwrap();
+        assert!(problem.is_rate_limited());
+        assert!(!problem.is_bad_nonce());
+        assert_eq!(problem.retry_after, None);
+
+        let problem = serde_json::from_str::<Problem>(
+            r#"{
+  "type": "urn:ietf:params:acme:error:badNonce",
+  "detail": "JWS has an invalid anti-replay nonce",
+  "status": 400
+}"#,
+        )
+        .unwrap();
+        assert!(problem.is_bad_nonce());
+        assert!(!problem.is_rate_limited());
+    }
+}
@@ -0,0 +1,1211 @@
+use super::api::{self, Authorization, Directory, Identifier, Order, Problem, Status};
+use super::jws::{base64url, AccountKey, EabKey, Header};
+use super::net::HttpClient;
+use crate::errors::*;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the server may reject a nonce at any time, retry with a fresh one
+const BAD_NONCE_RETRIES: usize = 3;
+// without Retry-After the delay between polls starts low and is doubled
+const MIN_POLL_DELAY: Duration = Duration::from_secs(1);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(10);
+pub const DEFAULT_ORDER_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_VALIDATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long to poll the acme server for a status change before giving up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Waiting for an order to become ready or for the certificate
+    pub order: Duration,
+    /// Waiting for an authorization to be validated
+    pub validation: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            order: DEFAULT_ORDER_TIMEOUT,
+            validation: DEFAULT_VALIDATION_TIMEOUT,
+        }
+    }
+}
+
+/// A http error that didn't come with an acme problem document.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http error {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Turn error responses into an error, preferring the problem document if
+/// the server sent one. `id` is what `HttpClient::configure` returned.
+fn check_response(http: &HttpClient, id: u64, res: ureq::Response) -> Result<ureq::Response> {
+    if let Some(err) = res.synthetic_error() {
+        // the relay knows why it couldn't connect
+        let message = match http.relay_error(id, err) {
+            Some(relay_err) => format!("{:#}", relay_err),
+            None => err.to_string(),
+        };
+        return Err(HttpError {
+            status: res.status(),
+            message,
+        }
+        .into());
+    }
+
+    if res.ok() {
+        return Ok(res);
+    }
+
+    let status = res.status();
+    let status_text = res.status_text().to_string();
+    let is_problem = res.content_type() == "application/problem+json";
+    let retry_after = retry_after(&res);
+    let body = res.into_string().unwrap_or_default();
+
+    if is_problem {
+        if let Ok(mut problem) = serde_json::from_str::<Problem>(&body) {
+            problem.retry_after = retry_after;
+            return Err(problem.into());
+        }
+    }
+
+    Err(HttpError {
+        status,
+        message: format!("{} {}", status_text, body.trim()),
+    }
+    .into())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(res: ureq::Response) -> Result<T> {
+    let body = res.into_string()?;
+    trace!("Response: {}", body);
+    serde_json::from_str(&body).context("Failed to parse response from acme server")
+}
+
+/// Parse a `Link: <url>;rel="alternate"` header, see RFC 8555 section 7.4.2.
+fn alternate_link(link: &str) -> Option<String> {
+    let mut parts = link.split(';').map(str::trim);
+    let url = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+    let alternate = parts.any(|param| {
+        param
+            .strip_prefix("rel=")
+            .map(|rel| rel.trim_matches('"') == "alternate")
+            .unwrap_or(false)
+    });
+    if alternate {
+        Some(url.to_string())
+    } else {
+        None
+    }
+}
+
+/// Parse a Retry-After header, either a number of seconds or a http date,
+/// see RFC 7231 section 7.1.3.
+fn parse_retry_after(value: &str, now: time::Timespec) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let secs = (parse_http_date(value)? - now).num_seconds();
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+fn parse_http_date(value: &str) -> Option<time::Timespec> {
+    let date = time::strptime(value.trim(), "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    Some(date.to_timespec())
+}
+
+/// How many seconds our clock is ahead of the Date header of the server.
+fn clock_skew(res: &ureq::Response, now: time::Timespec) -> Option<i64> {
+    let date = parse_http_date(res.header("date")?)?;
+    Some((now - date).num_seconds())
+}
+
+fn retry_after(res: &ureq::Response) -> Option<Duration> {
+    parse_retry_after(res.header("retry-after")?, time::get_time())
+}
+
+/// Waits between polls of a resource until the deadline is reached.
+struct Poller {
+    timeout: Duration,
+    deadline: Instant,
+    delay: Duration,
+}
+
+impl Poller {
+    fn new(timeout: Duration) -> Poller {
+        Poller {
+            timeout,
+            deadline: Instant::now() + timeout,
+            delay: MIN_POLL_DELAY,
+        }
+    }
+
+    /// Sleep until the next poll, as long as the server asked us to if it
+    /// sent Retry-After. Returns false if the deadline has passed, the last
+    /// poll happens right at the deadline.
+    fn wait(&mut self, retry_after: Option<Duration>) -> bool {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return false;
+        }
+        let delay = retry_after.unwrap_or(self.delay);
+        self.delay = (self.delay * 2).min(MAX_POLL_DELAY);
+        trace!("Polling again in {:?}", delay);
+        thread::sleep(delay.min(self.deadline - now));
+        true
+    }
+}
+
+fn challenge_error(auth: &Authorization) -> Option<&Problem> {
+    auth.challenges.iter().find_map(|c| c.error.as_ref())
+}
+
+fn location(res: &ureq::Response) -> Result<String> {
+    res.header("location")
+        .map(String::from)
+        .ok_or_else(|| anyhow!("acme server didn't send a location header"))
+}
+
+pub struct Client {
+    directory: Directory,
+    nonces: Mutex<Vec<String>>,
+    http: HttpClient,
+    clock_skew: Option<i64>,
+}
+
+impl Client {
+    pub fn new(url: &str) -> Result<Client> {
+        Self::with_http(url, &HttpClient::default())
+    }
+
+    /// Connect to an acme server with custom tls and network settings.
+    pub fn with_http(url: &str, http: &HttpClient) -> Result<Client> {
+        debug!("Fetching acme directory from {:?}", url);
+        let mut req = ureq::get(url);
+        let id = http.configure(&mut req);
+        let res = check_response(http, id, req.call()).context("Failed to fetch acme directory")?;
+        let clock_skew = clock_skew(&res, time::get_time());
+        let directory = read_json(res)?;
+        Ok(Client {
+            directory,
+            nonces: Mutex::new(Vec::new()),
+            http: http.clone(),
+            clock_skew,
+        })
+    }
+
+    pub fn directory(&self) -> &Directory {
+        &self.directory
+    }
+
+    /// How many seconds our clock was ahead of the server when the directory
+    /// was fetched, negative if it's behind. None if the server didn't send a
+    /// Date header.
+    pub fn clock_skew(&self) -> Option<i64> {
+        self.clock_skew
+    }
+
+    /// Fetch the suggested renewal window of a certificate and how long the
+    /// server wants us to wait before asking again. Returns None if the
+    /// server doesn't support ARI.
+    pub fn renewal_info(
+        &self,
+        cert_id: &str,
+    ) -> Result<Option<(api::RenewalInfo, Option<Duration>)>> {
+        let base = match &self.directory.renewal_info {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+        let url = format!("{}/{}", base.trim_end_matches('/'), cert_id);
+        debug!("Fetching renewal info from {:?}", url);
+        let mut req = ureq::get(&url);
+        let id = self.http.configure(&mut req);
+        let res =
+            check_response(&self.http, id, req.call()).context("Failed to fetch renewal info")?;
+        let retry_after = retry_after(&res);
+        Ok(Some((read_json(res)?, retry_after)))
+    }
+
+    fn nonce(&self) -> Result<String> {
+        if let Some(nonce) = self.nonces.lock().unwrap().pop() {
+            return Ok(nonce);
+        }
+
+        debug!("Requesting new nonce");
+        let mut req = ureq::head(&self.directory.new_nonce);
+        let id = self.http.configure(&mut req);
+        let res = check_response(&self.http, id, req.call())?;
+        res.header("replay-nonce")
+            .map(String::from)
+            .ok_or_else(|| anyhow!("acme server didn't send a nonce"))
+    }
+
+    fn post(
+        &self,
+        key: &AccountKey,
+        kid: Option<&str>,
+        url: &str,
+        payload: Option<&str>,
+        accept: &str,
+    ) -> Result<ureq::Response> {
+        let mut attempt = 0;
+        loop {
+            let nonce = self.nonce()?;
+            let header = match kid {
+                Some(kid) => Header::with_kid(kid, &nonce, url),
+                None => Header::with_jwk(key.jwk()?, &nonce, url),
+            };
+            let body = key.sign(&header, payload)?;
+
+            debug!("Sending request to {:?}", url);
+            let mut req = ureq::post(url);
+            let id = self.http.configure(&mut req);
+            let res = req
+                .set("content-type", "application/jose+json")
+                .set("accept", accept)
+                .send_string(&body);
+
+            if let Some(nonce) = res.header("replay-nonce") {
+                self.nonces.lock().unwrap().push(nonce.to_string());
+            }
+
+            match check_response(&self.http, id, res) {
+                Err(err) if attempt < BAD_NONCE_RETRIES && is_bad_nonce(&err) => {
+                    debug!("Retrying with new nonce: {:#}", err);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+fn is_bad_nonce(err: &Error) -> bool {
+    err.downcast_ref::<Problem>()
+        .map(Problem::is_bad_nonce)
+        .unwrap_or(false)
+}
+
+pub struct Account {
+    client: Client,
+    key: AccountKey,
+    kid: String,
+    /// The contact the server has for the account, None if its response
+    /// didn't include the account object
+    contact: Option<Vec<String>>,
+    timeouts: Timeouts,
+}
+
+fn contact_error(err: Error, contact: &[String]) -> Error {
+    let invalid = err
+        .downcast_ref::<Problem>()
+        .is_some_and(Problem::is_invalid_contact);
+    if invalid {
+        err.context(format!(
+            "The acme server rejected the contact {:?}, check acme_email",
+            contact
+        ))
+    } else {
+        err
+    }
+}
+
+impl Account {
+    fn lookup(
+        client: Client,
+        key: AccountKey,
+        contact: &[String],
+        only_return_existing: bool,
+        eab: Option<&EabKey>,
+    ) -> Result<Account> {
+        let url = &client.directory.new_account;
+        let external_account_binding = match eab {
+            Some(eab) => Some(eab.bind(&key.jwk()?, url)?),
+            None => None,
+        };
+        let payload = serde_json::to_string(&api::NewAccount {
+            contact,
+            terms_of_service_agreed: true,
+            only_return_existing,
+            external_account_binding,
+        })?;
+        let res = client
+            .post(&key, None, url, Some(&payload), "application/json")
+            .map_err(|err| {
+                let required = err
+                    .downcast_ref::<Problem>()
+                    .is_some_and(Problem::is_external_account_required);
+                if required && eab.is_none() {
+                    err.context("The acme server requires an external account binding, set eab_kid and eab_hmac_key")
+                } else {
+                    contact_error(err, contact)
+                }
+            })?;
+        let kid = location(&res)?;
+        debug!("Account url is {:?}", kid);
+        let contact = read_json::<api::AccountObject>(res)
+            .ok()
+            .map(|account| account.contact);
+        Ok(Account {
+            client,
+            key,
+            kid,
+            contact,
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    /// Register a new account or look up the existing account for this key.
+    /// The external account binding is only needed if the acme server
+    /// requires it.
+    pub fn new(
+        client: Client,
+        key: AccountKey,
+        contact: &[String],
+        eab: Option<&EabKey>,
+    ) -> Result<Account> {
+        Self::lookup(client, key, contact, false, eab)
+    }
+
+    /// Look up the account for this key without registering a new one.
+    pub fn existing(client: Client, key: AccountKey) -> Result<Account> {
+        Self::lookup(client, key, &[], true, None)
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn key(&self) -> &AccountKey {
+        &self.key
+    }
+
+    /// The account url, also known as key id.
+    pub fn url(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn contact(&self) -> Option<&[String]> {
+        self.contact.as_deref()
+    }
+
+    /// If the contact stored by the server differs from this one, the order
+    /// doesn't matter. An unknown contact is assumed to differ.
+    pub fn contact_differs(&self, contact: &[String]) -> bool {
+        let mut expected = contact.to_vec();
+        expected.sort();
+        match &self.contact {
+            Some(current) => {
+                let mut current = current.clone();
+                current.sort();
+                current != expected
+            }
+            None => true,
+        }
+    }
+
+    /// Replace the contact of the account, see RFC 8555 section 7.3.2. An
+    /// empty list removes the contact.
+    pub fn update_contact(&mut self, contact: &[String]) -> Result<()> {
+        let res = self
+            .post(&self.kid, &api::AccountUpdate { contact })
+            .map_err(|err| contact_error(err, contact))?;
+        let account =
+            read_json::<api::AccountObject>(res).context("Failed to parse the updated account")?;
+        self.contact = Some(account.contact);
+        Ok(())
+    }
+
+    /// Replace the account key, see RFC 8555 section 7.3.5. The account keeps
+    /// using the old key if the server rejects the change.
+    pub fn change_key(&mut self, new_key: AccountKey) -> Result<()> {
+        let url = &self.client.directory.key_change;
+        let inner = serde_json::to_string(&api::KeyChange {
+            account: &self.kid,
+            old_key: self.key.jwk()?,
+        })?;
+        let inner = new_key.sign(&Header::key_change(new_key.jwk()?, url), Some(&inner))?;
+        self.client.post(
+            &self.key,
+            Some(&self.kid),
+            url,
+            Some(&inner),
+            "application/json",
+        )?;
+        self.key = new_key;
+        Ok(())
+    }
+
+    fn post<T: Serialize>(&self, url: &str, payload: &T) -> Result<ureq::Response> {
+        let payload = serde_json::to_string(payload)?;
+        self.client.post(
+            &self.key,
+            Some(&self.kid),
+            url,
+            Some(&payload),
+            "application/json",
+        )
+    }
+
+    fn post_as_get(&self, url: &str) -> Result<ureq::Response> {
+        self.client
+            .post(&self.key, Some(&self.kid), url, None, "application/json")
+    }
+
+    /// Create a new order, returns the url of the order and its state.
+    pub fn new_order(&self, dns_names: &[&str]) -> Result<(String, Order)> {
+        let order = api::NewOrder {
+            identifiers: dns_names.iter().map(|name| Identifier::dns(name)).collect(),
+        };
+        let res = self.post(&self.client.directory.new_order, &order)?;
+        let url = location(&res)?;
+        Ok((url, read_json(res)?))
+    }
+
+    /// Fetch a resource that's polled, with the Retry-After of the response.
+    fn poll<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<(T, Option<Duration>)> {
+        let res = self.post_as_get(url)?;
+        let retry_after = retry_after(&res);
+        Ok((read_json(res)?, retry_after))
+    }
+
+    pub fn order(&self, url: &str) -> Result<Order> {
+        self.poll(url).map(|(order, _)| order)
+    }
+
+    pub fn authorization(&self, url: &str) -> Result<Authorization> {
+        self.poll(url).map(|(auth, _)| auth)
+    }
+
+    fn poll_order(
+        &self,
+        url: &str,
+        mut order: Order,
+        mut retry_after: Option<Duration>,
+        waiting: &[Status],
+    ) -> Result<Order> {
+        let mut poller = Poller::new(self.timeouts.order);
+        while waiting.contains(&order.status) {
+            if !poller.wait(retry_after) {
+                bail!(
+                    "Timeout after {}s while waiting for order {:?}, it's still {}",
+                    poller.timeout.as_secs(),
+                    url,
+                    order.status_message()
+                );
+            }
+            let (next, next_retry_after) = self.poll(url)?;
+            order = next;
+            retry_after = next_retry_after;
+        }
+        Ok(order)
+    }
+
+    /// Poll the order as long as its status is one of `waiting`, until the
+    /// order timeout is reached.
+    pub fn wait_for_order(&self, url: &str, waiting: &[Status]) -> Result<Order> {
+        let (order, retry_after) = self.poll(url)?;
+        self.poll_order(url, order, retry_after, waiting)
+    }
+
+    /// Revoke a certificate issued to this account, see RFC 8555 section 7.6.
+    /// The reason is a CRLReason code from RFC 5280.
+    pub fn revoke(&self, cert_der: &[u8], reason: u8) -> Result<()> {
+        let revocation = api::Revocation {
+            certificate: base64url(cert_der),
+            reason,
+        };
+        self.post(&self.client.directory.revoke_cert, &revocation)?;
+        Ok(())
+    }
+
+    /// Tell the server the challenge is ready and wait until the
+    /// authorization has been validated.
+    pub fn validate(&self, auth_url: &str, challenge: &api::Challenge) -> Result<()> {
+        self.post(&challenge.url, &serde_json::json!({}))?;
+
+        let mut poller = Poller::new(self.timeouts.validation);
+        loop {
+            let (auth, retry_after) = self.poll::<Authorization>(auth_url)?;
+            match auth.status {
+                Status::Pending => {
+                    if poller.wait(retry_after) {
+                        continue;
+                    }
+                    let problem = challenge_error(&auth)
+                        .map(|p| format!(": {}", p))
+                        .unwrap_or_default();
+                    bail!(
+                        "Timeout after {}s while waiting for validation of {:?}, it's still {:?}{}",
+                        poller.timeout.as_secs(),
+                        auth.identifier.value,
+                        auth.status,
+                        problem
+                    );
+                }
+                Status::Valid => return Ok(()),
+                _ => {
+                    let msg = format!(
+                        "Validation of {:?} failed ({:?})",
+                        auth.identifier.value, auth.status
+                    );
+                    // the problem is kept, failed validations are counted
+                    match challenge_error(&auth) {
+                        Some(problem) => return Err(Error::from(problem.clone()).context(msg)),
+                        None => bail!("{}: no error details", msg),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Submit the csr and wait for the certificate to be issued.
+    pub fn finalize(&self, order_url: &str, order: &Order, csr_der: &[u8]) -> Result<Order> {
+        let finalize = api::Finalize {
+            csr: base64url(csr_der),
+        };
+        let res = self.post(&order.finalize, &finalize)?;
+        let retry_after = retry_after(&res);
+        let order = read_json::<Order>(res)?;
+
+        let order = self
+            .poll_order(
+                order_url,
+                order,
+                retry_after,
+                &[Status::Ready, Status::Processing],
+            )
+            .context("Certificate wasn't issued in time")?;
+        if order.status != Status::Valid {
+            bail!("Order is {}", order.status_message());
+        }
+        Ok(order)
+    }
+
+    /// Download a certificate chain, this also returns the urls of the
+    /// alternate chains the server offers for this certificate.
+    pub fn download_certificate(&self, url: &str) -> Result<(String, Vec<String>)> {
+        let res = self.client.post(
+            &self.key,
+            Some(&self.kid),
+            url,
+            None,
+            "application/pem-certificate-chain",
+        )?;
+        let alternates = res
+            .all("link")
+            .into_iter()
+            .filter_map(alternate_link)
+            .collect();
+        Ok((res.into_string()?, alternates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct State {
+        requests: Vec<String>,
+        bad_nonce_sent: bool,
+        validated: bool,
+        finalized: bool,
+        reject_key_change: bool,
+        revoked: bool,
+        require_eab: bool,
+        stall_validation: bool,
+        stall_processing: bool,
+        rate_limited: bool,
+        contact: Vec<String>,
+    }
+
+    fn response(
+        status: &str,
+        content_type: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> String {
+        let mut res = format!("HTTP/1.1 {}\r\nReplay-Nonce: nonce\r\n", status);
+        for (key, value) in headers {
+            res.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        format!(
+            "{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            res,
+            content_type,
+            body.len(),
+            body
+        )
+    }
+
+    fn decode(data: &serde_json::Value) -> String {
+        let data = base64::decode_config(data.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    fn handle(state: &Mutex<State>, url: &str, method: &str, path: &str, body: &str) -> String {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", method, path));
+        let json = "application/json";
+
+        let payload = if method == "POST" {
+            let jws = serde_json::from_str::<serde_json::Value>(body).unwrap();
+            decode(&jws["payload"])
+        } else {
+            String::new()
+        };
+
+        match (method, path) {
+            ("GET", "/directory") => response(
+                "200 OK",
+                json,
+                &[("Date", "Wed, 21 Oct 2015 07:28:00 GMT".to_string())],
+                &serde_json::json!({
+                    "newNonce": format!("{}/nonce", url),
+                    "newAccount": format!("{}/account", url),
+                    "newOrder": format!("{}/order", url),
+                    "revokeCert": format!("{}/revoke", url),
+                    "keyChange": format!("{}/key-change", url),
+                })
+                .to_string(),
+            ),
+            ("HEAD", "/nonce") => response("200 OK", json, &[], ""),
+            ("POST", "/account") if !state.bad_nonce_sent => {
+                state.bad_nonce_sent = true;
+                response(
+                    "400 Bad Request",
+                    "application/problem+json",
+                    &[],
+                    r#"{"type":"urn:ietf:params:acme:error:badNonce","detail":"bad nonce"}"#,
+                )
+            }
+            ("POST", "/account") if state.require_eab => {
+                let payload = serde_json::from_str::<serde_json::Value>(&payload).unwrap();
+                let eab = &payload["externalAccountBinding"];
+                if eab.is_null() {
+                    return response(
+                        "400 Bad Request",
+                        "application/problem+json",
+                        &[],
+                        r#"{"type":"urn:ietf:params:acme:error:externalAccountRequired","detail":"eab required"}"#,
+                    );
+                }
+                let protected =
+                    serde_json::from_str::<serde_json::Value>(&decode(&eab["protected"])).unwrap();
+                assert_eq!(protected["alg"], "HS256");
+                assert_eq!(protected["kid"], "kid-1");
+                assert_eq!(protected["url"], format!("{}/account", url));
+                let jwk =
+                    serde_json::from_str::<serde_json::Value>(&decode(&eab["payload"])).unwrap();
+                let jws = serde_json::from_str::<serde_json::Value>(body).unwrap();
+                let outer =
+                    serde_json::from_str::<serde_json::Value>(&decode(&jws["protected"])).unwrap();
+                assert_eq!(jwk, outer["jwk"]);
+                response(
+                    "201 Created",
+                    json,
+                    &[("Location", format!("{}/account/1", url))],
+                    r#"{"status":"valid"}"#,
+                )
+            }
+            ("POST", "/account") => {
+                let payload = serde_json::from_str::<serde_json::Value>(&payload).unwrap();
+                assert_eq!(payload["termsOfServiceAgreed"], true);
+                if payload["onlyReturnExisting"].is_null() && state.contact.is_empty() {
+                    state.contact = serde_json::from_value(payload["contact"].clone()).unwrap();
+                }
+                response(
+                    "201 Created",
+                    json,
+                    &[("Location", format!("{}/account/1", url))],
+                    &serde_json::json!({"status": "valid", "contact": state.contact}).to_string(),
+                )
+            }
+            ("POST", "/account/1") => {
+                let payload = serde_json::from_str::<serde_json::Value>(&payload).unwrap();
+                let contact: Vec<String> =
+                    serde_json::from_value(payload["contact"].clone()).unwrap();
+                if contact
+                    .iter()
+                    .any(|c| !c.starts_with("mailto:") || !c.contains('@'))
+                {
+                    return response(
+                        "400 Bad Request",
+                        "application/problem+json",
+                        &[],
+                        r#"{"type":"urn:ietf:params:acme:error:invalidContact","detail":"invalid contact"}"#,
+                    );
+                }
+                state.contact = contact;
+                response(
+                    "200 OK",
+                    json,
+                    &[],
+                    &serde_json::json!({"status": "valid", "contact": state.contact}).to_string(),
+                )
+            }
+            ("POST", "/order") if state.rate_limited => response(
+                "429 Too Many Requests",
+                "application/problem+json",
+                &[("Retry-After", "3600".to_string())],
+                r#"{"type":"urn:ietf:params:acme:error:rateLimited","detail":"too many certificates already issued"}"#,
+            ),
+            ("POST", "/order") => response(
+                "201 Created",
+                json,
+                &[("Location", format!("{}/order/1", url))],
+                &serde_json::json!({
+                    "status": "pending",
+                    "identifiers": [{"type": "dns", "value": "example.com"}],
+                    "authorizations": [format!("{}/authz/1", url)],
+                    "finalize": format!("{}/finalize/1", url),
+                })
+                .to_string(),
+            ),
+            ("POST", "/authz/1") if state.stall_validation => response(
+                "200 OK",
+                json,
+                &[],
+                &serde_json::json!({
+                    "status": "pending",
+                    "identifier": {"type": "dns", "value": "example.com"},
+                    "challenges": [{
+                        "type": "http-01",
+                        "url": format!("{}/chall/1", url),
+                        "status": "processing",
+                        "token": "token",
+                        "error": {
+                            "type": "urn:ietf:params:acme:error:connection",
+                            "detail": "Timeout during connect (likely firewall problem)",
+                        },
+                    }],
+                })
+                .to_string(),
+            ),
+            ("POST", "/authz/1") => response(
+                "200 OK",
+                json,
+                &[],
+                &serde_json::json!({
+                    "status": if state.validated { "valid" } else { "pending" },
+                    "identifier": {"type": "dns", "value": "example.com"},
+                    "challenges": [{
+                        "type": "http-01",
+                        "url": format!("{}/chall/1", url),
+                        "status": "pending",
+                        "token": "token",
+                    }],
+                })
+                .to_string(),
+            ),
+            ("POST", "/chall/1") => {
+                assert_eq!(payload, "{}");
+                state.validated = true;
+                response("200 OK", json, &[], "{}")
+            }
+            ("POST", "/finalize/1") | ("POST", "/order/1") if state.stall_processing => response(
+                "200 OK",
+                json,
+                &[("Retry-After", "1".to_string())],
+                &serde_json::json!({
+                    "status": "processing",
+                    "identifiers": [{"type": "dns", "value": "example.com"}],
+                    "finalize": format!("{}/finalize/1", url),
+                })
+                .to_string(),
+            ),
+            ("POST", "/finalize/1") => {
+                assert!(payload.starts_with("{\"csr\":\""));
+                state.finalized = true;
+                response(
+                    "200 OK",
+                    json,
+                    &[],
+                    &serde_json::json!({
+                        "status": "valid",
+                        "identifiers": [{"type": "dns", "value": "example.com"}],
+                        "finalize": format!("{}/finalize/1", url),
+                        "certificate": format!("{}/cert/1", url),
+                    })
+                    .to_string(),
+                )
+            }
+            ("POST", "/key-change") => {
+                let inner = serde_json::from_str::<serde_json::Value>(&payload).unwrap();
+                let protected =
+                    serde_json::from_str::<serde_json::Value>(&decode(&inner["protected"]))
+                        .unwrap();
+                assert_eq!(protected["url"], format!("{}/key-change", url));
+                assert_eq!(protected["nonce"], serde_json::Value::Null);
+                assert_eq!(protected["jwk"]["kty"], "EC");
+                let inner =
+                    serde_json::from_str::<serde_json::Value>(&decode(&inner["payload"])).unwrap();
+                assert_eq!(inner["account"], format!("{}/account/1", url));
+                assert_eq!(inner["oldKey"]["crv"], "P-256");
+
+                if state.reject_key_change {
+                    response(
+                        "409 Conflict",
+                        "application/problem+json",
+                        &[],
+                        r#"{"type":"urn:ietf:params:acme:error:malformed","detail":"key in use"}"#,
+                    )
+                } else {
+                    response("200 OK", json, &[], r#"{"status":"valid"}"#)
+                }
+            }
+            ("POST", "/revoke") => {
+                assert_eq!(payload, r#"{"certificate":"AQID","reason":1}"#);
+                if state.revoked {
+                    response(
+                        "400 Bad Request",
+                        "application/problem+json",
+                        &[],
+                        r#"{"type":"urn:ietf:params:acme:error:alreadyRevoked","detail":"already revoked"}"#,
+                    )
+                } else {
+                    state.revoked = true;
+                    response("200 OK", json, &[], "")
+                }
+            }
+            ("POST", "/cert/1") => {
+                assert!(state.finalized);
+                assert_eq!(payload, "");
+                response(
+                    "200 OK",
+                    "application/pem-certificate-chain",
+                    &[
+                        ("Link", format!("<{}/directory>;rel=\"index\"", url)),
+                        ("Link", format!("<{}/cert/1/1>;rel=\"alternate\"", url)),
+                    ],
+                    "CERT",
+                )
+            }
+            _ => response("404 Not Found", "text/plain", &[], "not found"),
+        }
+    }
+
+    fn serve(state: Arc<Mutex<State>>, url: String, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let path = parts.next().unwrap().to_string();
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((key, value)) = header.split_once(':') {
+                    if key.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+
+            let res = handle(&state, &url, &method, &path, &body);
+            stream.write_all(res.as_bytes()).unwrap();
+        }
+    }
+
+    fn mock_server() -> (String, Arc<Mutex<State>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        {
+            let url = url.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let state = state.clone();
+                    let url = url.clone();
+                    thread::spawn(move || serve(state, url, stream.unwrap()));
+                }
+            });
+        }
+        (url, state)
+    }
+
+    #[test]
+    fn test_order_flow() {
+        let (url, state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let contact = vec!["mailto:nobody@example.com".to_string()];
+        let acc = Account::new(client, AccountKey::generate().unwrap(), &contact, None).unwrap();
+        assert_eq!(acc.kid, format!("{}/account/1", url));
+
+        let (order_url, order) = acc.new_order(&["example.com"]).unwrap();
+        assert_eq!(order_url, format!("{}/order/1", url));
+        assert_eq!(order.status, Status::Pending);
+
+        let auth_url = &order.authorizations[0];
+        let auth = acc.authorization(auth_url).unwrap();
+        let chall = auth.challenge("http-01").unwrap();
+        assert_eq!(chall.token, "token");
+        acc.validate(auth_url, chall).unwrap();
+
+        let order = acc.finalize(&order_url, &order, b"csr").unwrap();
+        let (cert, alternates) = acc
+            .download_certificate(order.certificate.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(cert, "CERT");
+        assert_eq!(alternates, vec![format!("{}/cert/1/1", url)]);
+
+        let state = state.lock().unwrap();
+        assert_eq!(
+            state.requests,
+            vec![
+                "GET /directory",
+                "HEAD /nonce",
+                "POST /account",
+                "POST /account",
+                "POST /order",
+                "POST /authz/1",
+                "POST /chall/1",
+                "POST /authz/1",
+                "POST /finalize/1",
+                "POST /cert/1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validation_timeout() {
+        let (url, state) = mock_server();
+        state.lock().unwrap().stall_validation = true;
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let mut acc = Account::existing(client, AccountKey::generate().unwrap()).unwrap();
+        acc.set_timeouts(Timeouts {
+            validation: Duration::from_secs(1),
+            ..Default::default()
+        });
+
+        let auth_url = format!("{}/authz/1", url);
+        let auth = acc.authorization(&auth_url).unwrap();
+        let err = acc
+            .validate(&auth_url, auth.challenge("http-01").unwrap())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Timeout after 1s while waiting for validation of \"example.com\", it's still Pending: urn:ietf:params:acme:error:connection: Timeout during connect (likely firewall problem)");
+    }
+
+    #[test]
+    fn test_order_timeout() {
+        let (url, state) = mock_server();
+        state.lock().unwrap().stall_processing = true;
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let mut acc = Account::existing(client, AccountKey::generate().unwrap()).unwrap();
+        acc.set_timeouts(Timeouts {
+            order: Duration::from_secs(2),
+            ..Default::default()
+        });
+
+        let (order_url, order) = acc.new_order(&["example.com"]).unwrap();
+        let start = Instant::now();
+        let err = acc.finalize(&order_url, &order, b"csr").unwrap_err();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(
+            format!("{:#}", err),
+            format!("Certificate wasn't issued in time: Timeout after 2s while waiting for order \"{}/order/1\", it's still Processing", url)
+        );
+
+        // the server asked us to wait a second between polls
+        let state = state.lock().unwrap();
+        let polls = state
+            .requests
+            .iter()
+            .filter(|r| *r == "POST /order/1")
+            .count();
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = time::strptime("Wed, 21 Oct 2015 07:28:00 GMT", "%a, %d %b %Y %H:%M:%S GMT")
+            .unwrap()
+            .to_timespec();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let (url, _state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        // the mock server claims it's still 2015
+        let skew = client.clock_skew().unwrap();
+        assert!(skew > 9 * 365 * 24 * 3600, "{}", skew);
+    }
+
+    #[test]
+    fn test_key_change() {
+        let (url, state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let mut acc = Account::existing(client, AccountKey::generate().unwrap()).unwrap();
+        let old = acc.key().thumbprint().unwrap();
+
+        let new_key = AccountKey::generate().unwrap();
+        let new = new_key.thumbprint().unwrap();
+        acc.change_key(new_key).unwrap();
+        assert_eq!(acc.key().thumbprint().unwrap(), new);
+        assert_ne!(old, new);
+
+        state.lock().unwrap().reject_key_change = true;
+        let err = acc.change_key(AccountKey::generate().unwrap()).unwrap_err();
+        assert!(err.downcast_ref::<Problem>().is_some());
+        assert_eq!(acc.key().thumbprint().unwrap(), new);
+    }
+
+    #[test]
+    fn test_update_contact() {
+        let (url, _state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let old = vec!["mailto:old@example.com".to_string()];
+        let mut acc = Account::new(client, AccountKey::generate().unwrap(), &old, None).unwrap();
+        assert_eq!(acc.contact(), Some(&old[..]));
+        assert!(!acc.contact_differs(&old));
+
+        let new = vec!["mailto:new@example.com".to_string()];
+        assert!(acc.contact_differs(&new));
+        acc.update_contact(&new).unwrap();
+        assert_eq!(acc.contact(), Some(&new[..]));
+
+        let invalid = vec!["mailto:nobody".to_string()];
+        let err = acc.update_contact(&invalid).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "The acme server rejected the contact [\"mailto:nobody\"], check acme_email: urn:ietf:params:acme:error:invalidContact: invalid contact"
+        );
+        assert_eq!(acc.contact(), Some(&new[..]));
+
+        // the contact can be removed entirely
+        acc.update_contact(&[]).unwrap();
+        assert_eq!(acc.contact(), Some(&[][..]));
+        assert!(!acc.contact_differs(&[]));
+    }
+
+    #[test]
+    fn test_revoke() {
+        let (url, _state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let acc = Account::existing(client, AccountKey::generate().unwrap()).unwrap();
+
+        acc.revoke(&[1, 2, 3], 1).unwrap();
+        let err = acc.revoke(&[1, 2, 3], 1).unwrap_err();
+        assert!(err.downcast_ref::<Problem>().unwrap().is_already_revoked());
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let (url, state) = mock_server();
+        state.lock().unwrap().rate_limited = true;
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let acc = Account::existing(client, AccountKey::generate().unwrap()).unwrap();
+
+        let err = acc.new_order(&["example.com"]).unwrap_err();
+        let problem = err.downcast_ref::<Problem>().unwrap();
+        assert!(problem.is_rate_limited());
+        assert_eq!(problem.retry_after, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_external_account_binding() {
+        let (url, state) = mock_server();
+        state.lock().unwrap().require_eab = true;
+        let contact = vec!["mailto:nobody@example.com".to_string()];
+
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let err = Account::new(client, AccountKey::generate().unwrap(), &contact, None)
+            .err()
+            .unwrap();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("set eab_kid and eab_hmac_key"), "{}", msg);
+        assert!(msg.contains("eab required"), "{}", msg);
+
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let eab = EabKey::new("kid-1", "c2VjcmV0").unwrap();
+        let acc = Account::new(
+            client,
+            AccountKey::generate().unwrap(),
+            &contact,
+            Some(&eab),
+        )
+        .unwrap();
+        assert_eq!(acc.kid, format!("{}/account/1", url));
+    }
+
+    #[test]
+    fn test_alternate_link() {
+        assert_eq!(
+            alternate_link(r#"<https://example.com/cert/1/1>;rel="alternate""#).as_deref(),
+            Some("https://example.com/cert/1/1")
+        );
+        assert_eq!(
+            alternate_link("<https://example.com/cert/1/1>; rel=alternate").as_deref(),
+            Some("https://example.com/cert/1/1")
+        );
+        assert_eq!(
+            alternate_link(r#"<https://example.com/directory>;rel="index""#),
+            None
+        );
+        assert_eq!(alternate_link("https://example.com/cert/1/1"), None);
+    }
+
+    #[test]
+    fn test_problem_response() {
+        let (url, _state) = mock_server();
+        let client = Client::new(&format!("{}/directory", url)).unwrap();
+        let err = client
+            .post(
+                &AccountKey::generate().unwrap(),
+                None,
+                &format!("{}/missing", url),
+                None,
+                "application/json",
+            )
+            .unwrap_err();
+        let err = err.downcast::<HttpError>().unwrap();
+        assert_eq!(err.status, 404);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::config::{ExtendedKeyUsage, KeyType, KeyUsage};
+use crate::errors::*;
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::stack::Stack;
+use openssl::x509::extension::{self, SubjectAlternativeName};
+use openssl::x509::{X509Extension, X509Req, X509ReqBuilder};
+
+/// The TLS Feature extension from RFC 7633
+pub const TLS_FEATURE_OID: &str = "1.3.6.1.5.5.7.1.24";
+// SEQUENCE { INTEGER 5 }, status_request
+const TLS_FEATURE_STATUS_REQUEST: &[u8] = &[0x30, 0x03, 0x02, 0x01, 0x05];
+
+pub fn create_private_key(key_type: KeyType) -> Result<PKey<Private>> {
+    let key = match key_type {
+        KeyType::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+        KeyType::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?)?,
+        KeyType::EcP256 => ec_key(Nid::X9_62_PRIME256V1)?,
+        KeyType::EcP384 => ec_key(Nid::SECP384R1)?,
+    };
+    Ok(key)
+}
+
+fn ec_key(nid: Nid) -> Result<PKey<Private>> {
+    let group = EcGroup::from_curve_name(nid)?;
+    let key = EcKey::generate(&group)?;
+    Ok(PKey::from_ec_key(key)?)
+}
+
+fn must_staple_extension() -> Result<X509Extension> {
+    let oid = Asn1Object::from_str(TLS_FEATURE_OID)?;
+    let value = Asn1OctetString::new_from_bytes(TLS_FEATURE_STATUS_REQUEST)?;
+    Ok(X509Extension::new_from_der(&oid, false, &value)?)
+}
+
+/// The extensions of the CSR besides the dns names, none are added by
+/// default.
+#[derive(Debug, Default)]
+pub struct Extensions<'a> {
+    pub must_staple: bool,
+    pub key_usages: &'a [KeyUsage],
+    pub extended_key_usages: &'a [ExtendedKeyUsage],
+}
+
+fn key_usage_extension(usages: &[KeyUsage]) -> Result<X509Extension> {
+    let mut ext = extension::KeyUsage::new();
+    ext.critical();
+    for usage in usages {
+        match usage {
+            KeyUsage::DigitalSignature => ext.digital_signature(),
+            KeyUsage::NonRepudiation => ext.non_repudiation(),
+            KeyUsage::KeyEncipherment => ext.key_encipherment(),
+            KeyUsage::DataEncipherment => ext.data_encipherment(),
+            KeyUsage::KeyAgreement => ext.key_agreement(),
+            KeyUsage::KeyCertSign => ext.key_cert_sign(),
+            KeyUsage::CrlSign => ext.crl_sign(),
+            KeyUsage::EncipherOnly => ext.encipher_only(),
+            KeyUsage::DecipherOnly => ext.decipher_only(),
+        };
+    }
+    Ok(ext.build()?)
+}
+
+fn extended_key_usage_extension(usages: &[ExtendedKeyUsage]) -> Result<X509Extension> {
+    let mut ext = extension::ExtendedKeyUsage::new();
+    for usage in usages {
+        match usage {
+            ExtendedKeyUsage::ServerAuth => ext.server_auth(),
+            ExtendedKeyUsage::ClientAuth => ext.client_auth(),
+            ExtendedKeyUsage::CodeSigning => ext.code_signing(),
+            ExtendedKeyUsage::EmailProtection => ext.email_protection(),
+            ExtendedKeyUsage::TimeStamping => ext.time_stamping(),
+        };
+    }
+    Ok(ext.build()?)
+}
+
+pub fn create_csr(
+    pkey: &PKey<Private>,
+    dns_names: &[&str],
+    extensions: &Extensions,
+) -> Result<X509Req> {
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_pubkey(pkey)?;
+
+    let mut stack = Stack::new()?;
+    let mut san = SubjectAlternativeName::new();
+    for name in dns_names {
+        san.dns(name);
+    }
+    stack.push(san.build(&builder.x509v3_context(None))?)?;
+    if extensions.must_staple {
+        stack.push(must_staple_extension()?)?;
+    }
+    if !extensions.key_usages.is_empty() {
+        stack.push(key_usage_extension(extensions.key_usages)?)?;
+    }
+    if !extensions.extended_key_usages.is_empty() {
+        stack.push(extended_key_usage_extension(
+            extensions.extended_key_usages,
+        )?)?;
+    }
+    builder.add_extensions(&stack)?;
+
+    builder.sign(pkey, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_text(req: &X509Req) -> String {
+        String::from_utf8(req.to_text().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_csr() {
+        let pkey = create_private_key(KeyType::EcP256).unwrap();
+        let req = create_csr(
+            &pkey,
+            &["example.com", "www.example.com"],
+            &Extensions::default(),
+        )
+        .unwrap();
+        assert!(req.verify(&pkey).unwrap());
+        let text = to_text(&req);
+        assert!(text.contains("DNS:example.com, DNS:www.example.com"));
+        assert!(!text.contains("TLS Feature"));
+        assert!(!text.contains("Key Usage"));
+    }
+
+    #[test]
+    fn test_must_staple_csr() {
+        let pkey = create_private_key(KeyType::EcP384).unwrap();
+        let extensions = Extensions {
+            must_staple: true,
+            ..Default::default()
+        };
+        let req = create_csr(&pkey, &["example.com"], &extensions).unwrap();
+        assert!(req.verify(&pkey).unwrap());
+        let text = to_text(&req);
+        assert!(text.contains("DNS:example.com"));
+        assert!(text.contains("TLS Feature"));
+        assert!(text.contains("status_request"));
+    }
+
+    #[test]
+    fn test_key_usage_csr() {
+        let pkey = create_private_key(KeyType::EcP256).unwrap();
+        let extensions = Extensions {
+            must_staple: false,
+            key_usages: &[KeyUsage::DigitalSignature, KeyUsage::KeyAgreement],
+            extended_key_usages: &[ExtendedKeyUsage::ServerAuth, ExtendedKeyUsage::ClientAuth],
+        };
+        let req = create_csr(&pkey, &["example.com"], &extensions).unwrap();
+        assert!(req.verify(&pkey).unwrap());
+
+        let der = req.to_der().unwrap();
+        let contains = |needle: &[u8]| der.windows(needle.len()).any(|w| w == needle);
+        // keyUsage, critical, BIT STRING with digitalSignature and keyAgreement
+        assert!(contains(&[
+            0x06, 0x03, 0x55, 0x1d, 0x0f, 0x01, 0x01, 0xff, 0x04, 0x04, 0x03, 0x02, 0x03, 0x88
+        ]));
+        // extKeyUsage with serverAuth and clientAuth
+        assert!(contains(&[0x06, 0x03, 0x55, 0x1d, 0x25]));
+        assert!(contains(&[
+            0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01
+        ]));
+        assert!(contains(&[
+            0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02
+        ]));
+        assert!(!to_text(&req).contains("TLS Feature"));
+    }
+}
@@ -0,0 +1,434 @@
+use crate::cert::{self, SctPolicy};
+use crate::chall::Challenge;
+use crate::config::{ChallengeType, ExtendedKeyUsage, KeyType, KeyUsage};
+use crate::dns::DnsChallenge;
+use crate::errors::*;
+use crate::persist::FilePersist;
+use crate::tls_alpn;
+use openssl::pkey::{PKey, Private};
+use std::sync::Mutex;
+
+pub mod api;
+pub mod client;
+pub mod csr;
+pub mod jws;
+pub mod net;
+pub mod tls;
+
+use self::api::{Problem, Status};
+use self::client::{Account, Client, HttpError, Timeouts};
+use self::jws::{AccountKey, EabKey};
+use self::net::HttpClient;
+
+// don't register multiple accounts if certs are renewed concurrently
+static ACCOUNT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Check if an error returned by the acme server is likely to go away if the
+/// request is retried later.
+pub fn is_transient(err: &Error) -> bool {
+    err.chain().any(|err| {
+        if let Some(problem) = err.downcast_ref::<Problem>() {
+            problem.is_bad_nonce() || problem.kind == "urn:ietf:params:acme:error:serverInternal"
+        } else if let Some(err) = err.downcast_ref::<HttpError>() {
+            err.status >= 500
+        } else {
+            false
+        }
+    })
+}
+
+/// The problem document if the acme server rejected a request because of a
+/// rate limit. Retrying right away only makes it worse.
+pub fn rate_limit(err: &Error) -> Option<&Problem> {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<Problem>())
+        .find(|problem| problem.is_rate_limited())
+}
+
+/// Check if the acme server failed to validate one of the identifiers, these
+/// failures count towards the back-off of a certificate.
+pub fn is_validation_failure(err: &Error) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<Problem>())
+        .any(|problem| {
+            problem.is_validation_failure()
+                || problem
+                    .subproblems
+                    .iter()
+                    .any(Problem::is_validation_failure)
+        })
+}
+
+/// A freshly issued certificate with its private key.
+#[derive(Debug)]
+pub struct Certificate {
+    pub private_key: String,
+    pub fullchain: String,
+}
+
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub acme_url: &'a str,
+    pub account_email: Option<&'a str>,
+    pub name: &'a str,
+    pub primary_name: &'a str,
+    pub alt_names: &'a [String],
+    pub challenge_type: ChallengeType,
+    pub key_type: KeyType,
+    /// Use this key instead of creating a new one
+    pub private_key: Option<&'a PKey<Private>>,
+    pub must_staple: bool,
+    pub key_usages: &'a [KeyUsage],
+    pub extended_key_usages: &'a [ExtendedKeyUsage],
+    /// The embedded SCTs the certificate needs before it's stored
+    pub sct_policy: Option<SctPolicy>,
+    /// Complete the challenges but don't finalize the order
+    pub dry_run: bool,
+    /// Bind new accounts to this external account
+    pub eab: Option<&'a EabKey>,
+    /// The issuer of the topmost certificate of the preferred chain
+    pub preferred_chain: Option<&'a str>,
+    /// The tls and network settings for the acme server
+    pub http: &'a HttpClient,
+    pub timeouts: Timeouts,
+}
+
+fn chain_matches(chain: &str, preferred: &str) -> Result<bool> {
+    let issuer = cert::chain_issuer(chain.as_bytes())?;
+    Ok(issuer.as_deref() == Some(preferred))
+}
+
+/// Download the certificate, if there's a preferred chain the alternate
+/// chains are tried until one of them matches it.
+fn download_chain(acc: &Account, url: &str, preferred: Option<&str>) -> Result<String> {
+    let (default, alternates) = acc.download_certificate(url)?;
+    let preferred = match preferred {
+        Some(preferred) => preferred,
+        None => return Ok(default),
+    };
+    if chain_matches(&default, preferred)? {
+        return Ok(default);
+    }
+
+    for url in alternates {
+        debug!("downloading alternate chain: {:?}", url);
+        let (chain, _) = acc.download_certificate(&url)?;
+        if chain_matches(&chain, preferred)? {
+            info!("using alternate chain issued by {:?}", preferred);
+            return Ok(chain);
+        }
+    }
+
+    warn!(
+        "acme server didn't offer a chain issued by {:?}, using the default chain",
+        preferred
+    );
+    Ok(default)
+}
+
+/// The account contact for `acme_email`.
+pub fn contact(email: Option<&str>) -> Vec<String> {
+    email
+        .map(|email| vec![format!("mailto:{}", email)])
+        .unwrap_or_default()
+}
+
+pub fn request(
+    persist: FilePersist,
+    challenge: &mut Challenge,
+    dns: &mut DnsChallenge,
+    req: &Request,
+) -> Result<()> {
+    let client = Client::with_http(req.acme_url, req.http)?;
+
+    let contact = contact(req.account_email);
+
+    let mut acc = {
+        let _lock = ACCOUNT_LOCK.lock().unwrap();
+        if let Some(pem) = persist.load_acc_privkey()? {
+            info!("authenticating with existing account");
+            let mut acc = Account::new(client, AccountKey::from_pem(&pem)?, &contact, req.eab)?;
+            // the contact is only sent to the server during registration
+            if acc.contact_differs(&contact) && !req.dry_run {
+                info!(
+                    "updating account contact from {:?} to {:?}",
+                    acc.contact().unwrap_or_default(),
+                    contact
+                );
+                if let Err(err) = acc.update_contact(&contact) {
+                    warn!("Failed to update account contact: {:#}", err);
+                }
+            }
+            acc
+        } else {
+            info!("registering account");
+            let acc = Account::new(client, AccountKey::generate()?, &contact, req.eab)?;
+            if req.dry_run {
+                info!("successfully created account, not saving private key (dry run)");
+            } else {
+                info!("successfully created account, saving private key");
+                persist.store_acc_privkey(&acc.key().to_pem()?)?;
+            }
+            acc
+        }
+    };
+
+    acc.set_timeouts(req.timeouts);
+
+    // Order a new TLS certificate for a domain.
+    let mut dns_names = vec![req.primary_name];
+    dns_names.extend(req.alt_names.iter().map(String::as_str));
+    info!("sending certificate order");
+    let (order_url, mut order) = acc.new_order(&dns_names)?;
+
+    // If the ownership of the domain(s) have already been
+    // authorized in a previous order, you might be able to
+    // skip validation. The ACME API provider decides.
+    loop {
+        // are we done?
+        match order.status {
+            Status::Ready | Status::Valid => {
+                info!("order has been confirmed");
+                break;
+            }
+            Status::Pending => (),
+            _ => bail!("Order is {}", order.status_message()),
+        }
+
+        // Get the possible authorizations (for a single domain
+        // this will only be one element).
+        info!("fetch necessary authentications");
+        let mut auths = Vec::new();
+        for url in &order.authorizations {
+            let auth = acc.authorization(url)?;
+            if auth.status == Status::Valid {
+                if req.dry_run {
+                    info!(
+                        "dry run: {} is already authorized, challenge was not tested",
+                        auth.identifier.value
+                    );
+                } else {
+                    debug!("{:?} is already authorized", auth.identifier.value);
+                }
+                continue;
+            }
+            auths.push((url, auth));
+        }
+
+        match req.challenge_type {
+            ChallengeType::Http01 => {
+                // For HTTP, the challenge is a text file that needs to
+                // be placed in your web server's root:
+                //
+                // /var/www/.well-known/acme-challenge/<token>
+                //
+                // The important thing is that it's accessible over the
+                // web for the domain(s) you are trying to get a
+                // certificate for:
+                //
+                // http://mydomain.io/.well-known/acme-challenge/<token>
+                for (url, auth) in &auths {
+                    let chall = auth
+                        .challenge("http-01")
+                        .ok_or_else(|| anyhow!("acme server didn't offer http challenge"))?;
+
+                    // The token is the filename and the proof is the
+                    // contents of the file
+                    let proof = acc.key().key_authorization(&chall.token)?;
+                    challenge.write(&chall.token, &proof)?;
+
+                    // After the file is accessible from the web, the calls
+                    // this to tell the ACME API to start checking the
+                    // existence of the proof.
+                    //
+                    // The order at ACME will change status to either
+                    // confirm ownership of the domain, or fail due to the
+                    // not finding the proof.
+                    acc.validate(url, chall)?;
+                    if req.dry_run {
+                        info!(
+                            "dry run: validation succeeded for {}",
+                            auth.identifier.value
+                        );
+                    }
+                }
+            }
+            ChallengeType::Dns01 => {
+                // For DNS, the proof is a TXT record that needs to be
+                // published for every domain before we ask the ACME API
+                // to validate:
+                //
+                // _acme-challenge.mydomain.io. TXT <proof>
+                let mut challs = Vec::new();
+                for (url, auth) in &auths {
+                    let chall = auth
+                        .challenge("dns-01")
+                        .ok_or_else(|| anyhow!("acme server didn't offer dns challenge"))?;
+                    let proof = acc.key().key_authorization(&chall.token)?;
+                    let proof = jws::base64url(openssl::sha::sha256(proof.as_bytes()));
+                    dns.publish(&auth.identifier.value, &proof)?;
+                    challs.push((url, auth, chall));
+                }
+
+                dns.wait_for_propagation()?;
+
+                for (url, auth, chall) in challs {
+                    acc.validate(url, chall)?;
+                    if req.dry_run {
+                        info!(
+                            "dry run: validation succeeded for {}",
+                            auth.identifier.value
+                        );
+                    }
+                }
+            }
+            ChallengeType::TlsAlpn01 => {
+                // For TLS-ALPN, the proof is a self signed certificate that
+                // the daemon presents on port 443 to clients that ask for
+                // the acme-tls/1 protocol with the domain as SNI name
+                for (url, auth) in &auths {
+                    let chall = auth
+                        .challenge("tls-alpn-01")
+                        .ok_or_else(|| anyhow!("acme server didn't offer tls-alpn challenge"))?;
+                    let proof = acc.key().key_authorization(&chall.token)?;
+                    let pem = tls_alpn::challenge_cert(&auth.identifier.value, &proof)?;
+                    challenge.write(tls_alpn::token(&auth.identifier.value).as_str(), &pem)?;
+
+                    acc.validate(url, chall)?;
+                    if req.dry_run {
+                        info!(
+                            "dry run: validation succeeded for {}",
+                            auth.identifier.value
+                        );
+                    }
+                }
+            }
+        }
+
+        // Update the state against the ACME API, the order may take a
+        // moment to notice the authorizations are valid.
+        order = acc.wait_for_order(&order_url, &[Status::Pending])?;
+    }
+
+    if req.dry_run {
+        info!("dry run: not requesting certificate for {:?}", req.name);
+        return Ok(());
+    }
+
+    // Ownership is proven. Create a private key for the certificate and
+    // submit the CSR. This causes the ACME provider to enter a state of
+    // "processing" that is polled until the certificate is either issued or
+    // rejected.
+    let pkey = match req.private_key {
+        Some(pkey) => pkey.clone(),
+        None => csr::create_private_key(req.key_type)?,
+    };
+    let extensions = csr::Extensions {
+        must_staple: req.must_staple,
+        key_usages: req.key_usages,
+        extended_key_usages: req.extended_key_usages,
+    };
+    let csr = csr::create_csr(&pkey, &dns_names, &extensions)?;
+    let order = acc.finalize(&order_url, &order, &csr.to_der()?)?;
+
+    // Now download the certificate. Also stores the cert in
+    // the persistence.
+    info!("downloading certificate");
+    let cert_url = order
+        .certificate
+        .ok_or_else(|| anyhow!("acme server didn't provide a certificate url"))?;
+    let cert = Certificate {
+        private_key: String::from_utf8(pkey.private_key_to_pem_pkcs8()?)?,
+        fullchain: download_chain(&acc, &cert_url, req.preferred_chain)?,
+    };
+
+    if let Some(policy) = &req.sct_policy {
+        policy
+            .check(cert.fullchain.as_bytes())
+            .context("Certificate transparency check failed, not installing the certificate")?;
+    }
+
+    info!("storing certificate");
+    persist
+        .store_cert(req.name, &cert)
+        .context("Failed to store certificate")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problem(kind: &str) -> Error {
+        Problem {
+            kind: format!("urn:ietf:params:acme:error:{}", kind),
+            detail: Some("details".to_string()),
+            subproblems: vec![],
+            identifier: None,
+            retry_after: None,
+        }
+        .into()
+    }
+
+    fn http_error(status: u16) -> Error {
+        HttpError {
+            status,
+            message: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(is_transient(&problem("badNonce")));
+        assert!(is_transient(
+            &http_error(503).context("Fail to get certificate")
+        ));
+        assert!(is_transient(&problem("serverInternal")));
+    }
+
+    #[test]
+    fn test_permanent_errors() {
+        assert!(!is_transient(&problem("rateLimited")));
+        assert!(!is_transient(&http_error(404)));
+        assert!(!is_transient(&problem("unauthorized")));
+        assert!(!is_transient(&anyhow!("Validation failed")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let err = problem("rateLimited").context("Fail to get certificate");
+        assert_eq!(
+            rate_limit(&err).unwrap().kind,
+            "urn:ietf:params:acme:error:rateLimited"
+        );
+        assert!(rate_limit(&problem("badNonce")).is_none());
+        assert!(rate_limit(&http_error(429)).is_none());
+    }
+
+    #[test]
+    fn test_validation_failure() {
+        let err = problem("dns").context("Validation of \"example.com\" failed (Invalid)");
+        assert!(is_validation_failure(&err));
+        assert!(is_validation_failure(&problem("unauthorized")));
+        let mut rejected = Problem {
+            kind: "urn:ietf:params:acme:error:malformed".to_string(),
+            detail: None,
+            subproblems: vec![],
+            identifier: None,
+            retry_after: None,
+        };
+        assert!(!is_validation_failure(&rejected.clone().into()));
+        rejected.subproblems.push(Problem {
+            kind: "urn:ietf:params:acme:error:rejectedIdentifier".to_string(),
+            ..rejected.clone()
+        });
+        assert!(is_validation_failure(&rejected.into()));
+
+        assert!(!is_validation_failure(&problem("rateLimited")));
+        assert!(!is_validation_failure(&problem("serverInternal")));
+        assert!(!is_validation_failure(&http_error(503)));
+        assert!(!is_validation_failure(&anyhow!("Connection refused")));
+    }
+}
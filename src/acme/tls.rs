@@ -0,0 +1,166 @@
+//! Tls settings of the acme client, for acme servers with a certificate that
+//! isn't issued by one of the public roots, like pebble or internal CAs.
+use crate::errors::*;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Add all certificates of a pem file, text outside of the pem blocks like
+/// comments is ignored. A certificate that can't be used as trust anchor is
+/// an error, so it's not silently missing.
+fn add_pem_file(store: &mut RootCertStore, path: &Path) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| anyhow!("Failed to open acme ca certificate: {:?}", path))?;
+    let (valid, invalid) = store
+        .add_pem_file(&mut BufReader::new(file))
+        .map_err(|_| anyhow!("Failed to parse acme ca certificate: {:?}", path))?;
+    if invalid > 0 {
+        bail!("{} invalid certificate(s) in {:?}", invalid, path);
+    }
+    if valid == 0 {
+        bail!("No valid certificates found in {:?}", path);
+    }
+    debug!("Loaded {} acme ca certificate(s) from {:?}", valid, path);
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct TlsConfig(Arc<ClientConfig>);
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish()
+    }
+}
+
+impl TlsConfig {
+    /// Trust the certificates in the `ca_certs` files in addition to the
+    /// public roots, or don't verify the certificate of the acme server at
+    /// all. Returns None if the defaults should be used.
+    pub fn new(ca_certs: &[&Path], accept_invalid_certs: bool) -> Result<Option<TlsConfig>> {
+        if ca_certs.is_empty() && !accept_invalid_certs {
+            return Ok(None);
+        }
+
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+        for path in ca_certs {
+            add_pem_file(&mut config.root_store, path)?;
+        }
+
+        if accept_invalid_certs {
+            warn!("Certificate verification for the acme server is disabled");
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerifier));
+        }
+
+        Ok(Some(TlsConfig(Arc::new(config))))
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Name, X509};
+
+    fn ca_pem() -> Vec<u8> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "internal ca").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        cert.build().to_pem().unwrap()
+    }
+
+    #[test]
+    fn test_default() {
+        assert!(TlsConfig::new(&[], false).unwrap().is_none());
+        assert!(TlsConfig::new(&[], true).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_ca_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, ca_pem()).unwrap();
+        assert!(TlsConfig::new(&[&path], false).unwrap().is_some());
+
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(TlsConfig::new(&[&path], false).is_err());
+        let missing = dir.path().join("missing");
+        let err = TlsConfig::new(&[&missing], false).unwrap_err();
+        assert!(format!("{:#}", err).contains(&format!("{:?}", missing)));
+    }
+
+    #[test]
+    fn test_ca_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("bundle.pem");
+        let mut pem = b"# internal root\n".to_vec();
+        pem.extend(ca_pem());
+        pem.extend(b"\n# intermediate\nsubject=CN = internal ca\n");
+        pem.extend(ca_pem());
+        std::fs::write(&bundle, &pem).unwrap();
+        let other = dir.path().join("other.pem");
+        std::fs::write(&other, ca_pem()).unwrap();
+
+        let tls = TlsConfig::new(&[&bundle, &other], false).unwrap().unwrap();
+        let roots = webpki_roots::TLS_SERVER_ROOTS.0.len();
+        assert_eq!(tls.client_config().root_store.len(), roots + 3);
+
+        // a broken certificate next to a valid one
+        pem.extend(b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n");
+        std::fs::write(&bundle, &pem).unwrap();
+        let err = TlsConfig::new(&[&other, &bundle], false).unwrap_err();
+        assert!(err.to_string().contains("1 invalid certificate(s)"));
+    }
+}
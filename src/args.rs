@@ -1,5 +1,10 @@
 use crate::errors::*;
-use std::io::stdout;
+use crate::persist::LiveFile;
+use std::io::{stdout, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
@@ -12,6 +17,9 @@ pub struct Args {
     /// Silent output (except errors)
     #[structopt(short, long, global = true)]
     pub quiet: bool,
+    /// The format of log lines
+    #[structopt(long, global = true, default_value = "text", possible_values = &["text", "json"], env = "ACME_LOG_FORMAT")]
+    pub log_format: LogFormat,
     #[structopt(
         short,
         long,
@@ -27,18 +35,86 @@ pub struct Args {
         env = "ACME_CONFIG_DIR"
     )]
     pub config_dir: String,
-    #[structopt(long, value_name = "path", env = "ACME_CHALL_DIR")]
-    pub chall_dir: Option<String>,
+    /// Challenges are written to the first directory, the daemon also serves
+    /// challenges from the others
+    #[structopt(
+        long,
+        value_name = "path",
+        env = "ACME_CHALL_DIR",
+        number_of_values = 1
+    )]
+    pub chall_dir: Vec<String>,
     #[structopt(long, value_name = "path", env = "ACME_DATA_DIR")]
     pub data_dir: Option<String>,
     #[structopt(long, env = "ACME_URL")]
     pub acme_url: Option<String>,
     #[structopt(long, env = "ACME_EMAIL")]
     pub acme_email: Option<String>,
+    /// Renew certificates with less than this many days left
+    #[structopt(long, value_name = "days", env = "ACME_RENEW_IF_DAYS_LEFT")]
+    pub renew_if_days_left: Option<i64>,
+    /// The key identifier for external account binding
+    #[structopt(long, env = "ACME_EAB_KID")]
+    pub eab_kid: Option<String>,
+    /// The base64url encoded hmac key for external account binding
+    #[structopt(long, env = "ACME_EAB_HMAC_KEY", hide_env_values = true)]
+    pub eab_hmac_key: Option<String>,
+    /// Use the let's encrypt staging server, certificates and the account are
+    /// stored separately in the staging folder of the data directory
+    #[structopt(long, global = true)]
+    pub staging: bool,
+    /// Trust the certificates in this file for the acme server, in addition
+    /// to the public roots
+    #[structopt(long, value_name = "path", env = "ACME_CA_CERT")]
+    pub acme_ca_cert: Option<String>,
+    /// Trust the pem certificates in these files for the acme server, in
+    /// addition to the public roots, replaces `acme_ca_roots` of the config
+    #[structopt(long, value_name = "path", env = "ACME_CA_ROOT", number_of_values = 1)]
+    pub acme_ca_root: Vec<String>,
+    /// Don't verify the tls certificate of the acme server, only use this for
+    /// testing
+    #[structopt(long)]
+    pub accept_invalid_certs: bool,
+    /// Connect to the acme server from this local ip address
+    #[structopt(long, value_name = "ip", env = "ACME_SOURCE_ADDRESS")]
+    pub acme_source_address: Option<String>,
+    /// Connect to the acme server through this http:// or socks5:// proxy
+    #[structopt(
+        long,
+        value_name = "url",
+        env = "ACME_PROXY_URL",
+        hide_env_values = true
+    )]
+    pub acme_proxy_url: Option<String>,
+    /// How many seconds to wait if another instance is modifying the data
+    /// directory
+    #[structopt(long, global = true, value_name = "seconds", default_value = "300")]
+    pub lock_timeout: u64,
+    /// Exit immediately if another instance is modifying the data directory
+    #[structopt(long, global = true)]
+    pub no_wait: bool,
+    /// Don't change the group and modes of existing files in the data
+    /// directory, for setups that manage them externally
+    #[structopt(long, global = true)]
+    pub no_fix_permissions: bool,
     #[structopt(subcommand)]
     pub subcommand: SubCommand,
 }
 
+impl Args {
+    /// How long to wait for the lock on the data directory, None if we
+    /// shouldn't wait at all.
+    pub fn lock_wait(&self) -> Option<Duration> {
+        if self.no_wait {
+            None
+        } else {
+            Some(Duration::from_secs(self.lock_timeout))
+        }
+    }
+}
+
+// structopt can't box subcommands, the args are only parsed once anyway
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, StructOpt)]
 pub enum SubCommand {
     #[structopt(flatten)]
@@ -47,36 +123,372 @@ pub enum SubCommand {
     Completions(Completions),
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, StructOpt)]
 pub enum Cmd {
     /// Run the redirect daemon
     Daemon(DaemonArgs),
     /// Show the status of our certificates
-    Status,
+    Status(StatusArgs),
     /// Request new certificates if needed
     Renew(RenewArgs),
     /// Check if the challenges could be completed
+    #[structopt(visible_alias = "self-check")]
     Check(CheckArgs),
     /// Load the configuration and dump it to stdout as json
     DumpConfig,
+    /// Validate the configuration and report all problems found
+    CheckConfig(CheckConfigArgs),
+    /// Look for permission problems in the data and challenge directories
+    Doctor(DoctorArgs),
+    /// Manage the acme account
+    Account(AccountCmd),
+    /// Revoke a certificate
+    Revoke(RevokeArgs),
+    /// Switch a certificate back to its previous version
+    Rollback(RollbackArgs),
+    /// Import a certificate that was issued by another acme client
+    Import(ImportArgs),
+    /// Request a certificate for names that aren't in any config file
+    Issue(IssueArgs),
+    /// Delete old versions of certificates
+    Prune(PruneArgs),
+    /// Delete everything that belongs to a certificate from the data
+    /// directory
+    Remove(RemoveArgs),
+    /// Delete stale challenge proofs from the challenge directory
+    CleanChallenges(CleanChallengesArgs),
+    /// Print the path of a file of a live certificate
+    Path(PathArgs),
+    /// Retry the exec hooks that failed during earlier runs
+    RunHooks(RunHooksArgs),
+    /// Check that the web server presents the live certificates
+    VerifyDeployment(VerifyDeploymentArgs),
+}
+
+impl Cmd {
+    /// The positional arguments of the command that are names of configured
+    /// certificates.
+    pub fn cert_args(&self) -> Vec<&str> {
+        let certs = match self {
+            Cmd::Status(args) => &args.certs,
+            Cmd::Renew(args) => &args.certs,
+            Cmd::Check(args) => &args.certs,
+            Cmd::VerifyDeployment(args) => &args.certs,
+            Cmd::Prune(args) => &args.certs,
+            Cmd::Rollback(args) => return vec![args.cert.as_str()],
+            Cmd::Remove(args) => return vec![args.name.as_str()],
+            Cmd::Revoke(args) => return args.cert.iter().map(String::as_str).collect(),
+            Cmd::Path(args) => return args.name.iter().map(String::as_str).collect(),
+            _ => return Vec::new(),
+        };
+        certs.iter().map(String::as_str).collect()
+    }
+
+    /// If the command modifies certificates or accounts in the data
+    /// directory, these need to hold the lock.
+    pub fn modifies_data_dir(&self) -> bool {
+        match self {
+            Cmd::Renew(_)
+            | Cmd::Revoke(_)
+            | Cmd::Rollback(_)
+            | Cmd::Import(_)
+            | Cmd::Issue(_)
+            | Cmd::Prune(_)
+            | Cmd::Remove(_)
+            | Cmd::CleanChallenges(_)
+            | Cmd::RunHooks(_)
+            | Cmd::Account(AccountCmd::Rollover) => true,
+            Cmd::Doctor(args) => args.fix,
+            Cmd::Daemon(_)
+            | Cmd::Status(_)
+            | Cmd::Check(_)
+            | Cmd::VerifyDeployment(_)
+            | Cmd::DumpConfig
+            | Cmd::CheckConfig(_)
+            | Cmd::Path(_)
+            | Cmd::Account(AccountCmd::Show)
+            | Cmd::Account(AccountCmd::Update) => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct DaemonArgs {
-    /// The address to listen on
-    #[structopt(short = "B", long, default_value = "[::]:80", env = "ACME_BIND_ADDR")]
-    pub bind_addr: String,
+    /// The addresses to listen on, can be set multiple times or as a comma
+    /// separated list
+    #[structopt(
+        short = "B",
+        long,
+        default_value = "[::]:80",
+        env = "ACME_BIND_ADDR",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub bind_addr: Vec<SocketAddr>,
+    /// Listen on a unix domain socket instead of --bind-addr
+    #[structopt(long, value_name = "path", env = "ACME_BIND_UNIX")]
+    pub bind_unix: Option<PathBuf>,
+    /// The permissions of the unix domain socket (in octal)
+    #[structopt(long, value_name = "mode", default_value = "660", parse(try_from_str = parse_mode))]
+    pub bind_unix_mode: u32,
+    /// Only answer acme challenges on --bind-addr and --bind-unix, all other
+    /// requests are answered with 404 instead of being redirected
+    #[structopt(long)]
+    pub challenge_only: bool,
+    /// Additional addresses that only answer acme challenges, can be set
+    /// multiple times or as a comma separated list
+    #[structopt(
+        long,
+        value_name = "addr",
+        env = "ACME_BIND_CHALLENGE",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub bind_challenge: Vec<SocketAddr>,
+    /// Answer tls-alpn-01 challenges on this address, usually port 443
+    #[structopt(long, value_name = "addr", env = "ACME_BIND_TLS_ALPN")]
+    pub bind_tls_alpn: Option<SocketAddr>,
     /// Drop from root to this user
     #[structopt(long)]
     pub user: Option<String>,
     /// Chroot into the challenge directory
     #[structopt(long)]
     pub chroot: bool,
+    /// Expect a PROXY protocol (v1 or v2) header on every connection and log
+    /// the client address from it
+    #[structopt(long)]
+    pub proxy_protocol: bool,
+    /// Renew certificates in a helper process at this interval, like `renew`
+    /// from a timer, eg. `12h`
+    #[structopt(long, value_name = "duration", parse(try_from_str = parse_duration))]
+    pub renew_interval: Option<Duration>,
+    /// Wait up to this long in addition to --renew-interval, chosen randomly
+    /// for every run
+    #[structopt(
+        long,
+        value_name = "duration",
+        requires = "renew-interval",
+        parse(try_from_str = parse_duration)
+    )]
+    pub renew_jitter: Option<Duration>,
+    /// How long to wait for the lock on the data directory before a renew
+    /// run, this is set with the global --lock-timeout and --no-wait flags
+    #[structopt(skip)]
+    pub lock_wait: Option<Duration>,
+    /// Answer all requests except acme challenges with 503 while this file
+    /// exists
+    #[structopt(long, value_name = "path")]
+    pub maintenance_file: Option<PathBuf>,
+    /// Redirect the hosts in the [redirects] table of this toml file, the
+    /// file is reloaded on SIGHUP
+    #[structopt(long, value_name = "path")]
+    pub redirect_map: Option<PathBuf>,
+    /// Serve prometheus metrics on this address
+    #[structopt(long, env = "ACME_METRICS_ADDR")]
+    pub metrics_addr: Option<String>,
+    /// Accept challenge proofs pushed by renew on this address, requests
+    /// need the secret from chall_push_secret_file
+    #[structopt(long, env = "ACME_CONTROL_ADDR")]
+    pub control_addr: Option<String>,
+    /// Limit the requests per second of each client ip, requests above the
+    /// limit are answered with 429
+    #[structopt(long, value_name = "requests")]
+    pub rate_limit: Option<u32>,
+    /// How many requests a client can make at once before --rate-limit
+    /// applies, defaults to the rate
+    #[structopt(long, value_name = "requests")]
+    pub rate_limit_burst: Option<u32>,
+    /// Limit the acme challenge requests per second of each client ip, they
+    /// aren't limited by default
+    #[structopt(long, value_name = "requests")]
+    pub challenge_rate_limit: Option<u32>,
+    /// How many client ips are tracked for rate limiting, the least recently
+    /// seen ones are forgotten
+    #[structopt(long, value_name = "clients", default_value = "10000")]
+    pub rate_limit_clients: usize,
+    /// How long a client can take to start sending a request, in seconds,
+    /// slower clients are answered with 408 and disconnected
+    #[structopt(long, value_name = "secs", default_value = "5")]
+    pub client_request_timeout: u64,
+    /// How long a client can take to close the connection after the response,
+    /// in seconds
+    #[structopt(long, value_name = "secs", default_value = "1")]
+    pub client_disconnect_timeout: u64,
+    /// How long idle connections are kept open for further requests and how
+    /// long a client can pause while sending a request, in seconds, 0
+    /// disables keep-alive
+    #[structopt(long, value_name = "secs", default_value = "5")]
+    pub keep_alive: u64,
+    /// The maximum size of the request line and headers, larger requests are
+    /// answered with 431
+    #[structopt(long, value_name = "bytes", default_value = "8192")]
+    pub max_header_size: usize,
+}
+
+impl DaemonArgs {
+    /// The addresses from --bind-addr, without duplicates.
+    pub fn bind_addrs(&self) -> Vec<SocketAddr> {
+        dedup_addrs(&self.bind_addr)
+    }
+
+    /// The addresses from --bind-challenge, without duplicates.
+    pub fn challenge_addrs(&self) -> Vec<SocketAddr> {
+        dedup_addrs(&self.bind_challenge)
+    }
+}
+
+fn dedup_addrs(list: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for addr in list {
+        if !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+    addrs
+}
+
+/// A duration in seconds, or with one of the suffixes `s`, `m`, `h` and `d`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Invalid duration: {:?}", s),
+    };
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid duration: {:?}", s))?;
+    let secs = num
+        .checked_mul(factor)
+        .ok_or_else(|| anyhow!("Duration is too long: {:?}", s))?;
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_mode(s: &str) -> Result<u32> {
+    u32::from_str_radix(s, 8).with_context(|| anyhow!("Invalid file mode: {:?}", s))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LogFormat> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Unknown format: {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusFormat {
+    Table,
+    /// The unaligned format of older versions, one line per certificate
+    Plain,
+    Json,
+}
+
+impl FromStr for StatusFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StatusFormat> {
+        match s {
+            "table" => Ok(StatusFormat::Table),
+            "plain" => Ok(StatusFormat::Plain),
+            "json" => Ok(StatusFormat::Json),
+            _ => bail!("Unknown format: {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusSort {
+    Name,
+    DaysLeft,
+    Expiry,
+}
+
+impl FromStr for StatusSort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StatusSort> {
+        match s {
+            "name" => Ok(StatusSort::Name),
+            "days-left" => Ok(StatusSort::DaysLeft),
+            "expiry" => Ok(StatusSort::Expiry),
+            _ => bail!("Unknown sort order: {:?}", s),
+        }
+    }
 }
 
 #[derive(Debug, Clone, StructOpt)]
+pub struct StatusArgs {
+    /// The output format
+    #[structopt(long, default_value = "table", possible_values = &["table", "plain", "json"])]
+    pub format: StatusFormat,
+    /// Output json, this is the same as --format json
+    #[structopt(long)]
+    pub json: bool,
+    /// Send a notification for certificates below warn_if_days_left
+    #[structopt(long)]
+    pub notify: bool,
+    /// Don't ask the acme server for the suggested renewal windows (ARI)
+    #[structopt(long)]
+    pub skip_ari: bool,
+    /// Print a single line summary and report the health in the exit code,
+    /// like a monitoring plugin
+    #[structopt(long)]
+    pub check: bool,
+    /// Warn about certificates with this many days left or less, the
+    /// default is the renew threshold of each certificate
+    #[structopt(long, requires = "check")]
+    pub warn_days: Option<i64>,
+    /// Treat certificates with this many days left or less as critical, by
+    /// default only expired certificates are
+    #[structopt(long, requires = "check")]
+    pub crit_days: Option<i64>,
+    /// Also show the details of the live certificates, this is set with the
+    /// global --verbose flag
+    #[structopt(skip)]
+    pub verbose: bool,
+    /// Sort the table, by default certificates are shown in the order of the
+    /// config
+    #[structopt(long, possible_values = &["days-left", "name", "expiry"])]
+    pub sort: Option<StatusSort>,
+    /// Don't color the output, this is also the default if stdout isn't a
+    /// terminal or NO_COLOR is set
+    #[structopt(long)]
+    pub no_color: bool,
+    /// Only show specific certs, glob patterns like `web-*` are supported
+    pub certs: Vec<String>,
+}
+
+impl StatusArgs {
+    pub fn format(&self) -> StatusFormat {
+        if self.json {
+            StatusFormat::Json
+        } else {
+            self.format
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, StructOpt)]
 pub struct RenewArgs {
-    /// Do not actually do anything, just show what would happen
+    /// Complete the challenges, but don't request or install certificates and
+    /// don't execute any commands
     #[structopt(short = "n", long)]
     pub dry_run: bool,
     /// Renew certificates even if they are not about to expire
@@ -86,29 +498,520 @@ pub struct RenewArgs {
     /// Do not execute the configured exec commands
     #[structopt(long)]
     pub skip_restarts: bool,
+    /// Execute the configured exec commands even if no certificate changed
+    #[structopt(long, conflicts_with = "skip-restarts")]
+    pub force_exec: bool,
     /// Don't clean up old certs that are not live anymore
     #[structopt(long)]
     pub skip_cleanup: bool,
     /// Only execute hooks without actually renewing certs
     #[structopt(long)]
     pub hooks_only: bool,
-    /// Only renew specific certs
+    /// Renew certs with `must_staple` enabled if the live cert doesn't have
+    /// the must-staple extension
+    #[structopt(long)]
+    pub check_staple: bool,
+    /// Don't renew certs because the dns names of the live cert differ from
+    /// the configured dns_names
+    #[structopt(long)]
+    pub no_san_check: bool,
+    /// Verify that http-01 challenges are reachable before creating an
+    /// order, certs that fail the check are skipped
+    #[structopt(long)]
+    pub self_check: bool,
+    /// Don't check if the CAA records of the dns names allow the CA to issue
+    /// certificates before creating an order
+    #[structopt(long)]
+    pub skip_caa_check: bool,
+    /// Don't ask the acme server for its suggested renewal window (ARI),
+    /// only `renew_if_days_left` decides when certs are renewed
+    #[structopt(long)]
+    pub skip_ari: bool,
+    /// Renew up to this many certificates at the same time
+    #[structopt(long, default_value = "1")]
+    pub max_concurrent: usize,
+    /// Wait this many seconds between creating orders for different certs
+    #[structopt(long, value_name = "seconds", default_value = "0")]
+    pub delay_between: u64,
+    /// Renew even if the system clock differs from the acme server by more
+    /// than max_clock_skew
+    #[structopt(long)]
+    pub ignore_clock_skew: bool,
+    /// Write a json report of the run to this file, `-` for stdout
+    #[structopt(long, value_name = "path")]
+    pub report_json: Option<String>,
+    /// Exit successfully even if some certs failed to renew, for setups
+    /// that check the report instead
+    #[structopt(long)]
+    pub exit_zero_on_failure: bool,
+    /// Only renew specific certs, glob patterns like `web-*` are supported
     pub certs: Vec<String>,
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct CheckArgs {
-    /// Only check specific certs
+    /// Only check specific certs, glob patterns like `web-*` are supported
+    pub certs: Vec<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct VerifyDeploymentArgs {
+    /// Connect to this port instead of verify_port of the certs
+    #[structopt(long)]
+    pub port: Option<u16>,
+    /// Only verify specific certs, glob patterns like `web-*` are supported
     pub certs: Vec<String>,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+pub struct CheckConfigArgs {
+    /// Exit with an error if any warnings are found
+    #[structopt(long)]
+    pub warnings_as_errors: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct DoctorArgs {
+    /// Apply the fixes that only tighten permissions or create missing
+    /// directories
+    #[structopt(long)]
+    pub fix: bool,
+    /// Check that the challenge directory is readable by the user the daemon
+    /// runs as
+    #[structopt(long)]
+    pub user: Option<String>,
+    /// Check that private keys belong to this group and are readable by it,
+    /// eg. the group of the web server. Defaults to the group setting
+    #[structopt(long)]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum AccountCmd {
+    /// Show the account url and the thumbprint of the account key
+    Show,
+    /// Replace the account key with a newly generated one
+    Rollover,
+    /// Update the contact of the account to acme_email, or remove it if there's none
+    Update,
+}
+
+/// The revocation reasons from RFC 5280 that are accepted by acme servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RevokeReason {
+    Unspecified,
+    KeyCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+}
+
+impl RevokeReason {
+    pub fn code(self) -> u8 {
+        match self {
+            RevokeReason::Unspecified => 0,
+            RevokeReason::KeyCompromise => 1,
+            RevokeReason::AffiliationChanged => 3,
+            RevokeReason::Superseded => 4,
+            RevokeReason::CessationOfOperation => 5,
+        }
+    }
+}
+
+impl FromStr for RevokeReason {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RevokeReason> {
+        match s {
+            "unspecified" => Ok(RevokeReason::Unspecified),
+            "key-compromise" => Ok(RevokeReason::KeyCompromise),
+            "affiliation-changed" => Ok(RevokeReason::AffiliationChanged),
+            "superseded" => Ok(RevokeReason::Superseded),
+            "cessation-of-operation" => Ok(RevokeReason::CessationOfOperation),
+            _ => bail!("Unknown revocation reason: {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RevokeArgs {
+    /// The reason for the revocation
+    #[structopt(long, default_value = "unspecified", possible_values = &[
+        "unspecified",
+        "key-compromise",
+        "affiliation-changed",
+        "superseded",
+        "cessation-of-operation",
+    ])]
+    pub reason: RevokeReason,
+    /// Revoke the certificate in this file instead of a live certificate
+    #[structopt(long, value_name = "path", conflicts_with = "cert")]
+    pub cert_file: Option<PathBuf>,
+    /// The name of the live certificate to revoke
+    #[structopt(required_unless = "cert-file")]
+    pub cert: Option<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RollbackArgs {
+    /// Do not execute the configured exec commands
+    #[structopt(long)]
+    pub skip_restarts: bool,
+    /// The name of the certificate
+    pub cert: String,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ImportArgs {
+    /// The name of the configured certificate
+    #[structopt(long)]
+    pub name: String,
+    /// The certificate, optionally followed by its chain (like certbot's
+    /// fullchain.pem)
+    #[structopt(long, value_name = "path")]
+    pub cert: PathBuf,
+    /// The private key of the certificate
+    #[structopt(long, value_name = "path")]
+    pub key: PathBuf,
+    /// The intermediate certificates, if they're not in --cert
+    #[structopt(long, value_name = "path")]
+    pub chain: Option<PathBuf>,
+    /// Replace the live certificate if there already is one
+    #[structopt(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct IssueArgs {
+    /// The name of the certificate in the data directory
+    #[structopt(long)]
+    pub name: String,
+    /// The names the certificate is valid for, the first one is the common
+    /// name
+    #[structopt(
+        long = "dns-name",
+        value_name = "name",
+        required = true,
+        number_of_values = 1
+    )]
+    pub dns_names: Vec<String>,
+    /// Write a config file for the certificate into the config directory
+    #[structopt(long)]
+    pub save_config: bool,
+    /// Replace a configured or live certificate with the same name
+    #[structopt(long)]
+    pub force: bool,
+    /// Do not execute the configured exec commands
+    #[structopt(long)]
+    pub skip_restarts: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct PruneArgs {
+    /// Only show which versions would be deleted
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+    /// Only prune specific certs, glob patterns like `web-*` are supported
+    pub certs: Vec<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RemoveArgs {
+    /// Revoke the live certificate before deleting it
+    #[structopt(long)]
+    pub revoke: bool,
+    /// The reason for the revocation
+    #[structopt(long, default_value = "unspecified", possible_values = &[
+        "unspecified",
+        "key-compromise",
+        "affiliation-changed",
+        "superseded",
+        "cessation-of-operation",
+    ])]
+    pub reason: RevokeReason,
+    /// Don't ask for confirmation
+    #[structopt(short = "y", long)]
+    pub yes: bool,
+    /// The name of the certificate
+    pub name: String,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct CleanChallengesArgs {
+    /// Only show which proofs would be deleted
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+    /// Delete proofs older than this, the default is chall_max_age
+    #[structopt(long, value_name = "seconds")]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct PathArgs {
+    /// The file of the certificate
+    #[structopt(long, default_value = "fullchain", possible_values = &["fullchain", "privkey", "chain", "cert"])]
+    pub kind: LiveFile,
+    /// Resolve the live symlink to the folder of the current version
+    #[structopt(long)]
+    pub resolve: bool,
+    /// Print the name and path of every configured certificate, separated by
+    /// a tab
+    #[structopt(long, conflicts_with = "name")]
+    pub all: bool,
+    /// The name of the certificate
+    #[structopt(required_unless = "all")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RunHooksArgs {
+    /// Only show which hooks would be executed
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+}
+
 #[derive(Debug, Clone, StructOpt)]
 pub struct Completions {
     #[structopt(possible_values=&Shell::variants())]
     pub shell: Shell,
 }
 
+/// The hidden subcommand the completions call as `__complete-certs --
+/// <words>...`, it's handled before the arguments are parsed since clap
+/// can't generate completions for it.
+pub const COMPLETE_CERTS: &str = "__complete-certs";
+
+/// Cert names are completed by calling `__complete-certs` with the command
+/// line, the static completions of clap are used for everything else.
+const BASH_CERTS: &str = r#"
+_acme-redirect_certs() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" certs
+    if [[ "$cur" != -* ]]; then
+        certs="$("${COMP_WORDS[0]}" __complete-certs -- "${COMP_WORDS[@]:1:COMP_CWORD-1}" 2>/dev/null)"
+        if [[ -n "$certs" ]]; then
+            COMPREPLY=($(compgen -W "$certs" -- "$cur"))
+            return 0
+        fi
+    fi
+    _acme-redirect "$@"
+}
+
+complete -F _acme-redirect_certs -o bashdefault -o default acme-redirect
+"#;
+
+const ZSH_CERTS: &str = r#"
+_acme-redirect() {
+    local -a certs
+    if [[ "$PREFIX" != -* ]]; then
+        certs=(${(f)"$("${words[1]}" __complete-certs -- "${(@)words[2,CURRENT-1]}" 2>/dev/null)"})
+        if (( ${#certs} )); then
+            compadd -a certs
+            return
+        fi
+    fi
+    _acme-redirect_static "$@"
+}
+
+_acme-redirect "$@"
+"#;
+
+const FISH_CERTS: &str = r#"
+function __acme_redirect_certs
+    set -l words (commandline -opc)
+    $words[1] __complete-certs -- $words[2..-1] 2>/dev/null
+end
+complete -c acme-redirect -n 'test -n "$(__acme_redirect_certs)"' -f -a '(__acme_redirect_certs)'
+"#;
+
+/// The completion script for a shell, cert names are only completed
+/// dynamically in bash, zsh and fish.
+pub fn completions(shell: Shell) -> Result<String> {
+    let mut buf = Vec::new();
+    Args::clap().gen_completions_to("acme-redirect", shell, &mut buf);
+    let mut script = String::from_utf8(buf)?;
+    match shell {
+        Shell::Bash => script.push_str(BASH_CERTS),
+        Shell::Zsh => {
+            // the generated function is wrapped, it's called at the end
+            script = script
+                .replacen("\n_acme-redirect() {", "\n_acme-redirect_static() {", 1)
+                .trim_end()
+                .trim_end_matches("_acme-redirect \"$@\"")
+                .to_string();
+            script.push_str(ZSH_CERTS);
+        }
+        Shell::Fish => script.push_str(FISH_CERTS),
+        _ => (),
+    }
+    Ok(script)
+}
+
 pub fn gen_completions(args: &Completions) -> Result<()> {
-    Args::clap().gen_completions_to("acme-redirect", args.shell, &mut stdout());
+    stdout().write_all(completions(args.shell)?.as_bytes())?;
     Ok(())
 }
+
+/// Complete the name of a certificate after `words` if that's where the
+/// subcommand expects one. The command line is parsed with a placeholder in
+/// place of the word that's completed, so options and their values are
+/// handled like in a real run.
+pub fn complete_certs(words: &[String]) -> Vec<String> {
+    const PLACEHOLDER: &str = "\u{0}placeholder";
+    let argv = std::iter::once("acme-redirect")
+        .chain(words.iter().map(String::as_str))
+        .chain(std::iter::once(PLACEHOLDER));
+    let args = match Args::from_iter_safe(argv) {
+        Ok(args) => args,
+        Err(_) => return Vec::new(),
+    };
+    let cmd = match &args.subcommand {
+        SubCommand::Cmds(cmd) => cmd,
+        _ => return Vec::new(),
+    };
+    if !cmd.cert_args().contains(&PLACEHOLDER) {
+        return Vec::new();
+    }
+    crate::config::cert_names(&args)
+        .into_iter()
+        // certs that were already given
+        .filter(|name| !cmd.cert_args().contains(&name.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon_args(args: &[&str]) -> DaemonArgs {
+        let args = Args::from_iter_safe(["acme-redirect", "daemon"].iter().chain(args)).unwrap();
+        match args.subcommand {
+            SubCommand::Cmds(Cmd::Daemon(args)) => args,
+            _ => panic!("not the daemon subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::from_secs(43200));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        for s in &["", "h", "12hours", "1.5h", "-1h", "12 h"] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_renew_interval() {
+        let args = daemon_args(&[]);
+        assert_eq!(args.renew_interval, None);
+        let args = daemon_args(&["--renew-interval", "12h", "--renew-jitter", "1h"]);
+        assert_eq!(args.renew_interval, Some(Duration::from_secs(43200)));
+        assert_eq!(args.renew_jitter, Some(Duration::from_secs(3600)));
+
+        let args = ["acme-redirect", "daemon", "--renew-jitter", "1h"];
+        assert!(Args::from_iter_safe(args.iter()).is_err());
+    }
+
+    #[test]
+    fn test_bind_addr_default() {
+        let args = daemon_args(&[]);
+        assert_eq!(args.bind_addrs(), vec!["[::]:80".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_multiple_bind_addrs() {
+        let args = daemon_args(&[
+            "-B",
+            "0.0.0.0:80",
+            "--bind-addr",
+            "[::]:80,127.0.0.1:8080",
+            "-B",
+            "0.0.0.0:80",
+        ]);
+        assert_eq!(
+            args.bind_addrs(),
+            vec![
+                "0.0.0.0:80".parse::<SocketAddr>().unwrap(),
+                "[::]:80".parse().unwrap(),
+                "127.0.0.1:8080".parse().unwrap(),
+            ]
+        );
+        assert!(Args::from_iter_safe(&["acme-redirect", "daemon", "-B", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn test_bind_challenge() {
+        let args = daemon_args(&[]);
+        assert!(!args.challenge_only);
+        assert!(args.challenge_addrs().is_empty());
+
+        let args = daemon_args(&[
+            "--bind-challenge",
+            "127.0.0.1:402,[::1]:402",
+            "--bind-challenge",
+            "127.0.0.1:402",
+        ]);
+        assert_eq!(args.bind_addrs(), vec!["[::]:80".parse().unwrap()]);
+        assert_eq!(
+            args.challenge_addrs(),
+            vec![
+                "127.0.0.1:402".parse::<SocketAddr>().unwrap(),
+                "[::1]:402".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complete_certs() {
+        let dir = tempfile::tempdir().unwrap();
+        let certs = dir.path().join("certs.d");
+        std::fs::create_dir(&certs).unwrap();
+        for name in &["b.example.com", "a.example.com"] {
+            let conf = format!("[cert]\nname = \"{}\"\ndns_names = [\"{}\"]\n", name, name);
+            std::fs::write(certs.join(format!("{}.conf", name)), conf).unwrap();
+        }
+        std::fs::write(certs.join("broken.conf"), "[cert\n").unwrap();
+
+        let config = dir.path().join("acme-redirect.conf");
+        let complete = |words: &[&str]| {
+            let mut argv = vec![
+                "--config".to_string(),
+                config.to_str().unwrap().to_string(),
+                "--config-dir".to_string(),
+                certs.to_str().unwrap().to_string(),
+            ];
+            argv.extend(words.iter().map(|w| w.to_string()));
+            complete_certs(&argv)
+        };
+        assert_eq!(
+            complete(&["status"]),
+            vec!["a.example.com", "b.example.com"]
+        );
+        assert_eq!(
+            complete(&["renew", "-n", "a.example.com"]),
+            vec!["b.example.com"]
+        );
+        assert_eq!(
+            complete(&["rollback"]),
+            vec!["a.example.com", "b.example.com"]
+        );
+        // the value of an option
+        assert!(complete(&["status", "--sort"]).is_empty());
+        assert!(complete(&["daemon"]).is_empty());
+        assert!(complete(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_completions() {
+        let bash = completions(Shell::Bash).unwrap();
+        assert!(bash.contains("complete -F _acme-redirect_certs"));
+        let zsh = completions(Shell::Zsh).unwrap();
+        assert!(zsh.contains("\n_acme-redirect_static() {"));
+        assert!(zsh.trim_end().ends_with("_acme-redirect \"$@\""));
+        assert_eq!(zsh.matches("\n_acme-redirect \"$@\"").count(), 1);
+        let fish = completions(Shell::Fish).unwrap();
+        assert!(fish.contains(COMPLETE_CERTS));
+    }
+}
@@ -0,0 +1,296 @@
+//! ACME Renewal Information (RFC 9773), the CA suggests a window in which a
+//! certificate should be renewed, e.g. earlier than usual if it's going to be
+//! revoked. Responses are cached in the data dir until the server wants to be
+//! asked again.
+use crate::acme::client::Client;
+use crate::acme::jws::base64url;
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::{FilePersist, LiveFile};
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// How long a response is used if the server didn't send Retry-After
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(6 * 3600);
+/// The bounds for Retry-After, as recommended in RFC 9773 section 4.3.2
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(60);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// The unique identifier of a certificate, the key identifier of the issuer
+/// and the DER encoded serial number, both base64url encoded.
+fn encode_cert_id(key_id: &[u8], serial: &[u8]) -> String {
+    let mut der = Vec::with_capacity(serial.len() + 1);
+    // the serial number is a positive integer
+    let pad = match serial.first() {
+        Some(b) => b & 0x80 != 0,
+        None => true,
+    };
+    if pad {
+        der.push(0);
+    }
+    der.extend_from_slice(serial);
+    format!("{}.{}", base64url(key_id), base64url(der))
+}
+
+pub fn cert_id(cert: &X509) -> Result<String> {
+    let key_id = cert
+        .authority_key_id()
+        .ok_or_else(|| anyhow!("Certificate has no authority key identifier"))?;
+    let serial = cert.serial_number().to_bn()?.to_vec();
+    Ok(encode_cert_id(key_id.as_slice(), &serial))
+}
+
+/// Parse a RFC 3339 timestamp into seconds since the epoch.
+fn parse_time(value: &str) -> Result<i64> {
+    let (datetime, offset) = if let Some(datetime) = value.strip_suffix(&['Z', 'z'][..]) {
+        (datetime, 0)
+    } else {
+        let idx = value
+            .rfind(&['+', '-'][..])
+            .filter(|idx| *idx > 10)
+            .ok_or_else(|| anyhow!("Timestamp has no timezone: {:?}", value))?;
+        let (datetime, offset) = value.split_at(idx);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..]
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid timezone: {:?}", value))?;
+        let offset = hours.parse::<i64>()? * 3600 + minutes.parse::<i64>()? * 60;
+        (datetime, sign * offset)
+    };
+    // fractions of a second don't matter
+    let datetime = datetime.split('.').next().unwrap_or(datetime);
+    let tm = time::strptime(datetime, "%Y-%m-%dT%H:%M:%S")
+        .with_context(|| anyhow!("Invalid timestamp: {:?}", value))?;
+    Ok(tm.to_timespec().sec - offset)
+}
+
+/// The suggested renewal window, as sent by the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    pub start: String,
+    pub end: String,
+    pub explanation_url: Option<String>,
+}
+
+impl Window {
+    fn validate(&self) -> Result<()> {
+        let start = parse_time(&self.start)?;
+        let end = parse_time(&self.end)?;
+        if end < start {
+            bail!("Renewal window ends before it starts");
+        }
+        Ok(())
+    }
+
+    /// A renewal is due once the window started.
+    pub fn has_started(&self, now: i64) -> bool {
+        parse_time(&self.start)
+            .map(|start| now >= start)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cached {
+    cert_id: String,
+    window: Window,
+    /// Don't ask the server again before this time, in seconds since the
+    /// epoch
+    next_update: i64,
+}
+
+fn load_cached(persist: &FilePersist, name: &str) -> Option<Cached> {
+    let json = match persist.load_renewal_info(name) {
+        Ok(json) => json?,
+        Err(err) => {
+            debug!("{:?}: Failed to read cached renewal info: {:#}", name, err);
+            return None;
+        }
+    };
+    match serde_json::from_str::<Cached>(&json) {
+        Ok(cached) if cached.window.validate().is_ok() => Some(cached),
+        _ => {
+            debug!("{:?}: Ignoring invalid cached renewal info", name);
+            None
+        }
+    }
+}
+
+fn next_update(now: i64, retry_after: Option<Duration>) -> i64 {
+    let delay = retry_after
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+        .max(MIN_RETRY_AFTER)
+        .min(MAX_RETRY_AFTER);
+    now + delay.as_secs() as i64
+}
+
+/// The suggested renewal window of the live certificate, None if there's no
+/// live certificate or the acme server doesn't support ARI.
+pub fn lookup(config: &Config, cert: &CertConfig, persist: &FilePersist) -> Result<Option<Window>> {
+    let path = persist.live_file(&cert.name, LiveFile::Fullchain);
+    let pem = match fs::read(&path) {
+        Ok(pem) => pem,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| anyhow!("Failed to read {:?}", path)),
+    };
+    let cert_id = cert_id(&X509::from_pem(&pem)?)?;
+
+    let now = time::get_time().sec;
+    if let Some(cached) = load_cached(persist, &cert.name) {
+        if cached.cert_id == cert_id && now < cached.next_update {
+            debug!("{:?}: Using cached renewal info", cert.name);
+            return Ok(Some(cached.window));
+        }
+    }
+
+    let http = config.acme.http_client()?;
+    let client = Client::with_http(cert.acme_url(&config.acme), &http)?;
+    let (info, retry_after) = match client.renewal_info(&cert_id)? {
+        Some(info) => info,
+        None => {
+            debug!("{:?}: acme server doesn't support ARI", cert.name);
+            return Ok(None);
+        }
+    };
+    let window = Window {
+        start: info.suggested_window.start,
+        end: info.suggested_window.end,
+        explanation_url: info.explanation_url,
+    };
+    window
+        .validate()
+        .context("acme server sent an invalid renewal window")?;
+
+    let cached = Cached {
+        cert_id,
+        window: window.clone(),
+        next_update: next_update(now, retry_after),
+    };
+    // status may run without write access to the data dir
+    if let Err(err) = persist.store_renewal_info(&cert.name, &serde_json::to_string(&cached)?) {
+        debug!("{:?}: Failed to cache renewal info: {:#}", cert.name, err);
+    }
+    Ok(Some(window))
+}
+
+/// Like `lookup`, but errors are logged and treated like a server without
+/// ARI support, so the renewal falls back to `renew_if_days_left`.
+pub fn suggested_window(
+    config: &Config,
+    cert: &CertConfig,
+    persist: &FilePersist,
+) -> Option<Window> {
+    match lookup(config, cert, persist) {
+        Ok(window) => window,
+        Err(err) => {
+            warn!("{:?}: Failed to fetch renewal info: {:#}", cert.name, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use std::path::PathBuf;
+
+    fn window(start: &str, end: &str) -> Window {
+        Window {
+            start: start.to_string(),
+            end: end.to_string(),
+            explanation_url: None,
+        }
+    }
+
+    #[test]
+    fn test_cert_id() {
+        // the example of RFC 9773 section 4.1
+        let key_id = [
+            0x69, 0x88, 0x5b, 0x6b, 0x87, 0x46, 0x40, 0x41, 0xe1, 0xb3, 0x7b, 0x84, 0x7b, 0xa0,
+            0xae, 0x2c, 0xde, 0x01, 0xc8, 0xd4,
+        ];
+        assert_eq!(
+            encode_cert_id(&key_id, &[0x87, 0x65, 0x43, 0x21]),
+            "aYhba4dGQEHhs3uEe6CuLN4ByNQ.AIdlQyE"
+        );
+        assert_eq!(encode_cert_id(&[0x01], &[0x12, 0x34]), "AQ.EjQ");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2021-01-03T00:00:00Z").unwrap(), 1609632000);
+        assert_eq!(
+            parse_time("2021-01-03T00:00:00.123456Z").unwrap(),
+            1609632000
+        );
+        assert_eq!(parse_time("2021-01-03T02:00:00+02:00").unwrap(), 1609632000);
+        assert_eq!(parse_time("2021-01-02T23:30:00-00:30").unwrap(), 1609632000);
+        assert!(parse_time("2021-01-03T00:00:00").is_err());
+        assert!(parse_time("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let w = window("2021-01-03T00:00:00Z", "2021-01-07T00:00:00Z");
+        assert!(w.validate().is_ok());
+        assert!(!w.has_started(1609631999));
+        assert!(w.has_started(1609632000));
+        assert!(window("2021-01-07T00:00:00Z", "2021-01-03T00:00:00Z")
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_next_update() {
+        assert_eq!(next_update(1000, None), 1000 + 6 * 3600);
+        assert_eq!(next_update(1000, Some(Duration::from_secs(1))), 1060);
+        assert_eq!(
+            next_update(1000, Some(Duration::from_secs(7 * 24 * 3600))),
+            1000 + 24 * 3600
+        );
+    }
+
+    #[test]
+    fn test_cached_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::for_cert(&config, &cert);
+        // no live cert, nothing to ask for
+        assert_eq!(lookup(&config, &cert, &persist).unwrap(), None);
+
+        let w = window("2021-01-03T00:00:00Z", "2021-01-07T00:00:00Z");
+        let cached = Cached {
+            cert_id: "AQ.EjQ".to_string(),
+            window: w.clone(),
+            next_update: time::get_time().sec + 3600,
+        };
+        persist
+            .store_renewal_info("example.com", &serde_json::to_string(&cached).unwrap())
+            .unwrap();
+        assert_eq!(load_cached(&persist, "example.com").unwrap().window, w);
+
+        persist
+            .store_renewal_info("example.com", "{\"cert_id\":")
+            .unwrap();
+        assert!(load_cached(&persist, "example.com").is_none());
+    }
+}
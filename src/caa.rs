@@ -0,0 +1,365 @@
+//! Check the CAA records of a certificate's dns names before creating an
+//! order, so a restrictive record fails the renew right away instead of
+//! during finalization (RFC 8659).
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use std::fmt;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::rr::rdata::caa::{Value, CAA};
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::system_conf;
+use trust_dns_resolver::Resolver;
+
+/// Issuer domain names of common acme servers, if it's not the domain of the
+/// directory url.
+const KNOWN_IDENTITIES: &[(&str, &str)] = &[
+    ("letsencrypt.org", "letsencrypt.org"),
+    ("zerossl.com", "sectigo.com"),
+    ("buypass.com", "buypass.com"),
+    ("pki.goog", "pki.goog"),
+    ("ssl.com", "ssl.com"),
+];
+
+fn url_host(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+/// Guess the issuer domain name a CA uses in CAA records from its directory
+/// url, this is the registered domain of the host for unknown servers.
+pub fn ca_identity(acme_url: &str) -> Option<String> {
+    let host = url_host(acme_url)?
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    for (domain, identity) in KNOWN_IDENTITIES {
+        if host == *domain || host.ends_with(&format!(".{}", domain)) {
+            return Some(identity.to_string());
+        }
+    }
+    let labels = host.rsplit('.').take(2).collect::<Vec<_>>();
+    Some(labels.into_iter().rev().collect::<Vec<_>>().join("."))
+}
+
+/// The result of the check for a single dns name.
+#[derive(Debug, PartialEq)]
+pub enum Authorization {
+    /// Neither the name nor any of its parents have CAA records
+    NoRecords,
+    /// The records at this domain authorize the CA
+    Authorized(String),
+    /// The records at this domain only authorize these issuers
+    Forbidden(String, Vec<String>),
+}
+
+impl Authorization {
+    pub fn is_permitted(&self) -> bool {
+        !matches!(self, Authorization::Forbidden(..))
+    }
+}
+
+impl fmt::Display for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Authorization::NoRecords => write!(f, "no CAA records, permitted"),
+            Authorization::Authorized(domain) => {
+                write!(f, "permitted by CAA records at {:?}", domain)
+            }
+            Authorization::Forbidden(domain, issuers) if issuers.is_empty() => {
+                write!(f, "CAA records at {:?} don't allow any issuer", domain)
+            }
+            Authorization::Forbidden(domain, issuers) => write!(
+                f,
+                "CAA records at {:?} only allow {}",
+                domain,
+                issuers.join(", ")
+            ),
+        }
+    }
+}
+
+fn same_domain(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Decide if the CAA records of the relevant record set allow `identity` to
+/// issue a certificate for the name, returns the allowed issuers if not.
+fn authorizes(records: &[CAA], identity: &str, wildcard: bool) -> Result<(), Vec<String>> {
+    // an unknown property that's marked as critical has to be understood
+    if records
+        .iter()
+        .any(|caa| caa.issuer_critical() && caa.tag().is_unknown())
+    {
+        return Err(Vec::new());
+    }
+
+    let has_issuewild = records.iter().any(|caa| caa.tag().is_issuewild());
+    let relevant = records
+        .iter()
+        .filter(|caa| {
+            if wildcard && has_issuewild {
+                caa.tag().is_issuewild()
+            } else {
+                caa.tag().is_issue()
+            }
+        })
+        .collect::<Vec<_>>();
+    if relevant.is_empty() {
+        return Ok(());
+    }
+
+    let mut issuers = Vec::new();
+    for caa in relevant {
+        if let Value::Issuer(Some(name), _) = caa.value() {
+            let name = name.to_ascii();
+            if same_domain(&name, identity) {
+                return Ok(());
+            }
+            let name = name.trim_end_matches('.').to_string();
+            if !issuers.contains(&name) {
+                issuers.push(name);
+            }
+        }
+    }
+    Err(issuers)
+}
+
+/// Check a single dns name, `lookup` returns the CAA records of a domain and
+/// is expected to follow CNAMEs. The name and then each of its parents are
+/// looked up until a domain has records.
+pub fn check_name<F>(name: &str, identity: &str, mut lookup: F) -> Result<Authorization>
+where
+    F: FnMut(&str) -> Result<Vec<CAA>>,
+{
+    let (wildcard, name) = match name.strip_prefix("*.") {
+        Some(name) => (true, name),
+        None => (false, name),
+    };
+    let name = name.trim_end_matches('.');
+
+    let mut domain = name;
+    loop {
+        let records = lookup(domain)
+            .with_context(|| anyhow!("Failed to lookup CAA records of {:?}", domain))?;
+        if !records.is_empty() {
+            return match authorizes(&records, identity, wildcard) {
+                Ok(()) => Ok(Authorization::Authorized(domain.to_string())),
+                Err(issuers) => Ok(Authorization::Forbidden(domain.to_string(), issuers)),
+            };
+        }
+        match domain.split_once('.') {
+            // the root zone isn't checked
+            Some((_, parent)) if !parent.is_empty() => domain = parent,
+            _ => return Ok(Authorization::NoRecords),
+        }
+    }
+}
+
+fn resolve(resolver: &Resolver, domain: &str) -> Result<Vec<CAA>> {
+    // look up the absolute name, the search domains don't apply
+    let lookup = match resolver.lookup(&format!("{}.", domain), RecordType::CAA) {
+        Ok(lookup) => lookup,
+        Err(err) => {
+            if let ResolveErrorKind::NoRecordsFound { .. } = err.kind() {
+                return Ok(Vec::new());
+            }
+            return Err(err.into());
+        }
+    };
+    // the answer also contains the CNAME records that were followed
+    Ok(lookup
+        .iter()
+        .filter_map(|rdata| match rdata {
+            RData::CAA(caa) => Some(caa.clone()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Check the CAA records of every dns name of the cert and log the result,
+/// fails if any of them doesn't authorize the CA. If the records couldn't be
+/// resolved a warning is logged and the order is attempted anyway.
+pub fn check_cert(config: &Config, cert: &CertConfig) -> Result<()> {
+    let identity = match cert.caa_identity(&config.acme) {
+        Some(identity) => identity,
+        None => {
+            warn!(
+                "{:?}: Skipping CAA check, the CA's issuer domain name is unknown",
+                cert.name
+            );
+            return Ok(());
+        }
+    };
+
+    let (resolver_config, opts) =
+        system_conf::read_system_conf().context("Failed to read resolver config")?;
+    let resolver = Resolver::new(resolver_config, opts).context("Failed to setup resolver")?;
+
+    let dns_names = cert.ascii_dns_names()?;
+    let mut forbidden = Vec::new();
+    for dns_name in &dns_names {
+        match check_name(dns_name, &identity, |domain| resolve(&resolver, domain)) {
+            Ok(result) if result.is_permitted() => {
+                info!("{:?}: CAA check for {:?}: {}", cert.name, dns_name, result);
+            }
+            Ok(result) => {
+                error!("{:?}: CAA check for {:?}: {}", cert.name, dns_name, result);
+                forbidden.push(dns_name.as_str());
+            }
+            Err(err) => {
+                warn!(
+                    "{:?}: CAA check for {:?} is inconclusive: {:#}",
+                    cert.name, dns_name, err
+                );
+                // the other names would most likely fail the same way
+                break;
+            }
+        }
+    }
+
+    if !forbidden.is_empty() {
+        bail!(
+            "CAA records don't allow {:?} to issue certificates for {:?}, not creating an order",
+            identity,
+            forbidden
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use trust_dns_resolver::proto::rr::rdata::caa::Property;
+    use trust_dns_resolver::Name;
+
+    fn issue(name: &str) -> CAA {
+        CAA::new_issue(false, Some(Name::from_str(name).unwrap()), vec![])
+    }
+
+    fn issuewild(name: &str) -> CAA {
+        CAA::new_issuewild(false, Some(Name::from_str(name).unwrap()), vec![])
+    }
+
+    fn check(name: &str, zones: &[(&str, Vec<CAA>)]) -> Authorization {
+        let zones = zones.iter().cloned().collect::<HashMap<_, _>>();
+        check_name(name, "letsencrypt.org", |domain| {
+            Ok(zones.get(domain).cloned().unwrap_or_default())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ca_identity() {
+        assert_eq!(
+            ca_identity("https://acme-v02.api.letsencrypt.org/directory").as_deref(),
+            Some("letsencrypt.org")
+        );
+        assert_eq!(
+            ca_identity("https://acme-staging-v02.api.letsencrypt.org/directory").as_deref(),
+            Some("letsencrypt.org")
+        );
+        assert_eq!(
+            ca_identity("https://acme.zerossl.com/v2/DV90").as_deref(),
+            Some("sectigo.com")
+        );
+        assert_eq!(
+            ca_identity("https://acme.internal.example.com:8443/dir").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(ca_identity("https:///dir"), None);
+    }
+
+    #[test]
+    fn test_no_records() {
+        assert_eq!(check("www.example.com", &[]), Authorization::NoRecords);
+    }
+
+    #[test]
+    fn test_parent_records() {
+        let zones = [("example.com", vec![issue("letsencrypt.org")])];
+        assert_eq!(
+            check("a.b.example.com", &zones),
+            Authorization::Authorized("example.com".to_string())
+        );
+
+        // the closest records are relevant, even if a parent would allow it
+        let zones = [
+            ("example.com", vec![issue("letsencrypt.org")]),
+            ("b.example.com", vec![issue("pki.goog.")]),
+        ];
+        assert_eq!(
+            check("a.b.example.com", &zones),
+            Authorization::Forbidden("b.example.com".to_string(), vec!["pki.goog".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_forbidden() {
+        let zones = [(
+            "example.com",
+            vec![
+                CAA::new_issue(false, None, vec![]),
+                issuewild("letsencrypt.org"),
+            ],
+        )];
+        assert_eq!(
+            check("example.com", &zones),
+            Authorization::Forbidden("example.com".to_string(), vec![])
+        );
+        assert!(!check("example.com", &zones).is_permitted());
+        // issuewild takes precedence for wildcard names
+        assert_eq!(
+            check("*.example.com", &zones),
+            Authorization::Authorized("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_only_iodef() {
+        let zones = [(
+            "example.com",
+            vec![CAA::new_iodef(
+                false,
+                "mailto:security@example.com".parse().unwrap(),
+            )],
+        )];
+        assert_eq!(
+            check("example.com", &zones),
+            Authorization::Authorized("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_critical_unknown_property() {
+        let critical = CAA {
+            issuer_critical: true,
+            tag: Property::Unknown("tbs".to_string()),
+            value: Value::Unknown(b"Unknown".to_vec()),
+        };
+        let zones = [("example.com", vec![issue("letsencrypt.org"), critical])];
+        assert!(!check("example.com", &zones).is_permitted());
+    }
+
+    #[test]
+    fn test_lookup_error() {
+        let err =
+            check_name("www.example.com", "letsencrypt.org", |_| bail!("SERVFAIL")).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to lookup CAA records of \"www.example.com\": SERVFAIL"
+        );
+    }
+}
@@ -1,10 +1,373 @@
+use crate::config::{CertConfig, KeyType};
 use crate::errors::*;
-use openssl::x509::X509;
+use foreign_types::ForeignTypeRef;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey};
+use openssl::x509::{X509Ref, X509VerifyResult, X509};
+use openssl_sys as ffi;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::os::raw::{c_int, c_long, c_uchar, c_void};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct CertInfo {
-    pub not_before: String,
+    pub not_before: time::Tm,
     pub expires: time::Tm,
+    pub key_type: Option<KeyType>,
+    pub must_staple: bool,
+    /// The common name of the CA that issued the certificate
+    pub issuer: Option<String>,
+    /// The issuer of the topmost certificate in the chain
+    pub chain_issuer: Option<String>,
+    /// The dns names of the certificate, lowercased
+    pub dns_names: Vec<String>,
+}
+
+/// NID_tlsfeature, the TLS Feature extension from RFC 7633. openssl-sys
+/// doesn't export it.
+const NID_TLSFEATURE: c_int = 1020;
+const TLS_FEATURE_STATUS_REQUEST: c_long = 5;
+
+unsafe extern "C" fn free_integer(integer: *mut c_void) {
+    ffi::ASN1_INTEGER_free(integer as *mut ffi::ASN1_INTEGER);
+}
+
+/// Check if the certificate has the TLS Feature extension with
+/// status_request, also known as OCSP must-staple.
+fn detect_must_staple(x509: &X509Ref) -> Result<bool> {
+    let mut crit = 0;
+    // the extension is decoded as a stack of ASN1_INTEGER
+    let features =
+        unsafe { ffi::X509_get_ext_d2i(x509.as_ptr(), NID_TLSFEATURE, &mut crit, ptr::null_mut()) }
+            as *mut ffi::OPENSSL_STACK;
+    if features.is_null() {
+        // -1 means the extension isn't present
+        if crit == -1 {
+            return Ok(false);
+        }
+        bail!("Invalid TLS Feature extension");
+    }
+
+    unsafe {
+        let found = (0..ffi::OPENSSL_sk_num(features)).any(|i| {
+            let feature = ffi::OPENSSL_sk_value(features, i) as *const ffi::ASN1_INTEGER;
+            ffi::ASN1_INTEGER_get(feature) == TLS_FEATURE_STATUS_REQUEST
+        });
+        ffi::OPENSSL_sk_pop_free(features, Some(free_integer));
+        Ok(found)
+    }
+}
+
+/// NID_ct_precert_scts, the embedded SCT list from RFC 6962.
+const NID_CT_PRECERT_SCTS: c_int = 951;
+const SCT_VERSION_V1: c_int = 0;
+
+// openssl-sys doesn't have bindings for the certificate transparency api
+extern "C" {
+    fn SCT_get_version(sct: *const c_void) -> c_int;
+    fn SCT_get0_log_id(sct: *const c_void, log_id: *mut *mut c_uchar) -> usize;
+    fn SCT_get_timestamp(sct: *const c_void) -> u64;
+    fn SCT_LIST_free(list: *mut ffi::OPENSSL_STACK);
+}
+
+/// The minimum number of embedded SCTs if min_scts isn't set, browsers
+/// require at least two from different logs.
+pub const DEFAULT_MIN_SCTS: usize = 2;
+
+/// A signed certificate timestamp embedded in a certificate, the promise of
+/// a certificate transparency log to publish it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sct {
+    /// The SHA-256 digest of the public key of the log, in base64
+    pub log_id: String,
+    pub timestamp: String,
+}
+
+impl fmt::Display for Sct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "log_id={} timestamp={}", self.log_id, self.timestamp)
+    }
+}
+
+/// Read a SCT of the list that was decoded by openssl.
+unsafe fn read_sct(sct: *const c_void) -> Result<Sct> {
+    let version = SCT_get_version(sct);
+    if version != SCT_VERSION_V1 {
+        bail!("Unsupported SCT version: {}", version);
+    }
+    let mut log_id = ptr::null_mut();
+    let len = SCT_get0_log_id(sct, &mut log_id);
+    if log_id.is_null() {
+        bail!("Invalid SCT");
+    }
+    let log_id = slice::from_raw_parts(log_id, len);
+    // milliseconds since the epoch
+    let millis = SCT_get_timestamp(sct) as i64;
+    let timestamp = time::at_utc(time::Timespec::new(millis / 1000, 0));
+    Ok(Sct {
+        log_id: base64::encode(log_id),
+        timestamp: timestamp.rfc3339().to_string(),
+    })
+}
+
+/// The signed certificate timestamps embedded in the certificate.
+fn embedded_scts(x509: &X509Ref) -> Result<Vec<Sct>> {
+    let mut crit = 0;
+    let list = unsafe {
+        ffi::X509_get_ext_d2i(
+            x509.as_ptr(),
+            NID_CT_PRECERT_SCTS,
+            &mut crit,
+            ptr::null_mut(),
+        )
+    } as *mut ffi::OPENSSL_STACK;
+    if list.is_null() {
+        if crit == -1 {
+            return Ok(Vec::new());
+        }
+        bail!("Invalid SCT list");
+    }
+
+    unsafe {
+        let scts = (0..ffi::OPENSSL_sk_num(list))
+            .map(|i| read_sct(ffi::OPENSSL_sk_value(list, i)))
+            .collect();
+        SCT_LIST_free(list);
+        scts
+    }
+}
+
+/// How many SCTs a new certificate needs to have embedded, from verify_sct,
+/// min_scts and sct_strict.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SctPolicy {
+    pub min: usize,
+    /// Without strict a certificate without any SCTs is accepted, some CAs
+    /// deliver them with TLS or OCSP instead
+    pub strict: bool,
+}
+
+impl SctPolicy {
+    /// Check the SCTs embedded in the first certificate of a pem chain, the
+    /// SCTs are logged.
+    pub fn check(&self, fullchain: &[u8]) -> Result<Vec<Sct>> {
+        let x509 = X509::from_pem(fullchain).context("Failed to parse pem file")?;
+        let scts = embedded_scts(&x509).context("Failed to parse certificate extensions")?;
+        for sct in &scts {
+            info!("embedded SCT: {}", sct);
+        }
+        if scts.is_empty() && !self.strict {
+            warn!("Certificate has no embedded SCTs, the CA might deliver them with TLS or OCSP instead");
+        } else if scts.len() < self.min {
+            bail!(
+                "Certificate has {} embedded SCT(s), at least {} are required",
+                scts.len(),
+                self.min
+            );
+        }
+        Ok(scts)
+    }
+}
+
+pub fn detect_key_type<T: HasPublic>(pkey: &PKey<T>) -> Option<KeyType> {
+    match pkey.id() {
+        Id::RSA => match pkey.bits() {
+            2048 => Some(KeyType::Rsa2048),
+            4096 => Some(KeyType::Rsa4096),
+            _ => None,
+        },
+        Id::EC => {
+            let curve = pkey.ec_key().ok()?.group().curve_name()?;
+            match curve {
+                Nid::X9_62_PRIME256V1 => Some(KeyType::EcP256),
+                Nid::SECP384R1 => Some(KeyType::EcP384),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn issuer_cn(cert: &X509) -> Result<Option<String>> {
+    let cn = cert
+        .issuer_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .map(|cn| cn.data().as_utf8())
+        .transpose()?;
+    Ok(cn.map(|cn| cn.to_string()))
+}
+
+/// The common name of the issuer of the last certificate in a pem chain,
+/// this is usually the root certificate the chain leads to.
+pub fn chain_issuer(fullchain: &[u8]) -> Result<Option<String>> {
+    let chain = X509::stack_from_pem(fullchain).context("Failed to parse pem file")?;
+    match chain.last() {
+        Some(top) => issuer_cn(top),
+        None => Ok(None),
+    }
+}
+
+/// The dns names in the subject alternative names of a certificate, in
+/// lowercase.
+pub fn dns_names(cert: &X509) -> Vec<String> {
+    cert.subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname())
+                .map(|name| name.to_ascii_lowercase())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// If a certificate with these names is valid for `name`, a wildcard only
+/// covers a single label.
+fn covers(names: &[String], name: &str) -> bool {
+    if names.iter().any(|n| n == name) {
+        return true;
+    }
+    match name.split_once('.') {
+        Some((label, parent)) if label != "*" => {
+            names.iter().any(|n| n.strip_prefix("*.") == Some(parent))
+        }
+        _ => false,
+    }
+}
+
+/// Check that the installed files of a certificate belong together: the
+/// private key matches the leaf, every certificate of the chain is signed by
+/// the next one and the leaf is valid for all `configured` names. Returns the
+/// problems that were found, the key is only checked if it's given.
+pub fn verify_installed(
+    fullchain: &[u8],
+    privkey: Option<&[u8]>,
+    configured: &[String],
+) -> Result<Vec<String>> {
+    let chain = X509::stack_from_pem(fullchain).context("Failed to parse certificate")?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| anyhow!("The fullchain doesn't contain a certificate"))?;
+    let mut problems = Vec::new();
+
+    if let Some(privkey) = privkey {
+        let pkey = PKey::private_key_from_pem(privkey).context("Failed to parse private key")?;
+        if !leaf.public_key()?.public_eq(&pkey) {
+            problems.push("the private key doesn't belong to the certificate".to_string());
+        }
+    }
+
+    for (i, pair) in chain.windows(2).enumerate() {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+        let issuer_key = issuer.public_key()?;
+        let signed = issuer.issued(cert) == X509VerifyResult::OK
+            && cert.verify(&issuer_key).unwrap_or(false);
+        if !signed {
+            problems.push(format!(
+                "certificate {} of the chain isn't issued by certificate {}",
+                i + 1,
+                i + 2
+            ));
+        }
+    }
+
+    let names = dns_names(leaf);
+    for name in configured {
+        if !covers(&names, name) {
+            problems.push(format!("the certificate isn't valid for {:?}", name));
+        }
+    }
+    Ok(problems)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The details of a certificate that monitoring and pinned keys (TLSA, HPKP)
+/// need, logged after a renewal and shown by `status --verbose`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CertDetails {
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub dns_names: Vec<String>,
+    pub issuer: Option<String>,
+    /// The SHA-256 fingerprint of the certificate, in hex
+    pub sha256_fingerprint: String,
+    /// The SHA-256 digest of the SubjectPublicKeyInfo, in base64
+    pub spki_sha256: String,
+    pub scts: usize,
+    pub sct_list: Vec<Sct>,
+}
+
+impl CertDetails {
+    /// The details of the first certificate in a pem file.
+    pub fn from_pem(s: &[u8]) -> Result<CertDetails> {
+        let x509 = X509::from_pem(s).context("Failed to parse pem file")?;
+        let serial = x509.serial_number().to_bn()?.to_vec();
+        let spki = x509.public_key()?.public_key_to_der()?;
+        let sct_list = embedded_scts(&x509).context("Failed to parse certificate extensions")?;
+        Ok(CertDetails {
+            serial: hex(&serial),
+            not_before: parse_date(&x509.not_before().to_string())
+                .rfc3339()
+                .to_string(),
+            not_after: parse_date(&x509.not_after().to_string())
+                .rfc3339()
+                .to_string(),
+            dns_names: dns_names(&x509),
+            issuer: issuer_cn(&x509)?,
+            sha256_fingerprint: hex(&x509.digest(MessageDigest::sha256())?),
+            spki_sha256: base64::encode(openssl::sha::sha256(&spki)),
+            scts: sct_list.len(),
+            sct_list,
+        })
+    }
+
+    /// The details as labeled values, in the order they're shown.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("serial", self.serial.clone()),
+            ("not_before", self.not_before.clone()),
+            ("not_after", self.not_after.clone()),
+            ("dns_names", self.dns_names.join(",")),
+            (
+                "issuer",
+                self.issuer.clone().unwrap_or_else(|| "-".to_string()),
+            ),
+            ("sha256", self.sha256_fingerprint.clone()),
+            ("spki_sha256", self.spki_sha256.clone()),
+            ("scts", self.scts.to_string()),
+        ]
+    }
+}
+
+/// A single line of `key=value` pairs, values with spaces are quoted.
+impl fmt::Display for CertDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (key, value)) in self.fields().into_iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            if value.contains(' ') {
+                write!(f, "{}={:?}", key, value)?;
+            } else {
+                write!(f, "{}={}", key, value)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl CertInfo {
@@ -12,7 +375,7 @@ impl CertInfo {
         // load as x509
         let x509 = X509::from_pem(s).context("Failed to parse pem file")?;
 
-        let not_before = x509.not_before().to_string();
+        let not_before = parse_date(&x509.not_before().to_string());
 
         // convert asn1 time to Tm
         let not_after = x509.not_after().to_string();
@@ -23,9 +386,20 @@ impl CertInfo {
 
         // dur.num_days()
 
+        let key_type = detect_key_type(&x509.public_key()?);
+        let must_staple =
+            detect_must_staple(&x509).context("Failed to parse certificate extensions")?;
+        let issuer = issuer_cn(&x509)?;
+        let chain_issuer = chain_issuer(s)?;
+
         Ok(CertInfo {
             not_before,
             expires,
+            key_type,
+            must_staple,
+            issuer,
+            chain_issuer,
+            dns_names: dns_names(&x509),
         })
     }
 
@@ -33,8 +407,438 @@ impl CertInfo {
         let dur = self.expires - time::now();
         dur.num_days()
     }
+
+    /// Negative if the certificate is expired.
+    pub fn seconds_left(&self) -> i64 {
+        (self.expires - time::now()).num_seconds()
+    }
+
+    /// How long the certificate is valid for in total.
+    pub fn lifetime(&self) -> Duration {
+        let secs = (self.expires - self.not_before).num_seconds();
+        Duration::from_secs(secs.max(0) as u64)
+    }
+}
+
+/// The difference between the configured dns_names and the names of an
+/// issued certificate, ignoring their order and case.
+#[derive(Debug, Default, PartialEq)]
+pub struct DnsNameChanges {
+    /// Configured names the certificate isn't valid for
+    pub added: Vec<String>,
+    /// Names of the certificate that aren't configured anymore
+    pub removed: Vec<String>,
+}
+
+impl DnsNameChanges {
+    pub fn new(cert: &CertConfig, existing: &CertInfo) -> DnsNameChanges {
+        let configured = cert
+            .ascii_dns_names()
+            .unwrap_or_else(|_| cert.dns_names.iter().map(|n| n.to_lowercase()).collect())
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let issued = existing.dns_names.iter().cloned().collect::<BTreeSet<_>>();
+        DnsNameChanges {
+            added: configured.difference(&issued).cloned().collect(),
+            removed: issued.difference(&configured).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for DnsNameChanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut changes = Vec::new();
+        if !self.added.is_empty() {
+            changes.push(format!("added {}", self.added.join(", ")));
+        }
+        if !self.removed.is_empty() {
+            changes.push(format!("removed {}", self.removed.join(", ")));
+        }
+        f.write_str(&changes.join("; "))
+    }
 }
 
 fn parse_date(s: &str) -> time::Tm {
     time::strptime(s, "%h %e %H:%M:%S %Y %Z").expect("strptime")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::pkey::Public;
+    use openssl::rsa::Rsa;
+
+    fn ec_key(nid: Nid) -> PKey<Public> {
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    const MUST_STAPLE: &str = "-----BEGIN CERTIFICATE-----
+MIIBrTCCAVKgAwIBAgIUXU2H7R4Sxa2MZvy7GBSxglpFVKMwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wHhcNMjYxMDE0MTEzMjM4WhcNMjcwMTEy
+MTEzMjM4WjAWMRQwEgYDVQQDDAtleGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqG
+SM49AwEHA0IABLd4uO8wolhLnat2oGoElWQX1Gg2nOtkOICxeRh0T5rfqhGYHSkg
+fbNkRTYSCGXJ3PQeuyUw055VweTtk1FIJTajfjB8MB0GA1UdDgQWBBR03Tng206j
+19rE2GGSXpslKvZuNDAfBgNVHSMEGDAWgBR03Tng206j19rE2GGSXpslKvZuNDAP
+BgNVHRMBAf8EBTADAQH/MBYGA1UdEQQPMA2CC2V4YW1wbGUuY29tMBEGCCsGAQUF
+BwEYBAUwAwIBBTAKBggqhkjOPQQDAgNJADBGAiEAwbHxDlnbAi+xYkZ269Cma7mG
+0RwkmpSx6sNWGalSjOQCIQDZu09IQgKyP8po7YmtItS+7kyRSb/TIvE5Rfk7f/k/
+fw==
+-----END CERTIFICATE-----
+";
+
+    const NO_MUST_STAPLE: &str = "-----BEGIN CERTIFICATE-----
+MIIBmDCCAT+gAwIBAgIUWaXYetuLvnfAxX1YZy5ijJlN0BowCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wHhcNMjYxMDE0MTEzMjM4WhcNMjcwMTEy
+MTEzMjM4WjAWMRQwEgYDVQQDDAtleGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqG
+SM49AwEHA0IABFojyuw5EMdFBKRuiZU921BuA8/ZPg90KNjtxNQbtgfWbt50sYoa
+jZDLuScQAp73Nc1asC9S6HA9ujfX2vgTbiejazBpMB0GA1UdDgQWBBRxeYCST2+X
+zcbHGgXQTd9TCt6usjAfBgNVHSMEGDAWgBRxeYCST2+XzcbHGgXQTd9TCt6usjAP
+BgNVHRMBAf8EBTADAQH/MBYGA1UdEQQPMA2CC2V4YW1wbGUuY29tMAoGCCqGSM49
+BAMCA0cAMEQCIG99NL9LPLyoilI35bFcnzqoqquPxXDn7e2kbn/4Wa3EAiAno0BU
+cXmDlq7A2rHJIDi7d5Bu5EZLFOrrQOWvkyWu5A==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_must_staple() {
+        let info = CertInfo::from_pem(MUST_STAPLE.as_bytes()).unwrap();
+        assert!(info.must_staple);
+        assert_eq!(info.key_type, Some(KeyType::EcP256));
+    }
+
+    fn cert_config(dns_names: &[&str]) -> CertConfig {
+        CertConfig {
+            name: "example.com".to_string(),
+            dns_names: dns_names.iter().map(|n| n.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dns_name_changes() {
+        let info = CertInfo::from_pem(NO_MUST_STAPLE.as_bytes()).unwrap();
+        assert_eq!(info.dns_names, &["example.com"]);
+
+        let changes = DnsNameChanges::new(&cert_config(&["Example.COM"]), &info);
+        assert!(changes.is_empty());
+
+        let changes = DnsNameChanges::new(&cert_config(&["example.com", "api.example.com"]), &info);
+        assert_eq!(changes.added, &["api.example.com"]);
+        assert!(changes.removed.is_empty());
+        assert_eq!(changes.to_string(), "added api.example.com");
+
+        let changes = DnsNameChanges::new(&cert_config(&["www.example.com"]), &info);
+        assert_eq!(
+            changes.to_string(),
+            "added www.example.com; removed example.com"
+        );
+    }
+
+    #[test]
+    fn test_dns_name_order() {
+        let info = CertInfo {
+            dns_names: vec!["www.example.com".to_string(), "example.com".to_string()],
+            ..CertInfo::from_pem(NO_MUST_STAPLE.as_bytes()).unwrap()
+        };
+        let cert = cert_config(&["example.com", "WWW.example.com", "www.example.com"]);
+        assert!(DnsNameChanges::new(&cert, &info).is_empty());
+
+        let cert = cert_config(&["münchen.example.com", "example.com", "www.example.com"]);
+        assert_eq!(
+            DnsNameChanges::new(&cert, &info).added,
+            &["xn--mnchen-3ya.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_no_must_staple() {
+        let info = CertInfo::from_pem(NO_MUST_STAPLE.as_bytes()).unwrap();
+        assert!(!info.must_staple);
+    }
+
+    /// A certificate issued by Let's Encrypt, with two embedded SCTs.
+    const LETS_ENCRYPT: &str = "-----BEGIN CERTIFICATE-----
+MIIE1DCCA7ygAwIBAgISA22Gkmt31e1mitao+ENL+sr3MA0GCSqGSIb3DQEBCwUA
+MEoxCzAJBgNVBAYTAlVTMRYwFAYDVQQKEw1MZXQncyBFbmNyeXB0MSMwIQYDVQQD
+ExpMZXQncyBFbmNyeXB0IEF1dGhvcml0eSBYMzAeFw0yMDA0MjgyMzMxMjdaFw0y
+MDA3MjcyMzMxMjdaMCUxIzAhBgNVBAMTGmNhY2hlLnJlYnVpbGRlci5menlsYWIu
+bmV0MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAETyu5fNMOS/Lm/CwddSGEBH/XznHo
++nzPGVWxRDRl6UayntgPnTxBRi4HzUj91249mL0Q+/bYLWJdWueAJomi7CRVU3jo
+E8oDVR6f528TRna2qoi0KTs8vJgMETy80yy7o4IChTCCAoEwDgYDVR0PAQH/BAQD
+AgeAMB0GA1UdJQQWMBQGCCsGAQUFBwMBBggrBgEFBQcDAjAMBgNVHRMBAf8EAjAA
+MB0GA1UdDgQWBBREiBvysibRSVuw2Ur0qygxYaGtADAfBgNVHSMEGDAWgBSoSmpj
+BH3duubRObemRWXv86jsoTBvBggrBgEFBQcBAQRjMGEwLgYIKwYBBQUHMAGGImh0
+dHA6Ly9vY3NwLmludC14My5sZXRzZW5jcnlwdC5vcmcwLwYIKwYBBQUHMAKGI2h0
+dHA6Ly9jZXJ0LmludC14My5sZXRzZW5jcnlwdC5vcmcvMDsGA1UdEQQ0MDKCGmNh
+Y2hlLnJlYnVpbGRlci5menlsYWIubmV0ghRyZWJ1aWxkZXIuZnp5bGFiLm5ldDBM
+BgNVHSAERTBDMAgGBmeBDAECATA3BgsrBgEEAYLfEwEBATAoMCYGCCsGAQUFBwIB
+FhpodHRwOi8vY3BzLmxldHNlbmNyeXB0Lm9yZzCCAQQGCisGAQQB1nkCBAIEgfUE
+gfIA8AB1APCVpFnyANGCQBAtL5OIjq1L/h1H45nh0DSmsKiqjrJzAAABccNYerAA
+AAQDAEYwRAIgP3HbNC75DEiLEE/TKhGw09fSWp/TewhRl/4XvmoxnWMCIE/3+yGf
+gdi3bgjXhtspUqkKKcA/HLS7YXiwtu3hnc8SAHcAsh4FzIuizYogTodm+Su5iiUg
+Z2va+nDnsklTLe+LkF4AAAFxw1h6owAABAMASDBGAiEAsQkiJ6UNE//GvhIyoJVs
+Ah2ad7w+zPW2gVmYQFeVOJACIQDUhFc8FYzFDo3mIhHoY6+ODjLK4l6ruR28606D
+X1WLbzANBgkqhkiG9w0BAQsFAAOCAQEAV/xkamOUFhtjyy6MPPBfT7nBYSBjTo7h
+nlIuj5QZ5dHYM2eOZg77VOGpSgD5mlj0pqyspDMCkhsHVrmGFOcFKWgvwN5W6WF/
+l7VHipzyxsPctUQK8pPRfOR8l2iMBj9+qpKmLx6v/BRN5ycj2giMuw6pbIoB3n6T
+nXq0uZRfAm2kmQ64WusLvkvgpS61J0m70JI2mXdr+epeXwKdWcmnZJ4CCOiSYdv/
+AxdDRttRGfpNyAxuMiyCccwXW2rNfc7EHQ7Myb7f3eE9cE6wLu/JLCCUotgafi08
+aJ6TSPxS0YlSBhKYNbOUI7R8ZbjAJe/vI1IcYYhMaIW0kAzo4nxEmg==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_cert_details() {
+        let details = CertDetails::from_pem(LETS_ENCRYPT.as_bytes()).unwrap();
+        assert_eq!(
+            details,
+            CertDetails {
+                serial: "03:6D:86:92:6B:77:D5:ED:66:8A:D6:A8:F8:43:4B:FA:CA:F7".to_string(),
+                not_before: "2020-04-28T23:31:27Z".to_string(),
+                not_after: "2020-07-27T23:31:27Z".to_string(),
+                dns_names: vec![
+                    "cache.rebuilder.fzylab.net".to_string(),
+                    "rebuilder.fzylab.net".to_string(),
+                ],
+                issuer: Some("Let's Encrypt Authority X3".to_string()),
+                sha256_fingerprint: "AD:D1:8D:A0:A5:CD:9A:1D:B7:EF:40:7D:A6:C4:33:EE:7A:E0:99:09:14:77:2C:C3:F6:64:CB:8A:C8:98:21:09".to_string(),
+                spki_sha256: "pqsDcTSUOxrezwg7yASKO2Oyn4AAb1F3VLDqK27JzrU=".to_string(),
+                scts: 2,
+                sct_list: vec![
+                    Sct {
+                        log_id: "8JWkWfIA0YJAEC0vk4iOrUv+HUfjmeHQNKawqKqOsnM=".to_string(),
+                        timestamp: "2020-04-29T00:31:27Z".to_string(),
+                    },
+                    Sct {
+                        log_id: "sh4FzIuizYogTodm+Su5iiUgZ2va+nDnsklTLe+LkF4=".to_string(),
+                        timestamp: "2020-04-29T00:31:27Z".to_string(),
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            details.to_string(),
+            "serial=03:6D:86:92:6B:77:D5:ED:66:8A:D6:A8:F8:43:4B:FA:CA:F7 \
+             not_before=2020-04-28T23:31:27Z not_after=2020-07-27T23:31:27Z \
+             dns_names=cache.rebuilder.fzylab.net,rebuilder.fzylab.net \
+             issuer=\"Let's Encrypt Authority X3\" \
+             sha256=AD:D1:8D:A0:A5:CD:9A:1D:B7:EF:40:7D:A6:C4:33:EE:7A:E0:99:09:14:77:2C:C3:F6:64:CB:8A:C8:98:21:09 \
+             spki_sha256=pqsDcTSUOxrezwg7yASKO2Oyn4AAb1F3VLDqK27JzrU= scts=2"
+        );
+
+        let details = CertDetails::from_pem(MUST_STAPLE.as_bytes()).unwrap();
+        assert_eq!(details.scts, 0);
+        assert_eq!(details.issuer.as_deref(), Some("example.com"));
+        assert_eq!(details.dns_names, &["example.com"]);
+    }
+
+    #[test]
+    fn test_sct_policy() {
+        let policy = SctPolicy {
+            min: 2,
+            strict: false,
+        };
+        assert_eq!(policy.check(LETS_ENCRYPT.as_bytes()).unwrap().len(), 2);
+        // no SCTs at all is only a warning
+        assert!(policy.check(MUST_STAPLE.as_bytes()).unwrap().is_empty());
+
+        let policy = SctPolicy {
+            min: 3,
+            strict: false,
+        };
+        let err = policy.check(LETS_ENCRYPT.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Certificate has 2 embedded SCT(s), at least 3 are required"
+        );
+
+        let policy = SctPolicy {
+            min: 1,
+            strict: true,
+        };
+        assert!(policy.check(MUST_STAPLE.as_bytes()).is_err());
+    }
+
+    // the Let's Encrypt Authority X3 intermediate, cross-signed by DST Root CA X3
+    const INTERMEDIATE: &str = "-----BEGIN CERTIFICATE-----
+MIIEkjCCA3qgAwIBAgIQCgFBQgAAAVOFc2oLheynCDANBgkqhkiG9w0BAQsFADA/
+MSQwIgYDVQQKExtEaWdpdGFsIFNpZ25hdHVyZSBUcnVzdCBDby4xFzAVBgNVBAMT
+DkRTVCBSb290IENBIFgzMB4XDTE2MDMxNzE2NDA0NloXDTIxMDMxNzE2NDA0Nlow
+SjELMAkGA1UEBhMCVVMxFjAUBgNVBAoTDUxldCdzIEVuY3J5cHQxIzAhBgNVBAMT
+GkxldCdzIEVuY3J5cHQgQXV0aG9yaXR5IFgzMIIBIjANBgkqhkiG9w0BAQEFAAOC
+AQ8AMIIBCgKCAQEAnNMM8FrlLke3cl03g7NoYzDq1zUmGSXhvb418XCSL7e4S0EF
+q6meNQhY7LEqxGiHC6PjdeTm86dicbp5gWAf15Gan/PQeGdxyGkOlZHP/uaZ6WA8
+SMx+yk13EiSdRxta67nsHjcAHJyse6cF6s5K671B5TaYucv9bTyWaN8jKkKQDIZ0
+Z8h/pZq4UmEUEz9l6YKHy9v6Dlb2honzhT+Xhq+w3Brvaw2VFn3EK6BlspkENnWA
+a6xK8xuQSXgvopZPKiAlKQTGdMDQMc2PMTiVFrqoM7hD8bEfwzB/onkxEz0tNvjj
+/PIzark5McWvxI0NHWQWM6r6hCm21AvA2H3DkwIDAQABo4IBfTCCAXkwEgYDVR0T
+AQH/BAgwBgEB/wIBADAOBgNVHQ8BAf8EBAMCAYYwfwYIKwYBBQUHAQEEczBxMDIG
+CCsGAQUFBzABhiZodHRwOi8vaXNyZy50cnVzdGlkLm9jc3AuaWRlbnRydXN0LmNv
+bTA7BggrBgEFBQcwAoYvaHR0cDovL2FwcHMuaWRlbnRydXN0LmNvbS9yb290cy9k
+c3Ryb290Y2F4My5wN2MwHwYDVR0jBBgwFoAUxKexpHsscfrb4UuQdf/EFWCFiRAw
+VAYDVR0gBE0wSzAIBgZngQwBAgEwPwYLKwYBBAGC3xMBAQEwMDAuBggrBgEFBQcC
+ARYiaHR0cDovL2Nwcy5yb290LXgxLmxldHNlbmNyeXB0Lm9yZzA8BgNVHR8ENTAz
+MDGgL6AthitodHRwOi8vY3JsLmlkZW50cnVzdC5jb20vRFNUUk9PVENBWDNDUkwu
+Y3JsMB0GA1UdDgQWBBSoSmpjBH3duubRObemRWXv86jsoTANBgkqhkiG9w0BAQsF
+AAOCAQEA3TPXEfNjWDjdGBX7CVW+dla5cEilaUcne8IkCJLxWh9KEik3JHRRHGJo
+uM2VcGfl96S8TihRzZvoroed6ti6WqEBmtzw3Wodatg+VyOeph4EYpr/1wXKtx8/
+wApIvJSwtmVi4MFU5aMqrSDE6ea73Mj2tcMyo5jMd6jmeWUHK8so/joWUoHOUgwu
+X4Po1QYz+3dszkDqMp4fklxBwXRsW10KXzPMTZ+sOPAveyxindmjkW8lGy+QsRlG
+PfZ+G6Z6h7mjem0Y+iWlkYcV4PIWL1iwBi8saCbGS5jN2p8M+X+Q7UNKEkROb3N6
+KOqkqm57TH2H3eDJAkSnh6/DNFu0Qg==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_chain_issuer() {
+        let chain = format!("{}{}", MUST_STAPLE, INTERMEDIATE);
+        assert_eq!(
+            chain_issuer(chain.as_bytes()).unwrap().as_deref(),
+            Some("DST Root CA X3")
+        );
+        assert_eq!(
+            chain_issuer(MUST_STAPLE.as_bytes()).unwrap().as_deref(),
+            Some("example.com")
+        );
+        let info = CertInfo::from_pem(chain.as_bytes()).unwrap();
+        assert_eq!(info.chain_issuer.as_deref(), Some("DST Root CA X3"));
+        assert!(info.must_staple);
+    }
+
+    fn ec_private_key() -> PKey<openssl::pkey::Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// A certificate for `names` with the key of `subject`, signed by the
+    /// issuer or self signed.
+    fn issue(
+        cn: &str,
+        names: &[&str],
+        subject: &PKey<openssl::pkey::Private>,
+        issuer: Option<(&X509, &PKey<openssl::pkey::Private>)>,
+    ) -> X509 {
+        use openssl::asn1::Asn1Time;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::X509Name;
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        match issuer {
+            Some((issuer, _)) => cert.set_issuer_name(issuer.subject_name()).unwrap(),
+            None => cert.set_issuer_name(&name).unwrap(),
+        }
+        cert.set_pubkey(subject).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        if !names.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for name in names {
+                san.dns(name);
+            }
+            let san = san
+                .build(&cert.x509v3_context(issuer.map(|(i, _)| &**i), None))
+                .unwrap();
+            cert.append_extension(san).unwrap();
+        }
+        let signer = issuer.map(|(_, key)| key).unwrap_or(subject);
+        cert.sign(signer, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    fn pem(certs: &[&X509]) -> Vec<u8> {
+        certs.iter().flat_map(|c| c.to_pem().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_verify_installed() {
+        let root_key = ec_private_key();
+        let root = issue("Root", &[], &root_key, None);
+        let ca_key = ec_private_key();
+        let ca = issue("Intermediate", &[], &ca_key, Some((&root, &root_key)));
+        let key = ec_private_key();
+        let leaf = issue(
+            "example.com",
+            &["example.com", "*.example.com"],
+            &key,
+            Some((&ca, &ca_key)),
+        );
+        let privkey = key.private_key_to_pem_pkcs8().unwrap();
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let configured = names(&["example.com", "www.example.com"]);
+
+        let fullchain = pem(&[&leaf, &ca, &root]);
+        let problems = verify_installed(&fullchain, Some(&privkey), &configured).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        // without root
+        let fullchain = pem(&[&leaf, &ca]);
+        assert!(verify_installed(&fullchain, None, &configured)
+            .unwrap()
+            .is_empty());
+
+        // the key of another renewal
+        let other = ec_private_key().private_key_to_pem_pkcs8().unwrap();
+        assert_eq!(
+            verify_installed(&fullchain, Some(&other), &configured).unwrap(),
+            &["the private key doesn't belong to the certificate"]
+        );
+
+        // the intermediate of another CA with the same name
+        let other_ca = issue("Intermediate", &[], &ec_private_key(), None);
+        let fullchain = pem(&[&leaf, &other_ca]);
+        assert_eq!(
+            verify_installed(&fullchain, Some(&privkey), &configured).unwrap(),
+            &["certificate 1 of the chain isn't issued by certificate 2"]
+        );
+        let fullchain = pem(&[&leaf, &root]);
+        assert_eq!(
+            verify_installed(&fullchain, Some(&privkey), &configured).unwrap(),
+            &["certificate 1 of the chain isn't issued by certificate 2"]
+        );
+
+        // wildcards only cover a single label
+        let fullchain = pem(&[&leaf, &ca]);
+        assert_eq!(
+            verify_installed(
+                &fullchain,
+                None,
+                &names(&["a.b.example.com", "example.org"])
+            )
+            .unwrap(),
+            &[
+                "the certificate isn't valid for \"a.b.example.com\"",
+                "the certificate isn't valid for \"example.org\"",
+            ]
+        );
+        assert!(verify_installed(b"", None, &configured).is_err());
+    }
+
+    #[test]
+    fn test_detect_key_type() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let rsa = PKey::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap();
+        assert_eq!(detect_key_type(&rsa), Some(KeyType::Rsa2048));
+        assert_eq!(
+            detect_key_type(&ec_key(Nid::X9_62_PRIME256V1)),
+            Some(KeyType::EcP256)
+        );
+        assert_eq!(
+            detect_key_type(&ec_key(Nid::SECP384R1)),
+            Some(KeyType::EcP384)
+        );
+        assert_eq!(detect_key_type(&ec_key(Nid::SECP521R1)), None);
+    }
+}
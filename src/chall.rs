@@ -1,64 +1,608 @@
+use crate::args::CleanChallengesArgs;
 use crate::config::Config;
+use crate::control::ControlClient;
 use crate::errors::*;
+use crate::permissions::Ownership;
+#[cfg(unix)]
+use nix::fcntl::{self, OFlag};
+#[cfg(unix)]
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+#[cfg(unix)]
+use nix::sys::stat::Mode;
+#[cfg(unix)]
+use nix::unistd;
 use rand::seq::SliceRandom;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::raw::c_int;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::process;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Key authorizations are well below this, anything larger isn't served.
+pub const MAX_PROOF_SIZE: u64 = 4096;
+/// Proofs are written under this prefix and renamed into place once they're
+/// complete. A `.` is never part of a token, so these are never served.
+const TMP_PREFIX: &str = ".tmp-";
 
 const VALID_CHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+// acme tokens are 43 characters, allow some room for other servers
+pub const MIN_TOKEN_LEN: usize = 20;
+pub const MAX_TOKEN_LEN: usize = 256;
+
+/// A challenge token that is safe to use as a single file name in the
+/// challs directory. It only contains base64url characters, so it can't
+/// contain `/`, `..` or null bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token(String);
+
+impl Token {
+    pub fn parse(t: &str) -> Result<Token> {
+        if t.len() < MIN_TOKEN_LEN || t.len() > MAX_TOKEN_LEN {
+            bail!("Token has an invalid length ({} bytes)", t.len());
+        }
+        if let Some(c) = t.chars().find(|c| !VALID_CHARS.contains(*c)) {
+            bail!("Token contains an invalid character: {:?}", c);
+        }
+        Ok(Token(t.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// proofs that have been written but not removed yet, in case we're
+// interrupted by a signal
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: c_int) {
+    // only async-signal-safe calls in here, the cleanup happens in a thread
+    let fd: RawFd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    unistd::write(fd, &[signal as u8]).ok();
+}
+
+/// Remove all pending challenge proofs if the process receives SIGINT or
+/// SIGTERM, the process exits afterwards.
+#[cfg(unix)]
+pub fn cleanup_on_signal() -> Result<()> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+    SIGNAL_PIPE.store(write, Ordering::SeqCst);
+
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for sig in &[Signal::SIGINT, Signal::SIGTERM] {
+        unsafe { signal::sigaction(*sig, &action) }?;
+    }
+
+    thread::spawn(move || {
+        let mut pipe = unsafe { File::from_raw_fd(read) };
+        let mut buf = [0];
+        if pipe.read_exact(&mut buf).is_ok() {
+            warn!("Interrupted, removing challenge proofs");
+            for path in PENDING.lock().unwrap().drain(..) {
+                debug!("Deleting challenge proof: {:?}", path);
+                fs::remove_file(path).ok();
+            }
+            process::exit(128 + buf[0] as i32);
+        }
+    });
+    Ok(())
+}
 
-#[inline]
-pub fn valid_token(t: &str) -> bool {
-    t.chars().all(|c| VALID_CHARS.contains(c))
+/// Without signals the proofs of an interrupted renew are only removed by
+/// the next renew or `clean-challenges`.
+#[cfg(not(unix))]
+pub fn cleanup_on_signal() -> Result<()> {
+    Ok(())
 }
 
+/// Proofs written with a `Challenge` are removed with `cleanup`, or at the
+/// latest when it's dropped. If chall_push_url is set they're pushed to the
+/// daemon instead of being written to the challenge directory, pushed proofs
+/// of an interrupted renew expire in the daemon.
 pub struct Challenge {
     path: PathBuf,
-    written: Vec<PathBuf>,
+    push: Option<ControlClient>,
+    written: Vec<Token>,
 }
 
 impl Challenge {
-    pub fn new(config: &Config) -> Challenge {
+    pub fn new(config: &Config) -> Result<Challenge> {
         let chall_dir = Path::new(&config.system.chall_dir);
         // TODO: consider creating the directory
-        Challenge {
+        Ok(Challenge {
             path: chall_dir.join("challs"),
+            push: ControlClient::from_config(&config.system)?,
             written: Vec::new(),
-        }
+        })
     }
 
     pub fn write(&mut self, token: &str, proof: &str) -> Result<()> {
-        if !valid_token(token) {
-            bail!("ACME server sent us malicious token")
+        let token = Token::parse(token).context("ACME server sent us malicious token")?;
+
+        if let Some(push) = &self.push {
+            self.written.push(token.clone());
+            return push.put(&token, proof);
         }
 
-        let path = self.path.join(token);
+        let path = self.path.join(token.as_str());
+        let tmp = self.path.join(format!("{}{}", TMP_PREFIX, token.as_str()));
         debug!("Writing challenge proof to {:?}", path);
-        fs::write(&path, proof).context("Failed to write challenge proof")?;
-
-        self.written.push(path);
+        PENDING
+            .lock()
+            .unwrap()
+            .extend(vec![tmp.clone(), path.clone()]);
+        self.written.push(token);
+        // the daemon has to be able to read it, whatever the umask is
+        let written = Ownership::default()
+            .create_file(&tmp, 0o644)
+            .and_then(|mut f| f.write_all(proof.as_bytes()))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if written.is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+        PENDING.lock().unwrap().retain(|p| *p != tmp);
+        written.context("Failed to write challenge proof")?;
 
         Ok(())
     }
 
     pub fn random(&mut self) -> Result<String> {
-        const TOKEN_LEN: usize = 16;
+        const TOKEN_LEN: usize = 32;
         let mut rng = rand::thread_rng();
 
-        let random = VALID_CHARS
-            .as_bytes()
-            .choose_multiple(&mut rng, TOKEN_LEN)
-            .map(|b| *b as char)
+        let random = (0..TOKEN_LEN)
+            .map(|_| *VALID_CHARS.as_bytes().choose(&mut rng).unwrap() as char)
             .collect::<String>();
 
         self.write(&random, &random)?;
         Ok(random)
     }
 
+    /// Remove all proofs that have been written, this continues with the
+    /// other proofs if one of them can't be removed.
     pub fn cleanup(&mut self) -> Result<()> {
-        for path in self.written.drain(..) {
+        let mut result = Ok(());
+        for token in self.written.drain(..) {
+            if let Some(push) = &self.push {
+                if let Err(err) = push.delete(&token) {
+                    result = Err(err);
+                }
+                continue;
+            }
+
+            let path = self.path.join(token.as_str());
             debug!("Deleting old challenge proof: {:?}", path);
-            fs::remove_file(path)?;
+            PENDING.lock().unwrap().retain(|p| *p != path);
+            match fs::remove_file(&path) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => {
+                    result = Err(Error::from(err)
+                        .context(anyhow!("Failed to delete challenge proof: {:?}", path)))
+                }
+            }
         }
-        Ok(())
+        result
+    }
+}
+
+impl Drop for Challenge {
+    fn drop(&mut self) {
+        if let Err(err) = self.cleanup() {
+            warn!("{:#}", err);
+        }
+    }
+}
+
+/// Delete proofs in the challs directory that are older than `max_age`, eg.
+/// left behind by a renew that was killed. Only files named like a token
+/// are touched. Returns the deleted paths.
+pub fn sweep(chall_dir: &Path, max_age: Duration, dry_run: bool) -> Result<Vec<PathBuf>> {
+    let path = chall_dir.join("challs");
+    let now = SystemTime::now();
+    let mut deleted = Vec::new();
+    for entry in
+        fs::read_dir(&path).with_context(|| anyhow!("Failed to read directory: {:?}", path))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        // proofs that were interrupted while they were written too
+        if name
+            .to_str()
+            .map(|name| name.strip_prefix(TMP_PREFIX).unwrap_or(name))
+            .map(Token::parse)
+            .and_then(Result::ok)
+            .is_none()
+        {
+            debug!("Skipping file that isn't a challenge proof: {:?}", name);
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        let path = entry.path();
+        if !dry_run {
+            fs::remove_file(&path)
+                .with_context(|| anyhow!("Failed to delete challenge proof: {:?}", path))?;
+        }
+        deleted.push(path);
+    }
+    Ok(deleted)
+}
+
+/// An open directory on unix, other platforms can't chroot and read the
+/// proofs by path.
+#[cfg(unix)]
+type DirHandle = File;
+#[cfg(not(unix))]
+type DirHandle = PathBuf;
+
+/// Open handles to the challenge directories, proofs can be read through them
+/// even after the process chrooted or dropped privileges.
+#[derive(Debug, Default)]
+pub struct ChallDirs {
+    dirs: Vec<(PathBuf, DirHandle)>,
+}
+
+impl ChallDirs {
+    pub fn open(config: &Config) -> Result<ChallDirs> {
+        let mut dirs = Vec::new();
+        for path in config.system.all_chall_dirs() {
+            let dir = Self::open_dir(path)
+                .with_context(|| anyhow!("Failed to open challenge directory: {:?}", path))?;
+            dirs.push((path.to_path_buf(), dir));
+        }
+        Ok(ChallDirs { dirs })
+    }
+
+    #[cfg(unix)]
+    fn open_dir(path: &Path) -> io::Result<DirHandle> {
+        File::open(path)
+    }
+
+    #[cfg(not(unix))]
+    fn open_dir(path: &Path) -> io::Result<DirHandle> {
+        fs::read_dir(path)?;
+        Ok(path.to_path_buf())
+    }
+
+    #[cfg(unix)]
+    fn open_at(dir: &File, path: &str, flags: OFlag) -> Result<File> {
+        // symlinks could point anywhere outside of the challenge directory
+        let fd = fcntl::openat(
+            dir.as_raw_fd(),
+            path,
+            flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    #[cfg(unix)]
+    fn read_from(dir: &DirHandle, token: &Token) -> Result<Vec<u8>> {
+        let challs = Self::open_at(dir, "challs", OFlag::O_RDONLY | OFlag::O_DIRECTORY)?;
+        // don't block on fifos, they are rejected below
+        let file = Self::open_at(&challs, token.as_str(), OFlag::O_RDONLY | OFlag::O_NONBLOCK)?;
+        Self::read_proof(file)
+    }
+
+    #[cfg(not(unix))]
+    fn read_from(dir: &DirHandle, token: &Token) -> Result<Vec<u8>> {
+        // symlinks could point anywhere outside of the challenge directory
+        let challs = dir.join("challs");
+        let path = challs.join(token.as_str());
+        for path in &[&challs, &path] {
+            if fs::symlink_metadata(path)?.file_type().is_symlink() {
+                bail!("Refusing to follow symlink: {:?}", path);
+            }
+        }
+        Self::read_proof(File::open(path)?)
+    }
+
+    fn read_proof(file: File) -> Result<Vec<u8>> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            bail!("Challenge proof is not a regular file");
+        }
+        if metadata.len() > MAX_PROOF_SIZE {
+            bail!("Challenge proof is too large ({} bytes)", metadata.len());
+        }
+        // older versions and other clients write proofs in place
+        if metadata.len() == 0 {
+            bail!("Challenge proof is empty, it might still be written");
+        }
+
+        let mut buf = Vec::new();
+        file.take(MAX_PROOF_SIZE + 1).read_to_end(&mut buf)?;
+        if buf.len() as u64 > MAX_PROOF_SIZE {
+            bail!("Challenge proof is too large");
+        }
+        Ok(buf)
+    }
+
+    /// Read the proof for a token from the first directory that has it.
+    pub fn read(&self, token: &Token) -> Option<Vec<u8>> {
+        for (path, dir) in &self.dirs {
+            debug!("Reading challenge proof from {:?}: {:?}", path, token);
+            match Self::read_from(dir, token) {
+                Ok(proof) => return Some(proof),
+                Err(err) => debug!("Failed to read challenge proof from {:?}: {:#}", path, err),
+            }
+        }
+        None
+    }
+}
+
+pub fn run(config: Config, args: CleanChallengesArgs) -> Result<()> {
+    let max_age = Duration::from_secs(args.max_age.unwrap_or(config.system.chall_max_age));
+    let deleted = sweep(&config.system.chall_dir, max_age, args.dry_run)?;
+    for path in &deleted {
+        if args.dry_run {
+            info!("would delete stale challenge proof {:?} (dry run)", path);
+        } else {
+            info!("deleted stale challenge proof {:?}", path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+
+    fn config(dirs: &[&Path]) -> Config {
+        Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dirs[0].to_path_buf(),
+                chall_dirs: dirs[1..].iter().map(|p| p.to_path_buf()).collect(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    fn token(t: &str) -> Token {
+        Token::parse(t).unwrap()
+    }
+
+    #[test]
+    fn test_token_validation() {
+        let valid = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+        assert_eq!(Token::parse(valid).unwrap().as_str(), valid);
+        assert!(Token::parse(&"a".repeat(MIN_TOKEN_LEN)).is_ok());
+        assert!(Token::parse(&"a".repeat(MAX_TOKEN_LEN)).is_ok());
+
+        assert!(Token::parse("").is_err());
+        assert!(Token::parse(&"a".repeat(MIN_TOKEN_LEN - 1)).is_err());
+        assert!(Token::parse(&"a".repeat(MAX_TOKEN_LEN + 1)).is_err());
+        for invalid in &[
+            "../../../../etc/passwd",
+            "/etc/passwd_0123456789",
+            "..0123456789abcdefghij",
+            "0123456789/abcdefghij",
+            "0123456789\\abcdefghij",
+            "0123456789\0abcdefghij",
+            "0123456789%2e%2eabcdef",
+            "0123456789.abcdefghij",
+            "0123456789 abcdefghij",
+            "0123456789äabcdefghij",
+        ] {
+            assert!(Token::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_read_from_multiple_dirs() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        for dir in &[&a, &b] {
+            fs::create_dir(dir.path().join("challs")).unwrap();
+        }
+        fs::write(a.path().join("challs/both_0123456789abcde"), "a").unwrap();
+        fs::write(b.path().join("challs/both_0123456789abcde"), "b").unwrap();
+        fs::write(b.path().join("challs/second_0123456789abc"), "b").unwrap();
+
+        let dirs = ChallDirs::open(&config(&[a.path(), b.path()])).unwrap();
+        assert_eq!(
+            dirs.read(&token("both_0123456789abcde")),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(
+            dirs.read(&token("second_0123456789abc")),
+            Some(b"b".to_vec())
+        );
+        assert_eq!(dirs.read(&token("missing_0123456789ab")), None);
+        assert!(Token::parse("../challs/both_0123456789abcde").is_err());
+    }
+
+    #[test]
+    fn test_oversized_proof() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let max = "a".repeat(MAX_PROOF_SIZE as usize);
+        fs::write(dir.path().join("challs/max_0123456789abcdef"), &max).unwrap();
+        fs::write(
+            dir.path().join("challs/large_0123456789abcd"),
+            format!("{}a", max),
+        )
+        .unwrap();
+
+        let dirs = ChallDirs::open(&config(&[dir.path()])).unwrap();
+        assert_eq!(
+            dirs.read(&token("max_0123456789abcdef")),
+            Some(max.into_bytes())
+        );
+        assert_eq!(dirs.read(&token("large_0123456789abcd")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_no_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(outside.path().join("challs")).unwrap();
+        fs::write(outside.path().join("secret"), "secret").unwrap();
+        fs::write(outside.path().join("challs/token_0123456789abcd"), "token").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret"),
+            dir.path().join("challs/link_0123456789abcde"),
+        )
+        .unwrap();
+        let dirs = ChallDirs::open(&config(&[dir.path()])).unwrap();
+        assert_eq!(dirs.read(&token("link_0123456789abcde")), None);
+
+        // the challs directory itself must not be a symlink either
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("challs"), dir.path().join("challs"))
+            .unwrap();
+        let dirs = ChallDirs::open(&config(&[dir.path()])).unwrap();
+        assert_eq!(dirs.read(&token("token_0123456789abcd")), None);
+    }
+
+    #[test]
+    fn test_cleanup_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let path = dir.path().join("challs/token_0123456789abcd");
+
+        let mut chall = Challenge::new(&config(&[dir.path()])).unwrap();
+        chall.write("token_0123456789abcd", "proof").unwrap();
+        assert!(path.exists());
+        assert!(PENDING.lock().unwrap().contains(&path));
+        drop(chall);
+        assert!(!path.exists());
+        assert!(!PENDING.lock().unwrap().contains(&path));
+
+        // proofs that are already gone are ignored
+        let mut chall = Challenge::new(&config(&[dir.path()])).unwrap();
+        chall.write("token_0123456789abcd", "proof").unwrap();
+        fs::remove_file(&path).unwrap();
+        chall.cleanup().unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let mut chall = Challenge::new(&config(&[dir.path()])).unwrap();
+        chall.write("token_0123456789abcd", "proof").unwrap();
+        let files = fs::read_dir(dir.path().join("challs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, &["token_0123456789abcd"]);
+        assert_eq!(
+            fs::read(dir.path().join("challs/token_0123456789abcd")).unwrap(),
+            b"proof"
+        );
+        assert!(!PENDING
+            .lock()
+            .unwrap()
+            .contains(&dir.path().join("challs/.tmp-token_0123456789abcd")));
+
+        // the temporary file could only be served by a traversal
+        assert!(Token::parse(".tmp-token_0123456789abcd").is_err());
+        drop(chall);
+
+        // a missing challs directory fails without leaving anything behind
+        let missing = dir.path().join("missing");
+        let mut chall = Challenge::new(&config(&[&missing])).unwrap();
+        assert!(chall.write("token_0123456789abcd", "proof").is_err());
+        assert!(!PENDING
+            .lock()
+            .unwrap()
+            .contains(&missing.join("challs/.tmp-token_0123456789abcd")));
+    }
+
+    #[test]
+    fn test_empty_proof() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let path = dir.path().join("challs/token_0123456789abcd");
+        let dirs = ChallDirs::open(&config(&[dir.path()])).unwrap();
+
+        // created, but not written yet
+        fs::write(&path, "").unwrap();
+        assert_eq!(dirs.read(&token("token_0123456789abcd")), None);
+        fs::write(&path, "proof").unwrap();
+        assert_eq!(
+            dirs.read(&token("token_0123456789abcd")),
+            Some(b"proof".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let challs = dir.path().join("challs");
+        fs::create_dir(&challs).unwrap();
+        fs::write(challs.join("stale_0123456789abcde"), "proof").unwrap();
+        fs::write(challs.join("README"), "not a proof").unwrap();
+        fs::write(challs.join("notes_0123456789abcde.txt"), "not a proof").unwrap();
+        fs::create_dir(challs.join("dir_0123456789abcdefg")).unwrap();
+
+        let deleted = sweep(dir.path(), Duration::from_secs(3600), false).unwrap();
+        assert!(deleted.is_empty());
+
+        let deleted = sweep(dir.path(), Duration::from_secs(0), true).unwrap();
+        assert_eq!(deleted, vec![challs.join("stale_0123456789abcde")]);
+        assert!(challs.join("stale_0123456789abcde").exists());
+
+        let deleted = sweep(dir.path(), Duration::from_secs(0), false).unwrap();
+        assert_eq!(deleted, vec![challs.join("stale_0123456789abcde")]);
+        let mut left = fs::read_dir(&challs)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "README",
+                "dir_0123456789abcdefg",
+                "notes_0123456789abcde.txt"
+            ]
+        );
+
+        // left behind by a renew that was killed while writing a proof
+        fs::write(challs.join(".tmp-partial_0123456789abcd"), "pro").unwrap();
+        let deleted = sweep(dir.path(), Duration::from_secs(0), false).unwrap();
+        assert_eq!(deleted, vec![challs.join(".tmp-partial_0123456789abcd")]);
+    }
+
+    #[test]
+    fn test_missing_dir() {
+        assert!(ChallDirs::open(&config(&[Path::new("/nonexistent")])).is_err());
     }
 }
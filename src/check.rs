@@ -1,52 +1,255 @@
 use crate::args::CheckArgs;
 use crate::chall::Challenge;
-use crate::config::Config;
+use crate::config::{CertConfig, ChallengeType, Config};
 use crate::errors::*;
-use std::collections::HashSet;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
 
-pub fn check(name: &str, token: &str) -> Result<()> {
+const HTTP_PORT: u16 = 80;
+// only the start of an unexpected response is shown
+const MAX_BODY_LEN: u64 = 1024;
+const MAX_BODY_DISPLAY: usize = 64;
+
+fn display_body(body: &str) -> String {
+    let mut display = body.chars().take(MAX_BODY_DISPLAY).collect::<String>();
+    if display.len() < body.len() {
+        display.push_str("...");
+    }
+    format!("{:?}", display)
+}
+
+/// Request the token from one specific address, the Host header is still set
+/// to the dns name like the acme server does.
+pub fn check_addr(name: &str, addr: SocketAddr, token: &str) -> Result<()> {
     let url = format!("http://{}/.well-known/acme-challenge/{}", name, token);
-    let r = ureq::get(&url)
+    let mut agent = ureq::agent();
+    agent.set_resolver(move |_: &str| Ok(vec![addr]));
+    let r = agent
+        .get(&url)
         .timeout_connect(3_000)
         .timeout_read(3_000)
         .timeout_write(3_000)
         .call();
 
+    if let Some(err) = r.synthetic_error() {
+        bail!("request failed: {}", err);
+    }
+
     let status = r.status();
+    let status_text = r.status_text().to_string();
+    let mut body = String::new();
+    r.into_reader()
+        .take(MAX_BODY_LEN)
+        .read_to_string(&mut body)
+        .context("Failed to read response")?;
+
     if status != 200 {
         bail!(
-            "response status code is wrong (expected 200, got {})",
-            status
+            "response status code is wrong (expected 200, got {} {}): {}",
+            status,
+            status_text,
+            display_body(&body)
         );
     }
 
-    let body = r.into_string()?;
     if body != token {
-        bail!("response body didn't match expected token");
+        bail!(
+            "response body didn't match expected token: {}",
+            display_body(&body)
+        );
     }
 
     Ok(())
 }
 
-pub fn run(config: Config, mut args: CheckArgs) -> Result<()> {
-    let mut chall = Challenge::new(&config);
-    let token = chall.random()?;
+/// All addresses a name resolves to, without duplicates.
+pub fn resolve(name: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for addr in (name, port).to_socket_addrs()? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        bail!("name didn't resolve to any address");
+    }
+    Ok(addrs)
+}
 
-    let filter = args.certs.drain(..).collect::<HashSet<_>>();
-    for cert in config.filter_certs(&filter) {
-        for dns_name in &cert.dns_names {
-            if let Err(err) = check(dns_name, &token) {
+/// Check the token on every address the name resolves to.
+pub fn check_name(name: &str, port: u16, token: &str) -> Result<Vec<(SocketAddr, Result<()>)>> {
+    let addrs = resolve(name, port).context("Failed to resolve name")?;
+    Ok(addrs
+        .into_iter()
+        .map(|addr| (addr, check_addr(name, addr, token)))
+        .collect())
+}
+
+/// Check all dns names of a cert and log the result for each address,
+/// returns false if any of them failed.
+pub fn check_cert(cert: &CertConfig, token: &str) -> Result<bool> {
+    if cert.challenge_type != ChallengeType::Http01 {
+        info!(
+            "Skipping {:?}, the cert uses {}",
+            cert.name, cert.challenge_type
+        );
+        return Ok(true);
+    }
+
+    let mut ok = true;
+    for dns_name in &cert.ascii_dns_names()? {
+        let results = match check_name(dns_name, HTTP_PORT, token) {
+            Ok(results) => results,
+            Err(err) => {
                 error!(
                     "Check failed ({:?} -> {:?}): {:#}",
                     cert.name, dns_name, err
                 );
+                ok = false;
+                continue;
+            }
+        };
+        for (addr, result) in results {
+            if let Err(err) = result {
+                error!(
+                    "Check failed ({:?} -> {:?} at {}): {:#}",
+                    cert.name,
+                    dns_name,
+                    addr.ip(),
+                    err
+                );
+                ok = false;
             } else {
-                info!("Verified {:?} -> {:?}: OK", cert.name, dns_name);
+                info!(
+                    "Verified {:?} -> {:?} at {}: OK",
+                    cert.name,
+                    dns_name,
+                    addr.ip()
+                );
             }
         }
     }
+    Ok(ok)
+}
 
+/// Write a random token and check if it's reachable for all names of the
+/// cert, the token is removed afterwards.
+pub fn self_check(config: &Config, cert: &CertConfig) -> Result<bool> {
+    let mut chall = Challenge::new(config)?;
+    let token = chall.random()?;
+    let result = check_cert(cert, &token);
     chall.cleanup()?;
+    result
+}
 
+pub fn run(config: Config, args: CheckArgs) -> Result<()> {
+    let mut chall = Challenge::new(&config)?;
+    let token = chall.random()?;
+
+    let mut failed = 0;
+    for cert in config.filter_managed_certs(&args.certs) {
+        match check_cert(cert, &token) {
+            Ok(true) => (),
+            Ok(false) => failed += 1,
+            Err(err) => {
+                error!("Check failed ({:?}): {:#}", cert.name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    chall.cleanup()?;
+
+    if failed > 0 {
+        bail!("Self-check failed for {} certificate(s)", failed);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Serve a single request with a fixed response, returns the address and
+    /// the Host header that was received.
+    fn serve_once(
+        status: &'static str,
+        body: &'static str,
+    ) -> (SocketAddr, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut host = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Host: ") {
+                    host = value.trim().to_string();
+                }
+            }
+            tx.send(host).ok();
+            let res = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            reader.get_mut().write_all(res.as_bytes()).unwrap();
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_check_addr() {
+        let (addr, host) = serve_once("200 OK", "abcd");
+        check_addr("example.com", addr, "abcd").unwrap();
+        assert_eq!(host.recv().unwrap(), "example.com");
+    }
+
+    #[test]
+    fn test_check_addr_wrong_status() {
+        let (addr, _) = serve_once("404 Not Found", "no such file");
+        let err = check_addr("example.com", addr, "abcd").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "response status code is wrong (expected 200, got 404 Not Found): \"no such file\""
+        );
+    }
+
+    #[test]
+    fn test_check_addr_wrong_body() {
+        let (addr, _) = serve_once("200 OK", "<html>welcome to nginx</html>");
+        let err = check_addr("example.com", addr, "abcd").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "response body didn't match expected token: \"<html>welcome to nginx</html>\""
+        );
+    }
+
+    #[test]
+    fn test_check_name_all_addrs() {
+        let results = check_name("localhost", 1, "abcd").unwrap();
+        assert!(!results.is_empty());
+        for (addr, result) in results {
+            assert!(addr.ip().is_loopback());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_display_body() {
+        assert_eq!(display_body("abc"), "\"abc\"");
+        let long = "a".repeat(100);
+        assert_eq!(display_body(&long), format!("\"{}...\"", "a".repeat(64)));
+    }
+}
@@ -0,0 +1,121 @@
+//! A system clock that's obviously wrong, eg. after restoring a VM from a
+//! snapshot, makes renew believe certificates have plenty of days left. The
+//! clock is compared to the Date header of the acme directory.
+use crate::acme::client::Client;
+use crate::config::Config;
+use crate::errors::*;
+
+/// The skew that's tolerated if max_clock_skew isn't set, in seconds.
+pub const DEFAULT_MAX_CLOCK_SKEW: u64 = 300;
+
+/// How many seconds the system clock is ahead of the acme server, negative
+/// if it's behind. None if the server didn't send a Date header.
+pub fn measure(config: &Config, acme_url: &str) -> Result<Option<i64>> {
+    let http = config.acme.http_client()?;
+    let client = Client::with_http(acme_url, &http)?;
+    Ok(client.clock_skew())
+}
+
+pub fn is_excessive(config: &Config, skew: i64) -> bool {
+    skew.unsigned_abs() > config.acme.max_clock_skew()
+}
+
+fn amount(secs: u64) -> String {
+    let (n, unit) = match secs {
+        0..=119 => (secs, "second"),
+        120..=7199 => (secs / 60, "minute"),
+        7200..=172_799 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    if n == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", n, unit)
+    }
+}
+
+/// Eg. `3 weeks behind`, for warnings.
+pub fn describe(skew: i64) -> String {
+    let direction = if skew < 0 { "behind" } else { "ahead of" };
+    format!(
+        "the system clock is {} {} the acme server",
+        amount(skew.unsigned_abs()),
+        direction
+    )
+}
+
+/// Refuse to renew if the clock is off by more than max_clock_skew, the
+/// days left of every certificate would be wrong. Failing to measure the
+/// skew isn't an error, renew reports problems with the acme server anyway.
+pub fn check_renew(config: &Config, ignore: bool) -> Result<()> {
+    let url = config.acme.url(config.acme.staging);
+    let skew = match measure(config, url) {
+        Ok(Some(skew)) => skew,
+        Ok(None) => {
+            debug!("The acme server didn't send a Date header, can't check the clock");
+            return Ok(());
+        }
+        Err(err) => {
+            debug!(
+                "Failed to check the clock against the acme server: {:#}",
+                err
+            );
+            return Ok(());
+        }
+    };
+    debug!("Clock skew to the acme server is {} seconds", skew);
+    if !is_excessive(config, skew) {
+        Ok(())
+    } else if ignore {
+        warn!("{}, continuing anyway", describe(skew));
+        Ok(())
+    } else {
+        bail!(
+            "{}, fix the clock or use --ignore-clock-skew",
+            describe(skew)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AcmeConfig;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            describe(-21 * 86400 - 600),
+            "the system clock is 21 days behind the acme server"
+        );
+        assert_eq!(
+            describe(3 * 3600),
+            "the system clock is 3 hours ahead of the acme server"
+        );
+        assert_eq!(
+            describe(-400),
+            "the system clock is 6 minutes behind the acme server"
+        );
+        assert_eq!(
+            describe(1),
+            "the system clock is 1 second ahead of the acme server"
+        );
+    }
+
+    #[test]
+    fn test_is_excessive() {
+        let mut config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: AcmeConfig::default(),
+            system: Default::default(),
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        assert!(!is_excessive(&config, 300));
+        assert!(!is_excessive(&config, -300));
+        assert!(is_excessive(&config, -301));
+        config.acme.max_clock_skew = Some(3600);
+        assert!(!is_excessive(&config, 1800));
+    }
+}
@@ -0,0 +1,389 @@
+//! Push challenge proofs to a running daemon instead of writing them to the
+//! challenge directory. The daemon keeps them in memory until they're
+//! deleted by renew or expire, so it doesn't need a shared directory with
+//! renew and renew doesn't need to run on the same host.
+use crate::chall::{Token, MAX_PROOF_SIZE};
+use crate::config::SystemConfig;
+use crate::errors::*;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: u64 = 10_000;
+/// Pushed proofs that are kept at most, there's one per name of an order.
+pub const MAX_PUSHED_PROOFS: usize = 1024;
+/// How long pushed proofs are served if chall_push_ttl isn't set.
+pub const CHALL_PUSH_TTL: u64 = 600;
+/// The paths of pushed proofs on the control listener, the second one is
+/// for upload scripts that aren't acme-redirect.
+const PROOF_PATHS: [&str; 2] = ["/challenges/{token}", "/_acme-redirect/chall/{token}"];
+
+/// The shared secret of renew and the daemon, surrounding whitespace is
+/// ignored so the file may end with a newline.
+pub fn read_secret(path: &Path) -> Result<String> {
+    let buf = fs::read_to_string(path)
+        .with_context(|| anyhow!("Failed to read chall_push_secret_file: {:?}", path))?;
+    let secret = buf.trim();
+    if secret.is_empty() {
+        bail!("chall_push_secret_file is empty: {:?}", path);
+    }
+    if secret.chars().any(|c| c.is_ascii_control()) {
+        bail!(
+            "chall_push_secret_file contains control characters: {:?}",
+            path
+        );
+    }
+    Ok(secret.to_string())
+}
+
+/// Sends proofs to the control listener of the daemon.
+#[derive(Debug, Clone)]
+pub struct ControlClient {
+    url: String,
+    secret: String,
+}
+
+impl ControlClient {
+    /// The client for chall_push_url, if it's set.
+    pub fn from_config(system: &SystemConfig) -> Result<Option<ControlClient>> {
+        let url = match &system.chall_push_url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let path = system
+            .chall_push_secret_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("chall_push_url requires chall_push_secret_file"))?;
+        Ok(Some(ControlClient {
+            url: url.trim_end_matches('/').to_string(),
+            secret: read_secret(path)?,
+        }))
+    }
+
+    fn url(&self, token: &Token) -> String {
+        format!("{}/challenges/{}", self.url, token.as_str())
+    }
+
+    fn send(&self, req: &mut ureq::Request, body: &str) -> Result<ureq::Response> {
+        let res = req
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .timeout_write(TIMEOUT)
+            .set("Authorization", &format!("Bearer {}", self.secret))
+            .send_string(body);
+        if let Some(err) = res.synthetic_error() {
+            bail!("{}", err);
+        }
+        Ok(res)
+    }
+
+    fn check_status(res: ureq::Response) -> Result<()> {
+        if !res.ok() {
+            bail!("http error {}: {}", res.status(), res.status_text());
+        }
+        Ok(())
+    }
+
+    pub fn put(&self, token: &Token, proof: &str) -> Result<()> {
+        let url = self.url(token);
+        debug!("Pushing challenge proof to {:?}", url);
+        self.send(&mut ureq::put(&url), proof)
+            .and_then(Self::check_status)
+            .with_context(|| anyhow!("Failed to push challenge proof to {:?}", url))
+    }
+
+    pub fn delete(&self, token: &Token) -> Result<()> {
+        let url = self.url(token);
+        debug!("Deleting pushed challenge proof: {:?}", url);
+        let res = self
+            .send(&mut ureq::delete(&url), "")
+            .with_context(|| anyhow!("Failed to delete challenge proof at {:?}", url))?;
+        // the proof expired or the daemon was restarted in the meantime
+        if res.status() == 404 {
+            return Ok(());
+        }
+        Self::check_status(res)
+            .with_context(|| anyhow!("Failed to delete challenge proof at {:?}", url))
+    }
+}
+
+/// Proofs that have been pushed to the daemon, they're served before the
+/// ones in the challenge directories.
+#[derive(Debug)]
+pub struct Proofs {
+    ttl: Duration,
+    proofs: Mutex<HashMap<String, (Instant, Vec<u8>)>>,
+}
+
+impl Proofs {
+    pub fn new(ttl: Duration) -> Proofs {
+        Proofs {
+            ttl,
+            proofs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn expire(&self, proofs: &mut HashMap<String, (Instant, Vec<u8>)>) {
+        let ttl = self.ttl;
+        proofs.retain(|token, (added, _)| {
+            let keep = added.elapsed() < ttl;
+            if !keep {
+                debug!("Pushed challenge proof expired: {:?}", token);
+            }
+            keep
+        });
+    }
+
+    pub fn insert(&self, token: &Token, proof: Vec<u8>) -> Result<()> {
+        let mut proofs = self.proofs.lock().unwrap();
+        self.expire(&mut proofs);
+        if proofs.len() >= MAX_PUSHED_PROOFS && !proofs.contains_key(token.as_str()) {
+            bail!("Too many pushed challenge proofs");
+        }
+        proofs.insert(token.as_str().to_string(), (Instant::now(), proof));
+        Ok(())
+    }
+
+    /// Returns false if there was no proof for this token.
+    pub fn remove(&self, token: &Token) -> bool {
+        self.proofs.lock().unwrap().remove(token.as_str()).is_some()
+    }
+
+    pub fn get(&self, token: &Token) -> Option<Vec<u8>> {
+        let mut proofs = self.proofs.lock().unwrap();
+        self.expire(&mut proofs);
+        proofs.get(token.as_str()).map(|(_, proof)| proof.clone())
+    }
+}
+
+/// The secret that's expected in the Authorization header of control
+/// requests.
+#[derive(Debug)]
+pub struct ControlSecret(String);
+
+impl ControlSecret {
+    pub fn new(secret: String) -> ControlSecret {
+        ControlSecret(secret)
+    }
+
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        let value = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match value {
+            Some(value) if value.len() == self.0.len() => {
+                openssl::memcmp::eq(value.as_bytes(), self.0.as_bytes())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_request(
+    req: &HttpRequest,
+    secret: &ControlSecret,
+    token: &str,
+) -> Result<Token, HttpResponse> {
+    if !secret.is_authorized(req) {
+        debug!("Rejecting control request without valid secret");
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+    Token::parse(token).map_err(|err| {
+        debug!("Rejecting control request: {:#}", err);
+        HttpResponse::BadRequest().finish()
+    })
+}
+
+async fn put_proof(
+    token: web::Path<String>,
+    req: HttpRequest,
+    body: web::Bytes,
+    secret: web::Data<ControlSecret>,
+    proofs: web::Data<Proofs>,
+) -> impl Responder {
+    let token = match parse_request(&req, &secret, &token) {
+        Ok(token) => token,
+        Err(res) => return res,
+    };
+    if body.len() as u64 > MAX_PROOF_SIZE {
+        return HttpResponse::PayloadTooLarge().finish();
+    }
+
+    match proofs.insert(&token, body.to_vec()) {
+        Ok(()) => {
+            info!("Received challenge proof: {:?}", token.as_str());
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => {
+            warn!("Rejecting challenge proof {:?}: {:#}", token.as_str(), err);
+            HttpResponse::ServiceUnavailable().finish()
+        }
+    }
+}
+
+async fn delete_proof(
+    token: web::Path<String>,
+    req: HttpRequest,
+    secret: web::Data<ControlSecret>,
+    proofs: web::Data<Proofs>,
+) -> impl Responder {
+    let token = match parse_request(&req, &secret, &token) {
+        Ok(token) => token,
+        Err(res) => return res,
+    };
+    if proofs.remove(&token) {
+        debug!("Deleted pushed challenge proof: {:?}", token.as_str());
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::PayloadConfig::new(MAX_PROOF_SIZE as usize))
+        .service(
+            web::resource(PROOF_PATHS)
+                .route(web::put().to(put_proof))
+                .route(web::delete().to(delete_proof)),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    fn token(t: &str) -> Token {
+        Token::parse(t).unwrap()
+    }
+
+    #[test]
+    fn test_proofs() {
+        let proofs = Proofs::new(Duration::from_secs(60));
+        let t = token("token_0123456789abcd");
+        assert_eq!(proofs.get(&t), None);
+        proofs.insert(&t, b"proof".to_vec()).unwrap();
+        assert_eq!(proofs.get(&t), Some(b"proof".to_vec()));
+        // it's still there for other validation attempts
+        assert_eq!(proofs.get(&t), Some(b"proof".to_vec()));
+        assert!(proofs.remove(&t));
+        assert!(!proofs.remove(&t));
+        assert_eq!(proofs.get(&t), None);
+    }
+
+    #[test]
+    fn test_proofs_expire() {
+        let proofs = Proofs::new(Duration::from_secs(0));
+        let t = token("token_0123456789abcd");
+        proofs.insert(&t, b"proof".to_vec()).unwrap();
+        assert_eq!(proofs.get(&t), None);
+    }
+
+    #[test]
+    fn test_proofs_limit() {
+        let proofs = Proofs::new(Duration::from_secs(60));
+        for i in 0..MAX_PUSHED_PROOFS {
+            let t = token(&format!("token_0123456789abcd_{}", i));
+            proofs.insert(&t, b"proof".to_vec()).unwrap();
+        }
+        let t = token("token_0123456789abcd_new");
+        assert!(proofs.insert(&t, b"proof".to_vec()).is_err());
+        // replacing a proof is still possible
+        let t = token("token_0123456789abcd_0");
+        proofs.insert(&t, b"new proof".to_vec()).unwrap();
+        assert_eq!(proofs.get(&t), Some(b"new proof".to_vec()));
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        fs::write(&path, "hunter2\n").unwrap();
+        assert_eq!(read_secret(&path).unwrap(), "hunter2");
+        fs::write(&path, "\n").unwrap();
+        assert!(read_secret(&path).is_err());
+        fs::write(&path, "hunter\r2").unwrap();
+        assert!(read_secret(&path).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_control_routes() {
+        let proofs = web::Data::new(Proofs::new(Duration::from_secs(60)));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ControlSecret::new("hunter2".to_string())))
+                .app_data(proofs.clone())
+                .configure(routes),
+        )
+        .await;
+        let uri = "/challenges/token_0123456789abcd";
+        let t = token("token_0123456789abcd");
+
+        for auth in &[
+            None,
+            Some("Bearer hunter3"),
+            Some("Bearer hunter"),
+            Some("hunter2"),
+        ] {
+            let req = test::TestRequest::put().uri(uri).set_payload("proof");
+            let req = match auth {
+                Some(auth) => req.header("Authorization", *auth),
+                None => req,
+            };
+            let res = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{:?}", auth);
+        }
+        assert_eq!(proofs.get(&t), None);
+
+        let req = test::TestRequest::put()
+            .uri(uri)
+            .header("Authorization", "Bearer hunter2")
+            .set_payload("proof")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(proofs.get(&t), Some(b"proof".to_vec()));
+
+        let req = test::TestRequest::put()
+            .uri("/challenges/short")
+            .header("Authorization", "Bearer hunter2")
+            .set_payload("proof")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri(uri)
+            .header("Authorization", "Bearer hunter2")
+            .set_payload("a".repeat(MAX_PROOF_SIZE as usize + 1))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(proofs.get(&t), Some(b"proof".to_vec()));
+
+        for status in &[StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let req = test::TestRequest::delete()
+                .uri(uri)
+                .header("Authorization", "Bearer hunter2")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), *status);
+        }
+        assert_eq!(proofs.get(&t), None);
+
+        let req = test::TestRequest::put()
+            .uri("/_acme-redirect/chall/token_0123456789abcd")
+            .header("Authorization", "Bearer hunter2")
+            .set_payload("other proof")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(proofs.get(&t), Some(b"other proof".to_vec()));
+    }
+}
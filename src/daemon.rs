@@ -1,100 +1,2714 @@
 use crate::args::DaemonArgs;
-use crate::chall;
-use crate::config::Config;
+use crate::chall::{self, ChallDirs};
+#[cfg(unix)]
+use crate::config::SystemConfig;
+use crate::config::{
+    self, CertConfig, Config, ConfigDir, DaemonConfig, ErrorCode, NotifyConfig, RedirectCode,
+};
+use crate::control::{self, ControlSecret, Proofs};
 use crate::errors::*;
 use crate::http_responses::*;
+use crate::logging::{self, AccessLog};
+use crate::metrics::{self, Metrics};
+use crate::notify;
+use crate::permissions;
+use crate::persist::LiveCerts;
+use crate::proxy_protocol;
+use crate::ratelimit::RateLimits;
+use crate::redirect_map::RedirectMap;
 use crate::sandbox;
-use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use crate::schedule::{self, Schedule};
+use crate::systemd::{self, ListenFd, Notifier};
+use crate::tls_alpn;
+use actix_http::http::ConnectionType;
+use actix_http::{HttpService, KeepAlive};
+use actix_rt::net::TcpStream;
+#[cfg(unix)]
+use actix_rt::net::UnixStream;
+#[cfg(unix)]
+use actix_rt::signal::unix::{signal, Signal, SignalKind};
+use actix_service::{map_config, pipeline_factory, Service};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::dev::{AppConfig, Server, ServiceRequest};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::web::Bytes;
 use actix_web::{middleware, App, HttpServer};
-use std::env;
+use actix_web::{route, web, HttpRequest, HttpResponse, Responder};
+#[cfg(unix)]
+use nix::unistd::Gid;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+#[cfg(unix)]
+use std::io::ErrorKind;
+use std::io::Read;
 use std::net::TcpListener;
-use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-fn get_host(req: &HttpRequest) -> Option<&str> {
-    if let Some(host) = req.headers().get("Host") {
-        if let Ok(host) = host.to_str() {
-            Some(host)
+const SHUTDOWN_TIMEOUT: u64 = 10;
+/// Custom response bodies are kept in memory, larger files are rejected.
+const MAX_PAGE_SIZE: u64 = 64 * 1024;
+/// How often the live certificates are checked against warn_if_days_left.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long the existence of the maintenance file is cached.
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The Retry-After of maintenance responses if maintenance_retry_after isn't set.
+const MAINTENANCE_RETRY_AFTER: u64 = 300;
+/// The max-age of permanent redirects if redirect_cache_seconds isn't set.
+const REDIRECT_CACHE_SECONDS: u64 = 300;
+/// Challenges and errors must never be cached by proxies in front of us, a
+/// cached 404 or redirect for a challenge path breaks the next validation.
+const NO_STORE: &str = "no-store";
+
+/// The Cache-Control of a redirect, temporary redirects are never cached.
+fn redirect_cache_control(code: RedirectCode, max_age: u64) -> String {
+    if code.is_permanent() && max_age > 0 {
+        format!("max-age={}", max_age)
+    } else {
+        NO_STORE.to_string()
+    }
+}
+
+/// The path and query string of the request as it's appended to the
+/// redirect url. The host of absolute urls in the request line is dropped and
+/// repeated leading slashes are collapsed, `https://host//other.host/` would
+/// be a protocol-relative url if it ends up in the path of a `redirect_to`.
+fn redirect_path(uri: &Uri) -> String {
+    let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let path = path_and_query.trim_start_matches('/');
+    format!("/{}", path)
+}
+
+/// Why a Host header was rejected, this is only logged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HostError {
+    Missing,
+    Unparsable,
+    ControlChars,
+    InvalidChar(char),
+    InvalidPort,
+    Invalid,
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostError::Missing => write!(f, "host is missing"),
+            HostError::Unparsable => write!(f, "host is not visible ascii"),
+            HostError::ControlChars => write!(f, "host contains control characters"),
+            HostError::InvalidChar(c) => write!(f, "host contains {:?}", c),
+            HostError::InvalidPort => write!(f, "host has an invalid port"),
+            HostError::Invalid => write!(f, "host is invalid"),
+        }
+    }
+}
+
+fn get_host(req: &HttpRequest) -> Result<&str, HostError> {
+    let host = req.headers().get("Host").ok_or(HostError::Missing)?;
+    let host = host.to_str().map_err(|_| HostError::Unparsable)?;
+    validate_host(host)
+}
+
+/// Reject anything that could change the meaning of the redirect url the
+/// host is put into, like userinfo, paths or fragments.
+fn validate_host(host: &str) -> Result<&str, HostError> {
+    if host.is_empty() {
+        return Err(HostError::Missing);
+    }
+    if host.chars().any(|c| c.is_ascii_control()) {
+        return Err(HostError::ControlChars);
+    }
+    if let Some(c) = host
+        .chars()
+        .find(|c| matches!(c, '@' | ' ' | '/' | '\\' | '?' | '#' | '%'))
+    {
+        return Err(HostError::InvalidChar(c));
+    }
+    let name = strip_port(host);
+    if name.is_empty() || (name.starts_with('[') && !name.ends_with(']')) {
+        return Err(HostError::Invalid);
+    }
+    let port = &host[name.len()..];
+    if !port.is_empty() {
+        match port.strip_prefix(':') {
+            Some(port) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => (),
+            _ => return Err(HostError::InvalidPort),
+        }
+    }
+    Ok(host)
+}
+
+/// Get the first value of a X-Forwarded-* header, proxies append to the list
+/// if the request was forwarded multiple times.
+fn get_forwarded<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+fn normalize_host(host: &str) -> String {
+    let host = strip_port(host);
+    config::to_ascii_name(host).unwrap_or_else(|_| host.to_lowercase())
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // ipv6 literal, eg. [::1]:8080
+        match host.find(']') {
+            Some(idx) => &host[..=idx],
+            None => host,
+        }
+    } else {
+        match host.find(':') {
+            Some(idx) => &host[..idx],
+            None => host,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum PathMatch {
+    Prefix(String),
+    Exact(String),
+}
+
+impl PathMatch {
+    fn parse(s: &str) -> PathMatch {
+        if let Some(path) = s.strip_prefix('=') {
+            PathMatch::Exact(path.to_string())
+        } else {
+            PathMatch::Prefix(s.to_string())
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatch::Exact(exact) => path == exact,
+        }
+    }
+}
+
+fn parse_paths(paths: &[String]) -> Vec<PathMatch> {
+    paths.iter().map(|p| PathMatch::parse(p)).collect()
+}
+
+#[derive(Debug, Default)]
+struct HostConfig {
+    redirect_to: Option<String>,
+    redirect_code: Option<RedirectCode>,
+    exclude_paths: Vec<PathMatch>,
+}
+
+#[derive(Debug, Default)]
+pub struct Redirects {
+    default_code: RedirectCode,
+    exclude_paths: Vec<PathMatch>,
+    trust_forwarded_headers: bool,
+    forwarded_https_code: ErrorCode,
+    strict_hosts: bool,
+    maintenance_retry_after: u64,
+    redirect_cache_seconds: u64,
+    health_path: Option<String>,
+    health_certs: Option<usize>,
+    hosts: HashMap<String, HostConfig>,
+    map: RedirectMap,
+    pages: Pages,
+}
+
+impl Redirects {
+    pub fn new(daemon: &DaemonConfig, certs: &[CertConfig]) -> Redirects {
+        let mut hosts = HashMap::new();
+        for cert in certs {
+            for name in &cert.dns_names {
+                hosts.insert(
+                    normalize_host(name),
+                    HostConfig {
+                        redirect_to: cert.redirect_to.clone(),
+                        redirect_code: cert.redirect_code,
+                        exclude_paths: parse_paths(&cert.exclude_paths),
+                    },
+                );
+            }
+        }
+        Redirects {
+            default_code: daemon.redirect_code,
+            exclude_paths: parse_paths(&daemon.exclude_paths),
+            trust_forwarded_headers: daemon.trust_forwarded_headers,
+            forwarded_https_code: daemon.forwarded_https_code,
+            strict_hosts: daemon.strict_hosts,
+            maintenance_retry_after: daemon
+                .maintenance_retry_after
+                .unwrap_or(MAINTENANCE_RETRY_AFTER),
+            redirect_cache_seconds: daemon
+                .redirect_cache_seconds
+                .unwrap_or(REDIRECT_CACHE_SECONDS),
+            health_path: daemon.health_path.clone(),
+            health_certs: daemon.health_show_certs.then_some(certs.len()),
+            hosts,
+            map: RedirectMap::default(),
+            pages: Pages::default(),
+        }
+    }
+
+    /// Redirect the hosts in the map, unless their certificate has a
+    /// `redirect_to`.
+    pub fn with_map(mut self, map: RedirectMap) -> Redirects {
+        self.map = map;
+        self
+    }
+
+    /// Use custom response bodies instead of the built-in pages.
+    pub fn with_pages(mut self, pages: Pages) -> Redirects {
+        self.pages = pages;
+        self
+    }
+
+    fn host(&self, host: &str) -> Option<&HostConfig> {
+        self.hosts.get(&normalize_host(host))
+    }
+
+    /// If redirects for this host are allowed, with `strict_hosts` only the
+    /// dns names of our certificates, including wildcards, are redirected.
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        if !self.strict_hosts {
+            return true;
+        }
+        let host = normalize_host(host);
+        if self.hosts.contains_key(&host) || self.map.target(&host).is_some() {
+            return true;
+        }
+        match host.split_once('.') {
+            Some((_, parent)) => self.hosts.contains_key(&format!("*.{}", parent)),
+            None => false,
+        }
+    }
+
+    pub fn redirect_url(&self, host: &str, path: &str) -> String {
+        if let Some(target) = self.host(host).and_then(|h| h.redirect_to.as_ref()) {
+            target.replace("{path}", path)
+        } else if let Some(url) = self.map.redirect_url(&normalize_host(host), path) {
+            url
+        } else {
+            format!("https://{}{}", host, path)
+        }
+    }
+
+    pub fn is_excluded(&self, host: &str, path: &str) -> bool {
+        let host_paths = self.host(host).map(|h| &h.exclude_paths[..]).unwrap_or(&[]);
+        self.exclude_paths
+            .iter()
+            .chain(host_paths)
+            .any(|m| m.matches(path))
+    }
+
+    pub fn redirect_code(&self, host: &str) -> RedirectCode {
+        self.host(host)
+            .and_then(|h| h.redirect_code)
+            .unwrap_or(self.default_code)
+    }
+}
+
+/// The response to health checks of load balancers, if the path is the
+/// configured `health_path`. This doesn't depend on the Host header.
+fn health_check(redirects: &Redirects, path: &str) -> Option<HttpResponse> {
+    if redirects.health_path.as_deref() != Some(path) {
+        return None;
+    }
+    let body = match redirects.health_certs {
+        Some(certs) => format!("OK, {} cert configs loaded\n", certs),
+        None => "OK\n".to_string(),
+    };
+    Some(HttpResponse::Ok().content_type("text/plain").body(body))
+}
+
+fn redirect_body(code: RedirectCode) -> &'static str {
+    match code.as_u16() {
+        302 => FOUND,
+        307 => TEMPORARY_REDIRECT,
+        308 => PERMANENT_REDIRECT,
+        _ => REDIRECT,
+    }
+}
+
+/// A response body that replaces one of the built-in pages.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Page {
+    body: Option<Bytes>,
+    content_type: Option<String>,
+}
+
+impl Page {
+    fn load(path: Option<&Path>, content_type: Option<&str>) -> Result<Page> {
+        let body = if let Some(path) = path {
+            let file = fs::File::open(path)
+                .with_context(|| anyhow!("Failed to open response body: {:?}", path))?;
+            let mut body = Vec::new();
+            file.take(MAX_PAGE_SIZE + 1)
+                .read_to_end(&mut body)
+                .with_context(|| anyhow!("Failed to read response body: {:?}", path))?;
+            if body.len() as u64 > MAX_PAGE_SIZE {
+                bail!(
+                    "Response body {:?} is larger than {} bytes",
+                    path,
+                    MAX_PAGE_SIZE
+                );
+            }
+            Some(Bytes::from(body))
         } else {
             None
+        };
+        Ok(Page {
+            body,
+            content_type: content_type.map(String::from),
+        })
+    }
+
+    fn respond(&self, mut res: HttpResponseBuilder, default: &'static str) -> HttpResponse {
+        if let Some(content_type) = &self.content_type {
+            res.content_type(content_type.as_str());
         }
-    } else {
-        None
+        match &self.body {
+            Some(body) => res.body(body.clone()),
+            None => res.body(default),
+        }
+    }
+}
+
+/// The response bodies configured in the daemon section, read from their
+/// files when the daemon starts and on reload.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pages {
+    redirect: Page,
+    not_found: Page,
+    maintenance: Page,
+}
+
+impl Pages {
+    pub fn load(daemon: &DaemonConfig) -> Result<Pages> {
+        Ok(Pages {
+            redirect: Page::load(
+                daemon.redirect_body_file.as_deref(),
+                daemon.redirect_content_type.as_deref(),
+            )?,
+            not_found: Page::load(
+                daemon.not_found_body_file.as_deref(),
+                daemon.not_found_content_type.as_deref(),
+            )?,
+            maintenance: Page::load(
+                daemon.maintenance_body_file.as_deref(),
+                daemon.maintenance_content_type.as_deref(),
+            )?,
+        })
+    }
+
+    fn redirect(&self, code: RedirectCode, location: String, max_age: u64) -> HttpResponse {
+        let status = StatusCode::from_u16(code.as_u16()).expect("redirect code is always valid");
+        let mut res = HttpResponse::build(status);
+        res.header("Location", location);
+        res.header("Cache-Control", redirect_cache_control(code, max_age));
+        self.redirect.respond(res, redirect_body(code))
+    }
+
+    fn not_found(&self) -> HttpResponse {
+        let mut res = HttpResponse::NotFound();
+        res.header("Cache-Control", NO_STORE);
+        self.not_found.respond(res, NOT_FOUND)
+    }
+
+    fn maintenance(&self, retry_after: u64) -> HttpResponse {
+        let mut res = HttpResponse::ServiceUnavailable();
+        res.header("Retry-After", retry_after.to_string());
+        res.header("Cache-Control", NO_STORE);
+        self.maintenance.respond(res, SERVICE_UNAVAILABLE)
     }
 }
 
+/// Switches the daemon into maintenance mode while a file exists, the file
+/// is checked at most once per MAINTENANCE_CHECK_INTERVAL.
+#[derive(Debug)]
+pub struct Maintenance {
+    path: PathBuf,
+    checked: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Maintenance {
+    pub fn new(path: PathBuf) -> Maintenance {
+        Maintenance {
+            path,
+            checked: Mutex::new(None),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        let mut checked = self.checked.lock().unwrap();
+        let previous = match *checked {
+            Some((at, active)) if at.elapsed() < MAINTENANCE_CHECK_INTERVAL => return active,
+            Some((_, active)) => Some(active),
+            None => None,
+        };
+        let active = self.path.exists();
+        if previous.unwrap_or(false) != active {
+            if active {
+                info!(
+                    "Maintenance file {:?} exists, answering with 503",
+                    self.path
+                );
+            } else {
+                info!(
+                    "Maintenance file {:?} is gone, redirecting again",
+                    self.path
+                );
+            }
+        }
+        *checked = Some((Instant::now(), active));
+        active
+    }
+}
+
+/// The extra headers from the config, added to every response that doesn't
+/// set them already.
+fn default_headers(daemon: &DaemonConfig) -> middleware::DefaultHeaders {
+    daemon.headers.iter().fold(
+        middleware::DefaultHeaders::new(),
+        |headers, (name, value)| headers.header(name.as_str(), value.as_str()),
+    )
+}
+
+#[inline]
+fn is_challenge(path: &str) -> bool {
+    path.starts_with("/.well-known/acme-challenge/")
+}
+
+#[inline]
+fn too_many_requests() -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header("Retry-After", "1")
+        .header("Cache-Control", NO_STORE)
+        .body(TOO_MANY_REQUESTS)
+}
+
 #[inline]
 fn bad_request() -> HttpResponse {
-    HttpResponse::BadRequest().body(BAD_REQUEST)
+    HttpResponse::BadRequest()
+        .header("Cache-Control", NO_STORE)
+        .body(BAD_REQUEST)
+}
+
+/// Limits for slow or chatty clients, a redirect only needs the request line
+/// and the Host header.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientLimits {
+    request_timeout: Duration,
+    disconnect_timeout: Duration,
+    keep_alive: Option<Duration>,
+    max_header_size: usize,
+}
+
+impl ClientLimits {
+    pub fn from_args(args: &DaemonArgs) -> ClientLimits {
+        ClientLimits {
+            request_timeout: Duration::from_secs(args.client_request_timeout),
+            disconnect_timeout: Duration::from_secs(args.client_disconnect_timeout),
+            keep_alive: match args.keep_alive {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_header_size: args.max_header_size,
+        }
+    }
+
+    fn request_timeout_ms(&self) -> u64 {
+        self.request_timeout.as_millis() as u64
+    }
+
+    fn disconnect_timeout_ms(&self) -> u64 {
+        self.disconnect_timeout.as_millis() as u64
+    }
+
+    /// The keep-alive timer of actix is also the timeout between two reads of
+    /// a request once the client started sending it, so it's never disabled.
+    /// Without keep-alive every connection is closed after the response.
+    fn keep_alive(&self) -> KeepAlive {
+        let timeout = self.keep_alive.unwrap_or(self.request_timeout);
+        KeepAlive::Timeout(timeout.as_secs() as usize)
+    }
+}
+
+/// The size of the request line and headers as they were sent, roughly.
+fn header_size(req: &ServiceRequest) -> usize {
+    let line = req.method().as_str().len() + req.uri().to_string().len() + 11;
+    req.headers().iter().fold(line, |size, (name, value)| {
+        size + name.as_str().len() + value.len() + 4
+    })
 }
 
 #[inline]
-fn not_found() -> HttpResponse {
-    HttpResponse::NotFound().body(NOT_FOUND)
+fn header_too_large() -> HttpResponse {
+    HttpResponse::build(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        .header("Cache-Control", NO_STORE)
+        .body(REQUEST_HEADER_FIELDS_TOO_LARGE)
 }
 
-#[get("/{p:.*}")]
-async fn redirect(req: HttpRequest) -> impl Responder {
+fn proof_response(proof: impl Into<Bytes>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .header("Cache-Control", NO_STORE)
+        .body(proof.into())
+}
+
+#[route("/{p:.*}", method = "GET", method = "HEAD")]
+async fn redirect(
+    req: HttpRequest,
+    redirects: web::Data<RwLock<Redirects>>,
+    metrics: web::Data<Metrics>,
+    maintenance: Option<web::Data<Maintenance>>,
+) -> impl Responder {
     debug!("REQ: {:?}", req);
+    let redirects = redirects.read().unwrap();
+
+    if let Some(res) = health_check(&redirects, req.path()) {
+        return res;
+    }
+
+    if maintenance.is_some_and(|m| m.is_active()) {
+        debug!("maintenance mode, not redirecting");
+        return redirects
+            .pages
+            .maintenance(redirects.maintenance_retry_after);
+    }
 
-    let host = if let Some(host) = get_host(&req) {
-        host
+    let forwarded_host = if redirects.trust_forwarded_headers {
+        let proto = get_forwarded(&req, "X-Forwarded-Proto");
+        if proto.is_some_and(|p| p.eq_ignore_ascii_case("https")) {
+            // redirecting would send the client back to the same url
+            debug!("request was forwarded from https, not redirecting");
+            let code = redirects.forwarded_https_code.as_u16();
+            if code == 404 {
+                return redirects.pages.not_found();
+            }
+            let status = StatusCode::from_u16(code).expect("error code is always valid");
+            return HttpResponse::build(status)
+                .header("Cache-Control", NO_STORE)
+                .body("");
+        }
+        get_forwarded(&req, "X-Forwarded-Host")
     } else {
-        return bad_request();
+        None
+    };
+
+    let host = match forwarded_host.map_or_else(|| get_host(&req), validate_host) {
+        Ok(host) => host,
+        Err(err) => {
+            match forwarded_host {
+                Some(host) => debug!("Rejecting request, forwarded {}: {:?}", err, host),
+                None => debug!(
+                    "Rejecting request, {}: {:?}",
+                    err,
+                    req.headers().get("Host")
+                ),
+            }
+            metrics.bad_requests.inc();
+            return bad_request();
+        }
     };
     debug!("host: {:?}", host);
 
-    let path = req.uri();
+    if !redirects.is_allowed_host(host) {
+        debug!(
+            "host is not in any certificate, not redirecting: {:?}",
+            host
+        );
+        return redirects.pages.not_found();
+    }
+
+    if redirects.is_excluded(host, req.path()) {
+        debug!("path is excluded from redirect: {:?}", req.path());
+        return redirects.pages.not_found();
+    }
+
+    let path = redirect_path(req.uri());
     debug!("path: {:?}", path);
 
-    let url = format!("https://{}{}", host, path);
+    let url = redirects.redirect_url(host, &path);
     if url.chars().any(|c| c == '\n' || c == '\r') {
+        metrics.bad_requests.inc();
         return bad_request();
     }
 
-    HttpResponse::MovedPermanently()
-        .header("Location", url)
-        .body(REDIRECT)
+    let code = redirects.redirect_code(host);
+    metrics.redirects.inc();
+    redirects
+        .pages
+        .redirect(code, url, redirects.redirect_cache_seconds)
 }
 
-#[get("/.well-known/acme-challenge/{chall}")]
-async fn acme(token: web::Path<String>, req: HttpRequest) -> impl Responder {
+#[route("/.well-known/acme-challenge/{chall}", method = "GET", method = "HEAD")]
+async fn acme(
+    token: web::Path<String>,
+    req: HttpRequest,
+    chall_dirs: web::Data<ChallDirs>,
+    proofs: Option<web::Data<Proofs>>,
+    redirects: web::Data<RwLock<Redirects>>,
+    metrics: web::Data<Metrics>,
+) -> impl Responder {
     debug!("REQ: {:?}", req);
     info!("acme: {:?}", token);
 
-    if !chall::valid_token(&token) {
-        return bad_request();
+    let token = match chall::Token::parse(&token) {
+        Ok(token) => token,
+        Err(err) => {
+            debug!("Rejecting challenge request: {:#}", err);
+            metrics.bad_requests.inc();
+            return bad_request();
+        }
+    };
+
+    // the challenge certificates include their private key
+    if tls_alpn::is_token(&token) {
+        metrics.challenge_not_found.inc();
+        return redirects.read().unwrap().pages.not_found();
+    }
+
+    if let Some(proof) = proofs.and_then(|proofs| proofs.get(&token)) {
+        debug!("Serving pushed challenge proof");
+        metrics.challenges.inc();
+        return proof_response(proof);
+    }
+
+    // reading files would block the worker
+    let proof = web::block(move || Ok::<_, ()>(chall_dirs.read(&token))).await;
+    if let Ok(Some(proof)) = proof {
+        metrics.challenges.inc();
+        proof_response(proof)
+    } else {
+        metrics.challenge_not_found.inc();
+        redirects.read().unwrap().pages.not_found()
+    }
+}
+
+/// Which requests end up in the access log. Acme challenges are always
+/// logged, other requests are skipped if they match log_exclude_paths and
+/// only every n-th one is logged with log_sample_rate.
+#[derive(Debug)]
+struct LogFilter {
+    exclude_paths: Vec<PathMatch>,
+    sample_rate: u64,
+    seen: AtomicU64,
+}
+
+impl LogFilter {
+    fn new(daemon: &DaemonConfig) -> LogFilter {
+        LogFilter {
+            exclude_paths: parse_paths(&daemon.log_exclude_paths),
+            sample_rate: daemon.log_sample_rate.unwrap_or(1).max(1),
+            seen: AtomicU64::new(0),
+        }
     }
 
-    let path = Path::new("challs").join(token.as_ref());
-    debug!("Reading challenge proof: {:?}", path);
-    if let Ok(proof) = fs::read(path) {
-        HttpResponse::Ok().body(proof)
+    fn should_log(&self, path: &str) -> bool {
+        if is_challenge(path) {
+            return true;
+        }
+        if self.exclude_paths.iter().any(|p| p.matches(path)) {
+            return false;
+        }
+        // the first request is always logged
+        self.seen.fetch_add(1, Ordering::Relaxed) % self.sample_rate == 0
+    }
+}
+
+fn access_logger() -> middleware::Logger {
+    let logger = middleware::Logger::default();
+    if logging::is_json() {
+        // requests are logged by AccessLog instead
+        logger.exclude_regex("")
     } else {
-        not_found()
+        logger
+    }
+}
+
+/// Everything except acme challenges on a challenge-only listener.
+async fn not_challenge(req: HttpRequest, redirects: web::Data<RwLock<Redirects>>) -> HttpResponse {
+    debug!("REQ: {:?}", req);
+    debug!("challenge-only listener, not redirecting");
+    redirects.read().unwrap().pages.not_found()
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(acme).service(redirect);
+}
+
+/// Only the acme challenges, there's no redirect catch-all.
+fn challenge_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(acme)
+        .service(web::resource("/{p:.*}").to(not_challenge));
+}
+
+/// Reloads the cert configs, the redirect map and response bodies on SIGHUP
+/// and swaps them into the running daemon.
+pub struct Reloader {
+    daemon: DaemonConfig,
+    notify: NotifyConfig,
+    config_dir: ConfigDir,
+    redirect_map: Option<PathBuf>,
+    redirects: web::Data<RwLock<Redirects>>,
+    metrics: web::Data<Metrics>,
+    live_certs: Option<LiveCerts>,
+}
+
+impl Reloader {
+    pub fn reload(&self) -> Result<()> {
+        let certs = self.config_dir.load_certs()?;
+        config::validate_certs(&certs)?;
+        let pages = Pages::load(&self.daemon).unwrap_or_else(|err| {
+            // the files might not be reachable after chrooting
+            warn!(
+                "Failed to reload response bodies, keeping old ones: {:#}",
+                err
+            );
+            self.redirects.read().unwrap().pages.clone()
+        });
+        let map = match &self.redirect_map {
+            Some(path) => RedirectMap::load(path).unwrap_or_else(|err| {
+                warn!("Failed to reload redirect map, keeping old one: {:#}", err);
+                self.redirects.read().unwrap().map.clone()
+            }),
+            None => RedirectMap::default(),
+        };
+        let redirects = Redirects::new(&self.daemon, &certs)
+            .with_pages(pages)
+            .with_map(map);
+        *self.redirects.write().unwrap() = redirects;
+        if let Some(live_certs) = &self.live_certs {
+            self.metrics.load_certs(live_certs, &certs);
+        }
+        info!("Loaded {} cert configs", certs.len());
+        Ok(())
+    }
+
+    /// Send a notification for every live certificate that's below
+    /// warn_if_days_left.
+    pub fn check_expiry(&self) -> Result<()> {
+        let live_certs = match &self.live_certs {
+            Some(live_certs) => live_certs,
+            None => return Ok(()),
+        };
+        for cert in self.config_dir.load_certs()? {
+            match live_certs.load_cert_info(&cert.name) {
+                Ok(Some(info)) => {
+                    if let Some(event) = notify::expiry_warning(&self.notify, &cert.name, &info) {
+                        notify::send(&self.notify, &event);
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => warn!("Failed to load certificate {:?}: {:#}", cert.name, err),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn check_expiry(reloader: Arc<Reloader>) {
+    let mut interval = actix_rt::time::interval(EXPIRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let reloader = reloader.clone();
+        if let Err(err) = web::block(move || reloader.check_expiry()).await {
+            warn!("Failed to check certificate expiry: {:#}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(mut hangup: Signal, reloader: Arc<Reloader>) {
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading cert configs");
+        if let Err(err) = reloader.reload() {
+            error!(
+                "Failed to reload cert configs, keeping old config: {:#}",
+                err
+            );
+        }
+    }
+}
+
+async fn shutdown(servers: Vec<Server>, notifier: Option<Arc<Notifier>>) {
+    info!("Shutting down, waiting for pending requests to finish");
+    if let Some(notifier) = &notifier {
+        notifier.notify("STOPPING=1");
+    }
+    for server in servers {
+        server.stop(true).await;
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_on(mut signal: Signal, servers: Vec<Server>, notifier: Option<Arc<Notifier>>) {
+    if signal.recv().await.is_some() {
+        shutdown(servers, notifier).await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_on_ctrl_c(servers: Vec<Server>, notifier: Option<Arc<Notifier>>) {
+    if actix_rt::signal::ctrl_c().await.is_ok() {
+        shutdown(servers, notifier).await;
+    }
+}
+
+async fn watchdog(notifier: Arc<Notifier>, interval: Duration) {
+    let mut interval = actix_rt::time::interval(interval);
+    loop {
+        interval.tick().await;
+        notifier.notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(unix)]
+fn register(kind: SignalKind) -> Result<Signal> {
+    signal(kind).context("Failed to register signal handler")
+}
+
+/// The sockets of one http server.
+#[derive(Default)]
+pub struct HttpSockets {
+    tcp: Vec<TcpListener>,
+    #[cfg(unix)]
+    unix: Vec<UnixListener>,
+}
+
+impl HttpSockets {
+    fn is_empty(&self) -> bool {
+        #[cfg(unix)]
+        let unix = self.unix.is_empty();
+        #[cfg(not(unix))]
+        let unix = true;
+        self.tcp.is_empty() && unix
+    }
+}
+
+#[derive(Default)]
+pub struct Listeners {
+    http: HttpSockets,
+    /// Only serve acme challenges on the http sockets
+    challenge_only: bool,
+    /// The sockets of --bind-challenge
+    challenge: HttpSockets,
+    metrics: Option<TcpListener>,
+    control: Option<TcpListener>,
+    /// The socket of --bind-tls-alpn
+    tls_alpn: Option<TcpListener>,
+}
+
+impl Listeners {
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn bind(args: &DaemonArgs, config: &Config) -> Result<Listeners> {
+        let mut listeners = Listeners::default();
+        if let Some(path) = &args.bind_unix {
+            #[cfg(unix)]
+            listeners.http.unix.push(
+                bind_unix(path, args.bind_unix_mode, &config.system)
+                    .with_context(|| anyhow!("Failed to bind unix socket: {:?}", path))?,
+            );
+            #[cfg(not(unix))]
+            bail!(
+                "Can't bind {:?}, unix sockets are not supported on this platform",
+                path
+            );
+        } else {
+            for addr in args.bind_addrs() {
+                let socket = TcpListener::bind(addr)
+                    .with_context(|| anyhow!("Failed to bind socket: {}", addr))?;
+                listeners.http.tcp.push(socket);
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// Use the sockets passed by systemd, or bind our own if there are none.
+    /// The sockets of --bind-challenge are always bound by us.
+    fn open(args: &DaemonArgs, config: &Config) -> Result<Listeners> {
+        let fds = systemd::listen_fds()?;
+        let mut listeners = if fds.is_empty() {
+            Listeners::bind(args, config)?
+        } else {
+            info!("Using {} sockets passed by the service manager", fds.len());
+            let mut listeners = Listeners::default();
+            for fd in fds {
+                match fd {
+                    ListenFd::Tcp(socket) => listeners.http.tcp.push(socket),
+                    #[cfg(unix)]
+                    ListenFd::Unix(socket) => listeners.http.unix.push(socket),
+                }
+            }
+            listeners
+        };
+        listeners.challenge_only = args.challenge_only;
+        if args.challenge_only {
+            info!("Only serving acme challenges, requests are not redirected");
+        }
+        for addr in args.challenge_addrs() {
+            let socket = TcpListener::bind(addr)
+                .with_context(|| anyhow!("Failed to bind challenge socket: {}", addr))?;
+            info!("Only serving acme challenges on {}", addr);
+            listeners.challenge.tcp.push(socket);
+        }
+        if let Some(addr) = &args.bind_tls_alpn {
+            let socket = TcpListener::bind(addr)
+                .with_context(|| anyhow!("Failed to bind tls-alpn-01 socket: {}", addr))?;
+            info!("Answering tls-alpn-01 challenges on {}", addr);
+            listeners.tls_alpn = Some(socket);
+        }
+        Ok(listeners)
     }
 }
 
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            debug!("Removing stale socket: {:?}", path);
+            fs::remove_file(path)?;
+        }
+        Ok(_) => bail!("File exists and is not a socket"),
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32, system: &SystemConfig) -> Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let socket = UnixListener::bind(path)?;
+    if let Some(name) = &system.socket_group {
+        let group = users::get_group_by_name(name)
+            .ok_or_else(|| anyhow!("Failed to look up group: {:?}", name))?;
+        let gid = Gid::from_raw(group.gid());
+        nix::unistd::chown(path, None, Some(gid)).context("Failed to change socket group")?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .context("Failed to set socket permissions")?;
+    Ok(socket)
+}
+
+/// The directories and files the daemon reads at runtime, opened before
+/// dropping privileges.
+pub struct Resources {
+    pub config_dir: ConfigDir,
+    pub chall_dirs: ChallDirs,
+    pub live_certs: Option<LiveCerts>,
+    pub pages: Pages,
+    pub redirect_map_path: Option<PathBuf>,
+    pub redirect_map: RedirectMap,
+    pub maintenance: Option<Maintenance>,
+    pub control_secret: Option<ControlSecret>,
+}
+
 #[actix_web::main]
-pub async fn spawn(socket: TcpListener) -> Result<()> {
-    HttpServer::new(move || {
-        App::new()
-            // enable logger
-            .wrap(middleware::Logger::default())
-            .service(acme)
-            .service(redirect)
-    })
-    .listen(socket)
-    .context("Failed to bind socket")?
-    .run()
-    .await
-    .context("Failed to start http daemon")?;
+pub async fn spawn(
+    listeners: Listeners,
+    notifier: Option<Notifier>,
+    proxy_protocol: bool,
+    config: Config,
+    resources: Resources,
+    rate_limits: RateLimits,
+    limits: ClientLimits,
+) -> Result<()> {
+    let Resources {
+        config_dir,
+        chall_dirs,
+        live_certs,
+        pages,
+        redirect_map_path,
+        redirect_map,
+        maintenance,
+        control_secret,
+    } = resources;
+    info!("Loaded {} cert configs", config.certs.len());
+    if redirect_map_path.is_some() {
+        info!("Loaded {} redirect map entries", redirect_map.len());
+    }
+    let redirects = Redirects::new(&config.daemon, &config.certs)
+        .with_pages(pages)
+        .with_map(redirect_map);
+    let redirects = web::Data::new(RwLock::new(redirects));
+
+    let metrics = Metrics::default();
+    if let Some(live_certs) = &live_certs {
+        metrics.load_certs(live_certs, &config.certs);
+    }
+    let metrics = web::Data::new(metrics);
+    let chall_dirs = web::Data::new(chall_dirs);
+    let maintenance = maintenance.map(web::Data::new);
+    let proofs = if listeners.control.is_some() {
+        let ttl = config
+            .daemon
+            .chall_push_ttl
+            .unwrap_or(control::CHALL_PUSH_TTL);
+        Some(web::Data::new(Proofs::new(Duration::from_secs(ttl))))
+    } else {
+        None
+    };
+    if let Some(socket) = listeners.tls_alpn {
+        let chall_dirs = chall_dirs.clone();
+        let proofs = proofs.clone();
+        let metrics = metrics.clone();
+        tls_alpn::spawn(socket, move |token| {
+            let proof = proofs
+                .as_ref()
+                .and_then(|proofs| proofs.get(token))
+                .or_else(|| chall_dirs.read(token));
+            if proof.is_some() {
+                metrics.challenges.inc();
+            } else {
+                metrics.challenge_not_found.inc();
+            }
+            proof
+        })
+        .context("Failed to start tls-alpn-01 listener")?;
+    }
+    if rate_limits.is_enabled() {
+        info!("Rate limiting requests per client ip");
+    }
+    let rate_limits = Arc::new(rate_limits);
+
+    // the app of the challenge-only listeners has the same middleware
+    let app = {
+        let redirects = redirects.clone();
+        let metrics = metrics.clone();
+        let daemon = config.daemon.clone();
+        let proofs = proofs.clone();
+        let log_filter = Arc::new(LogFilter::new(&config.daemon));
+        move |challenge_only: bool| {
+            let rate_limits = rate_limits.clone();
+            let log_filter = log_filter.clone();
+            let limit_metrics = metrics.clone();
+            let size_metrics = metrics.clone();
+            let app = App::new()
+                .wrap_fn(move |req, srv| {
+                    let res = if header_size(&req) <= limits.max_header_size {
+                        Ok(srv.call(req))
+                    } else {
+                        debug!(
+                            "Request headers are larger than {} bytes",
+                            limits.max_header_size
+                        );
+                        size_metrics.bad_requests.inc();
+                        Err(req)
+                    };
+                    async move {
+                        let mut res = match res {
+                            Ok(res) => res.await?,
+                            Err(req) => req.into_response(header_too_large()),
+                        };
+                        if limits.keep_alive.is_none() {
+                            res.response_mut()
+                                .head_mut()
+                                .set_connection_type(ConnectionType::Close);
+                        }
+                        Ok(res)
+                    }
+                })
+                .wrap_fn(move |req, srv| {
+                    let ip = req.peer_addr().map(|addr| addr.ip());
+                    let res = if rate_limits.check(ip, is_challenge(req.path())) {
+                        Ok(srv.call(req))
+                    } else {
+                        debug!("Rate limit exceeded by {:?}", ip);
+                        limit_metrics.rate_limited.inc();
+                        Err(req)
+                    };
+                    async move {
+                        match res {
+                            Ok(res) => res.await,
+                            Err(req) => Ok(req.into_response(too_many_requests())),
+                        }
+                    }
+                })
+                .wrap(default_headers(&daemon))
+                .wrap_fn(move |req, srv| {
+                    let log = log_filter
+                        .should_log(req.path())
+                        .then(|| AccessLog::new(&req));
+                    let res = srv.call(req);
+                    async move {
+                        let res = res.await?;
+                        if let Some(log) = log {
+                            log.finish(&res);
+                        }
+                        Ok(res)
+                    }
+                })
+                .app_data(redirects.clone())
+                .app_data(chall_dirs.clone())
+                .app_data(metrics.clone());
+            let app = match &maintenance {
+                Some(maintenance) => app.app_data(maintenance.clone()),
+                None => app,
+            };
+            let app = match &proofs {
+                Some(proofs) => app.app_data(proofs.clone()),
+                None => app,
+            };
+            if challenge_only {
+                app.configure(challenge_routes)
+            } else {
+                app.configure(routes)
+            }
+        }
+    };
+
+    let mut groups = vec![(listeners.challenge_only, listeners.http)];
+    if !listeners.challenge.is_empty() {
+        groups.push((true, listeners.challenge));
+    }
+    let mut servers = Vec::new();
+    for (challenge_only, sockets) in groups {
+        let app = {
+            let app = app.clone();
+            move || app(challenge_only)
+        };
+        servers.push(if proxy_protocol {
+            // HttpServer can't read from the connection before the http service,
+            // so the server is assembled by hand. The app config is only used to
+            // generate urls, which we don't do.
+            let mut builder = Server::build()
+                .disable_signals()
+                .shutdown_timeout(SHUTDOWN_TIMEOUT);
+            for socket in sockets.tcp {
+                let addr = socket.local_addr()?;
+                let app = app.clone();
+                builder = builder
+                    .listen("acme-redirect", socket, move || {
+                        pipeline_factory(|io: TcpStream| {
+                            let peer = io.peer_addr().ok();
+                            proxy_protocol::accept(io, peer)
+                        })
+                        .and_then(
+                            HttpService::build()
+                                .client_timeout(limits.request_timeout_ms())
+                                .client_disconnect(limits.disconnect_timeout_ms())
+                                .keep_alive(limits.keep_alive())
+                                .local_addr(addr)
+                                .finish(map_config(app(), |_| AppConfig::default())),
+                        )
+                    })
+                    .with_context(|| anyhow!("Failed to bind socket: {}", addr))?;
+            }
+            #[cfg(unix)]
+            for socket in sockets.unix {
+                let app = app.clone();
+                builder = builder
+                    .listen_uds("acme-redirect", socket, move || {
+                        pipeline_factory(|io: UnixStream| proxy_protocol::accept(io, None))
+                            .and_then(
+                                HttpService::build()
+                                    .client_timeout(limits.request_timeout_ms())
+                                    .client_disconnect(limits.disconnect_timeout_ms())
+                                    .keep_alive(limits.keep_alive())
+                                    .finish(map_config(app(), |_| AppConfig::default())),
+                            )
+                    })
+                    .context("Failed to bind socket")?;
+            }
+            builder.run()
+        } else {
+            let mut server = HttpServer::new(app)
+                .client_timeout(limits.request_timeout_ms())
+                .client_shutdown(limits.disconnect_timeout_ms())
+                .keep_alive(limits.keep_alive())
+                .disable_signals()
+                .shutdown_timeout(SHUTDOWN_TIMEOUT);
+            for socket in sockets.tcp {
+                let addr = socket.local_addr()?;
+                server = server
+                    .listen(socket)
+                    .with_context(|| anyhow!("Failed to bind socket: {}", addr))?;
+            }
+            #[cfg(unix)]
+            for socket in sockets.unix {
+                server = server.listen_uds(socket).context("Failed to bind socket")?;
+            }
+            server.run()
+        });
+    }
+
+    if let Some(socket) = listeners.metrics {
+        let metrics = metrics.clone();
+        servers.push(
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(metrics.clone())
+                    .configure(metrics::routes)
+            })
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(SHUTDOWN_TIMEOUT)
+            .listen(socket)
+            .context("Failed to bind metrics socket")?
+            .run(),
+        );
+    }
+
+    if let (Some(socket), Some(proofs), Some(secret)) = (listeners.control, proofs, control_secret)
+    {
+        let secret = web::Data::new(secret);
+        servers.push(
+            HttpServer::new(move || {
+                App::new()
+                    .wrap(access_logger())
+                    .app_data(proofs.clone())
+                    .app_data(secret.clone())
+                    .configure(control::routes)
+            })
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(SHUTDOWN_TIMEOUT)
+            .listen(socket)
+            .context("Failed to bind control socket")?
+            .run(),
+        );
+    }
+
+    let notifier = notifier.map(Arc::new);
+    let warn_expiry = config.notify.is_enabled() && config.notify.warn_if_days_left.is_some();
+    let reloader = Arc::new(Reloader {
+        daemon: config.daemon,
+        notify: config.notify,
+        config_dir,
+        redirect_map: redirect_map_path,
+        redirects,
+        metrics,
+        live_certs,
+    });
+    if warn_expiry {
+        actix_rt::spawn(check_expiry(reloader.clone()));
+    }
+    #[cfg(unix)]
+    {
+        actix_rt::spawn(reload_on_hangup(register(SignalKind::hangup())?, reloader));
+        for kind in &[SignalKind::terminate(), SignalKind::interrupt()] {
+            actix_rt::spawn(shutdown_on(
+                register(*kind)?,
+                servers.clone(),
+                notifier.clone(),
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // there's no SIGHUP, the configs can't be reloaded at runtime
+        drop(reloader);
+        actix_rt::spawn(shutdown_on_ctrl_c(servers.clone(), notifier.clone()));
+    }
+
+    // all sockets are listening at this point
+    if let Some(notifier) = &notifier {
+        if let Some(interval) = systemd::watchdog_interval() {
+            debug!("Sending watchdog notifications every {:?}", interval);
+            actix_rt::spawn(watchdog(notifier.clone(), interval));
+        }
+        notifier.notify("READY=1");
+    }
+
+    for server in servers {
+        server.await.context("Failed to start http daemon")?;
+    }
     Ok(())
 }
 
 pub fn run(config: Config, args: DaemonArgs) -> Result<()> {
-    env::set_current_dir(&config.system.chall_dir)?;
-    let socket = TcpListener::bind(&args.bind_addr).context("Failed to bind socket")?;
-    sandbox::init(&args).context("Failed to drop privileges")?;
-    spawn(socket)
+    let rate_limits = RateLimits::from_args(&args)?;
+    let limits = ClientLimits::from_args(&args);
+    // forked before anything else, while there's only a single thread
+    let _renew_helper = match Schedule::from_args(&args) {
+        Some(schedule) => Some(schedule::spawn(config.clone(), schedule)?),
+        None => None,
+    };
+    // keep the directories open so we can still read them after dropping privileges
+    let config_dir = ConfigDir::open(&config.config_dir)?.with_settings(&config);
+    let chall_dirs = match ChallDirs::open(&config) {
+        // pushed proofs don't need a challenge directory
+        Err(err) if args.control_addr.is_some() => {
+            warn!("Only serving pushed challenge proofs: {:#}", err);
+            ChallDirs::default()
+        }
+        chall_dirs => chall_dirs?,
+    };
+    // sockets need to be created before dropping privileges
+    let mut listeners = Listeners::open(&args, &config)?;
+    let notifier = Notifier::open()?;
+
+    if let Some(addr) = &args.metrics_addr {
+        let socket = TcpListener::bind(addr).context("Failed to bind metrics socket")?;
+        listeners.metrics = Some(socket);
+    }
+    let control_secret = if let Some(addr) = &args.control_addr {
+        let path = config
+            .system
+            .chall_push_secret_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("--control-addr requires chall_push_secret_file"))?;
+        let secret = control::read_secret(path)?;
+        let socket = TcpListener::bind(addr).context("Failed to bind control socket")?;
+        info!("Accepting pushed challenge proofs on {}", addr);
+        listeners.control = Some(socket);
+        Some(ControlSecret::new(secret))
+    } else {
+        None
+    };
+    let live_certs = if args.metrics_addr.is_some() || config.notify.warn_if_days_left.is_some() {
+        LiveCerts::open(&config)
+            .map_err(|err| warn!("Certificate expiry is unavailable: {:#}", err))
+            .ok()
+    } else {
+        None
+    };
+    let pages = Pages::load(&config.daemon)?;
+    let redirect_map = match &args.redirect_map {
+        Some(path) => RedirectMap::load(path)?,
+        None => RedirectMap::default(),
+    };
+    // files written by an older version or before the group was changed
+    permissions::fix_permissions(&config);
+
+    sandbox::init(&args, &config.system.chall_dir).context("Failed to drop privileges")?;
+    let resources = Resources {
+        config_dir,
+        chall_dirs,
+        live_certs,
+        pages,
+        redirect_map_path: args.redirect_map.clone(),
+        redirect_map,
+        maintenance: args.maintenance_file.clone().map(Maintenance::new),
+        control_secret,
+    };
+    spawn(
+        listeners,
+        notifier,
+        args.proxy_protocol,
+        config,
+        resources,
+        rate_limits,
+        limits,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use actix_web::http::{header, Method};
+    use actix_web::{test, App};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::fs;
+
+    fn cert(dns_names: &[&str], redirect_to: Option<&str>) -> CertConfig {
+        CertConfig {
+            name: dns_names[0].to_string(),
+            dns_names: dns_names.iter().map(|s| s.to_string()).collect(),
+            redirect_to: redirect_to.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_redirect_default() {
+        let redirects = Redirects::new(&DaemonConfig::default(), &[cert(&["example.com"], None)]);
+        let url = redirects.redirect_url("example.com", "/foo?bar=1");
+        assert_eq!(url, "https://example.com/foo?bar=1");
+    }
+
+    #[test]
+    fn test_redirect_unknown_host() {
+        let redirects = Redirects::new(
+            &DaemonConfig::default(),
+            &[cert(
+                &["old-domain.com"],
+                Some("https://new-domain.com{path}"),
+            )],
+        );
+        let url = redirects.redirect_url("example.com:8080", "/foo");
+        assert_eq!(url, "https://example.com:8080/foo");
+    }
+
+    #[test]
+    fn test_redirect_to_keep_path() {
+        let redirects = Redirects::new(
+            &DaemonConfig::default(),
+            &[cert(
+                &["old-domain.com", "www.old-domain.com"],
+                Some("https://new-domain.com{path}"),
+            )],
+        );
+        let url = redirects.redirect_url("WWW.old-domain.com:80", "/foo?bar=1");
+        assert_eq!(url, "https://new-domain.com/foo?bar=1");
+    }
+
+    #[test]
+    fn test_redirect_to_drop_path() {
+        let redirects = Redirects::new(
+            &DaemonConfig::default(),
+            &[cert(&["old-domain.com"], Some("https://new-domain.com/"))],
+        );
+        let url = redirects.redirect_url("old-domain.com", "/foo");
+        assert_eq!(url, "https://new-domain.com/");
+    }
+
+    #[test]
+    fn test_redirect_idn() {
+        let redirects = Redirects::new(
+            &DaemonConfig::default(),
+            &[cert(
+                &["münchen.example.com"],
+                Some("https://example.com{path}"),
+            )],
+        );
+        let url = redirects.redirect_url("xn--mnchen-3ya.example.com", "/");
+        assert_eq!(url, "https://example.com/");
+        let url = redirects.redirect_url("MÜNCHEN.example.com:80", "/");
+        assert_eq!(url, "https://example.com/");
+    }
+
+    #[test]
+    fn test_redirect_path() {
+        for (uri, path) in &[
+            ("/", "/"),
+            ("/foo?a=b&c=d", "/foo?a=b&c=d"),
+            ("/foo%2Fbar?q=%20%0D%0A", "/foo%2Fbar?q=%20%0D%0A"),
+            ("//evil.example/foo", "/evil.example/foo"),
+            ("///evil.example//foo?//", "/evil.example//foo?//"),
+            ("/?a=b", "/?a=b"),
+            ("http://example.com/foo?a=b", "/foo?a=b"),
+            ("http://example.com", "/"),
+        ] {
+            let uri = uri.parse::<Uri>().unwrap();
+            assert_eq!(redirect_path(&uri), *path, "{:?}", uri);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_redirect_query_string() {
+        for (uri, location) in &[
+            ("/foo?a=b&c=d", "https://example.com/foo?a=b&c=d"),
+            ("/%66oo?q=a%26b#frag", "https://example.com/%66oo?q=a%26b"),
+            ("//evil.example/foo", "https://example.com/evil.example/foo"),
+        ] {
+            let (status, l) = request(Method::GET, uri).await;
+            assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(l.as_deref(), Some(*location), "{:?}", uri);
+        }
+    }
+
+    async fn maintenance_request(
+        daemon: &DaemonConfig,
+        maintenance: &Path,
+        uri: &str,
+    ) -> (StatusCode, Option<String>, Vec<u8>) {
+        let redirects = Redirects::new(daemon, &[]).with_pages(Pages::load(daemon).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(redirects)))
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(Maintenance::new(maintenance.to_path_buf())))
+                .configure(routes),
+        )
+        .await;
+        let req = test::TestRequest::with_uri(uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let status = resp.status();
+        let retry_after = resp
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|v| v.to_str().unwrap().to_string());
+        (status, retry_after, test::read_body(resp).await.to_vec())
+    }
+
+    #[actix_rt::test]
+    async fn test_maintenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("maintenance");
+        let daemon = DaemonConfig::default();
+
+        let (status, _, _) = maintenance_request(&daemon, &path, "/foo").await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+        fs::write(&path, "").unwrap();
+        let (status, retry_after, body) = maintenance_request(&daemon, &path, "/foo").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(retry_after.as_deref(), Some("300"));
+        assert_eq!(body, SERVICE_UNAVAILABLE.as_bytes());
+
+        // challenges are still answered
+        let (status, _, _) = maintenance_request(
+            &daemon,
+            &path,
+            "/.well-known/acme-challenge/missing_0123456789abcdef",
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        fs::write(dir.path().join("503.html"), "<p>back soon</p>").unwrap();
+        let daemon = DaemonConfig {
+            maintenance_body_file: Some(dir.path().join("503.html")),
+            maintenance_content_type: Some("text/html".to_string()),
+            maintenance_retry_after: Some(60),
+            ..Default::default()
+        };
+        let (status, retry_after, body) = maintenance_request(&daemon, &path, "/foo").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(retry_after.as_deref(), Some("60"));
+        assert_eq!(body, b"<p>back soon</p>");
+    }
+
+    #[test]
+    fn test_maintenance_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("maintenance");
+        let maintenance = Maintenance::new(path.clone());
+        assert!(!maintenance.is_active());
+        fs::write(&path, "").unwrap();
+        assert!(!maintenance.is_active());
+
+        *maintenance.checked.lock().unwrap() = None;
+        assert!(maintenance.is_active());
+        fs::remove_file(&path).unwrap();
+        assert!(maintenance.is_active());
+    }
+
+    #[test]
+    fn test_validate_host() {
+        for host in &[
+            "example.com",
+            "example.com:8080",
+            "[::1]:8080",
+            "[::1]",
+            "127.0.0.1",
+        ] {
+            assert_eq!(validate_host(host), Ok(*host));
+        }
+        for (host, err) in &[
+            ("", HostError::Missing),
+            ("example.com\r\nSet-Cookie: a=b", HostError::ControlChars),
+            ("example.com\t", HostError::ControlChars),
+            ("attacker.example@example.com", HostError::InvalidChar('@')),
+            ("example.com evil", HostError::InvalidChar(' ')),
+            ("attacker.example/example.com", HostError::InvalidChar('/')),
+            (
+                "attacker.example\\example.com",
+                HostError::InvalidChar('\\'),
+            ),
+            ("attacker.example#example.com", HostError::InvalidChar('#')),
+            ("attacker.example?example.com", HostError::InvalidChar('?')),
+            ("attacker%2eexample", HostError::InvalidChar('%')),
+            ("example.com:", HostError::InvalidPort),
+            ("example.com:80:80", HostError::InvalidPort),
+            ("example.com:http", HostError::InvalidPort),
+            ("[::1]x", HostError::InvalidPort),
+            (":8080", HostError::Invalid),
+            ("[::1", HostError::Invalid),
+        ] {
+            assert_eq!(validate_host(host), Err(*err), "{:?}", host);
+        }
+    }
+
+    async fn host_request(host: Option<&[u8]>) -> (StatusCode, Option<String>) {
+        let redirects = web::Data::new(RwLock::new(Redirects::default()));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let mut req = test::TestRequest::with_uri("/foo");
+        if let Some(host) = host {
+            req = req.header("Host", header::HeaderValue::from_bytes(host).unwrap());
+        }
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .map(|l| l.to_str().unwrap().to_string());
+        (resp.status(), location)
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_hosts_rejected() {
+        assert_eq!(
+            host_request(Some(b"example.com:8080")).await,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                Some("https://example.com:8080/foo".to_string())
+            )
+        );
+        for host in &[
+            None,
+            Some(&b""[..]),
+            Some(b"m\xc3\xbcnchen.example.com"),
+            Some(b"example.com\t"),
+            Some(b"attacker.example/"),
+            Some(b"attacker.example\\"),
+            Some(b"user@attacker.example"),
+            Some(b"example.com#"),
+            Some(b"example.com:80 "),
+        ] {
+            assert_eq!(
+                host_request(*host).await,
+                (StatusCode::BAD_REQUEST, None),
+                "{:?}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    async fn request(method: Method, uri: &str) -> (StatusCode, Option<String>) {
+        let redirects = web::Data::new(RwLock::new(Redirects::default()));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let req = test::TestRequest::with_uri(uri)
+            .method(method)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .map(|l| l.to_str().unwrap().to_string());
+        (resp.status(), location)
+    }
+
+    #[actix_rt::test]
+    async fn test_head_redirect() {
+        let get = request(Method::GET, "/foo").await;
+        let head = request(Method::HEAD, "/foo").await;
+        assert_eq!(get.0, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(get, head);
+    }
+
+    #[actix_rt::test]
+    async fn test_head_challenge() {
+        let get = request(
+            Method::GET,
+            "/.well-known/acme-challenge/missing_0123456789abcdef",
+        )
+        .await;
+        let head = request(
+            Method::HEAD,
+            "/.well-known/acme-challenge/missing_0123456789abcdef",
+        )
+        .await;
+        assert_eq!(get.0, StatusCode::NOT_FOUND);
+        assert_eq!(get, head);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_challenge_rejected() {
+        let (status, location) = request(Method::POST, "/.well-known/acme-challenge/abc").await;
+        assert!(status.is_client_error());
+        assert_eq!(location, None);
+    }
+
+    #[test]
+    fn test_redirect_code() {
+        let mut permanent = cert(&["example.com"], None);
+        permanent.redirect_code = Some(RedirectCode::try_from(308).unwrap());
+        let daemon = DaemonConfig {
+            redirect_code: RedirectCode::try_from(302).unwrap(),
+            ..Default::default()
+        };
+        let redirects = Redirects::new(&daemon, &[permanent, cert(&["example.org"], None)]);
+        assert_eq!(redirects.redirect_code("example.com:80").as_u16(), 308);
+        assert_eq!(redirects.redirect_code("example.org").as_u16(), 302);
+        assert_eq!(redirects.redirect_code("example.net").as_u16(), 302);
+    }
+
+    #[test]
+    fn test_strict_hosts() {
+        let certs = &[
+            cert(&["example.com", "münchen.example.com"], None),
+            cert(&["*.example.org"], None),
+        ];
+        let redirects = Redirects::new(&DaemonConfig::default(), certs);
+        assert!(redirects.is_allowed_host("attacker.example"));
+
+        let daemon = DaemonConfig {
+            strict_hosts: true,
+            ..Default::default()
+        };
+        let redirects = Redirects::new(&daemon, certs);
+        assert!(redirects.is_allowed_host("example.com"));
+        assert!(redirects.is_allowed_host("EXAMPLE.com:8080"));
+        assert!(redirects.is_allowed_host("xn--mnchen-3ya.example.com"));
+        assert!(redirects.is_allowed_host("www.example.org"));
+        assert!(!redirects.is_allowed_host("example.org"));
+        assert!(!redirects.is_allowed_host("a.b.example.org"));
+        assert!(!redirects.is_allowed_host("www.example.com"));
+        assert!(!redirects.is_allowed_host("attacker.example"));
+        assert!(!redirects.is_allowed_host("localhost"));
+    }
+
+    #[test]
+    fn test_redirect_map() {
+        let map = RedirectMap::parse(
+            r#"
+            [redirects]
+            "old-brand.com" = "https://new-brand.com"
+            "*.old-brand.com" = "https://new-brand.com/legacy/"
+            "kept.example.com" = "https://elsewhere.example"
+        "#,
+        )
+        .unwrap();
+        let certs = &[cert(
+            &["kept.example.com"],
+            Some("https://kept.example.net{path}"),
+        )];
+        let daemon = DaemonConfig {
+            strict_hosts: true,
+            ..Default::default()
+        };
+        let redirects = Redirects::new(&daemon, certs).with_map(map);
+
+        assert_eq!(
+            redirects.redirect_url("Www.Old-Brand.com:8080", "/foo?bar=1"),
+            "https://new-brand.com/legacy/foo?bar=1"
+        );
+        assert_eq!(
+            redirects.redirect_url("old-brand.com", "/"),
+            "https://new-brand.com/"
+        );
+        // the redirect_to of a certificate takes precedence
+        assert_eq!(
+            redirects.redirect_url("kept.example.com", "/a"),
+            "https://kept.example.net/a"
+        );
+        assert_eq!(
+            redirects.redirect_url("other.example.com", "/a"),
+            "https://other.example.com/a"
+        );
+
+        // hosts in the map are redirected with strict_hosts
+        assert!(redirects.is_allowed_host("shop.old-brand.com"));
+        assert!(redirects.is_allowed_host("old-brand.com"));
+        assert!(!redirects.is_allowed_host("other.example.com"));
+    }
+
+    #[actix_rt::test]
+    async fn test_strict_hosts_challenge() {
+        let daemon = DaemonConfig {
+            strict_hosts: true,
+            ..Default::default()
+        };
+        let redirects = web::Data::new(RwLock::new(Redirects::new(&daemon, &[])));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/foo")
+            .header("Host", "attacker.example")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get(header::LOCATION).is_none());
+
+        // challenges are answered for any host
+        let req = test::TestRequest::with_uri("/.well-known/acme-challenge/foo.bar")
+            .header("Host", "attacker.example")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_custom_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("moved.html"), "<p>moved</p>").unwrap();
+        fs::write(dir.path().join("404.html"), "<p>gone</p>").unwrap();
+        let mut headers = BTreeMap::new();
+        headers.insert("X-Frame-Options".to_string(), "DENY".to_string());
+        let daemon = DaemonConfig {
+            strict_hosts: true,
+            redirect_body_file: Some(dir.path().join("moved.html")),
+            redirect_content_type: Some("text/html; charset=utf-8".to_string()),
+            not_found_body_file: Some(dir.path().join("404.html")),
+            headers,
+            ..Default::default()
+        };
+        let redirects = Redirects::new(&daemon, &[cert(&["example.com"], None)])
+            .with_pages(Pages::load(&daemon).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(default_headers(&daemon))
+                .app_data(web::Data::new(RwLock::new(redirects)))
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/foo")
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(resp.headers().get("x-frame-options").unwrap(), "DENY");
+        assert_eq!(test::read_body(resp).await, "<p>moved</p>");
+
+        let req = test::TestRequest::with_uri("/foo")
+            .header("Host", "attacker.example")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("x-frame-options").unwrap(), "DENY");
+        assert_eq!(test::read_body(resp).await, "<p>gone</p>");
+
+        let req = test::TestRequest::with_uri(
+            "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0",
+        )
+        .header("Host", "example.com")
+        .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::read_body(resp).await, "<p>gone</p>");
+    }
+
+    #[test]
+    fn test_custom_pages_content_type_only() {
+        let daemon = DaemonConfig {
+            not_found_content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        let pages = Pages::load(&daemon).unwrap();
+        let resp = pages.not_found();
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+    }
+
+    #[test]
+    fn test_custom_pages_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("huge.html");
+        fs::write(&path, vec![b'a'; MAX_PAGE_SIZE as usize + 1]).unwrap();
+        let daemon = DaemonConfig {
+            not_found_body_file: Some(path),
+            ..Default::default()
+        };
+        assert!(Pages::load(&daemon).is_err());
+
+        let daemon = DaemonConfig {
+            redirect_body_file: Some(dir.path().join("missing.html")),
+            ..Default::default()
+        };
+        assert!(Pages::load(&daemon).is_err());
+    }
+
+    #[test]
+    fn test_exclude_paths() {
+        let mut firmware = cert(&["devices.example.com"], None);
+        firmware.exclude_paths = vec!["/firmware/".to_string()];
+        let daemon = DaemonConfig {
+            exclude_paths: vec!["=/robots.txt".to_string()],
+            ..Default::default()
+        };
+        let redirects = Redirects::new(&daemon, &[firmware]);
+
+        assert!(redirects.is_excluded("devices.example.com", "/firmware/manifest.txt"));
+        assert!(!redirects.is_excluded("example.com", "/firmware/manifest.txt"));
+        assert!(redirects.is_excluded("example.com", "/robots.txt"));
+        assert!(!redirects.is_excluded("example.com", "/robots.txt.bak"));
+        assert!(!redirects.is_excluded("devices.example.com", "/"));
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let filter = LogFilter::new(&DaemonConfig {
+            log_exclude_paths: vec!["/favicon.ico".to_string(), "=/".to_string()],
+            log_sample_rate: Some(3),
+            ..Default::default()
+        });
+        assert!(!filter.should_log("/favicon.ico"));
+        assert!(!filter.should_log("/"));
+        // excluded requests don't advance the sampling
+        let logged = (0..7)
+            .map(|_| filter.should_log("/index.html"))
+            .collect::<Vec<_>>();
+        assert_eq!(logged, &[true, false, false, true, false, false, true]);
+        // challenges are never sampled
+        let chall = "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+        assert!((0..5).all(|_| filter.should_log(chall)));
+        assert!(!filter.should_log("/index.html"));
+
+        let filter = LogFilter::new(&DaemonConfig::default());
+        assert!((0..5).all(|_| filter.should_log("/favicon.ico")));
+    }
+
+    #[actix_rt::test]
+    async fn test_challenge_not_excludable() {
+        let daemon = DaemonConfig {
+            exclude_paths: vec!["/".to_string()],
+            ..Default::default()
+        };
+        let redirects = web::Data::new(RwLock::new(Redirects::new(&daemon, &[])));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/.well-known/acme-challenge/foo.bar")
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        // handled by the acme route, which rejects the invalid token
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::with_uri("/foo")
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn health_request(
+        daemon: &DaemonConfig,
+        uri: &str,
+        host: Option<&str>,
+    ) -> (StatusCode, Vec<u8>) {
+        let certs = &[cert(&["example.com"], None), cert(&["example.org"], None)];
+        let redirects = web::Data::new(RwLock::new(Redirects::new(daemon, certs)));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let mut req = test::TestRequest::with_uri(uri);
+        if let Some(host) = host {
+            req = req.header("Host", host);
+        }
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let status = resp.status();
+        (status, test::read_body(resp).await.to_vec())
+    }
+
+    #[actix_rt::test]
+    async fn test_health_path() {
+        let daemon = DaemonConfig {
+            health_path: Some("/healthz".to_string()),
+            strict_hosts: true,
+            ..Default::default()
+        };
+        let ok = (StatusCode::OK, b"OK\n".to_vec());
+        assert_eq!(health_request(&daemon, "/healthz", None).await, ok);
+        assert_eq!(
+            health_request(&daemon, "/healthz?probe=1", Some("10.0.0.1")).await,
+            ok
+        );
+        let (status, _) = health_request(&daemon, "/healthz/foo", Some("example.com")).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        let (status, _) = health_request(&daemon, "/", Some("example.com")).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+        let daemon = DaemonConfig {
+            health_show_certs: true,
+            ..daemon
+        };
+        assert_eq!(
+            health_request(&daemon, "/healthz", None).await,
+            (StatusCode::OK, b"OK, 2 cert configs loaded\n".to_vec())
+        );
+
+        // without health_path there's no exception for requests without Host
+        let (status, _) = health_request(&DaemonConfig::default(), "/healthz", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_health_path_challenge() {
+        let daemon = DaemonConfig {
+            health_path: Some("/.well-known/acme-challenge/foo.bar".to_string()),
+            ..Default::default()
+        };
+        // handled by the acme route, which rejects the invalid token
+        let (status, _) = health_request(
+            &daemon,
+            "/.well-known/acme-challenge/foo.bar",
+            Some("example.com"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    const TOKEN: &str = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+
+    /// Request a path with a challenge directory that has a proof for TOKEN
+    /// and a secret file next to the challs directory.
+    async fn challenge_request(uri: &str) -> (StatusCode, Option<String>, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        fs::write(dir.path().join("challs").join(TOKEN), "proof").unwrap();
+        fs::write(dir.path().join("secret"), "secret").unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+
+        let redirects = web::Data::new(RwLock::new(Redirects::default()));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri(uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = test::read_body(resp).await.to_vec();
+        (status, content_type, body)
+    }
+
+    /// The status and Cache-Control of a request, the challenge directory has
+    /// a proof for TOKEN.
+    async fn cache_request(
+        daemon: &DaemonConfig,
+        certs: &[CertConfig],
+        host: &str,
+        uri: &str,
+    ) -> (StatusCode, Option<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        fs::write(dir.path().join("challs").join(TOKEN), "proof").unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let redirects = Redirects::new(daemon, certs);
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(redirects)))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let req = test::TestRequest::with_uri(uri)
+            .header("Host", host)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cache_control = resp
+            .headers()
+            .get(header::CACHE_CONTROL)
+            .map(|v| v.to_str().unwrap().to_string());
+        (resp.status(), cache_control)
+    }
+
+    #[actix_rt::test]
+    async fn test_challenge_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        fs::write(dir.path().join("challs").join(TOKEN), "proof").unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let certs = vec![cert(&["example.com"], None)];
+        let redirects = Redirects::new(&DaemonConfig::default(), &certs);
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(redirects)))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(challenge_routes),
+        )
+        .await;
+
+        let uri = format!("/.well-known/acme-challenge/{}", TOKEN);
+        let req = test::TestRequest::with_uri(&uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&test::read_body(resp).await[..], b"proof");
+
+        // the host has a certificate, but isn't redirected
+        for uri in &["/", "/foo?bar=1", "/.well-known/acme-challenge/"] {
+            let req = test::TestRequest::with_uri(uri)
+                .header("Host", "example.com")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+            assert!(resp.headers().get(header::LOCATION).is_none());
+            assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), NO_STORE);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_cache_headers() {
+        let daemon = DaemonConfig {
+            strict_hosts: true,
+            ..Default::default()
+        };
+        let mut temporary = cert(&["example.org"], None);
+        temporary.redirect_code = Some(RedirectCode::try_from(302).unwrap());
+        let certs = &[cert(&["example.com"], None), temporary];
+        let no_store = Some("no-store".to_string());
+
+        let proof = format!("/.well-known/acme-challenge/{}", TOKEN);
+        for (host, uri, status, cache_control) in &[
+            ("example.com", proof.as_str(), StatusCode::OK, &no_store),
+            (
+                "example.com",
+                "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX1",
+                StatusCode::NOT_FOUND,
+                &no_store,
+            ),
+            (
+                "example.com",
+                "/.well-known/acme-challenge/a.b",
+                StatusCode::BAD_REQUEST,
+                &no_store,
+            ),
+            ("attacker.example", "/", StatusCode::NOT_FOUND, &no_store),
+            ("example.com:x", "/", StatusCode::BAD_REQUEST, &no_store),
+            (
+                "example.com",
+                "/foo",
+                StatusCode::MOVED_PERMANENTLY,
+                &Some("max-age=300".to_string()),
+            ),
+            // temporary redirects are never cached
+            ("example.org", "/foo", StatusCode::FOUND, &no_store),
+        ] {
+            assert_eq!(
+                cache_request(&daemon, certs, host, uri).await,
+                (*status, (*cache_control).clone()),
+                "{} {}",
+                host,
+                uri
+            );
+        }
+
+        let daemon = DaemonConfig {
+            redirect_cache_seconds: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            cache_request(&daemon, certs, "example.com", "/").await,
+            (StatusCode::MOVED_PERMANENTLY, no_store)
+        );
+        let mut permanent = cert(&["example.com"], None);
+        permanent.redirect_code = Some(RedirectCode::try_from(308).unwrap());
+        let daemon = DaemonConfig {
+            redirect_cache_seconds: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            cache_request(&daemon, &[permanent], "example.com", "/").await,
+            (
+                StatusCode::PERMANENT_REDIRECT,
+                Some("max-age=60".to_string())
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_challenge_proof() {
+        let (status, content_type, body) =
+            challenge_request(&format!("/.well-known/acme-challenge/{}", TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("text/plain"));
+        assert_eq!(body, b"proof");
+    }
+
+    #[actix_rt::test]
+    async fn test_pushed_challenge_proof() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        fs::write(dir.path().join("challs").join(TOKEN), "proof").unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+
+        let proofs = web::Data::new(Proofs::new(Duration::from_secs(60)));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(Redirects::default())))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(proofs.clone())
+                .configure(routes),
+        )
+        .await;
+
+        let pushed = "pushed_0123456789abcdef";
+        proofs
+            .insert(
+                &chall::Token::parse(pushed).unwrap(),
+                b"pushed proof".to_vec(),
+            )
+            .unwrap();
+        // a pushed proof takes precedence over the challenge directory
+        proofs
+            .insert(
+                &chall::Token::parse(TOKEN).unwrap(),
+                b"newer proof".to_vec(),
+            )
+            .unwrap();
+        for (token, proof) in &[(pushed, "pushed proof"), (TOKEN, "newer proof")] {
+            let req =
+                test::TestRequest::with_uri(&format!("/.well-known/acme-challenge/{}", token))
+                    .header("Host", "example.com")
+                    .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(test::read_body(resp).await, proof.as_bytes());
+        }
+
+        // the challenge directory is still used for everything else
+        proofs.remove(&chall::Token::parse(TOKEN).unwrap());
+        let req = test::TestRequest::with_uri(&format!("/.well-known/acme-challenge/{}", TOKEN))
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, b"proof".as_ref());
+    }
+
+    #[actix_rt::test]
+    async fn test_public_listener_rejects_push() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let token = chall::Token::parse(TOKEN).unwrap();
+
+        for challenge_only in &[false, true] {
+            let proofs = web::Data::new(Proofs::new(Duration::from_secs(60)));
+            let app = App::new()
+                .app_data(web::Data::new(RwLock::new(Redirects::default())))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(ControlSecret::new("hunter2".to_string())))
+                .app_data(proofs.clone());
+            let mut app = if *challenge_only {
+                test::init_service(app.configure(challenge_routes)).await
+            } else {
+                test::init_service(app.configure(routes)).await
+            };
+
+            for path in &["/challenges", "/_acme-redirect/chall"] {
+                let uri = format!("{}/{}", path, TOKEN);
+                let req = test::TestRequest::put()
+                    .uri(&uri)
+                    .header("Host", "example.com")
+                    .header("Authorization", "Bearer hunter2")
+                    .set_payload("proof")
+                    .to_request();
+                let resp = test::call_service(&mut app, req).await;
+                assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+                assert_eq!(proofs.get(&token), None);
+
+                proofs.insert(&token, b"proof".to_vec()).unwrap();
+                let req = test::TestRequest::delete()
+                    .uri(&uri)
+                    .header("Host", "example.com")
+                    .header("Authorization", "Bearer hunter2")
+                    .to_request();
+                let resp = test::call_service(&mut app, req).await;
+                assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+                assert_eq!(proofs.get(&token), Some(b"proof".to_vec()));
+                proofs.remove(&token);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_proof_not_served() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let path = dir.path().join("challs").join(TOKEN);
+        fs::write(&path, "").unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(Redirects::default())))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let uri = format!("/.well-known/acme-challenge/{}", TOKEN);
+        let req = test::TestRequest::with_uri(&uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // the writer finished
+        fs::write(&path, "proof").unwrap();
+        let req = test::TestRequest::with_uri(&uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await.to_vec(), b"proof");
+    }
+
+    #[actix_rt::test]
+    async fn test_tls_alpn_proof_not_served() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("challs")).unwrap();
+        let token = tls_alpn::token("example.com");
+        fs::write(
+            dir.path().join("challs").join(token.as_str()),
+            "private key",
+        )
+        .unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: Default::default(),
+            acme: Default::default(),
+            system: SystemConfig {
+                chall_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RwLock::new(Redirects::default())))
+                .app_data(web::Data::new(ChallDirs::open(&config).unwrap()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let uri = format!("/.well-known/acme-challenge/{}", token.as_str());
+        let req = test::TestRequest::with_uri(&uri)
+            .header("Host", "example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_challenge_invalid_tokens() {
+        let long = "a".repeat(chall::MAX_TOKEN_LEN + 1);
+        for token in &[
+            "%2e%2e%2fsecret",
+            "%2E%2E%2Fsecret",
+            "..%2fsecret",
+            "..%2F..%2F..%2Fetc%2Fpasswd",
+            "%2fetc%2fpasswd",
+            "%2e%2e",
+            "..",
+            "secret%00",
+            "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0%00",
+            "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0.txt",
+            "short",
+            long.as_str(),
+        ] {
+            let uri = format!("/.well-known/acme-challenge/{}", token);
+            let (status, _, body) = challenge_request(&uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", uri);
+            assert_ne!(body, b"secret");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_challenge_traversal_paths() {
+        // these don't match the challenge route, but must never serve files
+        for uri in &[
+            "/.well-known/acme-challenge/../secret",
+            "/.well-known/acme-challenge/../../etc/passwd",
+            "/.well-known/acme-challenge//etc/passwd",
+            "/.well-known/acme-challenge/",
+        ] {
+            let (status, _, body) = challenge_request(uri).await;
+            assert!(!status.is_success(), "{:?}", uri);
+            assert_ne!(body, b"secret");
+            assert_ne!(body, b"proof");
+        }
+    }
+
+    async fn forwarded_request(
+        daemon: &DaemonConfig,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, Option<String>) {
+        let redirects = web::Data::new(RwLock::new(Redirects::new(daemon, &[])));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(redirects)
+                .app_data(web::Data::new(ChallDirs::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .configure(routes),
+        )
+        .await;
+        let mut req = test::TestRequest::with_uri("/foo").header("Host", "backend.local");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .map(|l| l.to_str().unwrap().to_string());
+        (resp.status(), location)
+    }
+
+    #[actix_rt::test]
+    async fn test_forwarded_headers_ignored() {
+        let headers = &[
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "example.com"),
+        ];
+        let resp = forwarded_request(&DaemonConfig::default(), headers).await;
+        assert_eq!(
+            resp,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                Some("https://backend.local/foo".to_string())
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_forwarded_headers_trusted() {
+        let mut daemon = DaemonConfig {
+            trust_forwarded_headers: true,
+            ..Default::default()
+        };
+
+        let resp = forwarded_request(&daemon, &[("X-Forwarded-Host", "example.com, proxy")]).await;
+        assert_eq!(
+            resp,
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                Some("https://example.com/foo".to_string())
+            )
+        );
+
+        // forwarded hosts are validated like the Host header
+        let resp = forwarded_request(&daemon, &[("X-Forwarded-Host", "evil.example/x")]).await;
+        assert_eq!(resp, (StatusCode::BAD_REQUEST, None));
+
+        let resp = forwarded_request(&daemon, &[("X-Forwarded-Proto", "http")]).await;
+        assert_eq!(resp.0, StatusCode::MOVED_PERMANENTLY);
+
+        let resp = forwarded_request(&daemon, &[("X-Forwarded-Proto", "HTTPS")]).await;
+        assert_eq!(resp, (StatusCode::NOT_FOUND, None));
+
+        daemon.forwarded_https_code = ErrorCode::try_from(421).unwrap();
+        let resp = forwarded_request(&daemon, &[("X-Forwarded-Proto", "https")]).await;
+        assert_eq!(resp, (StatusCode::MISDIRECTED_REQUEST, None));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, dns_name: &str| {
+            let conf = format!(
+                "[cert]\nname = {:?}\ndns_names = [{:?}]\nredirect_to = \"https://example.com/\"\n",
+                name, dns_name
+            );
+            fs::write(dir.path().join(name), conf).unwrap();
+        };
+        write("a.conf", "a.example.com");
+
+        let reloader = Reloader {
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+            config_dir: ConfigDir::open(dir.path()).unwrap(),
+            redirect_map: None,
+            redirects: web::Data::new(RwLock::new(Redirects::default())),
+            metrics: web::Data::new(Metrics::default()),
+            live_certs: None,
+        };
+        let url = |host| reloader.redirects.read().unwrap().redirect_url(host, "/");
+
+        reloader.reload().unwrap();
+        assert_eq!(url("a.example.com"), "https://example.com/");
+        assert_eq!(url("b.example.com"), "https://b.example.com/");
+
+        write("b.conf", "b.example.com");
+        reloader.reload().unwrap();
+        assert_eq!(url("b.example.com"), "https://example.com/");
+
+        // invalid configs are rejected and the old config is kept
+        write("c.conf", "*.example.com");
+        assert!(reloader.reload().is_err());
+        assert_eq!(url("b.example.com"), "https://example.com/");
+    }
+
+    #[test]
+    fn test_reload_redirect_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("acme-redirect.d");
+        fs::create_dir(&config_dir).unwrap();
+        let path = dir.path().join("redirects.toml");
+        fs::write(
+            &path,
+            "[redirects]\n\"old.example\" = \"https://new.example\"\n",
+        )
+        .unwrap();
+
+        let reloader = Reloader {
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+            config_dir: ConfigDir::open(&config_dir).unwrap(),
+            redirect_map: Some(path.clone()),
+            redirects: web::Data::new(RwLock::new(Redirects::default())),
+            metrics: web::Data::new(Metrics::default()),
+            live_certs: None,
+        };
+        let url = |host| reloader.redirects.read().unwrap().redirect_url(host, "/");
+
+        reloader.reload().unwrap();
+        assert_eq!(url("old.example"), "https://new.example/");
+
+        fs::write(&path, "[redirects]\n\"*\" = \"https://default.example\"\n").unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(url("old.example"), "https://default.example/");
+
+        // an invalid map is logged and the old one is kept
+        fs::write(&path, "[redirects]\n\"*\" = \"default.example\"\n").unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(url("old.example"), "https://default.example/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("acme-redirect.sock");
+        let system = SystemConfig::default();
+
+        let socket = bind_unix(&path, 0o600, &system).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        // the old socket is replaced on restart
+        drop(socket);
+        bind_unix(&path, 0o660, &system).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_keeps_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("acme-redirect.sock");
+        fs::write(&path, "important").unwrap();
+
+        assert!(bind_unix(&path, 0o660, &SystemConfig::default()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "important");
+    }
 }
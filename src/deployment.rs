@@ -0,0 +1,273 @@
+//! Check that the web server presents the live certificate, eg. to catch a
+//! web server that wasn't reloaded after a renew. The certificate is fetched
+//! with a tls handshake on every address of the dns names and compared by its
+//! fingerprint, the chain and the host name aren't verified.
+use crate::args::VerifyDeploymentArgs;
+use crate::cert::CertDetails;
+use crate::check;
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::FilePersist;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+pub const HTTPS_PORT: u16 = 443;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The web server might still be reloading when its hook returned.
+const RENEW_ATTEMPTS: usize = 3;
+const RENEW_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The leaf certificate that's presented for `name` at one address.
+pub fn served_cert(name: &str, addr: SocketAddr) -> Result<CertDetails> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT).context("Failed to connect")?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut connector = SslConnector::builder(SslMethod::tls())?;
+    connector.set_verify(SslVerifyMode::NONE);
+    let stream = connector
+        .build()
+        .configure()?
+        .verify_hostname(false)
+        .connect(name, stream)
+        .map_err(|err| anyhow!("Tls handshake failed: {}", err))?;
+    let cert = stream
+        .ssl()
+        .peer_certificate()
+        .ok_or_else(|| anyhow!("No certificate was presented"))?;
+    CertDetails::from_pem(&cert.to_pem()?)
+}
+
+/// Compare the certificate presented at one address with the live one.
+pub fn check_addr(name: &str, addr: SocketAddr, live: &CertDetails) -> Result<()> {
+    let served = served_cert(name, addr)?;
+    if served.sha256_fingerprint != live.sha256_fingerprint {
+        bail!(
+            "serving {} (expires {}), the live certificate is {} (expires {})",
+            served.sha256_fingerprint,
+            served.not_after,
+            live.sha256_fingerprint,
+            live.not_after
+        );
+    }
+    Ok(())
+}
+
+/// The result for every address of every name, the address is None if the
+/// name didn't resolve.
+type Results = Vec<(String, Option<SocketAddr>, Result<()>)>;
+
+fn probe(names: &[String], port: u16, live: &CertDetails) -> Results {
+    let mut results = Vec::new();
+    for name in names {
+        match check::resolve(name, port) {
+            Ok(addrs) => {
+                for addr in addrs {
+                    results.push((name.clone(), Some(addr), check_addr(name, addr, live)));
+                }
+            }
+            Err(err) => results.push((
+                name.clone(),
+                None,
+                Err(err.context("Failed to resolve name")),
+            )),
+        }
+    }
+    results
+}
+
+fn is_ok(results: &Results) -> bool {
+    results.iter().all(|(_, _, result)| result.is_ok())
+}
+
+/// Log the result for each address, returns false if any of them failed.
+fn log_results(cert: &CertConfig, results: &Results) -> bool {
+    for (name, addr, result) in results {
+        let at = addr
+            .map(|addr| format!(" at {}", addr.ip()))
+            .unwrap_or_default();
+        match result {
+            Ok(()) => info!(
+                "Verified {:?} -> {:?}{}: serving the live certificate",
+                cert.name, name, at
+            ),
+            Err(err) => error!(
+                "Deployment check failed ({:?} -> {:?}{}): {:#}",
+                cert.name, name, at, err
+            ),
+        }
+    }
+    is_ok(results)
+}
+
+/// The names, port and live certificate of a verification, None if the
+/// certificate has no names to verify.
+fn prepare(
+    config: &Config,
+    cert: &CertConfig,
+    port: Option<u16>,
+) -> Result<Option<(Vec<String>, u16, CertDetails)>> {
+    let names = cert.deployment_names()?;
+    if names.is_empty() {
+        info!("Skipping {:?}, there are no names to verify", cert.name);
+        return Ok(None);
+    }
+    let live = FilePersist::for_cert(config, cert)
+        .load_cert_details(&cert.name)
+        .context("Failed to load the live certificate")?
+        .ok_or_else(|| anyhow!("The certificate hasn't been issued yet"))?;
+    let port = port.or(cert.verify_port).unwrap_or(HTTPS_PORT);
+    Ok(Some((names, port, live)))
+}
+
+/// Verify the deployment of a certificate and log the result for each
+/// address, returns false if any of them doesn't serve the live certificate.
+pub fn verify_cert(config: &Config, cert: &CertConfig, port: Option<u16>) -> Result<bool> {
+    match prepare(config, cert, port)? {
+        Some((names, port, live)) => Ok(log_results(cert, &probe(&names, port, &live))),
+        None => Ok(true),
+    }
+}
+
+/// Verify a renewed certificate after its hooks have been executed, this is
+/// retried a few times since the web server might still be reloading.
+pub fn verify_renewed(config: &Config, cert: &CertConfig) -> Result<bool> {
+    let (names, port, live) = match prepare(config, cert, None)? {
+        Some(prepared) => prepared,
+        None => return Ok(true),
+    };
+    let mut results = probe(&names, port, &live);
+    for _ in 1..RENEW_ATTEMPTS {
+        if is_ok(&results) {
+            break;
+        }
+        debug!(
+            "{:?}: the renewed certificate isn't served everywhere yet, checking again",
+            cert.name
+        );
+        thread::sleep(RENEW_RETRY_DELAY);
+        results = probe(&names, port, &live);
+    }
+    Ok(log_results(cert, &results))
+}
+
+pub fn run(config: Config, args: VerifyDeploymentArgs) -> Result<()> {
+    let mut failed = 0;
+    for cert in config.filter_managed_certs(&args.certs) {
+        match verify_cert(&config, cert, args.port) {
+            Ok(true) => (),
+            Ok(false) => failed += 1,
+            Err(err) => {
+                error!("Deployment check failed ({:?}): {:#}", cert.name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "The live certificate isn't served for {} certificate(s)",
+            failed
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use openssl::pkey::PKey;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::X509;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    /// A key and a self signed certificate as pem.
+    fn cert_pem(name: &str) -> String {
+        crate::tls_alpn::challenge_cert(name, "key-authorization").unwrap()
+    }
+
+    /// Serve a certificate until the test ends.
+    fn serve(pem: &str) -> SocketAddr {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key(&PKey::private_key_from_pem(pem.as_bytes()).unwrap())
+            .unwrap();
+        acceptor
+            .set_certificate(&X509::from_pem(pem.as_bytes()).unwrap())
+            .unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(mut stream) = acceptor.accept(stream) {
+                    stream.shutdown().ok();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_check_addr() {
+        let pem = cert_pem("example.com");
+        let addr = serve(&pem);
+        let live = CertDetails::from_pem(pem.as_bytes()).unwrap();
+        assert_eq!(
+            served_cert("example.com", addr).unwrap().sha256_fingerprint,
+            live.sha256_fingerprint
+        );
+        check_addr("example.com", addr, &live).unwrap();
+
+        // the web server wasn't reloaded
+        let renewed = CertDetails::from_pem(cert_pem("example.com").as_bytes()).unwrap();
+        let err = check_addr("example.com", addr, &renewed).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains(&format!("serving {}", live.sha256_fingerprint)));
+        assert!(err.contains(&format!(
+            "the live certificate is {}",
+            renewed.sha256_fingerprint
+        )));
+    }
+
+    #[test]
+    fn test_nothing_listening() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let live = CertDetails::from_pem(cert_pem("example.com").as_bytes()).unwrap();
+        assert!(check_addr("example.com", addr, &live).is_err());
+    }
+
+    #[test]
+    fn test_verify_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let mut cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string(), "*.example.com".to_string()],
+            ..Default::default()
+        };
+        let err = verify_cert(&config, &cert, None).unwrap_err();
+        assert_eq!(err.to_string(), "The certificate hasn't been issued yet");
+
+        // a cdn in front of the web server
+        cert.verify_names = Some(vec![]);
+        assert!(verify_cert(&config, &cert, None).unwrap());
+    }
+}
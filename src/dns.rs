@@ -0,0 +1,262 @@
+use crate::config::{CertConfig, DEFAULT_DNS_PROPAGATION_TIMEOUT};
+use crate::errors::*;
+use crate::hooks::{self, Exec, HookOptions};
+use std::ffi::OsString;
+use std::thread;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::system_conf;
+use trust_dns_resolver::Resolver;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[inline]
+pub fn record_name(domain: &str) -> String {
+    let domain = domain.strip_prefix("*.").unwrap_or(domain);
+    format!("_acme-challenge.{}", domain)
+}
+
+/// Run the hooks like exec hooks, with the same user, group and timeout.
+fn run_hooks(
+    execs: &[Exec],
+    options: &HookOptions,
+    domain: &str,
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    let env = [
+        ("ACME_DNS_NAME", OsString::from(domain)),
+        ("ACME_TXT_NAME", OsString::from(name)),
+        ("ACME_TXT_VALUE", OsString::from(value)),
+    ];
+    for exec in execs {
+        info!("executing dns hook: `{}`", exec);
+        hooks::run_hook(exec, &env, options)
+            .with_context(|| anyhow!("Failed to execute dns hook `{}`", exec))?;
+    }
+    Ok(())
+}
+
+fn has_txt_record(resolver: &Resolver, name: &str, value: &str) -> bool {
+    match resolver.txt_lookup(name) {
+        Ok(records) => records.iter().any(|txt| {
+            let data = txt
+                .iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect::<String>();
+            data == value
+        }),
+        Err(err) => {
+            debug!("Failed to resolve {:?}: {}", name, err);
+            false
+        }
+    }
+}
+
+struct Record {
+    domain: String,
+    name: String,
+    value: String,
+}
+
+pub struct DnsChallenge<'a> {
+    exec: &'a [Exec],
+    cleanup_exec: &'a [Exec],
+    options: HookOptions,
+    timeout: Duration,
+    published: Vec<Record>,
+}
+
+impl<'a> DnsChallenge<'a> {
+    pub fn new(cert: &'a CertConfig, options: HookOptions) -> DnsChallenge<'a> {
+        let timeout = cert
+            .dns_propagation_timeout
+            .unwrap_or(DEFAULT_DNS_PROPAGATION_TIMEOUT);
+        DnsChallenge {
+            exec: &cert.dns_exec,
+            cleanup_exec: &cert.dns_cleanup_exec,
+            options,
+            timeout: Duration::from_secs(timeout),
+            published: Vec::new(),
+        }
+    }
+
+    pub fn publish(&mut self, domain: &str, value: &str) -> Result<()> {
+        let name = record_name(domain);
+        debug!("Publishing TXT record {:?} => {:?}", name, value);
+        run_hooks(self.exec, &self.options, domain, &name, value)?;
+
+        self.published.push(Record {
+            domain: domain.to_string(),
+            name,
+            value: value.to_string(),
+        });
+
+        Ok(())
+    }
+
+    pub fn wait_for_propagation(&self) -> Result<()> {
+        let (config, mut opts) =
+            system_conf::read_system_conf().context("Failed to read resolver config")?;
+        // we need to see changes as soon as they happen
+        opts.cache_size = 0;
+        let resolver = Resolver::new(config, opts).context("Failed to setup resolver")?;
+
+        let start = Instant::now();
+        for record in &self.published {
+            info!("waiting for TXT record {:?} to propagate", record.name);
+            while !has_txt_record(&resolver, &record.name, &record.value) {
+                if start.elapsed() >= self.timeout {
+                    bail!(
+                        "TXT record {:?} didn't propagate within {}s",
+                        record.name,
+                        self.timeout.as_secs()
+                    );
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            debug!("TXT record {:?} is visible", record.name);
+        }
+
+        Ok(())
+    }
+
+    /// Run the cleanup hooks for every published record, even if some of
+    /// them fail.
+    pub fn cleanup(&mut self) -> Result<()> {
+        let mut errors = Vec::new();
+        for record in self.published.drain(..) {
+            debug!("Removing TXT record {:?}", record.name);
+            if let Err(err) = run_hooks(
+                self.cleanup_exec,
+                &self.options,
+                &record.domain,
+                &record.name,
+                &record.value,
+            ) {
+                errors.push(format!("{:?}: {:#}", record.name, err));
+            }
+        }
+        if !errors.is_empty() {
+            bail!(
+                "Failed to remove {} TXT record(s): {}",
+                errors.len(),
+                errors.join("; ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::fs;
+    #[cfg(unix)]
+    use std::path::Path;
+
+    #[test]
+    fn test_record_name() {
+        assert_eq!(record_name("example.com"), "_acme-challenge.example.com");
+        assert_eq!(record_name("*.example.com"), "_acme-challenge.example.com");
+    }
+
+    /// A cert with hooks that append their environment to a file.
+    #[cfg(unix)]
+    fn hook_cert(log: &Path, cleanup: &str) -> CertConfig {
+        let hook = |action: &str| {
+            format!(
+                "echo \"{} $ACME_DNS_NAME $ACME_TXT_NAME $ACME_TXT_VALUE\" >> {:?}",
+                action, log
+            )
+        };
+        CertConfig {
+            name: "example.com".to_string(),
+            dns_exec: vec![Exec::Shell(hook("publish"))],
+            dns_cleanup_exec: vec![Exec::Shell(hook("cleanup")), Exec::from(cleanup)],
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_publish_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let cert = hook_cert(&log, "true");
+        let mut dns = DnsChallenge::new(&cert, HookOptions::default());
+        dns.publish("example.com", "value1").unwrap();
+        dns.publish("*.example.com", "value2").unwrap();
+        dns.cleanup().unwrap();
+        // the records are only removed once
+        dns.cleanup().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "publish example.com _acme-challenge.example.com value1\n\
+             publish *.example.com _acme-challenge.example.com value2\n\
+             cleanup example.com _acme-challenge.example.com value1\n\
+             cleanup *.example.com _acme-challenge.example.com value2\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cleanup_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let cert = hook_cert(&log, "false");
+        let mut dns = DnsChallenge::new(&cert, HookOptions::default());
+        dns.publish("a.example.com", "value1").unwrap();
+        dns.publish("b.example.com", "value2").unwrap();
+        let err = dns.cleanup().unwrap_err().to_string();
+        assert!(
+            err.starts_with("Failed to remove 2 TXT record(s): "),
+            "{}",
+            err
+        );
+        assert!(err.contains("\"_acme-challenge.a.example.com\""), "{}", err);
+        assert!(err.contains("\"_acme-challenge.b.example.com\""), "{}", err);
+
+        // the failing hook didn't stop the cleanup of the other record
+        let log = fs::read_to_string(&log).unwrap();
+        assert!(log.contains("cleanup a.example.com"));
+        assert!(log.contains("cleanup b.example.com"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_publish_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let mut cert = hook_cert(&log, "true");
+        cert.dns_exec.push(Exec::from("false"));
+        let mut dns = DnsChallenge::new(&cert, HookOptions::default());
+        assert!(dns.publish("example.com", "value1").is_err());
+        // nothing was published, so there's nothing to remove
+        dns.cleanup().unwrap();
+        assert!(!fs::read_to_string(&log).unwrap().contains("cleanup"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_timeout() {
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_exec: vec![Exec::from("sleep 30")],
+            ..Default::default()
+        };
+        let options = HookOptions {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut dns = DnsChallenge::new(&cert, options);
+        let err = format!("{:#}", dns.publish("example.com", "value1").unwrap_err());
+        assert_eq!(
+            err,
+            "Failed to execute dns hook `sleep 30`: Hook timed out after 1s, killed it"
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
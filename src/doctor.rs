@@ -0,0 +1,695 @@
+//! Look for permission problems in the data and challenge directories, eg.
+//! between renew running as root, the daemon after dropping privileges and
+//! a web server group that reads the private keys.
+use crate::args::DoctorArgs;
+use crate::check_config::Level;
+use crate::config::Config;
+use crate::errors::*;
+use crate::hooks;
+use crate::persist::CERT_FILES;
+#[cfg(unix)]
+use crate::persist::PRIVATE_FILES;
+#[cfg(unix)]
+use nix::unistd::{self, AccessFlags, Gid, Uid};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// How a finding can be resolved, only `Manual` fixes aren't applied by
+/// `--fix`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fix {
+    Chmod(PathBuf, u32),
+    #[cfg(unix)]
+    Chgrp(PathBuf, String, Gid),
+    Mkdir(PathBuf),
+    Manual(String),
+}
+
+impl Fix {
+    fn is_safe(&self) -> bool {
+        !matches!(self, Fix::Manual(_))
+    }
+
+    fn apply(&self) -> Result<()> {
+        match self {
+            #[cfg(unix)]
+            Fix::Chmod(path, mode) => fs::set_permissions(path, fs::Permissions::from_mode(*mode))?,
+            #[cfg(not(unix))]
+            Fix::Chmod(_, _) => bail!("Permissions can't be changed on this platform"),
+            #[cfg(unix)]
+            Fix::Chgrp(path, _, gid) => unistd::chown(path, None, Some(*gid))?,
+            Fix::Mkdir(path) => fs::create_dir_all(path)?,
+            Fix::Manual(cmd) => bail!("Needs to be fixed by hand: {}", cmd),
+        }
+        Ok(())
+    }
+}
+
+fn quote_path(path: &Path) -> String {
+    hooks::quote(&path.to_string_lossy())
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fix::Chmod(path, mode) => write!(f, "chmod {:04o} {}", mode, quote_path(path)),
+            #[cfg(unix)]
+            Fix::Chgrp(path, group, _) => write!(f, "chgrp {} {}", group, quote_path(path)),
+            Fix::Mkdir(path) => write!(f, "mkdir -p {}", quote_path(path)),
+            Fix::Manual(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub level: Level,
+    pub path: PathBuf,
+    pub message: String,
+    pub fix: Option<Fix>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:?}: {}", self.level, self.path, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n    fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// A user the directories are checked against, with all its groups.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+struct Account {
+    name: String,
+    group: String,
+    uid: Uid,
+    gids: Vec<Gid>,
+}
+
+#[cfg(unix)]
+impl Account {
+    fn lookup(name: &str) -> Result<Account> {
+        let user = users::get_user_by_name(name)
+            .ok_or_else(|| anyhow!("Failed to look up user: {:?}", name))?;
+        let gids = users::get_user_groups(name, user.primary_group_id())
+            .unwrap_or_default()
+            .iter()
+            .map(|group| Gid::from_raw(group.gid()))
+            .chain(std::iter::once(Gid::from_raw(user.primary_group_id())))
+            .collect();
+        let gid = user.primary_group_id();
+        let group = users::get_group_by_gid(gid)
+            .map(|group| group.name().to_string_lossy().into_owned())
+            .unwrap_or_else(|| gid.to_string());
+        Ok(Account {
+            name: name.to_string(),
+            group,
+            uid: Uid::from_raw(user.uid()),
+            gids,
+        })
+    }
+
+    /// If the mode bits of the file grant all of `bits` (r=4, w=2, x=1).
+    fn mode_allows(&self, md: &fs::Metadata, bits: u32) -> bool {
+        if self.uid.is_root() {
+            return true;
+        }
+        let mode = md.mode();
+        let granted = if md.uid() == self.uid.as_raw() {
+            mode >> 6
+        } else if self.gids.contains(&Gid::from_raw(md.gid())) {
+            mode >> 3
+        } else {
+            mode
+        };
+        granted & bits == bits
+    }
+}
+
+/// Where the group of a private key is expected to be.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+struct KeyGroup {
+    name: String,
+    gid: Gid,
+}
+
+#[derive(Default)]
+struct Doctor {
+    findings: Vec<Finding>,
+    #[cfg(unix)]
+    daemon_user: Option<Account>,
+    #[cfg(unix)]
+    key_group: Option<KeyGroup>,
+}
+
+impl Doctor {
+    fn push(&mut self, level: Level, path: &Path, message: String, fix: Option<Fix>) {
+        self.findings.push(Finding {
+            level,
+            path: path.to_path_buf(),
+            message,
+            fix,
+        });
+    }
+
+    fn check_data_dir(&mut self, config: &Config) -> Result<()> {
+        let data_dir = &config.system.data_dir;
+        match fs::metadata(data_dir) {
+            Ok(_) => (),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                self.push(
+                    Level::Warning,
+                    data_dir,
+                    "data_dir doesn't exist yet, it's created by the first renew".to_string(),
+                    None,
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                self.push(
+                    Level::Error,
+                    data_dir,
+                    format!("data_dir is not accessible: {}", err),
+                    None,
+                );
+                return Ok(());
+            }
+        }
+        #[cfg(unix)]
+        self.check_writable(data_dir, "data_dir");
+
+        for root in &[data_dir.clone(), data_dir.join("staging")] {
+            if !root.is_dir() {
+                continue;
+            }
+            self.check_account_dir(root)?;
+            let accounts = root.join("accounts");
+            if accounts.is_dir() {
+                for entry in fs::read_dir(&accounts)? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        self.check_account_dir(&entry.path())?;
+                    }
+                }
+            }
+            let live = self.check_live(&root.join("live"))?;
+            self.check_versions(&root.join("certs"), &live)?;
+        }
+        Ok(())
+    }
+
+    /// Account keys should only be readable by the owner, this includes
+    /// the backups of replaced keys.
+    fn check_account_dir(&mut self, path: &Path) -> Result<()> {
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name != "acc.key" && !name.starts_with("acc.key.") {
+                continue;
+            }
+            #[cfg(unix)]
+            {
+                let md = entry.metadata()?;
+                let mode = md.mode() & 0o7777;
+                if md.is_file() && mode & 0o077 != 0 {
+                    self.push(
+                        Level::Error,
+                        &entry.path(),
+                        format!(
+                            "account key is accessible by other users (mode {:04o})",
+                            mode
+                        ),
+                        Some(Fix::Chmod(entry.path(), mode & !0o077)),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_versions(&mut self, certs: &Path, live: &HashMap<PathBuf, String>) -> Result<()> {
+        if !certs.is_dir() {
+            return Ok(());
+        }
+        let mut entries = fs::read_dir(certs)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            let missing = CERT_FILES
+                .iter()
+                .filter(|file| !path.join(file).exists())
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                let missing = missing.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+                self.push(
+                    Level::Error,
+                    &path,
+                    format!("version is missing files: {}", missing.join(", ")),
+                    Some(Fix::Manual(match live.get(&path) {
+                        Some(name) => {
+                            format!("acme-redirect renew --force-renew {}", hooks::quote(name))
+                        }
+                        None => format!("rm -r {}", quote_path(&path)),
+                    })),
+                );
+            }
+
+            #[cfg(unix)]
+            for file in PRIVATE_FILES {
+                let path = path.join(file);
+                if let Ok(md) = fs::symlink_metadata(&path) {
+                    if md.is_file() {
+                        self.check_private_file(&path, &md);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn check_private_file(&mut self, path: &Path, md: &fs::Metadata) {
+        let mode = md.mode() & 0o7777;
+        if mode & 0o007 != 0 {
+            self.push(
+                Level::Error,
+                path,
+                format!("private key is world-accessible (mode {:04o})", mode),
+                Some(Fix::Chmod(path.to_path_buf(), mode & !0o007)),
+            );
+        }
+        if let Some(group) = self.key_group.clone() {
+            if md.gid() != group.gid.as_raw() {
+                self.push(
+                    Level::Error,
+                    path,
+                    format!("private key doesn't belong to group {:?}", group.name),
+                    Some(Fix::Chgrp(
+                        path.to_path_buf(),
+                        group.name.clone(),
+                        group.gid,
+                    )),
+                );
+            }
+            if mode & 0o040 == 0 {
+                self.push(
+                    Level::Error,
+                    path,
+                    format!("private key isn't readable by group {:?}", group.name),
+                    Some(Fix::Chmod(path.to_path_buf(), (mode & !0o007) | 0o040)),
+                );
+            }
+        }
+    }
+
+    /// Returns the versions that are live, with the name of their certificate.
+    fn check_live(&mut self, live: &Path) -> Result<HashMap<PathBuf, String>> {
+        let mut versions = HashMap::new();
+        if !live.is_dir() {
+            return Ok(versions);
+        }
+        let mut entries = fs::read_dir(live)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // leftover of an interrupted symlink swap
+            if name.starts_with('.') || !entry.file_type()?.is_symlink() {
+                continue;
+            }
+            let path = entry.path();
+            let target = fs::read_link(&path)?;
+            if let Err(err) = fs::metadata(&path) {
+                self.push(
+                    Level::Error,
+                    &path,
+                    format!("live symlink points to {:?}: {}", target, err),
+                    Some(Fix::Manual(format!(
+                        "acme-redirect rollback {0}, or acme-redirect renew --force-renew {0}",
+                        hooks::quote(&name)
+                    ))),
+                );
+            } else {
+                versions.insert(live.join(target), name.to_string());
+            }
+        }
+        Ok(versions)
+    }
+
+    fn check_chall_dir(&mut self, config: &Config) -> Result<()> {
+        if config.system.chall_push_url.is_some() {
+            debug!("Challenge proofs are pushed to the daemon, not checking chall_dir");
+            return Ok(());
+        }
+        let chall_dir = &config.system.chall_dir;
+        let challs = chall_dir.join("challs");
+        if !challs.is_dir() {
+            self.push(
+                Level::Error,
+                &challs,
+                "challenge directory doesn't exist".to_string(),
+                Some(Fix::Mkdir(challs.clone())),
+            );
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            self.check_writable(&challs, "challenge directory");
+            if let Some(user) = self.daemon_user.clone() {
+                for (path, bits) in &[(chall_dir.as_path(), 0o1), (challs.as_path(), 0o5)] {
+                    let md = fs::metadata(path)?;
+                    if !user.mode_allows(&md, *bits) {
+                        self.push(
+                            Level::Error,
+                            path,
+                            format!(
+                                "not accessible by the daemon user {:?} (mode {:04o}, owner {}:{})",
+                                user.name,
+                                md.mode() & 0o7777,
+                                md.uid(),
+                                md.gid()
+                            ),
+                            Some(Fix::Manual(format!(
+                                "chgrp {} {} && chmod g+rx {}",
+                                hooks::quote(&user.group),
+                                quote_path(path),
+                                quote_path(path)
+                            ))),
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The renew user is the one running doctor. If the mode bits allow
+    /// writing but the kernel doesn't, something else is restricting it.
+    #[cfg(unix)]
+    fn check_writable(&mut self, path: &Path, what: &str) {
+        let access = unistd::access(path, AccessFlags::W_OK | AccessFlags::X_OK);
+        let err = match access {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        let md = match fs::metadata(path) {
+            Ok(md) => md,
+            Err(_) => return,
+        };
+        let uid = unistd::geteuid();
+        let gids = unistd::getgroups()
+            .unwrap_or_default()
+            .into_iter()
+            .chain(std::iter::once(unistd::getegid()))
+            .collect();
+        let user = Account {
+            name: uid.to_string(),
+            group: String::new(),
+            uid,
+            gids,
+        };
+        let (message, fix) = if user.mode_allows(&md, 0o3) {
+            (
+                format!(
+                    "{} is not writable even though its mode allows it ({}), check the ACLs with getfacl or the SELinux label with ls -Z",
+                    what, err
+                ),
+                None,
+            )
+        } else {
+            (
+                format!(
+                    "{} is not writable by uid {} (mode {:04o}, owner {}:{})",
+                    what,
+                    uid,
+                    md.mode() & 0o7777,
+                    md.uid(),
+                    md.gid()
+                ),
+                Some(Fix::Manual(format!("chown {} {}", uid, quote_path(path)))),
+            )
+        };
+        self.push(Level::Error, path, message, fix);
+    }
+}
+
+pub fn check(config: &Config, args: &DoctorArgs) -> Result<Vec<Finding>> {
+    #[allow(unused_mut)]
+    let mut doctor = Doctor::default();
+    #[cfg(unix)]
+    {
+        if let Some(name) = &args.user {
+            doctor.daemon_user = Some(Account::lookup(name)?);
+        }
+        if let Some(name) = args.group.as_ref().or(config.system.group.as_ref()) {
+            let group = users::get_group_by_name(name)
+                .ok_or_else(|| anyhow!("Failed to look up group: {:?}", name))?;
+            doctor.key_group = Some(KeyGroup {
+                name: name.to_string(),
+                gid: Gid::from_raw(group.gid()),
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    if args.user.is_some() || args.group.is_some() {
+        warn!("Ownership can't be checked on this platform, ignoring --user and --group");
+    }
+    doctor.check_data_dir(config)?;
+    doctor.check_chall_dir(config)?;
+    Ok(doctor.findings)
+}
+
+pub fn run(config: Config, args: DoctorArgs) -> Result<()> {
+    let findings = check(&config, &args)?;
+    let mut remaining = 0;
+    for finding in &findings {
+        match &finding.fix {
+            Some(fix) if args.fix && fix.is_safe() => match fix.apply() {
+                Ok(()) => {
+                    info!("fixed {:?}: {}", finding.path, fix);
+                    continue;
+                }
+                Err(err) => warn!("Failed to run {}: {:#}", fix, err),
+            },
+            _ => (),
+        }
+        println!("{}", finding);
+        if finding.level == Level::Error {
+            remaining += 1;
+        }
+    }
+    if remaining > 0 {
+        bail!("Found {} problem(s)", remaining);
+    }
+    info!("No problems found");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+    #[cfg(windows)]
+    use std::os::windows::fs::symlink_dir as symlink;
+
+    fn config(dir: &Path) -> Config {
+        Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.join("data"),
+                chall_dir: dir.join("chall"),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    fn args() -> DoctorArgs {
+        DoctorArgs {
+            fix: false,
+            user: None,
+            group: None,
+        }
+    }
+
+    fn write_version(path: &Path, files: &[&str], mode: u32) {
+        fs::create_dir_all(path).unwrap();
+        for file in files {
+            fs::write(path.join(file), "").unwrap();
+            #[cfg(unix)]
+            fs::set_permissions(path.join(file), fs::Permissions::from_mode(mode)).unwrap();
+            #[cfg(not(unix))]
+            let _ = mode;
+        }
+    }
+
+    #[test]
+    fn test_healthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        fs::create_dir_all(config.system.chall_dir.join("challs")).unwrap();
+        let version = config.system.data_dir.join("certs/20200101-example.com");
+        write_version(&version, CERT_FILES, 0o440);
+        fs::create_dir(config.system.data_dir.join("live")).unwrap();
+        symlink(&version, config.system.data_dir.join("live/example.com")).unwrap();
+        assert_eq!(check(&config, &args()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_broken_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let data_dir = &config.system.data_dir;
+        write_version(
+            &data_dir.join("certs/20200101-example.com"),
+            &["privkey", "fullchain"],
+            0o440,
+        );
+        fs::create_dir(data_dir.join("live")).unwrap();
+        symlink(
+            data_dir.join("certs/20200102-example.com"),
+            data_dir.join("live/example.com"),
+        )
+        .unwrap();
+
+        let findings = check(&config, &args()).unwrap();
+        let messages = findings
+            .iter()
+            .map(|f| (f.path.clone(), f.message.clone(), f.fix.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert_eq!(messages[0].0, data_dir.join("live/example.com"));
+        assert_eq!(
+            messages[0].2,
+            Some(Fix::Manual("acme-redirect rollback example.com, or acme-redirect renew --force-renew example.com".to_string()))
+        );
+        let version = data_dir.join("certs/20200101-example.com");
+        assert_eq!(messages[1].0, version);
+        assert_eq!(
+            messages[1].1,
+            "version is missing files: chain, cert, bundle"
+        );
+        assert_eq!(
+            messages[1].2,
+            Some(Fix::Manual(format!("rm -r {}", version.display())))
+        );
+        assert_eq!(messages[2].0, config.system.chall_dir.join("challs"));
+        assert_eq!(
+            messages[2].2,
+            Some(Fix::Mkdir(config.system.chall_dir.join("challs")))
+        );
+    }
+
+    #[test]
+    fn test_broken_live_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        fs::create_dir_all(config.system.chall_dir.join("challs")).unwrap();
+        let version = config.system.data_dir.join("certs/20200101-example.com");
+        write_version(&version, &["fullchain"], 0o444);
+        fs::create_dir(config.system.data_dir.join("live")).unwrap();
+        symlink(&version, config.system.data_dir.join("live/example.com")).unwrap();
+
+        let findings = check(&config, &args()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!(
+            findings[0].fix,
+            Some(Fix::Manual(
+                "acme-redirect renew --force-renew example.com".to_string()
+            ))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fix_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let data_dir = &config.system.data_dir;
+        let version = data_dir.join("certs/20200101-example.com");
+        write_version(&version, CERT_FILES, 0o444);
+        fs::write(data_dir.join("acc.key"), "").unwrap();
+        fs::set_permissions(data_dir.join("acc.key"), fs::Permissions::from_mode(0o640)).unwrap();
+
+        let findings = check(&config, &args()).unwrap();
+        let fixes = findings
+            .iter()
+            .filter_map(|f| f.fix.as_ref().map(|fix| fix.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fixes,
+            vec![
+                format!("chmod 0600 {}", data_dir.join("acc.key").display()),
+                format!("chmod 0440 {}", version.join("privkey").display()),
+                format!("chmod 0440 {}", version.join("bundle").display()),
+                format!(
+                    "mkdir -p {}",
+                    config.system.chall_dir.join("challs").display()
+                ),
+            ]
+        );
+
+        run(
+            config.clone(),
+            DoctorArgs {
+                fix: true,
+                ..args()
+            },
+        )
+        .unwrap();
+        for (path, mode) in &[
+            (data_dir.join("acc.key"), 0o600),
+            (version.join("privkey"), 0o440),
+            (version.join("fullchain"), 0o444),
+        ] {
+            let md = fs::metadata(path).unwrap();
+            assert_eq!(md.mode() & 0o777, *mode, "{:?}", path);
+        }
+        assert!(config.system.chall_dir.join("challs").is_dir());
+        assert_eq!(check(&config, &args()).unwrap(), vec![]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode_allows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("challs");
+        fs::create_dir(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        let md = fs::metadata(&path).unwrap();
+
+        let account = |uid: u32, gids: &[u32]| Account {
+            name: "test".to_string(),
+            group: "test".to_string(),
+            uid: Uid::from_raw(uid),
+            gids: gids.iter().map(|gid| Gid::from_raw(*gid)).collect(),
+        };
+        let (uid, gid) = (md.uid(), md.gid());
+        let other = uid.wrapping_add(12345).max(1);
+        let other_gid = gid.wrapping_add(12345);
+        assert!(account(uid, &[]).mode_allows(&md, 0o7));
+        assert!(account(other, &[gid]).mode_allows(&md, 0o5));
+        assert!(!account(other, &[gid]).mode_allows(&md, 0o2));
+        assert!(!account(other, &[other_gid]).mode_allows(&md, 0o1));
+        assert!(account(0, &[]).mode_allows(&md, 0o7));
+    }
+}
@@ -0,0 +1,457 @@
+//! Exec hooks that failed, kept in `hook-queue.json` in the data directory.
+//! A renewed certificate is only picked up once its hook succeeded, so failed
+//! hooks are retried at the start of the next renew run and with `run-hooks`
+//! until they succeed or `hook_max_attempts` is reached. Once any hook of a
+//! certificate succeeds the older entries of that certificate are dropped,
+//! reloading once is enough.
+use crate::args::RunHooksArgs;
+use crate::config::Config;
+use crate::errors::*;
+use crate::hooks::{self, Exec, HookOptions};
+use crate::persist::FilePersist;
+use crate::report::HookReport;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+
+/// How often a hook is executed before it's dropped from the queue,
+/// including the execution that failed first.
+pub const DEFAULT_HOOK_MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedHook {
+    /// None for the global hooks
+    pub cert: Option<String>,
+    pub exec: Exec,
+    /// The environment the hook was executed with
+    pub env: Vec<(String, String)>,
+    /// Unix timestamp of the first failure
+    pub queued: i64,
+    /// Unix timestamp of the latest failure
+    pub last_attempt: i64,
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+impl QueuedHook {
+    /// If the hook has been executed because this certificate was renewed,
+    /// the global hooks are executed for all renewed certificates.
+    pub fn concerns(&self, name: &str) -> bool {
+        match &self.cert {
+            Some(cert) => cert == name,
+            None => self
+                .env
+                .iter()
+                .filter(|(key, _)| key == "ACME_RENEWED_CERTS")
+                .any(|(_, certs)| certs.split(' ').any(|cert| cert == name)),
+        }
+    }
+
+    fn env(&self) -> Vec<(&str, OsString)> {
+        self.env
+            .iter()
+            .map(|(key, value)| (key.as_str(), OsString::from(value)))
+            .collect()
+    }
+
+    fn is_same(&self, outcome: &Outcome) -> bool {
+        self.cert.as_deref() == outcome.cert
+            && self.exec == *outcome.exec
+            && self.env.len() == outcome.env.len()
+            && self
+                .env
+                .iter()
+                .zip(outcome.env)
+                .all(|((k1, v1), (k2, v2))| k1 == k2 && OsString::from(v1) == *v2)
+    }
+}
+
+/// A hook that has been executed.
+#[derive(Debug)]
+pub struct Outcome<'a> {
+    pub cert: Option<&'a str>,
+    pub exec: &'a Exec,
+    pub env: &'a [(&'a str, OsString)],
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HookQueue {
+    pub hooks: Vec<QueuedHook>,
+}
+
+impl HookQueue {
+    /// Update the queue with the outcome of hooks that were executed
+    /// together. Failed hooks are queued or have their attempts counted,
+    /// hooks that failed too often are dropped with an error.
+    pub fn update(&mut self, outcomes: &[Outcome], now: i64, max_attempts: u32) {
+        let succeeded = outcomes
+            .iter()
+            .filter(|outcome| outcome.error.is_none())
+            .map(|outcome| outcome.cert)
+            .collect::<Vec<_>>();
+        self.hooks
+            .retain(|hook| !succeeded.contains(&hook.cert.as_deref()));
+
+        for outcome in outcomes {
+            let error = match &outcome.error {
+                Some(error) => error,
+                None => continue,
+            };
+            let idx = match self.hooks.iter().position(|hook| hook.is_same(outcome)) {
+                Some(idx) => idx,
+                None => {
+                    self.hooks.push(QueuedHook {
+                        cert: outcome.cert.map(String::from),
+                        exec: outcome.exec.clone(),
+                        env: outcome
+                            .env
+                            .iter()
+                            .map(|(key, value)| {
+                                (key.to_string(), value.to_string_lossy().into_owned())
+                            })
+                            .collect(),
+                        queued: now,
+                        last_attempt: now,
+                        attempts: 0,
+                        last_error: String::new(),
+                    });
+                    self.hooks.len() - 1
+                }
+            };
+            let hook = &mut self.hooks[idx];
+            hook.attempts += 1;
+            hook.last_attempt = now;
+            hook.last_error = error.clone();
+            if hook.attempts >= max_attempts {
+                error!(
+                    "Giving up on hook `{}` after {} attempt(s), it's not retried anymore",
+                    hook.exec, hook.attempts
+                );
+                self.hooks.remove(idx);
+            } else {
+                info!(
+                    "Queued hook `{}` for a retry ({} of {} attempts)",
+                    hook.exec, hook.attempts, max_attempts
+                );
+            }
+        }
+    }
+
+    /// The queued hooks that were executed because this certificate was
+    /// renewed.
+    pub fn pending(&self, name: &str) -> Vec<&QueuedHook> {
+        self.hooks
+            .iter()
+            .filter(|hook| hook.concerns(name))
+            .collect()
+    }
+}
+
+/// The queue is shared by production and staging certificates.
+fn persist(config: &Config) -> FilePersist {
+    FilePersist::with_staging(config, false)
+}
+
+/// The queue of the data directory, a queue that can't be read or parsed is
+/// logged and treated as empty.
+pub fn load(config: &Config) -> HookQueue {
+    let json = match persist(config).load_hook_queue() {
+        Ok(Some(json)) => json,
+        Ok(None) => return HookQueue::default(),
+        Err(err) => {
+            warn!("Failed to read the queue of failed hooks: {:#}", err);
+            return HookQueue::default();
+        }
+    };
+    match serde_json::from_str(&json) {
+        Ok(queue) => queue,
+        Err(err) => {
+            warn!("Ignoring invalid queue of failed hooks: {}", err);
+            HookQueue::default()
+        }
+    }
+}
+
+fn store(config: &Config, queue: &HookQueue) {
+    let stored = serde_json::to_string(queue)
+        .map_err(Error::from)
+        .and_then(|json| persist(config).store_hook_queue(&json));
+    if let Err(err) = stored {
+        warn!("Failed to write the queue of failed hooks: {:#}", err);
+    }
+}
+
+/// Update the queue after hooks have been executed, see
+/// [`HookQueue::update`]. Failing to write the queue is logged, it never
+/// fails the renewal.
+pub fn record(config: &Config, outcomes: &[Outcome]) {
+    if outcomes.is_empty() {
+        return;
+    }
+    let mut queue = load(config);
+    let before = queue.clone();
+    queue.update(
+        outcomes,
+        time::get_time().sec,
+        config.system.hook_max_attempts(),
+    );
+    if queue != before {
+        store(config, &queue);
+    }
+}
+
+/// Execute the queued hooks again, oldest first. Hooks of a certificate that
+/// are dropped because another one of its hooks succeeded aren't executed.
+pub fn retry(config: &Config, dry_run: bool) -> Result<Vec<HookReport>> {
+    let mut queue = load(config);
+    if queue.hooks.is_empty() {
+        return Ok(Vec::new());
+    }
+    info!("Retrying {} failed hook(s)", queue.hooks.len());
+
+    let options = HookOptions::from_config(&config.system)?;
+    let max_attempts = config.system.hook_max_attempts();
+    let mut reports = Vec::new();
+    for hook in queue.hooks.clone() {
+        if dry_run {
+            info!(
+                "retrying hook: `{}` (dry run, {} failed attempt(s))",
+                hook.exec, hook.attempts
+            );
+            continue;
+        }
+        if !queue.hooks.contains(&hook) {
+            continue;
+        }
+        info!(
+            "retrying hook: `{}` ({} failed attempt(s))",
+            hook.exec, hook.attempts
+        );
+        let env = hook.env();
+        let (exit_code, result) = hooks::execute(&hook.exec, &env, &options);
+        if let Err(err) = &result {
+            error!("Failed to execute hook `{}`: {:#}", hook.exec, err);
+        }
+        let error = result.err().map(|err| format!("{:#}", err));
+        let outcome = Outcome {
+            cert: hook.cert.as_deref(),
+            exec: &hook.exec,
+            env: &env,
+            error: error.clone(),
+        };
+        queue.update(&[outcome], time::get_time().sec, max_attempts);
+        reports.push(HookReport {
+            cert: hook.cert.clone(),
+            command: hook.exec.to_string(),
+            exit_code,
+            error,
+        });
+    }
+
+    if !dry_run {
+        store(config, &queue);
+    }
+    Ok(reports)
+}
+
+pub fn run(config: Config, args: RunHooksArgs) -> Result<()> {
+    if load(&config).hooks.is_empty() {
+        info!("No failed hooks are queued");
+        return Ok(());
+    }
+    let reports = retry(&config, args.dry_run)?;
+    let failed = reports.iter().filter(|r| !r.is_success()).count();
+    if failed > 0 {
+        bail!("{} hook(s) failed", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use std::path::PathBuf;
+
+    fn config(data_dir: PathBuf) -> Config {
+        Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir,
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    fn outcome<'a>(
+        cert: Option<&'a str>,
+        exec: &'a Exec,
+        env: &'a [(&'a str, OsString)],
+        error: Option<&str>,
+    ) -> Outcome<'a> {
+        Outcome {
+            cert,
+            exec,
+            env,
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_update_queue() {
+        let reload = Exec::from("systemctl reload nginx");
+        let restart = Exec::from("systemctl restart postfix");
+        let env = [(
+            "ACME_RENEWED_CERTS",
+            OsString::from("a.example.com b.example.com"),
+        )];
+        let mut queue = HookQueue::default();
+
+        queue.update(
+            &[
+                outcome(Some("a.example.com"), &reload, &[], Some("exit status: 1")),
+                outcome(None, &restart, &env, Some("exit status: 1")),
+            ],
+            1000,
+            3,
+        );
+        assert_eq!(queue.hooks.len(), 2);
+        assert_eq!(queue.hooks[0].attempts, 1);
+        assert_eq!(queue.pending("a.example.com").len(), 2);
+        assert_eq!(queue.pending("b.example.com").len(), 1);
+        assert!(queue.pending("c.example.com").is_empty());
+
+        // the same hook failed again
+        queue.update(
+            &[outcome(
+                Some("a.example.com"),
+                &reload,
+                &[],
+                Some("timed out"),
+            )],
+            2000,
+            3,
+        );
+        assert_eq!(queue.hooks.len(), 2);
+        assert_eq!(queue.hooks[0].attempts, 2);
+        assert_eq!(queue.hooks[0].queued, 1000);
+        assert_eq!(queue.hooks[0].last_attempt, 2000);
+        assert_eq!(queue.hooks[0].last_error, "timed out");
+
+        // another hook of the cert succeeded
+        let other = Exec::from("true");
+        queue.update(
+            &[outcome(Some("a.example.com"), &other, &[], None)],
+            3000,
+            3,
+        );
+        assert_eq!(queue.hooks.len(), 1);
+        assert_eq!(queue.hooks[0].cert, None);
+
+        // given up after the maximum number of attempts
+        queue.update(
+            &[outcome(None, &restart, &env, Some("exit status: 1"))],
+            4000,
+            3,
+        );
+        assert_eq!(queue.hooks[0].attempts, 2);
+        queue.update(
+            &[outcome(None, &restart, &env, Some("exit status: 1"))],
+            5000,
+            3,
+        );
+        assert!(queue.hooks.is_empty());
+
+        // a failure and a success of the same cert in one run
+        queue.update(
+            &[
+                outcome(Some("a.example.com"), &reload, &[], Some("exit status: 1")),
+                outcome(Some("a.example.com"), &other, &[], None),
+            ],
+            6000,
+            3,
+        );
+        assert_eq!(queue.hooks.len(), 1);
+        queue.update(
+            &[outcome(Some("a.example.com"), &reload, &[], None)],
+            6000,
+            1,
+        );
+        assert!(queue.hooks.is_empty());
+        // hooks are never queued if they only get a single attempt
+        queue.update(
+            &[outcome(
+                Some("a.example.com"),
+                &reload,
+                &[],
+                Some("exit status: 1"),
+            )],
+            7000,
+            1,
+        );
+        assert!(queue.hooks.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path().to_path_buf());
+        let marker = dir.path().join("marker");
+        // fails until the marker exists
+        let exec = Exec::Args(vec![
+            "test".to_string(),
+            "-e".to_string(),
+            marker.to_str().unwrap().to_string(),
+        ]);
+        let env = [("ACME_CERT_NAME", OsString::from("a.example.com"))];
+        record(
+            &config,
+            &[outcome(
+                Some("a.example.com"),
+                &exec,
+                &env,
+                Some("exit status: 1"),
+            )],
+        );
+        assert_eq!(load(&config).hooks.len(), 1);
+
+        // nothing is executed in a dry run
+        assert!(retry(&config, true).unwrap().is_empty());
+        let reports = retry(&config, false).unwrap();
+        assert!(!reports[0].is_success());
+        let queue = load(&config);
+        assert_eq!(queue.hooks[0].attempts, 2);
+        assert_eq!(
+            queue.hooks[0].env,
+            vec![("ACME_CERT_NAME".to_string(), "a.example.com".to_string())]
+        );
+        assert!(run(config.clone(), RunHooksArgs { dry_run: false }).is_err());
+
+        std::fs::write(&marker, "").unwrap();
+        let reports = retry(&config, false).unwrap();
+        assert_eq!(
+            reports,
+            vec![HookReport {
+                cert: Some("a.example.com".to_string()),
+                command: exec.to_string(),
+                exit_code: Some(0),
+                error: None,
+            }]
+        );
+        assert!(load(&config).hooks.is_empty());
+        assert!(retry(&config, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path().to_path_buf());
+        std::fs::write(dir.path().join("hook-queue.json"), "{").unwrap();
+        assert_eq!(load(&config), HookQueue::default());
+    }
+}
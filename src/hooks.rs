@@ -0,0 +1,555 @@
+//! Running the exec hooks of renewed certificates. Hooks are either shell
+//! commands or lists of arguments that are executed without a shell, they
+//! can be run as a different user and are killed together with everything
+//! they started if they don't finish in time. Their output is logged line by
+//! line, prefixed with the program name.
+use crate::config::SystemConfig;
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait for the output of background processes that were
+/// started by a hook, after the hook itself exited
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Split a command into words the way `sh` does, quotes and backslashes are
+/// removed but variables like `$ACME_CERT_NAME` are kept, they're expanded
+/// when the command runs. Operators like `|` or `;` aren't recognized.
+pub fn split_words(cmd: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated single quote in {:?}", cmd),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('\n') => (),
+                            Some(c @ '$') | Some(c @ '`') | Some(c @ '"') | Some(c @ '\\') => {
+                                word.push(c)
+                            }
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("Unterminated double quote in {:?}", cmd),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated double quote in {:?}", cmd),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => (),
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => word.get_or_insert_with(String::new).push('\\'),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+/// Quote a word for `sh`, if needed.
+pub(crate) fn quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExecTable {
+    args: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "a shell command, a list of arguments or a table with args"
+)]
+enum ExecRepr {
+    Shell(String),
+    Args(Vec<String>),
+    Table(ExecTable),
+}
+
+impl From<ExecRepr> for Exec {
+    fn from(repr: ExecRepr) -> Exec {
+        match repr {
+            ExecRepr::Shell(cmd) => Exec::Shell(cmd),
+            ExecRepr::Args(args) => Exec::Args(args),
+            ExecRepr::Table(table) => Exec::Args(table.args),
+        }
+    }
+}
+
+/// An exec hook, a string is executed with `sh -c`, a list of arguments is
+/// executed directly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, from = "ExecRepr")]
+pub enum Exec {
+    Shell(String),
+    Args(Vec<String>),
+}
+
+impl Exec {
+    /// The words of the command, as they're passed to the program.
+    pub fn words(&self) -> Result<Vec<String>> {
+        match self {
+            Exec::Shell(cmd) => split_words(cmd),
+            Exec::Args(args) => Ok(args.clone()),
+        }
+    }
+
+    pub fn uses_shell(&self) -> bool {
+        matches!(self, Exec::Shell(_))
+    }
+
+    /// The program the command is going to run, variable assignments in
+    /// front of a shell command are skipped.
+    pub fn program(&self) -> Result<Option<String>> {
+        let words = self.words()?;
+        let program = match self {
+            Exec::Shell(_) => words.into_iter().find(|word| !is_assignment(word)),
+            Exec::Args(_) => words.into_iter().next(),
+        };
+        Ok(program)
+    }
+
+    /// How the command is going to be executed, for check-config.
+    pub fn describe(&self) -> String {
+        match self {
+            Exec::Shell(cmd) => match split_words(cmd) {
+                Ok(words) => format!("runs sh -c {:?}, words: {:?}", cmd, words),
+                Err(err) => format!("runs sh -c {:?}, {:#}", cmd, err),
+            },
+            Exec::Args(args) => format!("runs {:?} without a shell", args),
+        }
+    }
+
+    fn command(&self) -> Result<Command> {
+        match self {
+            Exec::Shell(cmd) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(cmd);
+                Ok(command)
+            }
+            Exec::Args(args) => {
+                let (program, args) = args
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Hook has no arguments"))?;
+                let mut command = Command::new(program);
+                command.args(args);
+                Ok(command)
+            }
+        }
+    }
+}
+
+impl From<&str> for Exec {
+    fn from(cmd: &str) -> Exec {
+        Exec::Shell(cmd.to_string())
+    }
+}
+
+impl fmt::Display for Exec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exec::Shell(cmd) => write!(f, "{}", cmd),
+            Exec::Args(args) => {
+                let args = args.iter().map(|arg| quote(arg)).collect::<Vec<_>>();
+                write!(f, "{}", args.join(" "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HookOptions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub timeout: Option<Duration>,
+}
+
+impl HookOptions {
+    /// Resolve `exec_user` and `exec_group`, the primary group of the user is
+    /// used unless a group is set.
+    #[cfg(unix)]
+    pub fn from_config(system: &SystemConfig) -> Result<HookOptions> {
+        let mut options = HookOptions {
+            timeout: system.exec_timeout_secs.map(Duration::from_secs),
+            ..Default::default()
+        };
+        if let Some(name) = &system.exec_user {
+            let user = users::get_user_by_name(name)
+                .ok_or_else(|| anyhow!("Failed to look up exec_user: {:?}", name))?;
+            options.uid = Some(user.uid());
+            options.gid = Some(user.primary_group_id());
+        }
+        if let Some(name) = &system.exec_group {
+            let group = users::get_group_by_name(name)
+                .ok_or_else(|| anyhow!("Failed to look up exec_group: {:?}", name))?;
+            options.gid = Some(group.gid());
+        }
+        Ok(options)
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_config(system: &SystemConfig) -> Result<HookOptions> {
+        for name in system.exec_user.iter().chain(&system.exec_group) {
+            warn!(
+                "Switching users is not supported on this platform, not running hooks as {:?}",
+                name
+            );
+        }
+        Ok(HookOptions {
+            timeout: system.exec_timeout_secs.map(Duration::from_secs),
+            ..Default::default()
+        })
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        // a process group of its own, so everything it started can be killed
+        cmd.process_group(0);
+        if let Some(gid) = self.gid {
+            cmd.gid(gid);
+        }
+        // this also clears the supplementary groups
+        if let Some(uid) = self.uid {
+            cmd.uid(uid);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _cmd: &mut Command) {}
+}
+
+fn log_output<R>(output: Option<R>, prefix: String, stderr: bool, done: mpsc::Sender<()>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Some(output) = output {
+            for line in BufReader::new(output).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        debug!("Failed to read output of hook: {}", err);
+                        break;
+                    }
+                };
+                if stderr {
+                    warn!("{}: {}", prefix, line);
+                } else {
+                    info!("{}: {}", prefix, line);
+                }
+            }
+        }
+        let _ = done.send(());
+    });
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+    if let Err(err) = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL) {
+        debug!("Failed to kill process group of hook: {}", err);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    if let Err(err) = child.kill() {
+        debug!("Failed to kill hook: {}", err);
+    }
+}
+
+/// Wait for the hook to exit, returns None if it had to be killed.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => return Ok(Some(child.wait()?)),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            kill(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Run a single hook, fails if the hook exits with an error or had to be
+/// killed after the timeout.
+pub fn run_hook(exec: &Exec, env: &[(&str, OsString)], options: &HookOptions) -> Result<()> {
+    let status = run_hook_status(exec, env, options)?;
+    check_status(status, options)
+}
+
+/// Turn the result of [`run_hook_status`] into an error if the hook failed.
+pub fn check_status(status: Option<ExitStatus>, options: &HookOptions) -> Result<()> {
+    match status {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => bail!("Hook exited with {}", status),
+        None => bail!(
+            "Hook timed out after {}s, killed it",
+            options.timeout.unwrap_or_default().as_secs()
+        ),
+    }
+}
+
+/// Run a single hook for a report, the exit code is None if the hook couldn't
+/// be started or had to be killed.
+pub fn execute(
+    exec: &Exec,
+    env: &[(&str, OsString)],
+    options: &HookOptions,
+) -> (Option<i32>, Result<()>) {
+    match run_hook_status(exec, env, options) {
+        Ok(status) => (status.and_then(|s| s.code()), check_status(status, options)),
+        Err(err) => (None, Err(err)),
+    }
+}
+
+/// Run a single hook and return how it exited, None if it had to be killed
+/// after the timeout. Only failing to start the hook is an error.
+pub fn run_hook_status(
+    exec: &Exec,
+    env: &[(&str, OsString)],
+    options: &HookOptions,
+) -> Result<Option<ExitStatus>> {
+    let mut cmd = exec.command()?;
+    cmd.envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    options.apply(&mut cmd);
+    let mut child = if exec.uses_shell() {
+        cmd.spawn().context("Failed to spawn shell for hook")?
+    } else {
+        cmd.spawn().context("Failed to spawn hook")?
+    };
+
+    let prefix = exec
+        .program()
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| exec.to_string());
+    let (done_tx, done_rx) = mpsc::channel();
+    log_output(child.stdout.take(), prefix.clone(), false, done_tx.clone());
+    log_output(child.stderr.take(), prefix, true, done_tx);
+
+    let status = wait(&mut child, options.timeout)?;
+    let grace = Instant::now() + OUTPUT_GRACE;
+    for _ in 0..2 {
+        let remaining = grace.saturating_duration_since(Instant::now());
+        if done_rx.recv_timeout(remaining).is_err() {
+            debug!(
+                "Hook `{}` left processes behind that keep its output open",
+                exec
+            );
+            break;
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(cmd: &str) -> Vec<String> {
+        split_words(cmd).unwrap()
+    }
+
+    fn program(exec: Exec) -> Option<String> {
+        exec.program().unwrap()
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            words("systemctl reload nginx"),
+            &["systemctl", "reload", "nginx"]
+        );
+        assert_eq!(words("  a\tb \n"), &["a", "b"]);
+        assert_eq!(
+            words("\"/opt/my app/reload\" --name 'it'\\''s' \"\""),
+            &["/opt/my app/reload", "--name", "it's", ""]
+        );
+        assert_eq!(words("a\\ b c\\\\d"), &["a b", "c\\d"]);
+        assert_eq!(
+            words("say \"a \\\"quoted\\\" \\$word\" 'no \\escapes'"),
+            &["say", "a \"quoted\" $word", "no \\escapes"]
+        );
+        // variables are expanded by the shell when the command runs
+        assert_eq!(
+            words("echo $ACME_CERT_NAME \"${ACME_LIVE_DIR}/fullchain\" '$HOME'"),
+            &[
+                "echo",
+                "$ACME_CERT_NAME",
+                "${ACME_LIVE_DIR}/fullchain",
+                "$HOME"
+            ]
+        );
+        assert_eq!(words("ab\"c d\"'e f'g"), &["abc de fg"]);
+        assert!(words("").is_empty());
+
+        assert!(split_words("echo 'unterminated").is_err());
+        assert!(split_words("echo \"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_exec_program() {
+        assert_eq!(
+            program(Exec::from("systemctl reload nginx")),
+            Some("systemctl".to_string())
+        );
+        assert_eq!(
+            program(Exec::from("FOO=bar  /usr/bin/env")),
+            Some("/usr/bin/env".to_string())
+        );
+        assert_eq!(
+            program(Exec::from("'/opt/my app/reload' --now")),
+            Some("/opt/my app/reload".to_string())
+        );
+        assert_eq!(program(Exec::from("   ")), None);
+        // there's no shell that would handle the assignment
+        assert_eq!(
+            program(Exec::Args(vec!["FOO=bar".to_string(), "env".to_string()])),
+            Some("FOO=bar".to_string())
+        );
+        assert_eq!(program(Exec::Args(vec![])), None);
+    }
+
+    #[test]
+    fn test_exec_display() {
+        let exec = Exec::Args(vec![
+            "/opt/my app/reload".to_string(),
+            "--name=it's".to_string(),
+            "$HOME".to_string(),
+            "".to_string(),
+            "nginx".to_string(),
+        ]);
+        assert_eq!(
+            exec.to_string(),
+            "'/opt/my app/reload' '--name=it'\\''s' '$HOME' '' nginx"
+        );
+        // quoting round trips through the shell
+        assert_eq!(
+            Exec::Shell(exec.to_string()).words().unwrap(),
+            exec.words().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            Exec::from("echo \"$ACME_CERT_NAME renewed\"").describe(),
+            "runs sh -c \"echo \\\"$ACME_CERT_NAME renewed\\\"\", words: [\"echo\", \"$ACME_CERT_NAME renewed\"]"
+        );
+        assert_eq!(
+            Exec::Args(vec!["systemctl".to_string(), "reload".to_string()]).describe(),
+            "runs [\"systemctl\", \"reload\"] without a shell"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let env = [("ACME_CERT_NAME", OsString::from("example.com"))];
+        let options = HookOptions::default();
+        let exec = Exec::Shell(format!("echo hello; echo \"$ACME_CERT_NAME\" > {:?}", out));
+        run_hook(&exec, &env, &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "example.com\n");
+
+        let exec = Exec::from("echo oops >&2; exit 3");
+        let err = run_hook(&exec, &env, &options).unwrap_err();
+        assert_eq!(err.to_string(), "Hook exited with exit status: 3");
+
+        // no shell, the arguments are passed on as they are
+        let exec = Exec::Args(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("printf '%s|' \"$@\" > {:?}", out),
+            "sh".to_string(),
+            "$ACME_CERT_NAME".to_string(),
+            "with space".to_string(),
+        ]);
+        run_hook(&exec, &env, &options).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "$ACME_CERT_NAME|with space|"
+        );
+        assert!(run_hook(&Exec::Args(vec![]), &env, &options).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let options = HookOptions {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        // the background process is killed with the hook
+        let exec = Exec::Shell(format!("(sleep 2; touch {:?}) & sleep 30", out));
+        let err = run_hook(&exec, &[], &options).unwrap_err();
+        assert_eq!(err.to_string(), "Hook timed out after 1s, killed it");
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_secs(2));
+        assert!(!out.exists());
+    }
+}
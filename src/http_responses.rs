@@ -6,6 +6,30 @@ pub const REDIRECT: &str = r#"<html>
 </html>
 "#;
 
+pub const FOUND: &str = r#"<html>
+<head><title>302 Found</title></head>
+<body>
+<center><h1>302 Found</h1></center>
+</body>
+</html>
+"#;
+
+pub const TEMPORARY_REDIRECT: &str = r#"<html>
+<head><title>307 Temporary Redirect</title></head>
+<body>
+<center><h1>307 Temporary Redirect</h1></center>
+</body>
+</html>
+"#;
+
+pub const PERMANENT_REDIRECT: &str = r#"<html>
+<head><title>308 Permanent Redirect</title></head>
+<body>
+<center><h1>308 Permanent Redirect</h1></center>
+</body>
+</html>
+"#;
+
 pub const BAD_REQUEST: &str = r#"<html>
 <head><title>400 Bad Request</title></head>
 <body>
@@ -14,6 +38,22 @@ pub const BAD_REQUEST: &str = r#"<html>
 </html>
 "#;
 
+pub const REQUEST_HEADER_FIELDS_TOO_LARGE: &str = r#"<html>
+<head><title>431 Request Header Fields Too Large</title></head>
+<body>
+<center><h1>431 Request Header Fields Too Large</h1></center>
+</body>
+</html>
+"#;
+
+pub const TOO_MANY_REQUESTS: &str = r#"<html>
+<head><title>429 Too Many Requests</title></head>
+<body>
+<center><h1>429 Too Many Requests</h1></center>
+</body>
+</html>
+"#;
+
 pub const NOT_FOUND: &str = r#"<html>
 <head><title>404 Not Found</title></head>
 <body>
@@ -21,3 +61,11 @@ pub const NOT_FOUND: &str = r#"<html>
 </body>
 </html>
 "#;
+
+pub const SERVICE_UNAVAILABLE: &str = r#"<html>
+<head><title>503 Service Unavailable</title></head>
+<body>
+<center><h1>503 Service Unavailable</h1></center>
+</body>
+</html>
+"#;
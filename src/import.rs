@@ -0,0 +1,203 @@
+//! Import a certificate that was issued by another acme client, like certbot
+//! or lego, so renewals can take over on the normal schedule.
+use crate::acme::Certificate;
+use crate::args::ImportArgs;
+use crate::cert::{self, CertInfo};
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::{Exports, FilePersist};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use std::fs;
+use std::path::Path;
+
+fn read_pem(path: &Path) -> Result<String> {
+    let mut pem = fs::read_to_string(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    if !pem.ends_with('\n') {
+        pem.push('\n');
+    }
+    Ok(pem)
+}
+
+/// Check that the key belongs to the certificate and convert it to the
+/// format of issued keys. The names of the certificate are only compared
+/// with the config, a mismatch is reported but doesn't fail the import.
+pub fn prepare(cert: &CertConfig, fullchain: &str, private_key: &str) -> Result<Certificate> {
+    let leaf = X509::from_pem(fullchain.as_bytes()).context("Failed to parse certificate")?;
+    let pkey = PKey::private_key_from_pem(private_key.as_bytes())
+        .context("Failed to parse private key")?;
+    if !leaf.public_key()?.public_eq(&pkey) {
+        bail!("The private key doesn't belong to the certificate");
+    }
+
+    let names = cert::dns_names(&leaf);
+    for name in cert.ascii_dns_names()? {
+        if !names.contains(&name) {
+            warn!(
+                "{:?}: the imported certificate isn't valid for {:?}",
+                cert.name, name
+            );
+        }
+    }
+
+    let info = CertInfo::from_pem(fullchain.as_bytes())?;
+    if info.days_left() < 0 {
+        warn!("{:?}: the imported certificate is expired", cert.name);
+    }
+
+    Ok(Certificate {
+        private_key: String::from_utf8(pkey.private_key_to_pem_pkcs8()?)?,
+        fullchain: fullchain.to_string(),
+    })
+}
+
+/// Store the certificate as a new version and mark it live, this refuses to
+/// replace a live certificate unless `force` is set.
+pub fn import_cert(
+    config: &Config,
+    cert: &CertConfig,
+    fullcert: &Certificate,
+    force: bool,
+) -> Result<()> {
+    let persist = FilePersist::for_cert(config, cert).with_exports(Exports::for_cert(cert)?);
+    if let Some(live) = persist.live_version(&cert.name)? {
+        if !force {
+            bail!(
+                "{:?} already has a live certificate ({:?}), use --force to replace it",
+                cert.name,
+                live
+            );
+        }
+        warn!("{:?}: replacing live certificate {:?}", cert.name, live);
+    }
+    persist
+        .store_cert(&cert.name, fullcert)
+        .context("Failed to store certificate")
+}
+
+pub fn run(config: Config, args: ImportArgs) -> Result<()> {
+    let cert = config
+        .certs
+        .iter()
+        .find(|cert| cert.name == args.name)
+        .ok_or_else(|| anyhow!("Certificate {:?} is not configured", args.name))?;
+
+    let mut fullchain = read_pem(&args.cert)?;
+    if let Some(chain) = &args.chain {
+        fullchain.push_str(&read_pem(chain)?);
+    }
+    let private_key = read_pem(&args.key)?;
+
+    let fullcert = prepare(cert, &fullchain, &private_key)?;
+    import_cert(&config, cert, &fullcert, args.force)?;
+
+    let persist = FilePersist::for_cert(&config, cert);
+    if let Some(version) = persist.live_version(&cert.name)? {
+        info!("{:?} is now using {:?}", cert.name, version);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+    use std::path::PathBuf;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn cert_pem(key: &PKey<Private>, dns_names: &[&str]) -> String {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", dns_names[0]).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(60).unwrap())
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        for name in dns_names {
+            san.dns(name);
+        }
+        let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    fn config(data_dir: &Path) -> (Config, CertConfig) {
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string(), "www.example.com".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: data_dir.to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        (config, cert)
+    }
+
+    #[test]
+    fn test_key_mismatch() {
+        let (_, cert) = config(Path::new("/var/lib/acme-redirect"));
+        let fullchain = cert_pem(&key(), &["example.com", "www.example.com"]);
+        let other = String::from_utf8(key().private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let err = prepare(&cert, &fullchain, &other).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The private key doesn't belong to the certificate"
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, cert) = config(dir.path());
+        let key = key();
+        let private_key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        // names that are missing from the certificate are only a warning
+        let fullchain = cert_pem(&key, &["example.com"]);
+        let fullcert = prepare(&cert, &fullchain, &private_key).unwrap();
+        import_cert(&config, &cert, &fullcert, false).unwrap();
+
+        let persist = FilePersist::for_cert(&config, &cert);
+        let info = persist.load_cert_info("example.com").unwrap().unwrap();
+        assert!(info.days_left() > 30);
+        assert_eq!(
+            fs::read_to_string(persist.live_path("example.com").join("privkey")).unwrap(),
+            private_key
+        );
+
+        // the live certificate isn't replaced by accident
+        assert!(import_cert(&config, &cert, &fullcert, false).is_err());
+        assert_eq!(persist.list_versions("example.com").unwrap().len(), 1);
+        import_cert(&config, &cert, &fullcert, true).unwrap();
+        assert_eq!(persist.list_versions("example.com").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,425 @@
+//! The `include` key of the main config. Included files are merged after the
+//! file that includes them, in the order they're listed: scalar values of
+//! later files override earlier ones, lists are concatenated and tables are
+//! merged key by key.
+use crate::config::glob_match;
+use crate::errors::*;
+use crate::interpolate;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+
+const INCLUDE: &str = "include";
+
+/// A single file of the main config.
+#[derive(Debug)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    pub buf: String,
+    pub value: Value,
+}
+
+impl ConfigSource {
+    fn read(path: &Path) -> Result<ConfigSource> {
+        let buf = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to load config file {:?}", path))?;
+        let value = buf
+            .parse::<Value>()
+            .with_context(|| anyhow!("Failed to load config file {:?}", path))?;
+        Ok(ConfigSource {
+            path: path.to_path_buf(),
+            buf,
+            value,
+        })
+    }
+
+    fn includes(&self) -> Result<Vec<&str>> {
+        let values = match self.value.get(INCLUDE) {
+            Some(Value::Array(values)) => values,
+            Some(_) => bail!("{:?}: include must be a list of paths", self.path),
+            None => return Ok(Vec::new()),
+        };
+        values
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("{:?}: include must be a list of paths", self.path))
+            })
+            .collect()
+    }
+}
+
+fn is_pattern(name: &str) -> bool {
+    name.contains(&['*', '?'][..])
+}
+
+/// The files an include refers to, relative paths are relative to the
+/// directory of the file that includes them. The file name can be a glob
+/// pattern, the matches are sorted and hidden files are skipped unless the
+/// pattern starts with a dot.
+fn resolve(parent: &Path, include: &str) -> Result<Vec<PathBuf>> {
+    let path = parent
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(include);
+    let pattern = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if is_pattern(name) => name,
+        _ => return Ok(vec![path]),
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let entries = fs::read_dir(dir)
+        .with_context(|| anyhow!("{:?}: Failed to read included directory {:?}", parent, dir))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| {
+            anyhow!("{:?}: Failed to read included directory {:?}", parent, dir)
+        })?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.starts_with('.') && !pattern.starts_with('.') {
+            continue;
+        }
+        if glob_match(pattern, name) && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[derive(Debug, Default)]
+struct Loader {
+    /// The files that are currently being loaded, to detect cycles
+    stack: Vec<PathBuf>,
+    seen: HashSet<PathBuf>,
+    sources: Vec<ConfigSource>,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let canonical = fs::canonicalize(path)
+            .with_context(|| anyhow!("Failed to load config file {:?}", path))?;
+        if let Some(idx) = self.stack.iter().position(|p| *p == canonical) {
+            let cycle = self.stack[idx..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| format!("{:?}", path))
+                .collect::<Vec<_>>();
+            bail!("Config files include each other: {}", cycle.join(" -> "));
+        }
+        if !self.seen.insert(canonical.clone()) {
+            bail!("Config file {:?} is included more than once", path);
+        }
+
+        let source = ConfigSource::read(path)?;
+        let includes = source
+            .includes()?
+            .into_iter()
+            .map(|include| resolve(path, include))
+            .collect::<Result<Vec<_>>>()?;
+        self.sources.push(source);
+
+        self.stack.push(canonical);
+        for path in includes.into_iter().flatten() {
+            self.load(&path)?;
+        }
+        self.stack.pop();
+        Ok(())
+    }
+}
+
+/// All files of the main config, in the order they're merged.
+#[derive(Debug, Default)]
+pub struct ConfigFiles {
+    pub sources: Vec<ConfigSource>,
+}
+
+impl ConfigFiles {
+    /// Load the main config and everything it includes. Include cycles and
+    /// included files that don't exist are an error, a glob pattern that
+    /// doesn't match anything isn't.
+    pub fn load(path: &Path) -> Result<ConfigFiles> {
+        let mut loader = Loader::default();
+        loader.load(path)?;
+        Ok(ConfigFiles {
+            sources: loader.sources,
+        })
+    }
+
+    /// Expand environment variables in every file, errors name the file.
+    pub fn expand_env(&mut self) -> Result<()> {
+        for source in &mut self.sources {
+            interpolate::expand(&mut source.value, &interpolate::env)
+                .with_context(|| anyhow!("Failed to load config file {:?}", source.path))?;
+        }
+        Ok(())
+    }
+
+    pub fn merge(&self) -> Merged {
+        let mut merged = Merged::default();
+        for source in &self.sources {
+            if let Value::Table(table) = &source.value {
+                let mut table = table.clone();
+                table.remove(INCLUDE);
+                merged.merge_table(table, &source.path);
+            }
+        }
+        merged
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+type Sources = BTreeMap<String, Vec<PathBuf>>;
+
+/// The merged config, with the files every value came from.
+#[derive(Debug, Default, PartialEq)]
+pub struct Merged {
+    pub table: Table,
+    /// The dotted keys of all values that aren't tables, lists can have
+    /// multiple sources
+    pub sources: Sources,
+}
+
+/// Forget the sources of a value and everything below it, it's replaced
+/// with a value of a different type.
+fn remove_sources(sources: &mut Sources, path: &str) {
+    let prefix = format!("{}.", path);
+    sources.retain(|key, _| key != path && !key.starts_with(&prefix));
+}
+
+fn merge_into(into: &mut Table, from: Table, prefix: &str, source: &Path, sources: &mut Sources) {
+    for (key, value) in from {
+        let path = join_key(prefix, &key);
+        match (into.get_mut(&key), value) {
+            (Some(Value::Table(into)), Value::Table(from)) => {
+                merge_into(into, from, &path, source, sources);
+            }
+            (Some(Value::Array(into)), Value::Array(from)) => {
+                into.extend(from);
+                sources.entry(path).or_default().push(source.to_path_buf());
+            }
+            (_, Value::Table(from)) => {
+                remove_sources(sources, &path);
+                let mut table = Table::new();
+                merge_into(&mut table, from, &path, source, sources);
+                into.insert(key, Value::Table(table));
+            }
+            (_, value) => {
+                remove_sources(sources, &path);
+                sources.insert(path, vec![source.to_path_buf()]);
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Merged {
+    fn merge_table(&mut self, table: Table, source: &Path) {
+        merge_into(&mut self.table, table, "", source, &mut self.sources);
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(&self.table).context("Failed to serialize merged config")
+    }
+
+    /// All values that aren't tables with their dotted key and sources, in
+    /// the order of the keys.
+    pub fn entries(&self) -> Vec<(String, &Value, &[PathBuf])> {
+        let mut entries = Vec::new();
+        self.collect_entries(&self.table, "", &mut entries);
+        entries
+    }
+
+    fn collect_entries<'a>(
+        &'a self,
+        table: &'a Table,
+        prefix: &str,
+        entries: &mut Vec<(String, &'a Value, &'a [PathBuf])>,
+    ) {
+        for (key, value) in table {
+            let path = join_key(prefix, key);
+            if let Value::Table(table) = value {
+                self.collect_entries(table, &path, entries);
+            } else {
+                let sources = self.sources.get(&path).map(Vec::as_slice).unwrap_or(&[]);
+                entries.push((path, value, sources));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn names(files: &ConfigFiles) -> Vec<String> {
+        files
+            .sources
+            .iter()
+            .map(|source| {
+                source
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        let main = write(
+            dir.path(),
+            "main.conf",
+            r#"
+            include = ["conf.d/*.toml"]
+            [acme]
+            acme_email = "old@example.com"
+            renew_if_days_left = 10
+            [system]
+            exec = ["systemctl reload nginx"]
+        "#,
+        );
+        write(
+            &dir.path().join("conf.d"),
+            "10-email.toml",
+            "[acme]\nacme_email = \"certs@example.com\"\n",
+        );
+        write(
+            &dir.path().join("conf.d"),
+            "20-hooks.toml",
+            "[system]\nexec = [\"systemctl reload haproxy\"]\nexec_extra = []\n",
+        );
+        write(&dir.path().join("conf.d"), ".20-hooks.toml.swp", "garbage");
+        write(&dir.path().join("conf.d"), "README", "garbage");
+
+        let files = ConfigFiles::load(&main).unwrap();
+        assert_eq!(
+            names(&files),
+            &["main.conf", "10-email.toml", "20-hooks.toml"]
+        );
+        let merged = files.merge();
+        let expected = r#"
+            [acme]
+            acme_email = "certs@example.com"
+            renew_if_days_left = 10
+            [system]
+            exec = ["systemctl reload nginx", "systemctl reload haproxy"]
+            exec_extra = []
+        "#
+        .parse::<Value>()
+        .unwrap();
+        assert_eq!(Value::Table(merged.table.clone()), expected);
+
+        let entries = merged
+            .entries()
+            .into_iter()
+            .map(|(key, _, sources)| {
+                let sources = sources
+                    .iter()
+                    .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+                    .collect::<Vec<_>>();
+                (key, sources.join(", "))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            &[
+                ("acme.acme_email".to_string(), "10-email.toml".to_string()),
+                (
+                    "acme.renew_if_days_left".to_string(),
+                    "main.conf".to_string()
+                ),
+                (
+                    "system.exec".to_string(),
+                    "main.conf, 20-hooks.toml".to_string()
+                ),
+                ("system.exec_extra".to_string(), "20-hooks.toml".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = write(
+            dir.path(),
+            "main.conf",
+            "include = [\"a.toml\", \"b.toml\"]\n[acme]\nrenew_if_days_left = 1\n",
+        );
+        write(
+            dir.path(),
+            "a.toml",
+            "include = [\"c.toml\"]\n[acme]\nrenew_if_days_left = 2\n",
+        );
+        write(dir.path(), "b.toml", "[acme]\nrenew_if_days_left = 4\n");
+        write(dir.path(), "c.toml", "[acme]\nrenew_if_days_left = 3\n");
+
+        let files = ConfigFiles::load(&main).unwrap();
+        assert_eq!(names(&files), &["main.conf", "a.toml", "c.toml", "b.toml"]);
+        let merged = files.merge();
+        assert_eq!(
+            merged.table["acme"]["renew_if_days_left"],
+            Value::Integer(4)
+        );
+        // the include key itself isn't part of the config
+        assert!(!merged.table.contains_key("include"));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = write(dir.path(), "main.conf", "include = [\"missing.toml\"]\n");
+        let err = ConfigFiles::load(&main).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing.toml"));
+
+        // a pattern without matches is fine, a missing directory isn't
+        write(dir.path(), "main.conf", "include = [\"*.toml\"]\n");
+        assert_eq!(names(&ConfigFiles::load(&main).unwrap()), &["main.conf"]);
+        write(dir.path(), "main.conf", "include = [\"missing/*.toml\"]\n");
+        assert!(ConfigFiles::load(&main).is_err());
+
+        write(dir.path(), "main.conf", "include = \"a.toml\"\n");
+        let err = ConfigFiles::load(&main).unwrap_err();
+        assert!(err.to_string().ends_with("include must be a list of paths"));
+
+        write(dir.path(), "main.conf", "include = [\"a.toml\"]\n");
+        write(dir.path(), "a.toml", "include = [\"main.conf\"]\n");
+        let err = ConfigFiles::load(&main).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Config files include each other: "));
+
+        write(
+            dir.path(),
+            "main.conf",
+            "include = [\"a.toml\", \"*.toml\"]\n",
+        );
+        write(dir.path(), "a.toml", "");
+        let err = ConfigFiles::load(&main).unwrap_err();
+        assert!(err.to_string().ends_with("is included more than once"));
+    }
+}
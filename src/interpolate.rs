@@ -0,0 +1,195 @@
+//! Expand `${VAR}` in the string values of a parsed config file with the
+//! environment of the process, so the same configs can be deployed with
+//! different values. `$$` is a literal `$`, any other `$` is kept as it is
+//! so hooks can still use `$ACME_CERT_NAME`.
+use crate::errors::*;
+use std::env;
+use toml::Value;
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expand a single string, `lookup` returns the value of a variable or None
+/// if it isn't set.
+pub fn expand_str<F>(s: &str, lookup: &F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable reference in {:?}", s))?;
+            let name = &r[..end];
+            if !is_valid_name(name) {
+                bail!("Invalid variable name {:?} in {:?}", name, s);
+            }
+            let value = lookup(name)
+                .ok_or_else(|| anyhow!("Environment variable {:?} is not set", name))?;
+            out.push_str(&value);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn expand_value<F>(value: &mut Value, path: &str, lookup: &F) -> Result<()>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(s) => {
+            *s = expand_str(s, lookup).with_context(|| anyhow!("Failed to expand {}", path))?;
+        }
+        Value::Array(values) => {
+            for (idx, value) in values.iter_mut().enumerate() {
+                expand_value(value, &format!("{}[{}]", path, idx), lookup)?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                expand_value(value, &path, lookup)?;
+            }
+        }
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) | Value::Datetime(_) => (),
+    }
+    Ok(())
+}
+
+/// Expand all strings in `value`, including the ones in arrays and nested
+/// tables. Keys are never expanded.
+pub fn expand<F>(value: &mut Value, lookup: &F) -> Result<()>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    expand_value(value, "", lookup)
+}
+
+/// Look up a variable in the environment of the process, values that aren't
+/// valid utf8 are treated like unset variables.
+pub fn env(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "DOMAIN" => Some("staging.example.com".to_string()),
+            "EMAIL" => Some("certs@example.com".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    fn expanded(s: &str) -> Result<String> {
+        expand_str(s, &lookup)
+    }
+
+    #[test]
+    fn test_expand_str() {
+        assert_eq!(
+            expanded("www.${DOMAIN}").unwrap(),
+            "www.staging.example.com"
+        );
+        assert_eq!(
+            expanded("${EMAIL}${EMPTY} ${DOMAIN}").unwrap(),
+            "certs@example.com staging.example.com"
+        );
+        assert_eq!(expanded("no variables").unwrap(), "no variables");
+        // everything else is passed on to the shell of hooks
+        assert_eq!(
+            expanded("echo $ACME_CERT_NAME costs 5$").unwrap(),
+            "echo $ACME_CERT_NAME costs 5$"
+        );
+        assert_eq!(
+            expanded("$${DOMAIN} $$$${EMAIL}").unwrap(),
+            "${DOMAIN} $${EMAIL}"
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let err = expanded("www.${UNDEFINED}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable \"UNDEFINED\" is not set"
+        );
+        assert!(expanded("www.${DOMAIN").is_err());
+        assert!(expanded("${}").is_err());
+        assert!(expanded("${1DOMAIN}").is_err());
+        assert!(expanded("${DOMAIN NAME}").is_err());
+    }
+
+    #[test]
+    fn test_expand_nested() {
+        let mut value = r#"
+            [cert]
+            name = "${DOMAIN}"
+            dns_names = ["${DOMAIN}", "www.${DOMAIN}"]
+            exec = [
+                "echo $ACME_CERT_NAME",
+                "notify-send $${DOMAIN} --to ${EMAIL}",
+            ]
+            renew_if_days_left = 10
+
+            [cert.export]
+            fullchain = "/etc/ssl/${DOMAIN}.pem"
+        "#
+        .parse::<Value>()
+        .unwrap();
+        expand(&mut value, &lookup).unwrap();
+        let expected = r#"
+            [cert]
+            name = "staging.example.com"
+            dns_names = ["staging.example.com", "www.staging.example.com"]
+            exec = [
+                "echo $ACME_CERT_NAME",
+                "notify-send ${DOMAIN} --to certs@example.com",
+            ]
+            renew_if_days_left = 10
+
+            [cert.export]
+            fullchain = "/etc/ssl/staging.example.com.pem"
+        "#
+        .parse::<Value>()
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn test_expand_names_the_key() {
+        let mut value = "[cert]\ndns_names = [\"example.com\", \"${SUFFIX}\"]\n"
+            .parse::<Value>()
+            .unwrap();
+        let err = expand(&mut value, &lookup).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to expand cert.dns_names[1]: Environment variable \"SUFFIX\" is not set"
+        );
+    }
+}
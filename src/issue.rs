@@ -0,0 +1,240 @@
+//! Request a certificate for names that aren't in any config file yet, the
+//! certificate is stored in the data directory like a configured one and
+//! renewed from then on if its config file is saved or deployed later.
+use crate::args::{IssueArgs, RenewArgs};
+use crate::chall;
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::FilePersist;
+use crate::renew::{self, RenewOutcome};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize)]
+struct SavedCert<'a> {
+    name: &'a str,
+    dns_names: &'a [String],
+}
+
+#[derive(Serialize)]
+struct SavedConfig<'a> {
+    cert: SavedCert<'a>,
+}
+
+/// The name is used for folders in the data directory and the config file.
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        bail!("The certificate name can't be empty");
+    }
+    if name.starts_with('.') || name.contains(&['/', '\\'][..]) {
+        bail!("Invalid certificate name: {:?}", name);
+    }
+    Ok(())
+}
+
+/// The config of the certificate, as if it was loaded from its config file
+/// in the config directory.
+pub fn cert_config(config: &Config, args: &IssueArgs) -> Result<CertConfig> {
+    validate_name(&args.name)?;
+    let cert = CertConfig {
+        name: args.name.clone(),
+        dns_names: args.dns_names.clone(),
+        source: config_path(config, &args.name),
+        ..Default::default()
+    };
+    cert.validate()
+        .with_context(|| anyhow!("Invalid certificate {:?}", cert.name))?;
+    Ok(cert)
+}
+
+fn config_path(config: &Config, name: &str) -> PathBuf {
+    config.config_dir.join(format!("{}.conf", name))
+}
+
+/// The contents of a config file for the certificate.
+pub fn config_file(cert: &CertConfig) -> Result<String> {
+    let saved = SavedConfig {
+        cert: SavedCert {
+            name: &cert.name,
+            dns_names: &cert.dns_names,
+        },
+    };
+    toml::to_string(&saved).map_err(Error::from)
+}
+
+/// Refuse to replace anything that already exists for this name, unless
+/// `force` is set.
+fn check_conflicts(
+    config: &Config,
+    cert: &CertConfig,
+    save_config: bool,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    if let Some(existing) = config.certs.iter().find(|c| c.name == cert.name) {
+        bail!(
+            "{:?} is already configured in {:?}, use --force to replace it",
+            cert.name,
+            existing.source
+        );
+    }
+    if save_config && cert.source.exists() {
+        bail!(
+            "Config file {:?} already exists, use --force to replace it",
+            cert.source
+        );
+    }
+    let persist = FilePersist::for_cert(config, cert);
+    if let Some(live) = persist.live_version(&cert.name)? {
+        bail!(
+            "{:?} already has a live certificate ({:?}), use --force to replace it",
+            cert.name,
+            live
+        );
+    }
+    Ok(())
+}
+
+fn save_config(path: &Path, cert: &CertConfig) -> Result<()> {
+    let buf = config_file(cert)?;
+    fs::write(path, buf).with_context(|| anyhow!("Failed to write config file {:?}", path))?;
+    info!("{:?}: wrote config file {:?}", cert.name, path);
+    Ok(())
+}
+
+pub fn run(config: Config, args: IssueArgs) -> Result<()> {
+    let cert = cert_config(&config, &args)?;
+    check_conflicts(&config, &cert, args.save_config, args.force)?;
+    if args.force {
+        if let Some(existing) = config.certs.iter().find(|c| c.name == cert.name) {
+            warn!(
+                "{:?}: ignoring the config in {:?}",
+                cert.name, existing.source
+            );
+        }
+    }
+
+    chall::cleanup_on_signal().context("Failed to setup signal handler")?;
+    let renew_args = RenewArgs {
+        force_renew: true,
+        ..Default::default()
+    };
+    if renew::renew_cert_with_args(&config, &cert, &renew_args)? == RenewOutcome::Renewed {
+        let persist = FilePersist::for_cert(&config, &cert);
+        if let Some(version) = persist.live_version(&cert.name)? {
+            info!("{:?} is now using {:?}", cert.name, version);
+        }
+    }
+
+    if args.save_config {
+        save_config(&cert.source, &cert)?;
+    }
+    if !args.skip_restarts {
+        renew::run_hooks(&config, &[&cert], &[&cert.name], false, false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigDir, SystemConfig};
+
+    fn args(name: &str, dns_names: &[&str]) -> IssueArgs {
+        IssueArgs {
+            name: name.to_string(),
+            dns_names: dns_names.iter().map(|n| n.to_string()).collect(),
+            save_config: true,
+            force: false,
+            skip_restarts: false,
+        }
+    }
+
+    fn config(dir: &Path) -> Config {
+        Config {
+            certs: vec![CertConfig {
+                name: "example.com".to_string(),
+                dns_names: vec!["example.com".to_string()],
+                source: dir.join("conf.d/example.com.conf"),
+                ..Default::default()
+            }],
+            config_dir: dir.join("conf.d"),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.join("data"),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_cert_config() {
+        let config = config(Path::new("/etc/acme-redirect"));
+        let cert = cert_config(
+            &config,
+            &args("temp.example.com", &["temp.example.com", "www.example.com"]),
+        )
+        .unwrap();
+        assert_eq!(cert.name, "temp.example.com");
+        assert_eq!(cert.dns_names, &["temp.example.com", "www.example.com"]);
+        assert_eq!(
+            cert.source,
+            Path::new("/etc/acme-redirect/conf.d/temp.example.com.conf")
+        );
+
+        assert!(cert_config(&config, &args("../temp", &["temp.example.com"])).is_err());
+        assert!(cert_config(&config, &args(".temp", &["temp.example.com"])).is_err());
+        assert!(cert_config(&config, &args("temp", &["*.example.com"])).is_err());
+        assert!(cert_config(&config, &args("temp", &["invalid name"])).is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = config(Path::new("/etc/acme-redirect"));
+        let cert = cert_config(
+            &config,
+            &args("temp.example.com", &["temp.example.com", "www.example.com"]),
+        )
+        .unwrap();
+        let buf = config_file(&cert).unwrap();
+        assert_eq!(
+            buf,
+            "[cert]\nname = \"temp.example.com\"\ndns_names = [\"temp.example.com\", \"www.example.com\"]\n"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("temp.example.com.conf");
+        save_config(&path, &cert).unwrap();
+        let loaded = ConfigDir::open(dir.path()).unwrap().load_certs().unwrap();
+        assert_eq!(
+            loaded,
+            &[CertConfig {
+                source: path,
+                ..cert
+            }]
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        fs::create_dir(&config.config_dir).unwrap();
+
+        let configured = cert_config(&config, &args("example.com", &["example.com"])).unwrap();
+        let err = check_conflicts(&config, &configured, false, false).unwrap_err();
+        assert!(err.to_string().contains("use --force"));
+        check_conflicts(&config, &configured, false, true).unwrap();
+
+        let temp = cert_config(&config, &args("temp.example.com", &["temp.example.com"])).unwrap();
+        check_conflicts(&config, &temp, true, false).unwrap();
+        save_config(&temp.source, &temp).unwrap();
+        assert!(check_conflicts(&config, &temp, true, false).is_err());
+        check_conflicts(&config, &temp, false, false).unwrap();
+    }
+}
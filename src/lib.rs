@@ -1,13 +1,78 @@
+//! acme-redirect can also be used as a library to issue certificates from
+//! another program, without going through the command line interface.
+//!
+//! ```no_run
+//! use acme_redirect::{config, renew_cert, PersistState, RenewOutcome};
+//! use std::path::Path;
+//!
+//! # fn main() -> acme_redirect::errors::Result<()> {
+//! let config = config::load_from(
+//!     Path::new("/etc/acme-redirect.conf"),
+//!     Path::new("/etc/acme-redirect.d"),
+//! )?;
+//! for cert in &config.certs {
+//!     if renew_cert(&config, cert)? == RenewOutcome::Renewed {
+//!         let persist = PersistState::for_cert(&config, cert);
+//!         println!("renewed {}: {:?}", cert.name, persist.live_path(&cert.name));
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The library doesn't initialize logging, it only emits records through the
+//! `log` crate. Nothing changes the working directory or other process wide
+//! state, except the `daemon` and `sandbox` modules that are only meant to be
+//! used by the binary. Errors are returned to the caller.
+
+pub mod account;
 pub mod acme;
 pub mod args;
+pub mod ari;
+pub mod caa;
 pub mod cert;
 pub mod chall;
 pub mod check;
+pub mod check_config;
+pub mod clock;
 pub mod config;
+pub mod control;
 pub mod daemon;
+pub mod deployment;
+pub mod dns;
+pub mod doctor;
 pub mod errors;
+pub mod hook_queue;
+pub mod hooks;
 pub mod http_responses;
+pub mod import;
+pub mod include;
+pub mod interpolate;
+pub mod issue;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod path;
+pub mod permissions;
 pub mod persist;
+pub mod proxy_protocol;
+pub mod prune;
+pub mod ratelimit;
+pub mod redirect_map;
+pub mod remove;
 pub mod renew;
+pub mod report;
+pub mod revoke;
+pub mod rollback;
 pub mod sandbox;
+pub mod schedule;
+pub mod state;
 pub mod status;
+pub mod systemd;
+pub mod tls_alpn;
+pub mod unknown_keys;
+
+pub use crate::config::{CertConfig, Config};
+pub use crate::persist::FilePersist as PersistState;
+pub use crate::renew::{renew_cert, RenewOutcome};
@@ -0,0 +1,162 @@
+//! Advisory lock on the data directory, so only one process at a time
+//! modifies certificates and accounts.
+use crate::config::Config;
+use crate::errors::*;
+#[cfg(unix)]
+use nix::errno;
+#[cfg(unix)]
+use nix::fcntl::{self, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LOCK_FILE: &str = "acme-redirect.lock";
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Held while the data directory is locked. The lock is released when this
+/// is dropped, or by the kernel if the process dies in any way.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    // std opens files with O_CLOEXEC, hooks don't inherit the lock
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    options.mode(0o644);
+    // the file can't be opened by anybody else while we hold it
+    #[cfg(windows)]
+    options.share_mode(0);
+    options.open(path)
+}
+
+/// Open and lock the file, None if it's locked by somebody else.
+#[cfg(unix)]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    let file = open(path).with_context(|| anyhow!("Failed to open lock file: {:?}", path))?;
+    match fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(file)),
+        Err(nix::Error::Sys(errno::EWOULDBLOCK)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(windows)]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    // ERROR_SHARING_VIOLATION
+    const SHARING_VIOLATION: i32 = 32;
+    match open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.raw_os_error() == Some(SHARING_VIOLATION) => Ok(None),
+        Err(err) => Err(Error::from(err).context(anyhow!("Failed to open lock file: {:?}", path))),
+    }
+}
+
+fn holder(path: &Path) -> String {
+    match fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+        _ => "unknown pid".to_string(),
+    }
+}
+
+/// Lock `dir`, waiting up to `wait` for another process to release it.
+/// With `None` this fails immediately if the lock is taken.
+pub fn acquire_in(dir: &Path, wait: Option<Duration>) -> Result<Lock> {
+    let path = dir.join(LOCK_FILE);
+
+    let start = Instant::now();
+    let mut logged = false;
+    let mut file = loop {
+        if let Some(file) = try_lock(&path).with_context(|| anyhow!("Failed to lock {:?}", path))? {
+            break file;
+        }
+        let wait = match wait {
+            Some(wait) if start.elapsed() < wait => wait,
+            _ => bail!(
+                "Another instance is running ({}), lock file is {:?}",
+                holder(&path),
+                path
+            ),
+        };
+        if !logged {
+            info!(
+                "Another instance is running ({}), waiting up to {}s",
+                holder(&path),
+                wait.as_secs()
+            );
+            logged = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    debug!("Locked {:?}", path);
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Lock { _file: file, path })
+}
+
+/// Lock the data directory of the config.
+pub fn acquire(config: &Config, wait: Option<Duration>) -> Result<Lock> {
+    acquire_in(&config.system.data_dir, wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = acquire_in(dir.path(), None).unwrap();
+        assert_eq!(
+            fs::read_to_string(lock.path()).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        let err = acquire_in(dir.path(), None).unwrap_err();
+        assert!(
+            err.to_string().starts_with(&format!(
+                "Another instance is running (pid {})",
+                std::process::id()
+            )),
+            "{}",
+            err
+        );
+
+        let start = Instant::now();
+        assert!(acquire_in(dir.path(), Some(Duration::from_millis(500))).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        drop(lock);
+        acquire_in(dir.path(), None).unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = acquire_in(dir.path(), None).unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        acquire_in(dir.path(), Some(Duration::from_secs(10))).unwrap();
+        release.join().unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Log output, either the env_logger text format or one json object per line.
+//!
+//! Every json line has the keys `timestamp`, `level` and `target`. Regular
+//! log lines also have a `message`, the access log of the daemon (with the
+//! target `acme_redirect::access`) has `client`, `method`, `host`, `path`,
+//! `status`, `size` and `latency_ms` instead. With the text format the access
+//! log uses the same format as the actix logger.
+use crate::args::LogFormat;
+use crate::errors::*;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use env_logger::fmt::Formatter;
+use env_logger::Env;
+use log::Record;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+pub const ACCESS_LOG: &str = "acme_redirect::access";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn init(format: LogFormat, default_filter: &str) {
+    let mut builder =
+        env_logger::Builder::from_env(Env::default().default_filter_or(default_filter));
+    if format == LogFormat::Json {
+        JSON.store(true, Ordering::Relaxed);
+        builder.format(format_json);
+    }
+    builder.init();
+}
+
+/// If json logging is enabled, the daemon then writes its own access log
+/// instead of using the actix logger.
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut obj = Map::new();
+    obj.insert(
+        "timestamp".into(),
+        buf.timestamp_millis().to_string().into(),
+    );
+    obj.insert("level".into(), record.level().as_str().into());
+    obj.insert("target".into(), record.target().into());
+
+    let message = record.args().to_string();
+    match serde_json::from_str(&message) {
+        Ok(Value::Object(fields)) if record.target() == ACCESS_LOG => obj.extend(fields),
+        _ => {
+            obj.insert("message".into(), message.into());
+        }
+    }
+
+    serde_json::to_writer(&mut *buf, &obj)?;
+    writeln!(buf)
+}
+
+/// Header values are usually ascii, anything that isn't valid utf8 is
+/// escaped so it can still be logged.
+fn escape_header(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(value) => value.to_string(),
+        Err(_) => value.escape_ascii().to_string(),
+    }
+}
+
+pub struct AccessLog {
+    start: Instant,
+    client: Option<String>,
+    method: String,
+    host: Option<String>,
+    path: String,
+    /// The request line and headers of the text format
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(req: &ServiceRequest) -> AccessLog {
+        let header = |name| req.headers().get(name).map(|v| escape_header(v.as_bytes()));
+        AccessLog {
+            start: Instant::now(),
+            client: req.peer_addr().map(|addr| addr.to_string()),
+            method: req.method().to_string(),
+            host: header("Host"),
+            path: req.path().to_string(),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        }
+    }
+
+    /// `%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T` like the actix
+    /// logger.
+    fn text(&self, status: u16, size: Option<u64>, latency_ms: f64) -> String {
+        let dash = || "-".to_string();
+        format!(
+            "{} \"{}\" {} {} \"{}\" \"{}\" {:.6}",
+            self.client.clone().unwrap_or_else(dash),
+            self.request_line,
+            status,
+            size.map(|s| s.to_string()).unwrap_or_else(dash),
+            self.referer.clone().unwrap_or_else(dash),
+            self.user_agent.clone().unwrap_or_else(dash),
+            latency_ms / 1000.0
+        )
+    }
+
+    fn fields(&self, status: u16, size: Option<u64>, latency_ms: f64) -> Value {
+        json!({
+            "client": self.client,
+            "method": self.method,
+            "host": self.host,
+            "path": self.path,
+            "status": status,
+            "size": size,
+            "latency_ms": latency_ms,
+        })
+    }
+
+    pub fn finish<B: MessageBody>(self, res: &ServiceResponse<B>) {
+        let size = match res.response().body().size() {
+            BodySize::Sized(size) => Some(size),
+            BodySize::None | BodySize::Empty => Some(0),
+            BodySize::Stream => None,
+        };
+        let latency_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        let status = res.status().as_u16();
+        if is_json() {
+            info!(target: ACCESS_LOG, "{}", self.fields(status, size, latency_ms));
+        } else {
+            info!(target: ACCESS_LOG, "{}", self.text(status, size, latency_ms));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_escape_header() {
+        assert_eq!(escape_header(b"example.com"), "example.com");
+        assert_eq!(escape_header(b"\xffexample.com"), "\\xffexample.com");
+    }
+
+    #[test]
+    fn test_access_log_fields() {
+        let req = TestRequest::with_uri("/foo?bar=1")
+            .header("Host", HeaderValue::from_bytes(b"ex\xe4mple.com").unwrap())
+            .peer_addr("192.0.2.1:1234".parse().unwrap())
+            .to_srv_request();
+        let log = AccessLog::new(&req);
+        assert_eq!(
+            log.fields(301, Some(128), 0.5).to_string(),
+            r#"{"client":"192.0.2.1:1234","host":"ex\\xe4mple.com","latency_ms":0.5,"method":"GET","path":"/foo","size":128,"status":301}"#
+        );
+        assert_eq!(
+            log.text(301, Some(128), 1.5),
+            r#"192.0.2.1:1234 "GET /foo?bar=1 HTTP/1.1" 301 128 "-" "-" 0.001500"#
+        );
+    }
+}
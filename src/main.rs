@@ -1,15 +1,43 @@
-use acme_redirect::args::{self, Args, Cmd, SubCommand};
+use acme_redirect::account;
+use acme_redirect::args::{self, Args, Cmd, DaemonArgs, StatusArgs, SubCommand};
+use acme_redirect::chall;
 use acme_redirect::check;
+use acme_redirect::check_config;
 use acme_redirect::config;
 use acme_redirect::daemon;
+use acme_redirect::deployment;
+use acme_redirect::doctor;
 use acme_redirect::errors::*;
+use acme_redirect::hook_queue;
+use acme_redirect::import;
+use acme_redirect::issue;
+use acme_redirect::lock;
+use acme_redirect::logging;
+use acme_redirect::path;
+use acme_redirect::prune;
+use acme_redirect::remove;
 use acme_redirect::renew;
+use acme_redirect::revoke;
+use acme_redirect::rollback;
 use acme_redirect::status;
-use env_logger::Env;
+use std::env;
 use std::io;
+use std::path::Path;
 use structopt::StructOpt;
 
 fn main() -> Result<()> {
+    let argv = env::args().collect::<Vec<_>>();
+    if argv.get(1).map(String::as_str) == Some(args::COMPLETE_CERTS) {
+        let words = match argv.get(2).map(String::as_str) {
+            Some("--") => &argv[3..],
+            _ => &argv[2..],
+        };
+        for name in args::complete_certs(words) {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
     let args = Args::from_args();
 
     let logging = match (args.quiet, args.verbose) {
@@ -19,22 +47,55 @@ fn main() -> Result<()> {
         (false, 2) => "debug",
         (false, _) => "debug,acme_redirect=trace",
     };
-    env_logger::init_from_env(Env::default().default_filter_or(logging));
+    logging::init(args.log_format, logging);
 
     match args.subcommand.clone() {
         SubCommand::Cmds(subcommand) => {
-            let config = config::load(args)?;
+            let config_path = args.config.clone();
+            let verbose = args.verbose > 0;
+            let lock_wait = args.lock_wait();
+            // status also works on a copy of the data directory without configs
+            let offline =
+                matches!(subcommand, Cmd::Status(_)) && !Path::new(&args.config_dir).exists();
+            let config = match subcommand {
+                Cmd::CheckConfig(_) => config::load_unvalidated(args)?,
+                _ if offline => config::load_offline(args)?,
+                _ => config::load(args)?,
+            };
             trace!("Loaded runtime config: {:?}", config);
 
+            // released on exit, even if we're killed
+            let _lock = if subcommand.modifies_data_dir() {
+                Some(lock::acquire(&config, lock_wait)?)
+            } else {
+                None
+            };
+
             match subcommand {
-                Cmd::Daemon(args) => daemon::run(config, args)?,
-                Cmd::Status => status::run(config)?,
+                Cmd::Daemon(args) => daemon::run(config, DaemonArgs { lock_wait, ..args })?,
+                Cmd::Status(args) if offline => {
+                    status::run_offline(config, StatusArgs { verbose, ..args })?
+                }
+                Cmd::Status(args) => status::run(config, StatusArgs { verbose, ..args })?,
                 Cmd::Renew(args) => renew::run(config, args)?,
                 Cmd::Check(args) => check::run(config, args)?,
                 Cmd::DumpConfig => {
                     serde_json::to_writer_pretty(io::stdout(), &config)?;
                     println!();
                 }
+                Cmd::CheckConfig(args) => check_config::run(config, Path::new(&config_path), args)?,
+                Cmd::Doctor(args) => doctor::run(config, args)?,
+                Cmd::Account(cmd) => account::run(config, cmd)?,
+                Cmd::Revoke(args) => revoke::run(config, args)?,
+                Cmd::Rollback(args) => rollback::run(config, args)?,
+                Cmd::Import(args) => import::run(config, args)?,
+                Cmd::Issue(args) => issue::run(config, args)?,
+                Cmd::Prune(args) => prune::run(config, args)?,
+                Cmd::Remove(args) => remove::run(config, args)?,
+                Cmd::CleanChallenges(args) => chall::run(config, args)?,
+                Cmd::Path(args) => path::run(config, args)?,
+                Cmd::RunHooks(args) => hook_queue::run(config, args)?,
+                Cmd::VerifyDeployment(args) => deployment::run(config, args)?,
             }
         }
         SubCommand::Completions(completions) => args::gen_completions(&completions)?,
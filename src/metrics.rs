@@ -0,0 +1,170 @@
+use crate::cert::CertInfo;
+use crate::config::CertConfig;
+use crate::errors::*;
+use crate::persist::LiveCerts;
+use actix_web::{get, web, HttpResponse, Responder};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub redirects: Counter,
+    pub challenges: Counter,
+    pub challenge_not_found: Counter,
+    pub bad_requests: Counter,
+    pub rate_limited: Counter,
+    certs: RwLock<BTreeMap<String, CertInfo>>,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    write_header(out, name, "counter", help);
+    writeln!(out, "{} {}", name, counter.get()).unwrap();
+}
+
+impl Metrics {
+    /// Read the expiry of the live certificate for each of the configured
+    /// certs, replacing the previously loaded ones.
+    pub fn load_certs(&self, live: &LiveCerts, certs: &[CertConfig]) {
+        let mut infos = BTreeMap::new();
+        for cert in certs {
+            match live.load_cert_info(&cert.name) {
+                Ok(Some(info)) => {
+                    infos.insert(cert.name.clone(), info);
+                }
+                Ok(None) => debug!("No live certificate for {:?}", cert.name),
+                Err(err) => warn!("Failed to load certificate {:?}: {:#}", cert.name, err),
+            }
+        }
+        *self.certs.write().unwrap() = infos;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "acme_redirect_redirects_total",
+            "Number of redirects to https served.",
+            &self.redirects,
+        );
+        write_counter(
+            &mut out,
+            "acme_redirect_challenges_total",
+            "Number of acme challenge proofs served.",
+            &self.challenges,
+        );
+        write_counter(
+            &mut out,
+            "acme_redirect_challenge_not_found_total",
+            "Number of requests for unknown acme challenges.",
+            &self.challenge_not_found,
+        );
+        write_counter(
+            &mut out,
+            "acme_redirect_bad_requests_total",
+            "Number of requests rejected as invalid.",
+            &self.bad_requests,
+        );
+        write_counter(
+            &mut out,
+            "acme_redirect_rate_limited_total",
+            "Number of requests rejected by the rate limit.",
+            &self.rate_limited,
+        );
+
+        write_header(
+            &mut out,
+            "acme_redirect_cert_days_until_expiry",
+            "gauge",
+            "Days until the live certificate expires.",
+        );
+        for (name, info) in self.certs.read().unwrap().iter() {
+            writeln!(
+                out,
+                "acme_redirect_cert_days_until_expiry{{cert=\"{}\"}} {}",
+                escape_label(name),
+                info.days_left()
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[get("/metrics")]
+async fn serve(metrics: web::Data<Metrics>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(serve);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("example.com"), "example.com");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.redirects.inc();
+        metrics.redirects.inc();
+        metrics.bad_requests.inc();
+        metrics.certs.write().unwrap().insert(
+            "example.com".to_string(),
+            CertInfo {
+                not_before: time::now(),
+                expires: time::now() + time::Duration::days(30) + time::Duration::hours(1),
+                key_type: None,
+                must_staple: false,
+                issuer: None,
+                chain_issuer: None,
+                dns_names: vec![],
+            },
+        );
+
+        let out = metrics.render();
+        assert!(out.contains("\nacme_redirect_redirects_total 2\n"));
+        assert!(out.contains("\nacme_redirect_challenges_total 0\n"));
+        assert!(out.contains("\nacme_redirect_bad_requests_total 1\n"));
+        assert!(out.contains("# TYPE acme_redirect_cert_days_until_expiry gauge\n"));
+        assert!(out.ends_with("\nacme_redirect_cert_days_until_expiry{cert=\"example.com\"} 30\n"));
+    }
+}
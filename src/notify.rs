@@ -0,0 +1,218 @@
+//! Notifications about failed renewals and certificates that are close to
+//! their expiry, POSTed as json to a webhook and passed to hooks in
+//! environment variables. Failing to deliver a notification is logged but
+//! never aborts the caller.
+use crate::cert::CertInfo;
+use crate::config::NotifyConfig;
+use crate::errors::*;
+use serde::Serialize;
+use std::process::Command;
+
+const TIMEOUT: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum EventKind {
+    #[serde(rename = "renew-failed")]
+    RenewFailed,
+    #[serde(rename = "expiring")]
+    Expiring,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::RenewFailed => "renew-failed",
+            EventKind::Expiring => "expiring",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub cert: String,
+    pub error: Option<String>,
+    pub days_left: Option<i64>,
+    pub timestamp: String,
+}
+
+fn now() -> String {
+    time::now_utc().rfc3339().to_string()
+}
+
+impl Event {
+    pub fn renew_failed(cert: &str, err: &Error, info: Option<&CertInfo>) -> Event {
+        Event {
+            event: EventKind::RenewFailed,
+            cert: cert.to_string(),
+            error: Some(format!("{:#}", err)),
+            days_left: info.map(CertInfo::days_left),
+            timestamp: now(),
+        }
+    }
+
+    pub fn expiring(cert: &str, info: &CertInfo) -> Event {
+        Event {
+            event: EventKind::Expiring,
+            cert: cert.to_string(),
+            error: None,
+            days_left: Some(info.days_left()),
+            timestamp: now(),
+        }
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("ACME_NOTIFY_EVENT", self.event.as_str().to_string()),
+            ("ACME_CERT_NAME", self.cert.clone()),
+            ("ACME_TIMESTAMP", self.timestamp.clone()),
+        ];
+        if let Some(error) = &self.error {
+            env.push(("ACME_ERROR", error.clone()));
+        }
+        if let Some(days_left) = self.days_left {
+            env.push(("ACME_DAYS_LEFT", days_left.to_string()));
+        }
+        env
+    }
+}
+
+fn send_webhook(url: &str, event: &Event) -> Result<()> {
+    let body = serde_json::to_string(event)?;
+    let res = ureq::post(url)
+        .timeout_connect(TIMEOUT)
+        .timeout_read(TIMEOUT)
+        .timeout_write(TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&body);
+    if let Some(err) = res.synthetic_error() {
+        bail!("{}", err);
+    }
+    if !res.ok() {
+        bail!("http error {}: {}", res.status(), res.status_text());
+    }
+    Ok(())
+}
+
+fn run_exec(exec: &str, event: &Event) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(exec)
+        .envs(event.env())
+        .status()
+        .context("Failed to spawn shell for hook")?;
+    if !status.success() {
+        bail!("Hook exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Deliver the event to the webhook and every hook in `exec_on_failure`.
+pub fn send(config: &NotifyConfig, event: &Event) {
+    if let Some(url) = &config.webhook_url {
+        info!(
+            "{:?}: sending {} notification",
+            event.cert,
+            event.event.as_str()
+        );
+        if let Err(err) = send_webhook(url, event) {
+            warn!("Failed to send notification to webhook: {:#}", err);
+        }
+    }
+    for exec in &config.exec_on_failure {
+        info!("executing notification hook: {:?}", exec);
+        if let Err(err) = run_exec(exec, event) {
+            warn!("Failed to execute notification hook {:?}: {:#}", exec, err);
+        }
+    }
+}
+
+/// The expiry warning for a live certificate, if it's below
+/// `warn_if_days_left`.
+pub fn expiry_warning(config: &NotifyConfig, cert: &str, info: &CertInfo) -> Option<Event> {
+    let threshold = config.warn_if_days_left?;
+    if info.days_left() < threshold {
+        Some(Event::expiring(cert, info))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(days: i64) -> CertInfo {
+        let now = time::now_utc();
+        CertInfo {
+            not_before: now - time::Duration::days(90 - days),
+            expires: now + time::Duration::days(days) + time::Duration::hours(1),
+            key_type: None,
+            must_staple: false,
+            issuer: None,
+            chain_issuer: None,
+            dns_names: vec![],
+        }
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let mut config = NotifyConfig::default();
+        assert!(expiry_warning(&config, "example.com", &info(3)).is_none());
+
+        config.warn_if_days_left = Some(7);
+        assert!(expiry_warning(&config, "example.com", &info(7)).is_none());
+        let event = expiry_warning(&config, "example.com", &info(3)).unwrap();
+        assert_eq!(event.event, EventKind::Expiring);
+        assert_eq!(event.days_left, Some(3));
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event = Event {
+            event: EventKind::RenewFailed,
+            cert: "example.com".to_string(),
+            error: Some("Fail to get certificate".to_string()),
+            days_left: Some(12),
+            timestamp: "2020-07-27T23:31:27Z".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "renew-failed",
+                "cert": "example.com",
+                "error": "Fail to get certificate",
+                "days_left": 12,
+                "timestamp": "2020-07-27T23:31:27Z",
+            })
+        );
+        assert_eq!(
+            event.env(),
+            vec![
+                ("ACME_NOTIFY_EVENT", "renew-failed".to_string()),
+                ("ACME_CERT_NAME", "example.com".to_string()),
+                ("ACME_TIMESTAMP", "2020-07-27T23:31:27Z".to_string()),
+                ("ACME_ERROR", "Fail to get certificate".to_string()),
+                ("ACME_DAYS_LEFT", "12".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_delivery_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let config = NotifyConfig {
+            // nothing is listening on this port
+            webhook_url: Some("http://127.0.0.1:9/hook".to_string()),
+            exec_on_failure: vec![
+                "exit 1".to_string(),
+                format!("echo \"$ACME_CERT_NAME $ACME_DAYS_LEFT\" > {:?}", out),
+            ],
+            warn_if_days_left: None,
+        };
+        send(&config, &Event::expiring("example.com", &info(3)));
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "example.com 3\n");
+    }
+}
@@ -0,0 +1,160 @@
+use crate::args::PathArgs;
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::{FilePersist, LiveFile};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// The absolute path of a file of the live certificate, with `resolve` the
+/// live symlink is resolved to the folder of the current version.
+pub fn live_file_path(
+    config: &Config,
+    cert: &CertConfig,
+    file: LiveFile,
+    resolve: bool,
+) -> Result<PathBuf> {
+    let persist = FilePersist::for_cert(config, cert);
+    let path = persist.live_file(&cert.name, file);
+    if let Err(err) = fs::metadata(&path) {
+        bail!(
+            "{:?} has no live {}: {:?}: {}",
+            cert.name,
+            file.file_name(),
+            path,
+            err
+        );
+    }
+    if resolve {
+        fs::canonicalize(&path).with_context(|| anyhow!("Failed to resolve {:?}", path))
+    } else if path.is_absolute() {
+        Ok(path)
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
+
+pub fn run(config: Config, args: PathArgs) -> Result<()> {
+    if args.all {
+        let mut missing = 0;
+        for cert in &config.certs {
+            match live_file_path(&config, cert, args.kind, args.resolve) {
+                Ok(path) => println!("{}\t{}", cert.name, path.display()),
+                Err(err) => {
+                    warn!("{:#}", err);
+                    missing += 1;
+                }
+            }
+        }
+        if missing > 0 {
+            bail!(
+                "{} of {} certificates are missing",
+                missing,
+                config.certs.len()
+            );
+        }
+        return Ok(());
+    }
+
+    let name = args.name.as_deref().unwrap_or_default();
+    let cert = config
+        .certs
+        .iter()
+        .find(|cert| cert.name == name)
+        .ok_or_else(|| anyhow!("Certificate {:?} is not configured", name))?;
+    let path = live_file_path(&config, cert, args.kind, args.resolve)?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acme::Certificate;
+    use crate::config::SystemConfig;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509Name, X509};
+    use std::path::Path;
+
+    fn certificate() -> Certificate {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "example.com").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        Certificate {
+            private_key: String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            fullchain: String::from_utf8(cert.build().to_pem().unwrap()).unwrap(),
+        }
+    }
+
+    fn config(dir: &Path) -> Config {
+        let cert = |name: &str| CertConfig {
+            name: name.to_string(),
+            dns_names: vec![name.to_string()],
+            ..Default::default()
+        };
+        Config {
+            certs: vec![cert("example.com"), cert("example.org")],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_live_file_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let cert = &config.certs[0];
+        let persist = FilePersist::for_cert(&config, cert);
+        persist.store_cert("example.com", &certificate()).unwrap();
+
+        let path = live_file_path(&config, cert, LiveFile::Privkey, false).unwrap();
+        assert_eq!(path, dir.path().join("live/example.com/privkey"));
+
+        let resolved = live_file_path(&config, cert, LiveFile::Chain, true).unwrap();
+        let version = persist.live_version("example.com").unwrap().unwrap();
+        assert_eq!(
+            resolved,
+            fs::canonicalize(dir.path())
+                .unwrap()
+                .join("certs")
+                .join(version)
+                .join("chain")
+        );
+
+        let err =
+            live_file_path(&config, &config.certs[1], LiveFile::Fullchain, false).unwrap_err();
+        assert!(format!("{:#}", err).contains("\"example.org\" has no live fullchain"));
+    }
+
+    #[test]
+    fn test_parse_live_file() {
+        assert_eq!("cert".parse::<LiveFile>().unwrap(), LiveFile::Cert);
+        assert_eq!(
+            "fullchain".parse::<LiveFile>().unwrap(),
+            LiveFile::Fullchain
+        );
+        assert!("bundle".parse::<LiveFile>().is_err());
+    }
+}
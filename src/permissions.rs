@@ -0,0 +1,406 @@
+//! The group and modes of the certificates in the data directory. With the
+//! `group` setting private keys are readable by that group, eg. for a web
+//! server that doesn't run as root. New files get their group and mode when
+//! they're created, files written before the setting was changed are fixed by
+//! `ensure_tree_ownership`.
+use crate::config::Config;
+use crate::errors::*;
+use crate::persist::PRIVATE_FILES;
+#[cfg(unix)]
+use nix::unistd::{self, FchownatFlags, Gid};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Private keys, readable by the owner and the configured group.
+pub const PRIVATE_MODE: u32 = 0o640;
+/// Certificates without a private key.
+pub const PUBLIC_MODE: u32 = 0o644;
+/// The folders certificates are stored in. Other users can't enter them if
+/// there's a group, see `GROUP_DIR_MODE`.
+pub const DIR_MODE: u32 = 0o755;
+pub const GROUP_DIR_MODE: u32 = 0o750;
+/// Account keys are only used by renew, they never get the group.
+pub const ACCOUNT_KEY_MODE: u32 = 0o600;
+pub const ACCOUNT_DIR_MODE: u32 = 0o700;
+
+/// The group and mode a file or folder should have, `None` keeps the group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Wanted {
+    #[cfg(unix)]
+    gid: Option<Gid>,
+    mode: u32,
+}
+
+/// The group new files are created with, the default keeps the group of the
+/// process.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ownership {
+    #[cfg(unix)]
+    gid: Option<Gid>,
+}
+
+impl Ownership {
+    #[cfg(unix)]
+    pub fn lookup(group: Option<&str>) -> Result<Ownership> {
+        let gid = match group {
+            Some(name) => {
+                let group = users::get_group_by_name(name)
+                    .ok_or_else(|| anyhow!("Failed to look up group: {:?}", name))?;
+                Some(Gid::from_raw(group.gid()))
+            }
+            None => None,
+        };
+        Ok(Ownership { gid })
+    }
+
+    #[cfg(not(unix))]
+    pub fn lookup(_group: Option<&str>) -> Result<Ownership> {
+        Ok(Ownership {})
+    }
+
+    pub fn from_config(config: &Config) -> Result<Ownership> {
+        Self::lookup(config.system.group.as_deref())
+    }
+
+    #[cfg(unix)]
+    fn has_group(&self) -> bool {
+        self.gid.is_some()
+    }
+
+    #[cfg(not(unix))]
+    fn has_group(&self) -> bool {
+        false
+    }
+
+    /// The mode of the folders certificates are stored in.
+    pub fn dir_mode(&self) -> u32 {
+        if self.has_group() {
+            GROUP_DIR_MODE
+        } else {
+            DIR_MODE
+        }
+    }
+
+    /// The umask only removes bits from the mode the file is opened with, so
+    /// the exact mode is set once the group is changed and before anything is
+    /// written.
+    #[cfg(unix)]
+    fn apply(&self, file: &File, mode: u32) -> io::Result<()> {
+        if let Some(gid) = self.gid {
+            unistd::fchown(file.as_raw_fd(), None, Some(gid)).map_err(io::Error::other)?;
+        }
+        file.set_permissions(fs::Permissions::from_mode(mode))
+    }
+
+    /// Create or truncate a file with exactly `mode`, independent of the
+    /// umask. The mode is ignored on platforms that don't have unix
+    /// permissions.
+    pub fn create_file(&self, path: &Path, mode: u32) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(mode);
+        let file = options.open(path)?;
+        #[cfg(unix)]
+        self.apply(&file, mode)?;
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(file)
+    }
+
+    /// Like `fs::create_dir`, but with exactly `mode` and the group.
+    pub fn create_dir(&self, path: &Path, mode: u32) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            fs::DirBuilder::new().mode(mode).create(path)?;
+            self.apply(&File::open(path)?, mode)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = mode;
+            fs::create_dir(path)
+        }
+    }
+
+    /// Like `fs::create_dir_all`, all folders that are created get `mode`
+    /// and the group. Folders that exist already are left alone.
+    pub fn create_dir_all(&self, path: &Path, mode: u32) -> io::Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                self.create_dir_all(parent, mode)?;
+            }
+        }
+        match self.create_dir(path, mode) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+            res => res,
+        }
+    }
+}
+
+struct Walker {
+    ownership: Ownership,
+    changed: usize,
+}
+
+impl Walker {
+    fn wanted(&self, mode: u32, with_group: bool) -> Wanted {
+        #[cfg(not(unix))]
+        let _ = with_group;
+        Wanted {
+            #[cfg(unix)]
+            gid: if with_group { self.ownership.gid } else { None },
+            mode,
+        }
+    }
+
+    /// Files that already match are left alone, so their ctime doesn't
+    /// change. Symlinks are never followed.
+    #[cfg(unix)]
+    fn ensure(&mut self, path: &Path, md: &fs::Metadata, wanted: Wanted) -> Result<()> {
+        if md.file_type().is_symlink() {
+            return Ok(());
+        }
+        if let Some(gid) = wanted.gid {
+            if md.gid() != gid.as_raw() {
+                debug!("Changing group of {:?} to {}", path, gid);
+                unistd::fchownat(None, path, None, Some(gid), FchownatFlags::NoFollowSymlink)
+                    .with_context(|| anyhow!("Failed to change group of {:?}", path))?;
+                self.changed += 1;
+            }
+        }
+        if md.mode() & 0o7777 != wanted.mode {
+            debug!("Changing mode of {:?} to {:04o}", path, wanted.mode);
+            fs::set_permissions(path, fs::Permissions::from_mode(wanted.mode))
+                .with_context(|| anyhow!("Failed to change mode of {:?}", path))?;
+            self.changed += 1;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn ensure(&mut self, _path: &Path, _md: &fs::Metadata, _wanted: Wanted) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns false if the folder doesn't exist or isn't a folder.
+    fn ensure_dir(&mut self, path: &Path, wanted: Wanted) -> Result<bool> {
+        match fs::symlink_metadata(path) {
+            Ok(md) if md.is_dir() => {
+                self.ensure(path, &md, wanted)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(Error::from(err).context(anyhow!("Failed to read {:?}", path))),
+        }
+    }
+
+    fn walk_versions(&mut self, certs: &Path) -> Result<()> {
+        let dir_mode = self.ownership.dir_mode();
+        if !self.ensure_dir(certs, self.wanted(dir_mode, true))? {
+            return Ok(());
+        }
+        for entry in fs::read_dir(certs)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !self.ensure_dir(&path, self.wanted(dir_mode, true))? {
+                continue;
+            }
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let md = fs::symlink_metadata(entry.path())?;
+                if !md.is_file() {
+                    continue;
+                }
+                let private = entry
+                    .file_name()
+                    .to_str()
+                    .map(|name| PRIVATE_FILES.contains(&name))
+                    .unwrap_or(true);
+                let mode = if private { PRIVATE_MODE } else { PUBLIC_MODE };
+                self.ensure(&entry.path(), &md, self.wanted(mode, true))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_account(&mut self, path: &Path) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name != "acc.key" && !name.starts_with("acc.key.") {
+                continue;
+            }
+            let md = fs::symlink_metadata(entry.path())?;
+            if md.is_file() {
+                self.ensure(&entry.path(), &md, self.wanted(ACCOUNT_KEY_MODE, false))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk(&mut self, root: &Path) -> Result<()> {
+        if !root.is_dir() {
+            return Ok(());
+        }
+        let dir_mode = self.ownership.dir_mode();
+        self.ensure_dir(&root.join("live"), self.wanted(dir_mode, true))?;
+        self.walk_versions(&root.join("certs"))?;
+
+        self.walk_account(root)?;
+        let accounts = root.join("accounts");
+        if self.ensure_dir(&accounts, self.wanted(ACCOUNT_DIR_MODE, false))? {
+            for entry in fs::read_dir(&accounts)? {
+                let path = entry?.path();
+                if self.ensure_dir(&path, self.wanted(ACCOUNT_DIR_MODE, false))? {
+                    self.walk_account(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply the configured group and the modes of newly written files to the
+/// live, certs and account folders of the data directory, including the
+/// staging folder. Returns how many changes were made.
+pub fn ensure_tree_ownership(config: &Config) -> Result<usize> {
+    let mut walker = Walker {
+        ownership: Ownership::from_config(config)?,
+        changed: 0,
+    };
+    #[cfg(not(unix))]
+    if config.system.group.is_some() {
+        warn!("File ownership is not supported on this platform, ignoring group");
+    }
+    let data_dir = &config.system.data_dir;
+    walker.walk(data_dir)?;
+    walker.walk(&data_dir.join("staging"))?;
+    Ok(walker.changed)
+}
+
+/// Like `ensure_tree_ownership`, unless it's disabled with
+/// no_fix_permissions. Errors are only logged.
+pub fn fix_permissions(config: &Config) {
+    if config.system.no_fix_permissions {
+        return;
+    }
+    match ensure_tree_ownership(config) {
+        Ok(0) => (),
+        Ok(n) => info!(
+            "Fixed the group or mode of {} files in the data directory",
+            n
+        ),
+        Err(err) => warn!("Failed to fix permissions in the data directory: {:#}", err),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::SystemConfig;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn config(dir: &Path, group: Option<&str>) -> Config {
+        Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.to_path_buf(),
+                group: group.map(String::from),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    fn write(path: &Path, mode: u32) {
+        fs::write(path, "").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_tree_ownership() {
+        let dir = tempfile::tempdir().unwrap();
+        let version = dir.path().join("certs/20200101-example.com");
+        fs::create_dir_all(&version).unwrap();
+        fs::set_permissions(&version, fs::Permissions::from_mode(0o700)).unwrap();
+        write(&version.join("privkey"), 0o644);
+        write(&version.join("fullchain"), 0o400);
+        write(&version.join("chain"), 0o644);
+        write(&dir.path().join("acc.key"), 0o644);
+        fs::create_dir_all(dir.path().join("accounts/0123abcd")).unwrap();
+        for path in &["certs", "accounts", "accounts/0123abcd"] {
+            let mode = if path.starts_with("accounts") {
+                ACCOUNT_DIR_MODE
+            } else {
+                DIR_MODE
+            };
+            fs::set_permissions(dir.path().join(path), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        write(&dir.path().join("accounts/0123abcd/acc.key"), 0o640);
+        fs::create_dir(dir.path().join("live")).unwrap();
+        fs::set_permissions(dir.path().join("live"), fs::Permissions::from_mode(0o750)).unwrap();
+        symlink(&version, dir.path().join("live/example.com")).unwrap();
+        // symlinks are not followed out of the tree
+        let outside = tempfile::tempdir().unwrap();
+        write(&outside.path().join("secret"), 0o600);
+        symlink(outside.path().join("secret"), version.join("cert")).unwrap();
+
+        let config = config(dir.path(), None);
+        assert_eq!(ensure_tree_ownership(&config).unwrap(), 6);
+        assert_eq!(mode(&version), DIR_MODE);
+        assert_eq!(mode(&version.join("privkey")), PRIVATE_MODE);
+        assert_eq!(mode(&version.join("fullchain")), PUBLIC_MODE);
+        assert_eq!(mode(&version.join("chain")), PUBLIC_MODE);
+        assert_eq!(mode(&dir.path().join("acc.key")), ACCOUNT_KEY_MODE);
+        assert_eq!(
+            mode(&dir.path().join("accounts/0123abcd/acc.key")),
+            ACCOUNT_KEY_MODE
+        );
+        assert_eq!(mode(&outside.path().join("secret")), 0o600);
+
+        // nothing is touched if everything matches already
+        assert_eq!(ensure_tree_ownership(&config).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_ensure_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let version = dir.path().join("certs/20200101-example.com");
+        fs::create_dir_all(&version).unwrap();
+        write(&version.join("privkey"), PRIVATE_MODE);
+        write(&dir.path().join("acc.key"), ACCOUNT_KEY_MODE);
+
+        // the primary group of the current user, so this works without root
+        let gid = unistd::getegid();
+        let name = users::get_group_by_gid(gid.as_raw())
+            .unwrap()
+            .name()
+            .to_string_lossy()
+            .into_owned();
+        let config = config(dir.path(), Some(&name));
+        ensure_tree_ownership(&config).unwrap();
+        let md = fs::metadata(version.join("privkey")).unwrap();
+        assert_eq!(md.gid(), gid.as_raw());
+
+        let config = self::config(dir.path(), Some("acme-redirect-missing-group"));
+        assert!(ensure_tree_ownership(&config).is_err());
+    }
+}
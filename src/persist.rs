@@ -1,32 +1,167 @@
-use crate::cert::CertInfo;
-use crate::config::Config;
+use crate::acme::Certificate;
+use crate::cert::{self, CertDetails, CertInfo};
+use crate::config::{CertConfig, Config, ExportFormat};
 use crate::errors::*;
-use acme_micro::Certificate;
+use crate::permissions::{Ownership, ACCOUNT_DIR_MODE, DIR_MODE, PRIVATE_MODE, PUBLIC_MODE};
+#[cfg(unix)]
+use nix::errno::Errno;
+#[cfg(unix)]
+use nix::fcntl::{self, OFlag};
+#[cfg(unix)]
+use nix::sys::stat::Mode;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
 use std::collections::HashMap;
+use std::env;
 use std::ffi::OsStr;
 use std::fs;
-use std::fs::{DirEntry, File, OpenOptions};
+use std::fs::DirEntry;
+#[cfg(unix)]
+use std::fs::File;
 use std::io::prelude::*;
 use std::io::ErrorKind;
+#[cfg(unix)]
 use std::os::unix::fs::symlink;
-use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(windows)]
+use std::os::windows::fs::symlink_dir as symlink;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
+/// The files `store_cert` writes into a certificate folder.
+pub const CERT_FILES: &[&str] = &["privkey", "fullchain", "chain", "cert", "bundle"];
+/// The files that are written for the configured export formats.
+const EXPORT_FILES: &[&str] = &["bundle.p12", "haproxy.pem", "cert.der"];
+/// The files of a certificate folder that contain the private key.
+pub const PRIVATE_FILES: &[&str] = &["privkey", "bundle", "bundle.p12", "haproxy.pem"];
+
+/// The files of the live certificate that are meant to be used by other
+/// programs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LiveFile {
+    Fullchain,
+    Privkey,
+    Chain,
+    Cert,
+}
+
+impl LiveFile {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            LiveFile::Fullchain => "fullchain",
+            LiveFile::Privkey => "privkey",
+            LiveFile::Chain => "chain",
+            LiveFile::Cert => "cert",
+        }
+    }
+}
+
+impl FromStr for LiveFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LiveFile> {
+        match s {
+            "fullchain" => Ok(LiveFile::Fullchain),
+            "privkey" => Ok(LiveFile::Privkey),
+            "chain" => Ok(LiveFile::Chain),
+            "cert" => Ok(LiveFile::Cert),
+            _ => bail!("Unknown file: {:?}", s),
+        }
+    }
+}
+
+/// The additional formats a certificate is written in, with the pkcs12
+/// password already read so a missing password fails before the order.
+#[derive(Clone, Default)]
+pub struct Exports {
+    formats: Vec<ExportFormat>,
+    pkcs12_password: String,
+}
+
+impl Exports {
+    pub fn for_cert(cert: &CertConfig) -> Result<Exports> {
+        let pkcs12_password = if let Some(path) = &cert.pkcs12_password_file {
+            let password = fs::read_to_string(path)
+                .with_context(|| anyhow!("Failed to read pkcs12 password: {:?}", path))?;
+            password.trim_end_matches(&['\r', '\n'][..]).to_string()
+        } else if let Some(name) = &cert.pkcs12_password_env {
+            env::var(name)
+                .with_context(|| anyhow!("Failed to read pkcs12 password from ${}", name))?
+        } else {
+            String::new()
+        };
+        Ok(Exports {
+            formats: cert.export.clone(),
+            pkcs12_password,
+        })
+    }
+}
+
+/// Handle for the layout of the data directory, the account key in
+/// `acc.key`, every issued certificate in its own folder in `certs/` and the
+/// `live/` symlinks pointing to the versions in use. Certificates with their
+/// own acme server keep their account in `accounts/<hash of the url>/`.
 #[derive(Clone)]
 pub struct FilePersist {
     path: PathBuf,
+    account: PathBuf,
+    account_url: Option<String>,
+    exports: Exports,
+    group: Option<String>,
+}
+
+/// The folder name in `accounts/` for the account on an acme server.
+pub fn account_folder(acme_url: &str) -> String {
+    let hash = openssl::sha::sha256(acme_url.as_bytes());
+    hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 impl FilePersist {
     pub fn new(config: &Config) -> FilePersist {
+        Self::with_staging(config, config.acme.staging)
+    }
+
+    /// Staging certificates and their account are kept in a separate folder,
+    /// so they never replace production certificates.
+    pub fn with_staging(config: &Config, staging: bool) -> FilePersist {
+        let path = PathBuf::from(&config.system.data_dir);
+        let mut persist = Self::at(if staging { path.join("staging") } else { path });
+        persist.group = config.system.group.clone();
+        persist
+    }
+
+    fn at(path: PathBuf) -> FilePersist {
         FilePersist {
-            path: PathBuf::from(&config.system.data_dir),
+            account: path.clone(),
+            path,
+            account_url: None,
+            exports: Exports::default(),
+            group: None,
         }
     }
 
+    /// Also write these formats when storing a certificate.
+    pub fn with_exports(mut self, exports: Exports) -> FilePersist {
+        self.exports = exports;
+        self
+    }
+
+    pub fn for_cert(config: &Config, cert: &CertConfig) -> FilePersist {
+        let mut persist = Self::with_staging(config, cert.staging(&config.acme));
+        if cert.has_own_account(&config.acme) {
+            let url = cert.acme_url(&config.acme);
+            persist.account = persist.path.join("accounts").join(account_folder(url));
+            persist.account_url = Some(url.to_string());
+        }
+        persist
+    }
+
     fn acc_privkey_path(&self) -> PathBuf {
-        self.path.join("acc.key")
+        self.account.join("acc.key")
     }
 
     pub fn load_acc_privkey(&self) -> Result<Option<String>> {
@@ -80,6 +215,10 @@ impl FilePersist {
                 let path = entry.path();
 
                 if let Some(Some(name)) = path.file_name().map(OsStr::to_str) {
+                    // leftover of an interrupted symlink swap
+                    if name.starts_with('.') {
+                        continue;
+                    }
                     if let Ok(link) = fs::read_link(entry.path()) {
                         if let Some(Some(version)) = link.file_name().map(OsStr::to_str) {
                             live.insert(version.to_string(), name.to_string());
@@ -92,10 +231,306 @@ impl FilePersist {
         Ok(live)
     }
 
+    pub fn live_path(&self, name: &str) -> PathBuf {
+        self.path.join("live").join(name)
+    }
+
+    /// A file of the live certificate, through the live symlink so the path
+    /// stays the same across renewals.
+    pub fn live_file(&self, name: &str, file: LiveFile) -> PathBuf {
+        self.live_path(name).join(file.file_name())
+    }
+
+    /// The folder in certs/ the live symlink of a certificate points to.
+    pub fn live_version(&self, name: &str) -> Result<Option<String>> {
+        let link = match fs::read_link(self.live_path(name)) {
+            Ok(link) => link,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let version = link
+            .file_name()
+            .and_then(OsStr::to_str)
+            .ok_or_else(|| anyhow!("Invalid symlink for live cert: {:?}", link))?;
+        Ok(Some(version.to_string()))
+    }
+
+    /// The private key of the live certificate.
+    pub fn load_live_privkey(&self, name: &str) -> Result<String> {
+        let path = self.live_path(name).join("privkey");
+        fs::read_to_string(&path).with_context(|| anyhow!("Failed to read {:?}", path))
+    }
+
+    /// The date of the oldest version that still has the private key of the
+    /// live version, the key has been reused by all versions since then.
+    pub fn privkey_since(&self, name: &str) -> Result<Option<time::Tm>> {
+        let live = match self.live_version(name)? {
+            Some(live) => live,
+            None => return Ok(None),
+        };
+        let key = self.load_live_privkey(name)?;
+        let versions = self.list_versions(name)?;
+        let mut since = live.as_str();
+        if let Some(idx) = versions.iter().position(|v| *v == live) {
+            for version in versions[..idx].iter().rev() {
+                let path = self.path.join("certs").join(version).join("privkey");
+                match fs::read_to_string(path) {
+                    Ok(old) if old == key => since = version,
+                    _ => break,
+                }
+            }
+        }
+        let date = since.split('-').next().unwrap_or_default();
+        let date = time::strptime(date, "%Y%m%d")
+            .with_context(|| anyhow!("Invalid version folder: {:?}", since))?;
+        Ok(Some(date))
+    }
+
+    /// All folders in certs/ that belong to a certificate, oldest first.
+    pub fn list_versions(&self, name: &str) -> Result<Vec<String>> {
+        let path = self.path.join("certs");
+
+        let mut versions = Vec::new();
+        if path.exists() {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                if let Ok(folder) = entry.file_name().into_string() {
+                    if let Some(key) = version_key(&folder, name) {
+                        versions.push((key, folder));
+                    }
+                }
+            }
+        }
+        versions.sort();
+
+        Ok(versions.into_iter().map(|(_, folder)| folder).collect())
+    }
+
+    /// Point the live symlink of a certificate to a different folder. The new
+    /// symlink is created next to the old one and renamed over it, so the live
+    /// path always points to a complete certificate.
+    fn set_live(&self, name: &str, path: &Path) -> Result<()> {
+        let live = self.path.join("live");
+        let ownership = self.ownership()?;
+        ownership
+            .create_dir_all(&live, ownership.dir_mode())
+            .with_context(|| anyhow!("Failed to create folder: {:?}", &live))?;
+
+        let tmp = live.join(format!(".{}.new", name));
+        if let Err(err) = fs::remove_file(&tmp) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(err).context("Failed to delete stale symlink");
+            }
+        }
+        symlink(path, &tmp)
+            .with_context(|| anyhow!("Failed to create symlink: {:?} -> {:?}", path, tmp))?;
+
+        let dest = live.join(name);
+        if let Err(err) = fs::rename(&tmp, &dest) {
+            fs::remove_file(&tmp).ok();
+            return Err(err).with_context(|| anyhow!("Failed to replace symlink: {:?}", dest));
+        }
+        sync_dir(&live)
+    }
+
+    /// The versions of a certificate that are older than the live one and
+    /// exceed the number of versions to keep, the live version is counted
+    /// too.
+    pub fn prunable_versions(&self, name: &str, keep: usize) -> Result<Vec<PathBuf>> {
+        let live = match self.live_version(name)? {
+            Some(live) => live,
+            None => return Ok(Vec::new()),
+        };
+        let versions = self.list_versions(name)?;
+        let older = match versions.iter().position(|v| *v == live) {
+            Some(idx) => &versions[..idx],
+            None => return Ok(Vec::new()),
+        };
+
+        let keep_older = keep.saturating_sub(1);
+        let prune = older.len().saturating_sub(keep_older);
+        Ok(older[..prune]
+            .iter()
+            .map(|v| self.path.join("certs").join(v))
+            .collect())
+    }
+
+    /// Delete an old version of a certificate. Folders that contain anything
+    /// besides the files written by `store_cert` are left alone, returns false
+    /// if the folder was skipped.
+    pub fn delete_version(&self, path: &Path) -> Result<bool> {
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            if !CERT_FILES
+                .iter()
+                .chain(EXPORT_FILES)
+                .any(|f| OsStr::new(f) == name)
+            {
+                warn!("Not deleting {:?}, found unexpected file {:?}", path, name);
+                return Ok(false);
+            }
+        }
+        fs::remove_dir_all(path).with_context(|| anyhow!("Failed to delete {:?}", path))?;
+        Ok(true)
+    }
+
+    /// Everything in the data directory that belongs to a certificate: its
+    /// live symlink, the symlink moved aside by `retire_live_cert`, all
+    /// versions and the state of its renewals. Versions that are live for
+    /// another certificate with a similar name are left out.
+    pub fn cert_paths(&self, name: &str) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for path in &[self.live_path(name), self.path.join("revoked").join(name)] {
+            if fs::symlink_metadata(path).is_ok() {
+                paths.push(path.clone());
+            }
+        }
+        let live = self.list_live_certs()?;
+        for version in self.list_versions(name)? {
+            match live.get(&version) {
+                Some(other) if other != name => continue,
+                _ => paths.push(self.path.join("certs").join(version)),
+            }
+        }
+        for path in &[self.renewal_info_path(name), self.state_path(name)] {
+            if path.exists() {
+                paths.push(path.clone());
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Delete a path returned by `cert_paths`, folders are deleted like with
+    /// `delete_version`. Returns false if the path was skipped.
+    pub fn remove_cert_path(&self, path: &Path) -> Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            self.delete_version(path)
+        } else {
+            fs::remove_file(path).with_context(|| anyhow!("Failed to delete {:?}", path))?;
+            Ok(true)
+        }
+    }
+
+    /// Point the live symlink back to the version before the current one.
+    /// Returns the version that is now live.
+    pub fn rollback(&self, name: &str) -> Result<String> {
+        let live = self
+            .live_version(name)?
+            .ok_or_else(|| anyhow!("Certificate has no live version"))?;
+        let versions = self.list_versions(name)?;
+        let idx = versions
+            .iter()
+            .position(|v| *v == live)
+            .ok_or_else(|| anyhow!("Live version {:?} is missing", live))?;
+        let previous = idx
+            .checked_sub(1)
+            .map(|idx| &versions[idx])
+            .ok_or_else(|| anyhow!("There is no version older than {:?}", live))?;
+
+        let path = self.path.join("certs").join(previous);
+        let buf = fs::read(path.join("fullchain"))
+            .with_context(|| anyhow!("Failed to read certificate: {:?}", path))?;
+        let cert = CertInfo::from_pem(&buf)?;
+        if cert.days_left() < 0 {
+            bail!("Previous version {:?} is expired", previous);
+        }
+
+        self.set_live(name, &path)?;
+        Ok(previous.to_string())
+    }
+
+    /// Move the live symlink of a certificate into the revoked folder, the
+    /// certificate is then treated as missing and reissued on the next renew.
+    pub fn retire_live_cert(&self, name: &str) -> Result<PathBuf> {
+        let revoked = self.path.join("revoked");
+        Ownership::default()
+            .create_dir_all(&revoked, DIR_MODE)
+            .with_context(|| anyhow!("Failed to create folder: {:?}", &revoked))?;
+        let revoked = revoked.join(name);
+        fs::rename(self.live_path(name), &revoked)
+            .with_context(|| anyhow!("Failed to move live cert to {:?}", revoked))?;
+        Ok(revoked)
+    }
+
+    fn renewal_info_path(&self, name: &str) -> PathBuf {
+        self.path
+            .join("renewal-info")
+            .join(format!("{}.json", name))
+    }
+
+    /// The cached ARI response of a certificate, see `ari::lookup`.
+    pub fn load_renewal_info(&self, name: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.renewal_info_path(name)) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn store_renewal_info(&self, name: &str, json: &str) -> Result<()> {
+        let path = self.renewal_info_path(name);
+        if let Some(parent) = path.parent() {
+            Ownership::default()
+                .create_dir_all(parent, DIR_MODE)
+                .with_context(|| anyhow!("Failed to create folder: {:?}", parent))?;
+        }
+        write(&path, 0o644, json.as_bytes())
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.path.join("state").join(format!("{}.json", name))
+    }
+
+    /// The outcome of the latest renewals of a certificate, see `state`.
+    pub fn load_state(&self, name: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.state_path(name)) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The state is replaced with a rename, so it's never read half written.
+    pub fn store_state(&self, name: &str, json: &str) -> Result<()> {
+        let path = self.state_path(name);
+        if let Some(parent) = path.parent() {
+            Ownership::default()
+                .create_dir_all(parent, DIR_MODE)
+                .with_context(|| anyhow!("Failed to create folder: {:?}", parent))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        write(&tmp, 0o644, json.as_bytes())?;
+        fs::rename(&tmp, &path)
+            .with_context(|| anyhow!("Failed to move renewal state to {:?}", path))
+    }
+
+    fn hook_queue_path(&self) -> PathBuf {
+        self.path.join("hook-queue.json")
+    }
+
+    /// The exec hooks that failed and are retried, see `hook_queue`.
+    pub fn load_hook_queue(&self) -> Result<Option<String>> {
+        match fs::read_to_string(self.hook_queue_path()) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The queue is replaced with a rename like the renewal state.
+    pub fn store_hook_queue(&self, json: &str) -> Result<()> {
+        let path = self.hook_queue_path();
+        Ownership::default()
+            .create_dir_all(&self.path, DIR_MODE)
+            .with_context(|| anyhow!("Failed to create folder: {:?}", self.path))?;
+        let tmp = path.with_extension("json.tmp");
+        write(&tmp, 0o644, json.as_bytes())?;
+        fs::rename(&tmp, &path).with_context(|| anyhow!("Failed to move hook queue to {:?}", path))
+    }
+
     pub fn load_cert_info(&self, name: &str) -> Result<Option<CertInfo>> {
-        let mut path = self.path.join("live");
-        path.push(name);
-        path.push("fullchain");
+        let path = self.live_path(name).join("fullchain");
 
         if path.exists() {
             let buf = fs::read(&path)?;
@@ -106,27 +541,104 @@ impl FilePersist {
         }
     }
 
+    /// Check that the live fullchain and private key of a certificate
+    /// belong together and cover its dns_names, see
+    /// [`cert::verify_installed`]. The key is skipped if we're not allowed
+    /// to read it, None if there's no live certificate.
+    pub fn verify_live(&self, cert: &CertConfig) -> Result<Option<Vec<String>>> {
+        let path = self.live_file(&cert.name, LiveFile::Fullchain);
+        let fullchain = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| anyhow!("Failed to read {:?}", path)),
+        };
+        let path = self.live_file(&cert.name, LiveFile::Privkey);
+        let privkey = match fs::read(&path) {
+            Ok(buf) => Some(buf),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                debug!("Not checking the private key of {:?}: {}", cert.name, err);
+                None
+            }
+            Err(err) => return Err(err).with_context(|| anyhow!("Failed to read {:?}", path)),
+        };
+        let problems =
+            cert::verify_installed(&fullchain, privkey.as_deref(), &cert.ascii_dns_names()?)?;
+        Ok(Some(problems))
+    }
+
+    pub fn load_cert_details(&self, name: &str) -> Result<Option<CertDetails>> {
+        let path = self.live_path(name).join("fullchain");
+
+        if path.exists() {
+            let buf = fs::read(&path)?;
+            let details = CertDetails::from_pem(&buf)?;
+            Ok(Some(details))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn store_acc_privkey(&self, key: &str) -> Result<()> {
-        let path = self.acc_privkey_path();
+        if let Some(url) = &self.account_url {
+            Ownership::default()
+                .create_dir_all(&self.account, ACCOUNT_DIR_MODE)
+                .with_context(|| anyhow!("Failed to create folder: {:?}", self.account))?;
+            // so it's possible to tell which server the account is for
+            write(
+                &self.account.join("url"),
+                0o644,
+                format!("{}\n", url).as_bytes(),
+            )?;
+        }
+        write(&self.acc_privkey_path(), 0o600, key.as_bytes())
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .mode(0o600)
-            .open(path)?;
+    fn staged_acc_privkey_path(&self) -> PathBuf {
+        self.account.join("acc.key.new")
+    }
 
-        file.write_all(key.as_bytes())?;
+    /// Write a new account key next to the current one, it's only put in
+    /// place with `commit_acc_privkey` after the acme server accepted it.
+    pub fn stage_acc_privkey(&self, key: &str) -> Result<()> {
+        let path = self.staged_acc_privkey_path();
+        write(&path, 0o600, key.as_bytes())
+            .with_context(|| anyhow!("Failed to write new account key: {:?}", path))
+    }
+
+    pub fn discard_staged_acc_privkey(&self) -> Result<()> {
+        fs::remove_file(self.staged_acc_privkey_path())?;
         Ok(())
     }
 
+    /// Replace the account key with the staged one, the old key is kept as
+    /// a timestamped backup. Returns the path of the backup.
+    pub fn commit_acc_privkey(&self) -> Result<PathBuf> {
+        let path = self.acc_privkey_path();
+        let now = time::strftime("%Y%m%d%H%M%S", &time::now_utc())?;
+        let backup = self.account.join(format!("acc.key.{}", now));
+
+        fs::hard_link(&path, &backup)
+            .with_context(|| anyhow!("Failed to backup account key to {:?}", backup))?;
+        fs::rename(self.staged_acc_privkey_path(), &path)
+            .with_context(|| anyhow!("Failed to replace account key: {:?}", path))?;
+        Ok(backup)
+    }
+
+    /// The group the certificates are created with.
+    fn ownership(&self) -> Result<Ownership> {
+        Ownership::lookup(self.group.as_deref())
+    }
+
     pub fn store_cert(&self, name: &str, fullcert: &Certificate) -> Result<()> {
         let now = time::now_utc();
         let now = time::strftime("%Y%m%d", &now)?;
 
+        let ownership = self.ownership()?;
+        let dir_mode = ownership.dir_mode();
         let path = self.path.join("certs");
         debug!("creating folder: {:?}", path);
-        fs::create_dir_all(&path)
+        ownership
+            .create_dir_all(&path, dir_mode)
             .with_context(|| anyhow!("Failed to create folder: {:?}", &path))?;
 
         let mut i = 0;
@@ -139,7 +651,7 @@ impl FilePersist {
             let path = path.join(folder);
             debug!("try atomically claiming folder: {:?}", path);
 
-            let err = fs::create_dir(&path);
+            let err = ownership.create_dir(&path, dir_mode);
             match err {
                 Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
                 Err(_) => {
@@ -151,62 +663,246 @@ impl FilePersist {
             i += 1;
         };
 
-        debug!("splitting chain from cert");
-        let (chain, cert) = split_chain(fullcert.certificate())?;
+        // don't leave an incomplete version behind
+        if let Err(err) = write_cert_files(&path, fullcert, &self.exports, &ownership) {
+            if let Err(err) = fs::remove_dir_all(&path) {
+                warn!("Failed to delete incomplete cert {:?}: {:#}", path, err);
+            }
+            return Err(err);
+        }
 
-        let bundle = format!("{}{}", fullcert.private_key(), cert);
+        info!("marking cert live");
+        self.set_live(name, &path)
+    }
+}
 
-        debug!("writing privkey");
-        let privkey_path = path.join("privkey");
-        write(&privkey_path, 0o440, fullcert.private_key().as_bytes())?;
+/// Write all files of a certificate into its folder, everything is synced
+/// to disk before the certificate is marked live.
+fn write_cert_files(
+    path: &Path,
+    fullcert: &Certificate,
+    exports: &Exports,
+    ownership: &Ownership,
+) -> Result<()> {
+    debug!("splitting chain from cert");
+    let (chain, cert) = split_chain(&fullcert.fullchain)?;
 
-        debug!("writing full cert with intermediates");
-        let fullkey_path = path.join("fullchain");
-        write(&fullkey_path, 0o444, fullcert.certificate().as_bytes())?;
+    let bundle = format!("{}{}", fullcert.private_key, cert);
 
-        debug!("writing chain");
-        let chain_path = path.join("chain");
-        write(&chain_path, 0o444, chain.as_bytes())?;
+    debug!("writing privkey");
+    let privkey_path = path.join("privkey");
+    write_as(
+        ownership,
+        &privkey_path,
+        PRIVATE_MODE,
+        fullcert.private_key.as_bytes(),
+    )?;
 
-        debug!("writing single cert");
-        let cert_path = path.join("cert");
-        write(&cert_path, 0o444, cert.as_bytes())?;
+    debug!("writing full cert with intermediates");
+    let fullkey_path = path.join("fullchain");
+    write_as(
+        ownership,
+        &fullkey_path,
+        PUBLIC_MODE,
+        fullcert.fullchain.as_bytes(),
+    )?;
 
-        debug!("writing bundle");
-        let bundle_path = path.join("bundle");
-        write(&bundle_path, 0o440, bundle.as_bytes())?;
+    debug!("writing chain");
+    let chain_path = path.join("chain");
+    write_as(ownership, &chain_path, PUBLIC_MODE, chain.as_bytes())?;
 
-        info!("marking cert live");
-        let live = self.path.join("live");
-        fs::create_dir_all(&live)
-            .with_context(|| anyhow!("Failed to create folder: {:?}", &live))?;
-        let live = live.join(name);
+    debug!("writing single cert");
+    let cert_path = path.join("cert");
+    write_as(ownership, &cert_path, PUBLIC_MODE, cert.as_bytes())?;
+
+    debug!("writing bundle");
+    let bundle_path = path.join("bundle");
+    write_as(ownership, &bundle_path, PRIVATE_MODE, bundle.as_bytes())?;
+
+    for format in &exports.formats {
+        write_export(path, fullcert, *format, exports, ownership)
+            .with_context(|| anyhow!("Failed to export certificate as {:?}", format))?;
+    }
+
+    sync_dir(path)?;
+    if let Some(parent) = path.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
 
-        // TODO: this should be atomic (ln -sf)
-        // https://github.com/coreutils/coreutils/blob/2ed7c2867974ccf7abc61c34ad7bf9565489c18e/src/force-link.c#L142-L182
-        if live.exists() {
-            fs::remove_file(&live).context("Failed to delete old symlink")?;
+fn write_export(
+    path: &Path,
+    fullcert: &Certificate,
+    format: ExportFormat,
+    exports: &Exports,
+    ownership: &Ownership,
+) -> Result<()> {
+    match format {
+        ExportFormat::Pkcs12 => {
+            debug!("writing pkcs12 bundle");
+            let pkey = PKey::private_key_from_pem(fullcert.private_key.as_bytes())?;
+            let mut certs = X509::stack_from_pem(fullcert.fullchain.as_bytes())?.into_iter();
+            let cert = certs
+                .next()
+                .ok_or_else(|| anyhow!("Input has no certificates"))?;
+            let mut chain = Stack::new()?;
+            for cert in certs {
+                chain.push(cert)?;
+            }
+            let p12 = Pkcs12::builder()
+                .pkey(&pkey)
+                .cert(&cert)
+                .ca(chain)
+                .build2(&exports.pkcs12_password)?;
+            write_as(
+                ownership,
+                &path.join("bundle.p12"),
+                PRIVATE_MODE,
+                &p12.to_der()?,
+            )?;
+        }
+        ExportFormat::HaproxyPem => {
+            debug!("writing haproxy pem");
+            let pem = format!("{}{}", fullcert.private_key, fullcert.fullchain);
+            write_as(
+                ownership,
+                &path.join("haproxy.pem"),
+                PRIVATE_MODE,
+                pem.as_bytes(),
+            )?;
+        }
+        ExportFormat::Der => {
+            debug!("writing der cert");
+            let cert = X509::from_pem(fullcert.fullchain.as_bytes())?;
+            write_as(
+                ownership,
+                &path.join("cert.der"),
+                PUBLIC_MODE,
+                &cert.to_der()?,
+            )?;
         }
-        symlink(&path, &live)
-            .with_context(|| anyhow!("Failed to create symlink: {:?} -> {:?}", path, live))?;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// The sort key of a folder in certs/ if it belongs to this certificate, the
+/// folders are named `<date>-<name>` with an optional `-<n>` suffix.
+fn version_key(folder: &str, name: &str) -> Option<(String, u32)> {
+    let (date, rest) = folder.split_at(folder.find('-')?);
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let rest = rest[1..].strip_prefix(name)?;
+    let n = if rest.is_empty() {
+        0
+    } else {
+        let n = rest.strip_prefix('-')?;
+        if !n.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        n.parse().ok()?
+    };
+    Some((date.to_string(), n))
 }
 
-fn create(path: &Path, mode: u32) -> Result<File> {
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .mode(mode)
-        .open(path)
-        .map_err(Error::from)
+/// Read-only access to the live certificates through an open directory handle,
+/// this keeps working after the daemon chrooted into the challenge directory.
+/// Other platforms can't chroot, the certificates are read by path there.
+pub struct LiveCerts {
+    #[cfg(unix)]
+    fd: File,
+    #[cfg(not(unix))]
+    path: PathBuf,
 }
 
+impl LiveCerts {
+    #[cfg(unix)]
+    pub fn open(config: &Config) -> Result<LiveCerts> {
+        let path = &config.system.data_dir;
+        let fd =
+            File::open(path).with_context(|| anyhow!("Failed to open directory: {:?}", path))?;
+        Ok(LiveCerts { fd })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(config: &Config) -> Result<LiveCerts> {
+        let path = &config.system.data_dir;
+        if !path.is_dir() {
+            bail!("Failed to open directory: {:?}", path);
+        }
+        Ok(LiveCerts {
+            path: path.to_path_buf(),
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn load_cert_info(&self, name: &str) -> Result<Option<CertInfo>> {
+        let link = match fcntl::readlinkat(self.fd.as_raw_fd(), &Path::new("live").join(name)) {
+            Ok(link) => link,
+            Err(nix::Error::Sys(Errno::ENOENT)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        // the symlink is absolute and may point outside of our chroot
+        let folder = Path::new(&link)
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid symlink for live cert: {:?}", link))?;
+        let path = Path::new("certs").join(folder).join("fullchain");
+
+        let fd = fcntl::openat(
+            self.fd.as_raw_fd(),
+            &path,
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let cert = CertInfo::from_pem(&buf)?;
+        Ok(Some(cert))
+    }
+
+    #[cfg(not(unix))]
+    pub fn load_cert_info(&self, name: &str) -> Result<Option<CertInfo>> {
+        let link = match fs::read_link(self.path.join("live").join(name)) {
+            Ok(link) => link,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let folder = link
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid symlink for live cert: {:?}", link))?;
+        let buf = fs::read(self.path.join("certs").join(folder).join("fullchain"))?;
+
+        let cert = CertInfo::from_pem(&buf)?;
+        Ok(Some(cert))
+    }
+}
+
+/// Write a file that keeps the group of the process.
 fn write(path: &Path, mode: u32, data: &[u8]) -> Result<()> {
-    let mut f = create(&path, mode)?;
+    write_as(&Ownership::default(), path, mode, data)
+}
+
+fn write_as(ownership: &Ownership, path: &Path, mode: u32, data: &[u8]) -> Result<()> {
+    let mut f = ownership.create_file(path, mode)?;
     f.write_all(data)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|f| f.sync_all())
+        .with_context(|| anyhow!("Failed to sync folder: {:?}", path))
+}
+
+// directories can't be opened like files on windows
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
     Ok(())
 }
 
@@ -607,4 +1303,401 @@ aJ6TSPxS0YlSBhKYNbOUI7R8ZbjAJe/vI1IcYYhMaIW0kAzo4nxEmg==\r\n\
 -----END CERTIFICATE-----\r\n"
         );
     }
+
+    #[test]
+    fn test_retire_live_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        let certs = dir.path().join("certs/20200101-example.com");
+        fs::create_dir_all(&certs).unwrap();
+        fs::create_dir(dir.path().join("live")).unwrap();
+        symlink(&certs, persist.live_path("example.com")).unwrap();
+
+        let revoked = persist.retire_live_cert("example.com").unwrap();
+        assert_eq!(fs::read_link(revoked).unwrap(), certs);
+        assert!(persist.list_live_certs().unwrap().is_empty());
+        assert!(persist.retire_live_cert("example.com").is_err());
+    }
+
+    #[test]
+    fn test_remove_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        let pem = cert_pem(false);
+        persist
+            .store_cert("example.com-1", &certificate(&pem))
+            .unwrap();
+        persist
+            .store_cert("example.com", &certificate(&pem))
+            .unwrap();
+        persist
+            .store_cert("example.com", &certificate(&pem))
+            .unwrap();
+        persist.store_state("example.com", "{}").unwrap();
+
+        let versions = persist.list_versions("example.com").unwrap();
+        // the folder of example.com-1 looks like a version of example.com
+        assert_eq!(versions.len(), 3);
+        let paths = persist.cert_paths("example.com").unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                persist.live_path("example.com"),
+                dir.path().join("certs").join(&versions[0]),
+                dir.path().join("certs").join(&versions[2]),
+                dir.path().join("state/example.com.json"),
+            ]
+        );
+
+        for path in &paths {
+            assert!(persist.remove_cert_path(path).unwrap());
+        }
+        assert!(persist.cert_paths("example.com").unwrap().is_empty());
+        assert_eq!(persist.live_version("example.com").unwrap(), None);
+        assert!(persist.live_version("example.com-1").unwrap().is_some());
+        assert_eq!(
+            fs::read_to_string(persist.live_path("example.com-1").join("fullchain")).unwrap(),
+            pem
+        );
+    }
+
+    #[test]
+    fn test_privkey_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        assert!(persist.privkey_since("example.com").unwrap().is_none());
+
+        for (version, key) in &[
+            ("20200101-example.com", "first"),
+            ("20200301-example.com", "reused"),
+            ("20200501-example.com", "reused"),
+            ("20200701-example.com", "reused"),
+        ] {
+            let path = dir.path().join("certs").join(version);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("privkey"), key).unwrap();
+        }
+        fs::create_dir(dir.path().join("live")).unwrap();
+        let live = persist.live_path("example.com");
+        symlink(dir.path().join("certs/20200501-example.com"), &live).unwrap();
+
+        assert_eq!(persist.load_live_privkey("example.com").unwrap(), "reused");
+        let since = persist.privkey_since("example.com").unwrap().unwrap();
+        assert_eq!(time::strftime("%Y-%m-%d", &since).unwrap(), "2020-03-01");
+
+        // a version with a new key
+        fs::remove_file(&live).unwrap();
+        symlink(dir.path().join("certs/20200101-example.com"), &live).unwrap();
+        let since = persist.privkey_since("example.com").unwrap().unwrap();
+        assert_eq!(time::strftime("%Y-%m-%d", &since).unwrap(), "2020-01-01");
+    }
+
+    fn cert_pem(expired: bool) -> String {
+        cert_with_key(expired).1
+    }
+
+    /// A self-signed certificate and its private key.
+    fn cert_with_key(expired: bool) -> (String, String) {
+        use openssl::asn1::Asn1Time;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "example.com").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let (not_before, not_after) = if expired {
+            (
+                Asn1Time::from_unix(1_500_000_000),
+                Asn1Time::from_unix(1_600_000_000),
+            )
+        } else {
+            (Asn1Time::days_from_now(0), Asn1Time::days_from_now(90))
+        };
+        builder.set_not_before(&not_before.unwrap()).unwrap();
+        builder.set_not_after(&not_after.unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            String::from_utf8(builder.build().to_pem().unwrap()).unwrap(),
+        )
+    }
+
+    fn certificate(fullchain: &str) -> Certificate {
+        Certificate {
+            private_key: "privkey\n".to_string(),
+            fullchain: fullchain.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_version_key() {
+        assert_eq!(
+            version_key("20200101-example.com", "example.com"),
+            Some(("20200101".to_string(), 0))
+        );
+        assert_eq!(
+            version_key("20200101-example.com-12", "example.com"),
+            Some(("20200101".to_string(), 12))
+        );
+        assert_eq!(version_key("20200101-example.com", "example"), None);
+        assert_eq!(version_key("20200101-www.example.com", "example.com"), None);
+        assert_eq!(version_key("2020-example.com", "example.com"), None);
+    }
+
+    #[test]
+    fn test_store_cert_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        let first = cert_pem(false);
+        let second = cert_pem(false);
+        persist
+            .store_cert("example.com", &certificate(&first))
+            .unwrap();
+        persist
+            .store_cert("example.com", &certificate(&second))
+            .unwrap();
+        // an invalid certificate doesn't replace the live one
+        assert!(persist.store_cert("example.com", &certificate("")).is_err());
+
+        let versions = persist.list_versions("example.com").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(
+            persist.live_version("example.com").unwrap().as_ref(),
+            Some(&versions[1])
+        );
+        let live = persist.live_path("example.com");
+        assert_eq!(fs::read_to_string(live.join("fullchain")).unwrap(), second);
+        assert_eq!(persist.list_live_certs().unwrap().len(), 1);
+
+        assert_eq!(persist.rollback("example.com").unwrap(), versions[0]);
+        assert_eq!(fs::read_to_string(live.join("fullchain")).unwrap(), first);
+        assert!(persist.rollback("example.com").is_err());
+        assert!(persist.rollback("example.org").is_err());
+    }
+
+    #[test]
+    fn test_store_cert_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = CertConfig {
+            export: vec![
+                ExportFormat::Pkcs12,
+                ExportFormat::HaproxyPem,
+                ExportFormat::Der,
+            ],
+            pkcs12_password_file: Some(dir.path().join("p12.pass")),
+            ..Default::default()
+        };
+        assert!(Exports::for_cert(&cert).is_err());
+        fs::write(dir.path().join("p12.pass"), "hunter2\n").unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf())
+            .with_exports(Exports::for_cert(&cert).unwrap());
+
+        let (private_key, leaf) = cert_with_key(false);
+        let fullchain = format!("{}{}", leaf, cert_pem(false));
+        persist
+            .store_cert(
+                "example.com",
+                &Certificate {
+                    private_key: private_key.clone(),
+                    fullchain: fullchain.clone(),
+                },
+            )
+            .unwrap();
+
+        let live = persist.live_path("example.com");
+        assert_eq!(
+            fs::read_to_string(live.join("haproxy.pem")).unwrap(),
+            format!("{}{}", private_key, fullchain)
+        );
+        let der = fs::read(live.join("cert.der")).unwrap();
+        assert_eq!(
+            X509::from_der(&der).unwrap().to_pem().unwrap(),
+            leaf.as_bytes()
+        );
+
+        let p12 = Pkcs12::from_der(&fs::read(live.join("bundle.p12")).unwrap()).unwrap();
+        assert!(p12.parse2("wrong").is_err());
+        let p12 = p12.parse2("hunter2").unwrap();
+        assert_eq!(p12.cert.unwrap().to_pem().unwrap(), leaf.as_bytes());
+        assert_eq!(p12.ca.unwrap().len(), 1);
+
+        #[cfg(unix)]
+        for (file, mode) in &[
+            ("bundle.p12", 0o640),
+            ("haproxy.pem", 0o640),
+            ("cert.der", 0o644),
+        ] {
+            use std::os::unix::fs::PermissionsExt;
+            let meta = fs::metadata(live.join(file)).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, *mode, "{}", file);
+        }
+
+        // the exported files don't prevent pruning
+        let version = persist.live_version("example.com").unwrap().unwrap();
+        assert!(persist
+            .delete_version(&dir.path().join("certs").join(version))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_prune_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        assert!(persist
+            .prunable_versions("example.com", 1)
+            .unwrap()
+            .is_empty());
+
+        let certs = dir.path().join("certs");
+        for version in &[
+            "20200101-example.com",
+            "20200201-example.com",
+            "20200301-example.com",
+            "20200401-example.com",
+            "20200501-example.com",
+            "20200101-example.org",
+        ] {
+            let path = certs.join(version);
+            fs::create_dir_all(&path).unwrap();
+            for file in CERT_FILES {
+                fs::write(path.join(file), "").unwrap();
+            }
+        }
+        persist
+            .set_live("example.com", &certs.join("20200401-example.com"))
+            .unwrap();
+
+        let prunable = persist.prunable_versions("example.com", 2).unwrap();
+        assert_eq!(
+            prunable,
+            vec![
+                certs.join("20200101-example.com"),
+                certs.join("20200201-example.com"),
+            ]
+        );
+        assert_eq!(
+            persist.prunable_versions("example.com", 1).unwrap().len(),
+            3
+        );
+        assert!(persist
+            .prunable_versions("example.com", 4)
+            .unwrap()
+            .is_empty());
+
+        fs::write(certs.join("20200101-example.com/notes.txt"), "").unwrap();
+        assert!(!persist.delete_version(&prunable[0]).unwrap());
+        assert!(persist.delete_version(&prunable[1]).unwrap());
+        assert!(prunable[0].exists());
+        assert!(!prunable[1].exists());
+    }
+
+    #[test]
+    fn test_rollback_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        let old = dir.path().join("certs/20200428-example.com");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("fullchain"), cert_pem(true)).unwrap();
+        persist
+            .store_cert("example.com", &certificate(&cert_pem(false)))
+            .unwrap();
+
+        let err = persist.rollback("example.com").unwrap_err();
+        assert!(err.to_string().contains("expired"));
+        assert_ne!(
+            persist.live_version("example.com").unwrap().unwrap(),
+            "20200428-example.com"
+        );
+    }
+
+    #[test]
+    fn test_replace_acc_privkey() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::at(dir.path().to_path_buf());
+        persist.store_acc_privkey("old").unwrap();
+
+        persist.stage_acc_privkey("rejected").unwrap();
+        persist.discard_staged_acc_privkey().unwrap();
+        assert_eq!(persist.load_acc_privkey().unwrap().unwrap(), "old");
+
+        persist.stage_acc_privkey("new").unwrap();
+        let backup = persist.commit_acc_privkey().unwrap();
+        assert_eq!(persist.load_acc_privkey().unwrap().unwrap(), "new");
+        assert_eq!(fs::read_to_string(backup).unwrap(), "old");
+        assert!(!persist.staged_acc_privkey_path().exists());
+    }
+
+    #[test]
+    fn test_per_cert_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: crate::config::AcmeConfig {
+                acme_url: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+                ..Default::default()
+            },
+            system: crate::config::SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let global = CertConfig {
+            name: "example.com".to_string(),
+            ..Default::default()
+        };
+        let internal = CertConfig {
+            name: "internal.example.com".to_string(),
+            acme_url: Some("https://ca.internal/acme/directory".to_string()),
+            ..Default::default()
+        };
+
+        let persist = FilePersist::for_cert(&config, &internal);
+        persist.store_acc_privkey("internal").unwrap();
+        let account = dir
+            .path()
+            .join("accounts")
+            .join(account_folder("https://ca.internal/acme/directory"));
+        assert_eq!(
+            fs::read_to_string(account.join("url")).unwrap(),
+            "https://ca.internal/acme/directory\n"
+        );
+        assert_eq!(
+            fs::read_to_string(account.join("acc.key")).unwrap(),
+            "internal"
+        );
+        // certificates are still stored next to the others
+        assert_eq!(
+            persist.live_path("internal.example.com"),
+            dir.path().join("live/internal.example.com")
+        );
+
+        let persist = FilePersist::for_cert(&config, &global);
+        assert_eq!(persist.load_acc_privkey().unwrap(), None);
+        persist.store_acc_privkey("global").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("acc.key")).unwrap(),
+            "global"
+        );
+
+        // the same url as the global one shares the global account
+        let same = CertConfig {
+            acme_url: Some(config.acme.acme_url.clone()),
+            ..global
+        };
+        let persist = FilePersist::for_cert(&config, &same);
+        assert_eq!(persist.load_acc_privkey().unwrap().unwrap(), "global");
+    }
 }
@@ -0,0 +1,201 @@
+//! Parser for the PROXY protocol header that load balancers send before the
+//! actual connection data, see
+//! https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+use crate::errors::*;
+use actix_http::error::DispatchError;
+use actix_http::Protocol;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest possible v1 header including the trailing \r\n
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| anyhow!("Header is not terminated"))?;
+    let line = str::from_utf8(line).context("Header is not valid utf8")?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("Unsupported protocol"),
+    }
+
+    let (src, _dst, port, _dport) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(src), Some(dst), Some(sport), Some(dport)) if parts.next().is_none() => {
+            (src, dst, sport, dport)
+        }
+        _ => bail!("Unexpected number of fields"),
+    };
+    let ip = src.parse::<IpAddr>().context("Invalid source address")?;
+    let port = port.parse::<u16>().context("Invalid source port")?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, addr: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        bail!("Unsupported version");
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL, eg. health checks of the load balancer itself
+        0 => return Ok(None),
+        1 => (),
+        _ => bail!("Unsupported command"),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if addr.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addr[..4]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if addr.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        1 | 2 => bail!("Address block is too short"),
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+/// Read the PROXY protocol v1 or v2 header from the start of a connection,
+/// without consuming any data after it. Returns the address of the client if
+/// the header contains one.
+pub async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> Result<Option<SocketAddr>> {
+    let mut buf = vec![0; V2_SIGNATURE.len()];
+    io.read_exact(&mut buf).await?;
+
+    if buf.starts_with(V1_PREFIX) {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                bail!("Header is too long");
+            }
+            buf.push(io.read_u8().await?);
+        }
+        parse_v1(&buf)
+    } else if buf == V2_SIGNATURE {
+        let ver_cmd = io.read_u8().await?;
+        let family = io.read_u8().await?;
+        let len = io.read_u16().await?;
+        let mut addr = vec![0; len as usize];
+        io.read_exact(&mut addr).await?;
+        parse_v2(ver_cmd, family, &addr)
+    } else {
+        bail!("Connection didn't start with a PROXY protocol header")
+    }
+}
+
+/// Read the header of a new connection before it's handed to the http
+/// service. The client address from the header replaces the peer address of
+/// the socket, connections with an invalid header are dropped.
+pub async fn accept<T: AsyncRead + Unpin>(
+    mut io: T,
+    peer: Option<SocketAddr>,
+) -> std::result::Result<(T, Protocol, Option<SocketAddr>), DispatchError> {
+    let err = match actix_rt::time::timeout(HEADER_TIMEOUT, read_header(&mut io)).await {
+        Ok(Ok(addr)) => return Ok((io, Protocol::Http1, addr.or(peer))),
+        Ok(Err(err)) => err,
+        Err(_) => anyhow!("Timeout while waiting for header"),
+    };
+    warn!(
+        "Dropping connection from {:?}, invalid PROXY protocol header: {:#}",
+        peer, err
+    );
+    Err(DispatchError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        err.to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut io = data;
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let addr = rt.block_on(read_header(&mut io));
+        (addr, io.to_vec())
+    }
+
+    #[test]
+    fn test_v1_tcp4() {
+        let (addr, rest) =
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 80\r\nGET / HTTP/1.1\r\n");
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_v1_tcp6() {
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 80\r\n");
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_v1_unknown() {
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nGET");
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[test]
+    fn test_v1_invalid() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324 80\r\n").0.is_err());
+        assert!(read(b"PROXY TCP4 example.com 198.51.100.1 56324 80\r\n")
+            .0
+            .is_err());
+        assert!(
+            read(&[b"PROXY TCP4 "[..].to_vec(), vec![b'1'; 200]].concat())
+                .0
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_v2_tcp4() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend(&[0x21, 0x11, 0x00, 0x0c]);
+        data.extend(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x00, 0x50]);
+        data.extend(b"GET");
+        let (addr, rest) = read(&data);
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[test]
+    fn test_v2_local() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend(&[0x20, 0x00, 0x00, 0x00]);
+        data.extend(b"GET");
+        let (addr, rest) = read(&data);
+        assert_eq!(addr.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[test]
+    fn test_garbage() {
+        assert!(read(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .0
+            .is_err());
+        assert!(read(b"GET").0.is_err());
+    }
+}
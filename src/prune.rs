@@ -0,0 +1,30 @@
+use crate::args::PruneArgs;
+use crate::config::{CertConfig, Config};
+use crate::errors::*;
+use crate::persist::FilePersist;
+
+/// Delete old versions of a certificate, keeping `keep_versions` including
+/// the live one.
+pub fn prune_cert(config: &Config, cert: &CertConfig, dry_run: bool) -> Result<()> {
+    let persist = FilePersist::for_cert(config, cert);
+    let versions = persist
+        .prunable_versions(&cert.name, config.system.keep_versions)
+        .with_context(|| anyhow!("Failed to list versions of {:?}", cert.name))?;
+
+    for path in versions {
+        if dry_run {
+            info!("would delete old version {:?} (dry run)", path);
+        } else if persist.delete_version(&path)? {
+            info!("deleted old version {:?}", path);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(config: Config, args: PruneArgs) -> Result<()> {
+    for cert in config.filter_certs(&args.certs) {
+        prune_cert(&config, cert, args.dry_run)?;
+    }
+    Ok(())
+}
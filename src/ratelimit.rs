@@ -0,0 +1,234 @@
+//! Per client rate limiting for the daemon. Every client ip gets a token
+//! bucket that refills at the configured rate, only the most recently seen
+//! clients are tracked so the memory use stays bounded.
+use crate::args::DaemonArgs;
+use crate::errors::*;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// Requests per second
+    pub rate: u32,
+    /// Requests that can be made at once
+    pub burst: u32,
+}
+
+impl Limit {
+    pub fn new(rate: u32, burst: Option<u32>) -> Result<Limit> {
+        if rate == 0 {
+            bail!("Rate limit must be at least 1 request per second");
+        }
+        let burst = burst.unwrap_or(rate);
+        if burst == 0 {
+            bail!("Rate limit burst must be at least 1");
+        }
+        Ok(Limit { rate, burst })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// The clients ordered by their last request, oldest first
+    lru: BTreeMap<u64, IpAddr>,
+    seq: u64,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: Limit,
+    max_clients: usize,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit, max_clients: usize) -> RateLimiter {
+        RateLimiter {
+            limit,
+            max_clients: max_clients.max(1),
+            clients: Mutex::default(),
+        }
+    }
+
+    /// Take a token from the bucket of the client, returns false if the
+    /// request exceeds the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let clients = &mut *clients;
+        clients.seq += 1;
+        let seq = clients.seq;
+
+        let burst = f64::from(self.limit.burst);
+        let mut bucket = if let Some(mut bucket) = clients.buckets.remove(&ip) {
+            clients.lru.remove(&bucket.seq);
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * f64::from(self.limit.rate)).min(burst);
+            bucket.updated = now;
+            bucket
+        } else {
+            if clients.buckets.len() >= self.max_clients {
+                // forgetting a client gives it a full bucket on its next request
+                if let Some((_, oldest)) = clients.lru.pop_first() {
+                    clients.buckets.remove(&oldest);
+                }
+            }
+            Bucket {
+                tokens: burst,
+                updated: now,
+                seq,
+            }
+        };
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        bucket.seq = seq;
+        clients.lru.insert(seq, ip);
+        clients.buckets.insert(ip, bucket);
+        allowed
+    }
+
+    #[cfg(test)]
+    fn clients(&self) -> usize {
+        self.clients.lock().unwrap().buckets.len()
+    }
+}
+
+/// The limits for regular requests and for acme challenges, each has its
+/// own buckets so redirects can't use up the budget of the validation.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    pub requests: Option<RateLimiter>,
+    pub challenges: Option<RateLimiter>,
+}
+
+impl RateLimits {
+    pub fn from_args(args: &DaemonArgs) -> Result<RateLimits> {
+        let requests = args
+            .rate_limit
+            .map(|rate| Limit::new(rate, args.rate_limit_burst))
+            .transpose()
+            .context("Invalid --rate-limit")?
+            .map(|limit| RateLimiter::new(limit, args.rate_limit_clients));
+        if requests.is_none() && args.rate_limit_burst.is_some() {
+            bail!("--rate-limit-burst requires --rate-limit");
+        }
+        let challenges = args
+            .challenge_rate_limit
+            .map(|rate| Limit::new(rate, None))
+            .transpose()
+            .context("Invalid --challenge-rate-limit")?
+            .map(|limit| RateLimiter::new(limit, args.rate_limit_clients));
+        Ok(RateLimits {
+            requests,
+            challenges,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.requests.is_some() || self.challenges.is_some()
+    }
+
+    /// Check a request of this client, requests without a client address
+    /// (like unix domain sockets without PROXY protocol) aren't limited.
+    pub fn check(&self, ip: Option<IpAddr>, challenge: bool) -> bool {
+        let limiter = if challenge {
+            &self.challenges
+        } else {
+            &self.requests
+        };
+        match (limiter, ip) {
+            (Some(limiter), Some(ip)) => limiter.check(ip),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_burst_and_refill() {
+        let limiter = RateLimiter::new(Limit::new(2, Some(3)).unwrap(), 100);
+        let start = Instant::now();
+        let client = ip("192.0.2.1");
+        for _ in 0..3 {
+            assert!(limiter.check_at(client, start));
+        }
+        assert!(!limiter.check_at(client, start));
+        // other clients have their own bucket
+        assert!(limiter.check_at(ip("192.0.2.2"), start));
+
+        // two requests per second
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(client, later));
+        assert!(!limiter.check_at(client, later));
+
+        // the bucket doesn't fill beyond the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(client, much_later));
+        }
+        assert!(!limiter.check_at(client, much_later));
+    }
+
+    #[test]
+    fn test_bounded_clients() {
+        let limiter = RateLimiter::new(Limit::new(1, None).unwrap(), 2);
+        let now = Instant::now();
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(limiter.check_at(ip("192.0.2.2"), now));
+        // .1 is the least recently used client now
+        assert!(!limiter.check_at(ip("192.0.2.2"), now));
+        assert!(limiter.check_at(ip("2001:db8::1"), now));
+        assert_eq!(limiter.clients(), 2);
+
+        // .1 was forgotten and starts with a full bucket again
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("2001:db8::1"), now));
+        assert_eq!(limiter.clients(), 2);
+    }
+
+    #[test]
+    fn test_invalid_limit() {
+        assert!(Limit::new(0, None).is_err());
+        assert!(Limit::new(1, Some(0)).is_err());
+        assert_eq!(Limit::new(5, None).unwrap(), Limit { rate: 5, burst: 5 });
+    }
+
+    #[test]
+    fn test_separate_limits() {
+        let limits = RateLimits {
+            requests: Some(RateLimiter::new(Limit::new(1, None).unwrap(), 10)),
+            challenges: None,
+        };
+        let client = Some(ip("192.0.2.1"));
+        assert!(limits.check(client, false));
+        assert!(!limits.check(client, false));
+        for _ in 0..10 {
+            assert!(limits.check(client, true));
+        }
+        assert!(limits.check(None, false));
+    }
+}
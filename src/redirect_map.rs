@@ -0,0 +1,237 @@
+//! A map of hosts to redirect targets that's loaded from a separate toml file
+//! with `--redirect-map`, for hosts that don't need a certificate, eg. an old
+//! domain that's redirected to a new one:
+//!
+//! ```toml
+//! [redirects]
+//! "old-brand.com" = "https://new-brand.com"
+//! "*.old-brand.com" = "https://new-brand.com/legacy"
+//! "*" = "https://example.com"
+//! ```
+//!
+//! Exact hosts take precedence over wildcards, of the wildcards the longest
+//! matching suffix is used and `*` is used if nothing else matched.
+use crate::config;
+use crate::errors::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedirectMapFile {
+    #[serde(default)]
+    redirects: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RedirectMap {
+    exact: HashMap<String, String>,
+    /// The suffixes of `*.` entries with the leading dot, longest first
+    wildcards: Vec<(String, String)>,
+    default: Option<String>,
+}
+
+/// The target has to be an absolute http or https url, the path of the
+/// request is appended so it can't have a query string or fragment.
+fn validate_target(target: &str) -> Result<String> {
+    let rest = target
+        .strip_prefix("https://")
+        .or_else(|| target.strip_prefix("http://"))
+        .ok_or_else(|| anyhow!("target must be an absolute http or https url"))?;
+    let host = rest.split('/').next().unwrap_or_default();
+    if host.is_empty() {
+        bail!("target has no host");
+    }
+    if let Some(c) = target
+        .chars()
+        .find(|c| !c.is_ascii_graphic() || matches!(c, '?' | '#' | '\\'))
+    {
+        bail!("target can't contain {:?}", c);
+    }
+    if host.contains('@') {
+        bail!("target can't contain credentials");
+    }
+    Ok(target.trim_end_matches('/').to_string())
+}
+
+impl RedirectMap {
+    pub fn load(path: &Path) -> Result<RedirectMap> {
+        let buf = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read redirect map {:?}", path))?;
+        Self::parse(&buf).with_context(|| anyhow!("Invalid redirect map {:?}", path))
+    }
+
+    pub fn parse(buf: &str) -> Result<RedirectMap> {
+        let file = toml::from_str::<RedirectMapFile>(buf)?;
+        let mut map = RedirectMap::default();
+        for (source, target) in file.redirects {
+            let target = validate_target(&target)
+                .with_context(|| anyhow!("Invalid target for {:?}: {:?}", source, target))?;
+            if source == "*" {
+                map.default = Some(target);
+                continue;
+            }
+            let name = config::to_ascii_name(&source)?;
+            match name.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => {
+                    map.wildcards.push((suffix.to_string(), target))
+                }
+                Some(_) => bail!(
+                    "Invalid source {:?}, wildcards need to be in front of a dot",
+                    source
+                ),
+                None if name.contains('*') => bail!(
+                    "Invalid source {:?}, wildcards need to be in front of a dot",
+                    source
+                ),
+                None => {
+                    map.exact.insert(name, target);
+                }
+            }
+        }
+        map.wildcards
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        Ok(map)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty() && self.default.is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len() + self.default.iter().count()
+    }
+
+    /// The target for a normalized host, without a port.
+    pub fn target(&self, host: &str) -> Option<&str> {
+        if let Some(target) = self.exact.get(host) {
+            return Some(target);
+        }
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()) && host.len() > suffix.len())
+            .map(|(_, target)| target.as_str())
+            .or(self.default.as_deref())
+    }
+
+    /// The url a request is redirected to, the target with the path and
+    /// query string of the request appended.
+    pub fn redirect_url(&self, host: &str, path: &str) -> Option<String> {
+        self.target(host)
+            .map(|target| format!("{}{}", target, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> RedirectMap {
+        RedirectMap::parse(
+            r#"
+            [redirects]
+            "old-brand.com" = "https://new-brand.com/"
+            "shop.old-brand.com" = "https://shop.new-brand.com"
+            "*.old-brand.com" = "https://new-brand.com/legacy"
+            "*.eu.old-brand.com" = "https://eu.new-brand.com"
+            "*" = "http://example.com/fallback"
+        "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_exact_before_wildcard() {
+        let map = map();
+        assert_eq!(map.len(), 5);
+        assert_eq!(map.target("old-brand.com"), Some("https://new-brand.com"));
+        assert_eq!(
+            map.target("shop.old-brand.com"),
+            Some("https://shop.new-brand.com")
+        );
+        assert_eq!(
+            map.target("www.old-brand.com"),
+            Some("https://new-brand.com/legacy")
+        );
+    }
+
+    #[test]
+    fn test_longest_wildcard() {
+        let map = map();
+        assert_eq!(
+            map.target("shop.eu.old-brand.com"),
+            Some("https://eu.new-brand.com")
+        );
+        assert_eq!(
+            map.target("a.b.old-brand.com"),
+            Some("https://new-brand.com/legacy")
+        );
+        // the wildcard doesn't match the name itself
+        assert_eq!(
+            map.target("eu.old-brand.com"),
+            Some("https://new-brand.com/legacy")
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let map = map();
+        assert_eq!(
+            map.target("example.org"),
+            Some("http://example.com/fallback")
+        );
+        assert_eq!(
+            map.target("notold-brand.com"),
+            Some("http://example.com/fallback")
+        );
+
+        let map =
+            RedirectMap::parse("[redirects]\n\"old-brand.com\" = \"https://new-brand.com\"\n")
+                .unwrap();
+        assert_eq!(map.target("example.org"), None);
+        assert!(RedirectMap::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redirect_url() {
+        let map = map();
+        assert_eq!(
+            map.redirect_url("www.old-brand.com", "/foo?bar=1").unwrap(),
+            "https://new-brand.com/legacy/foo?bar=1"
+        );
+        assert_eq!(
+            map.redirect_url("old-brand.com", "/").unwrap(),
+            "https://new-brand.com/"
+        );
+    }
+
+    #[test]
+    fn test_normalized_sources() {
+        let map =
+            RedirectMap::parse("[redirects]\n\"Bücher.Example\" = \"https://books.example\"\n")
+                .unwrap();
+        assert_eq!(
+            map.target("xn--bcher-kva.example"),
+            Some("https://books.example")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for buf in &[
+            "[redirects]\n\"old-brand.com\" = \"new-brand.com\"\n",
+            "[redirects]\n\"old-brand.com\" = \"ftp://new-brand.com\"\n",
+            "[redirects]\n\"old-brand.com\" = \"https://\"\n",
+            "[redirects]\n\"old-brand.com\" = \"https://new-brand.com/?a=1\"\n",
+            "[redirects]\n\"old-brand.com\" = \"https://user@new-brand.com\"\n",
+            "[redirects]\n\"old-brand.com\" = \"https://new brand.com\"\n",
+            "[redirects]\n\"shop*.old-brand.com\" = \"https://new-brand.com\"\n",
+            "[redirects]\n\"*old-brand.com\" = \"https://new-brand.com\"\n",
+            "[redirect]\n\"old-brand.com\" = \"https://new-brand.com\"\n",
+        ] {
+            assert!(RedirectMap::parse(buf).is_err(), "{}", buf);
+        }
+    }
+}
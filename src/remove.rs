@@ -0,0 +1,179 @@
+//! Delete certificates that aren't needed anymore from the data directory,
+//! deleting their config file alone leaves the keys behind.
+use crate::args::RemoveArgs;
+use crate::config::Config;
+use crate::errors::*;
+use crate::persist::FilePersist;
+use crate::revoke;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// The names of live certificates in the data directory that aren't
+/// configured, and if they are staging certificates. Staging certificates
+/// of a name that's configured for production are orphaned too.
+pub fn orphaned_certs(config: &Config) -> Result<Vec<(String, bool)>> {
+    let mut orphaned = Vec::new();
+    for staging in &[false, true] {
+        let persist = FilePersist::with_staging(config, *staging);
+        let mut names = persist
+            .list_live_certs()?
+            .into_values()
+            .filter(|name| {
+                !config
+                    .certs
+                    .iter()
+                    .any(|c| &c.name == name && c.staging(&config.acme) == *staging)
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        orphaned.extend(names.into_iter().map(|name| (name, *staging)));
+    }
+    Ok(orphaned)
+}
+
+/// Where a certificate may be stored and the acme server it's from. A
+/// configured certificate is only looked up where it's issued to, others
+/// in both the production and the staging folder.
+fn locations(config: &Config, name: &str) -> Vec<(FilePersist, String)> {
+    if let Some(cert) = config.certs.iter().find(|c| c.name == name) {
+        vec![(
+            FilePersist::for_cert(config, cert),
+            cert.acme_url(&config.acme).to_string(),
+        )]
+    } else {
+        [false, true]
+            .iter()
+            .map(|staging| {
+                (
+                    FilePersist::with_staging(config, *staging),
+                    config.acme.url(*staging).to_string(),
+                )
+            })
+            .collect()
+    }
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+pub fn run(config: Config, args: RemoveArgs) -> Result<()> {
+    if let Some(cert) = config.certs.iter().find(|c| c.name == args.name) {
+        warn!(
+            "{:?} is still configured in {:?}, it's going to be issued again on the next renew",
+            cert.name, cert.source
+        );
+    }
+
+    let mut found = Vec::new();
+    for (persist, acme_url) in locations(&config, &args.name) {
+        let paths = persist.cert_paths(&args.name)?;
+        if !paths.is_empty() {
+            found.push((persist, acme_url, paths));
+        }
+    }
+    if found.is_empty() {
+        bail!("Found nothing to delete for {:?}", args.name);
+    }
+
+    for path in found.iter().flat_map(|(_, _, paths)| paths) {
+        println!("{}", path.display());
+    }
+    if !args.yes {
+        let prompt = if args.revoke {
+            "Revoke the certificate and delete these files?"
+        } else {
+            "Delete these files?"
+        };
+        if !confirm(prompt)? {
+            bail!("Aborted");
+        }
+    }
+
+    if args.revoke {
+        for (persist, acme_url, _) in &found {
+            let live = persist.live_path(&args.name);
+            if live.exists() {
+                revoke::revoke_file(
+                    &config,
+                    persist,
+                    acme_url,
+                    &live.join("fullchain"),
+                    args.reason,
+                )?;
+            }
+        }
+    }
+
+    let mut skipped = Vec::<PathBuf>::new();
+    for (persist, _, paths) in &found {
+        for path in paths {
+            if persist.remove_cert_path(path)? {
+                info!("deleted {:?}", path);
+            } else {
+                skipped.push(path.clone());
+            }
+        }
+    }
+    if !skipped.is_empty() {
+        bail!("Some folders weren't deleted: {:?}", skipped);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CertConfig, SystemConfig};
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+    #[cfg(windows)]
+    use std::os::windows::fs::symlink_dir as symlink;
+
+    #[test]
+    fn test_orphaned_certs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            certs: vec![CertConfig {
+                name: "example.com".to_string(),
+                dns_names: vec!["example.com".to_string()],
+                ..Default::default()
+            }],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        assert!(orphaned_certs(&config).unwrap().is_empty());
+
+        for (folder, name) in &[
+            ("", "example.com"),
+            ("", "old.example.com"),
+            ("", "a.example.com"),
+            ("staging/", "example.com"),
+        ] {
+            let live = dir.path().join(folder).join("live");
+            let version = dir.path().join(folder).join("certs").join(name);
+            fs::create_dir_all(&live).unwrap();
+            fs::create_dir_all(&version).unwrap();
+            symlink(&version, live.join(name)).unwrap();
+        }
+        assert_eq!(
+            orphaned_certs(&config).unwrap(),
+            vec![
+                ("a.example.com".to_string(), false),
+                ("old.example.com".to_string(), false),
+                ("example.com".to_string(), true),
+            ]
+        );
+    }
+}
@@ -1,13 +1,124 @@
 use crate::acme;
 use crate::args::RenewArgs;
-use crate::chall::Challenge;
-use crate::config::CertConfig;
+use crate::ari::{self, Window};
+use crate::caa;
+use crate::cert::{self, CertInfo, DnsNameChanges};
+use crate::chall::{self, Challenge};
+use crate::check;
+use crate::clock;
 use crate::config::Config;
+use crate::config::{CertConfig, KeyType};
+use crate::deployment;
+use crate::dns::DnsChallenge;
 use crate::errors::*;
-use crate::persist::FilePersist;
-use std::collections::HashSet;
+use crate::hook_queue;
+use crate::hooks::{self, Exec, HookOptions};
+use crate::notify::{self, Event};
+use crate::permissions;
+use crate::persist::{Exports, FilePersist};
+use crate::prune;
+use crate::report::{self, Action, CertReport, HookReport, Report};
+use crate::state;
+use openssl::pkey::{PKey, Private};
+use std::ffi::OsString;
 use std::fs;
-use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_RETRIES: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Below this the renew timer needs to run more often than once a day.
+const SHORT_RENEW_WINDOW: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(1 << attempt.min(16))
+        .map(|d| d.min(MAX_BACKOFF))
+        .unwrap_or(MAX_BACKOFF)
+}
+
+/// Enforces a minimum delay between orders, shared by all renew threads.
+struct Throttle {
+    delay: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    fn new(delay: Duration) -> Throttle {
+        Throttle {
+            delay,
+            last: Mutex::new(None),
+        }
+    }
+
+    fn wait(&self) {
+        let mut last = self.last.lock().unwrap();
+        if let Some(last) = *last {
+            let elapsed = last.elapsed();
+            if elapsed < self.delay {
+                let remaining = self.delay - elapsed;
+                debug!("Waiting {:?} before creating the next order", remaining);
+                thread::sleep(remaining);
+            }
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+/// Check if an existing certificate needs to be replaced, returns the reason
+/// if it does. If `check_sans` is set a cert that doesn't match the
+/// configured dns_names is replaced and with `check_staple` a cert that is
+/// missing the must-staple extension. The renewal window suggested by the CA
+/// takes precedence over the configured [`RenewThreshold`](crate::config::RenewThreshold).
+pub fn renewal_reason(
+    config: &Config,
+    cert: &CertConfig,
+    existing: &CertInfo,
+    window: Option<&Window>,
+    check_sans: bool,
+    check_staple: bool,
+) -> Option<String> {
+    let key_type = cert.key_type.unwrap_or(config.acme.key_type);
+    let changes = DnsNameChanges::new(cert, existing);
+    if existing.key_type != Some(key_type) {
+        Some(format!("key type changed to {}, renewing cert", key_type))
+    } else if check_sans && !changes.is_empty() {
+        Some(format!("dns_names changed ({}), renewing cert", changes))
+    } else if check_staple && cert.must_staple && !existing.must_staple {
+        Some("existing cert is missing the must-staple extension".to_string())
+    } else if let Some(window) = window {
+        if window.has_started(time::get_time().sec) {
+            Some(format!(
+                "renewal window suggested by the CA started at {}",
+                window.start
+            ))
+        } else {
+            None
+        }
+    } else if cert.renew_threshold(&config.acme).is_due(existing) {
+        Some("existing cert is below threshold".to_string())
+    } else {
+        None
+    }
+}
+
+/// Certificates that are only valid for a few days are renewed with little
+/// time left, a daily timer might only notice after they expired.
+fn warn_short_renew_window(config: &Config, cert: &CertConfig, existing: &CertInfo) {
+    let threshold = cert.renew_threshold(&config.acme);
+    let remaining = threshold.remaining(existing.lifetime());
+    if remaining < SHORT_RENEW_WINDOW {
+        info!(
+            "{:?}: renewing at {} leaves only {}h before expiry, make sure renew runs at least every {}h",
+            cert.name,
+            threshold,
+            remaining.as_secs() / 3600,
+            (remaining.as_secs() / 3600 / 2).max(1),
+        );
+    }
+}
 
 fn should_request_cert(
     args: &RenewArgs,
@@ -19,12 +130,30 @@ fn should_request_cert(
         info!("{:?}: force renewing", cert.name);
         Ok(true)
     } else if let Some(existing) = persist.load_cert_info(&cert.name)? {
-        let days_left = existing.days_left();
-        if days_left <= config.acme.renew_if_days_left {
-            info!("{:?}: existing cert is below threshold", cert.name);
+        let window = if args.skip_ari {
+            None
+        } else {
+            ari::suggested_window(config, cert, persist)
+        };
+        if let Some(reason) = renewal_reason(
+            config,
+            cert,
+            &existing,
+            window.as_ref(),
+            !args.no_san_check,
+            args.check_staple,
+        ) {
+            info!("{:?}: {}", cert.name, reason);
             Ok(true)
+        } else if let Some(window) = window {
+            info!(
+                "{:?}: cert already satisfied, the CA suggests renewing from {}",
+                cert.name, window.start
+            );
+            Ok(false)
         } else {
             info!("{:?}: cert already satisfied", cert.name);
+            warn_short_renew_window(config, cert, &existing);
             Ok(false)
         }
     } else {
@@ -33,72 +162,447 @@ fn should_request_cert(
     }
 }
 
-fn execute_hooks(hooks: &[String], dry_run: bool) -> Result<()> {
-    for exec in hooks {
+#[derive(Debug, PartialEq)]
+struct HookRun<'a> {
+    /// None for the global hooks
+    cert: Option<&'a str>,
+    hooks: &'a [Exec],
+    env: Vec<(&'static str, OsString)>,
+}
+
+/// Decide which hooks need to run after renewing, certificates that didn't
+/// change don't trigger their hooks unless `force` is set.
+fn plan_hooks<'a>(
+    config: &'a Config,
+    certs: &[&'a CertConfig],
+    renewed: &[&str],
+    force: bool,
+) -> Vec<HookRun<'a>> {
+    let renewed_certs = OsString::from(renewed.join(" "));
+    let mut runs = Vec::new();
+
+    let mut default_hooks = false;
+    // the global hooks are only told about staging if no production cert
+    // triggered them
+    let mut default_staging = true;
+    let mut all_staging = true;
+    for cert in certs {
+        if !force && !renewed.contains(&cert.name.as_str()) {
+            continue;
+        }
+
+        let staging = cert.staging(&config.acme);
+        all_staging &= staging;
+
+        if cert.exec.is_empty() {
+            default_hooks = true;
+            default_staging &= staging;
+            continue;
+        }
+
+        let persist = FilePersist::for_cert(config, cert);
+        let mut env = vec![
+            ("ACME_CERT_NAME", OsString::from(&cert.name)),
+            (
+                "ACME_LIVE_DIR",
+                persist.live_path(&cert.name).into_os_string(),
+            ),
+            ("ACME_RENEWED_CERTS", renewed_certs.clone()),
+        ];
+        if let Some(group) = &cert.group {
+            env.push(("ACME_CERT_GROUP", OsString::from(group)));
+        }
+        if staging {
+            env.push(("ACME_STAGING", OsString::from("1")));
+        }
+        runs.push(HookRun {
+            cert: Some(&cert.name),
+            hooks: &cert.exec,
+            env,
+        });
+    }
+
+    if default_hooks {
+        let mut env = vec![("ACME_RENEWED_CERTS", renewed_certs.clone())];
+        if default_staging {
+            env.push(("ACME_STAGING", OsString::from("1")));
+        }
+        runs.push(HookRun {
+            cert: None,
+            hooks: &config.system.exec,
+            env,
+        });
+    }
+
+    if force || !renewed.is_empty() {
+        let mut env = vec![("ACME_RENEWED_CERTS", renewed_certs)];
+        if all_staging && !certs.is_empty() {
+            env.push(("ACME_STAGING", OsString::from("1")));
+        }
+        runs.push(HookRun {
+            cert: None,
+            hooks: &config.system.exec_extra,
+            env,
+        });
+    }
+
+    runs
+}
+
+/// Execute the hooks, hooks that are skipped in a dry run aren't reported.
+fn execute_hooks(run: &HookRun, options: &HookOptions, dry_run: bool) -> Vec<HookReport> {
+    let mut reports = Vec::new();
+    for exec in run.hooks {
         if dry_run {
-            info!("executing hook: {:?} (dry run)", exec);
-        } else {
-            info!("executing hook: {:?}", exec);
+            info!("executing hook: `{}` (dry run)", exec);
+            continue;
+        }
+        info!("executing hook: `{}`", exec);
+        let (exit_code, result) = hooks::execute(exec, &run.env, options);
+        if let Err(err) = &result {
+            error!("Failed to execute hook `{}`: {:#}", exec, err);
+        }
+        reports.push(HookReport {
+            cert: run.cert.map(String::from),
+            command: exec.to_string(),
+            exit_code,
+            error: result.err().map(|err| format!("{:#}", err)),
+        });
+    }
+    reports
+}
+
+/// The certs that would trigger hooks but whose live fullchain and private
+/// key don't belong together, reloading a web server with them would only
+/// make it fail to start.
+fn inconsistent_certs<'a>(
+    config: &Config,
+    certs: &[&'a CertConfig],
+    renewed: &[&str],
+    force: bool,
+) -> Vec<&'a str> {
+    let mut inconsistent = Vec::new();
+    for cert in certs {
+        if !force && !renewed.contains(&cert.name.as_str()) {
+            continue;
+        }
+        let problems = match FilePersist::for_cert(config, cert).verify_live(cert) {
+            Ok(Some(problems)) => problems,
+            Ok(None) => continue,
+            Err(err) => vec![format!("{:#}", err)],
+        };
+        if !problems.is_empty() {
+            error!(
+                "{:?}: not executing hooks, the live certificate is broken: {}",
+                cert.name,
+                problems.join("; ")
+            );
+            inconsistent.push(cert.name.as_str());
+        }
+    }
+    inconsistent
+}
+
+fn execute_planned_hooks(
+    config: &Config,
+    certs: &[&CertConfig],
+    renewed: &[&str],
+    force: bool,
+    dry_run: bool,
+) -> Result<Vec<HookReport>> {
+    let options = HookOptions::from_config(&config.system)?;
+    let runs = plan_hooks(config, certs, renewed, force);
+    let mut reports = Vec::new();
+    let mut outcomes = Vec::new();
+    for run in &runs {
+        let hooks = execute_hooks(run, &options, dry_run);
+        if !dry_run {
+            outcomes.extend(run.hooks.iter().zip(&hooks).map(|(exec, report)| {
+                hook_queue::Outcome {
+                    cert: run.cert,
+                    exec,
+                    env: &run.env,
+                    error: report.error.clone(),
+                }
+            }));
+        }
+        reports.extend(hooks);
+    }
+    // failed hooks are retried by the next run
+    hook_queue::record(config, &outcomes);
+    Ok(reports)
+}
+
+fn check_hooks(config: &Config, hooks: &[HookReport]) -> Result<()> {
+    let failed = hooks.iter().filter(|h| !h.is_success()).count();
+    if failed > 0 && config.system.fail_on_hook_error {
+        bail!("{} hook(s) failed", failed);
+    }
+    Ok(())
+}
+
+/// Execute the hooks for the renewed certs, the same way they're executed
+/// after a renew. Failing hooks are only an error with `fail_on_hook_error`.
+pub fn run_hooks(
+    config: &Config,
+    certs: &[&CertConfig],
+    renewed: &[&str],
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let hooks = execute_planned_hooks(config, certs, renewed, force, dry_run)?;
+    check_hooks(config, &hooks)
+}
+
+/// What happened to a certificate passed to [`renew_cert`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenewOutcome {
+    /// A new certificate has been issued and is live now
+    Renewed,
+    /// The live certificate isn't due for renewal yet
+    NotDue,
+}
+
+/// Renew a single certificate if it's due, like `acme-redirect renew <cert>`
+/// but without running any hooks or cleaning up old certificates. Use
+/// [`run_hooks`] and [`prune::prune_cert`] for that.
+pub fn renew_cert(config: &Config, cert: &CertConfig) -> Result<RenewOutcome> {
+    renew_cert_with_args(config, cert, &RenewArgs::default())
+}
+
+/// Like [`renew_cert`], with the options of the renew subcommand. The
+/// certificate doesn't need to be part of the config.
+pub fn renew_cert_with_args(
+    config: &Config,
+    cert: &CertConfig,
+    args: &RenewArgs,
+) -> Result<RenewOutcome> {
+    let throttle = Throttle::new(Duration::from_secs(0));
+    if renew_cert_with(args, config, cert, &throttle)? {
+        Ok(RenewOutcome::Renewed)
+    } else {
+        Ok(RenewOutcome::NotDue)
+    }
+}
+
+fn load_reusable_key(persist: &FilePersist, name: &str) -> Result<Option<PKey<Private>>> {
+    if persist.live_version(name)?.is_none() {
+        return Ok(None);
+    }
+    let pem = persist.load_live_privkey(name)?;
+    let pkey = PKey::private_key_from_pem(pem.as_bytes()).context("Failed to parse private key")?;
+    Ok(Some(pkey))
+}
+
+/// The private key of the live certificate for `reuse_private_key`, a new
+/// key is created if there's no live certificate yet, its key can't be used
+/// or doesn't have the configured key type.
+fn reusable_key(persist: &FilePersist, name: &str, key_type: KeyType) -> Option<PKey<Private>> {
+    match load_reusable_key(persist, name) {
+        Ok(Some(pkey)) if cert::detect_key_type(&pkey) == Some(key_type) => {
+            info!(
+                "{:?}: reusing the private key of the live certificate",
+                name
+            );
+            Some(pkey)
+        }
+        Ok(Some(_)) => {
+            info!(
+                "{:?}: the live private key isn't {}, creating a new key",
+                name, key_type
+            );
+            None
+        }
+        Ok(None) => None,
+        Err(err) => {
+            warn!(
+                "{:?}: reuse_private_key is set, but the live private key can't be used. Creating a NEW key, pinned keys need to be updated: {:#}",
+                name, err
+            );
+            None
+        }
+    }
+}
 
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(exec)
-                .status()
-                .context("Failed to spawn shell for hook")?;
+/// Log the details of a newly issued certificate, for monitoring and
+/// pinned keys.
+fn log_details(persist: &FilePersist, name: &str) {
+    match persist.load_cert_details(name) {
+        Ok(Some(details)) => info!("{:?}: issued certificate {}", name, details),
+        Ok(None) => (),
+        Err(err) => warn!("{:?}: Failed to read the new certificate: {:#}", name, err),
+    }
+}
 
-            if !status.success() {
-                error!("Failed to execute hook: {:?}", exec);
+/// Like `request_cert`, but the outcome of an attempt is recorded in the
+/// state of the certificate.
+fn renew_cert_with(
+    args: &RenewArgs,
+    config: &Config,
+    cert: &CertConfig,
+    throttle: &Throttle,
+) -> Result<bool> {
+    let result = request_cert(args, config, cert, throttle);
+    if !args.dry_run && !args.hooks_only {
+        let persist = FilePersist::for_cert(config, cert);
+        match &result {
+            Ok(true) => {
+                state::record(&persist, &cert.name, None);
+                log_details(&persist, &cert.name);
             }
+            Ok(false) => (),
+            Err(err) => state::record(&persist, &cert.name, Some(err)),
         }
     }
+    result
+}
+
+/// Remove the http and dns challenges of an order, a failure of one of them
+/// doesn't keep the other from being removed.
+fn cleanup_challenges(challenge: &mut Challenge, dns: &mut DnsChallenge) -> Result<()> {
+    let errors = vec![
+        challenge
+            .cleanup()
+            .context("Failed to remove challenge proofs"),
+        dns.cleanup().context("Failed to remove dns challenges"),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .map(|err| format!("{:#}", err))
+    .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        bail!("{}", errors.join("; "));
+    }
     Ok(())
 }
 
-fn renew_cert(
+fn request_cert(
     args: &RenewArgs,
     config: &Config,
-    persist: &FilePersist,
     cert: &CertConfig,
-) -> Result<()> {
-    let mut challenge = Challenge::new(&config);
+    throttle: &Throttle,
+) -> Result<bool> {
+    let mut challenge = Challenge::new(config)?;
+    let mut dns = DnsChallenge::new(cert, HookOptions::from_config(&config.system)?);
+    let staging = cert.staging(&config.acme);
+    let persist = FilePersist::for_cert(config, cert);
+    // the external account is specific to the configured acme server
+    let eab = if staging || cert.has_own_account(&config.acme) {
+        None
+    } else {
+        config.acme.eab_key()?
+    };
+    let http = config.acme.http_client()?;
 
-    if !should_request_cert(&args, &config, &persist, &cert)? {
-        debug!("Not requesting a certificate for {:?}", cert.name);
-        return Ok(());
+    if !args.force_renew {
+        let now = time::get_time().sec;
+        if let Some(until) = state::rate_limited_until(&persist, &cert.name, now) {
+            warn!(
+                "{:?}: rate limited by the acme server, skipping until {}",
+                cert.name,
+                time::at_utc(time::Timespec::new(until, 0)).rfc3339()
+            );
+            return Ok(false);
+        }
+        let after = config.acme.backoff_after_failures();
+        if let Some((failures, until)) = state::backoff_until(&persist, &cert.name, now, after) {
+            warn!(
+                "skipping {:?}: {} consecutive failures, next attempt after {}",
+                cert.name,
+                failures,
+                time::at_utc(time::Timespec::new(until, 0)).rfc3339()
+            );
+            return Ok(false);
+        }
     }
 
-    if args.dry_run || args.hooks_only {
-        info!("renewing {:?} (dry run)", cert.name);
-    } else {
-        info!("renewing {:?}", cert.name);
-        acme::request(
-            persist.clone(),
-            &mut challenge,
-            &acme::Request {
-                account_email: config.acme.acme_email.as_deref(),
-                acme_url: &config.acme.acme_url,
-                primary_name: &cert.name,
-                alt_names: &cert.dns_names,
-            },
-        )
-        .with_context(|| anyhow!("Fail to get certificate {:?}", cert.name))?;
-        challenge.cleanup()?;
+    if !should_request_cert(args, config, &persist, cert)? {
+        debug!("Not requesting a certificate for {:?}", cert.name);
+        return Ok(false);
     }
 
-    if !args.skip_restarts {
-        if !cert.exec.is_empty() {
-            debug!("Executing hooks for this certificate");
-            execute_hooks(&cert.exec, args.dry_run)?;
+    if args.hooks_only {
+        info!("renewing {:?} (skipped, hooks only)", cert.name);
+    } else {
+        if args.dry_run {
+            info!("renewing {:?} (dry run)", cert.name);
+        } else if staging {
+            info!("renewing {:?} (staging)", cert.name);
         } else {
-            debug!("Executing global default hooks");
-            execute_hooks(&config.system.exec, args.dry_run)?;
+            info!("renewing {:?}", cert.name);
         }
+        if args.self_check && !check::self_check(config, cert)? {
+            bail!(
+                "Self-check failed for {:?}, not creating an order",
+                cert.name
+            );
+        }
+        if !args.skip_caa_check {
+            caa::check_cert(config, cert)?;
+        }
+        let persist = persist.with_exports(Exports::for_cert(cert)?);
+        let dns_names = cert.ascii_dns_names()?;
+        let (primary_name, alt_names) = dns_names
+            .split_first()
+            .ok_or_else(|| anyhow!("Certificate has no dns_names"))?;
+        let key_type = cert.key_type.unwrap_or(config.acme.key_type);
+        let private_key = if cert.reuse_private_key {
+            reusable_key(&persist, &cert.name, key_type)
+        } else {
+            None
+        };
+        let req = acme::Request {
+            account_email: cert.acme_email(&config.acme),
+            acme_url: cert.acme_url(&config.acme),
+            eab: eab.as_ref(),
+            preferred_chain: cert.preferred_chain.as_deref(),
+            http: &http,
+            timeouts: config.acme.timeouts(),
+            name: &cert.name,
+            primary_name,
+            alt_names,
+            challenge_type: cert.challenge_type,
+            key_type,
+            private_key: private_key.as_ref(),
+            must_staple: cert.must_staple,
+            key_usages: &cert.key_usages,
+            extended_key_usages: &cert.extended_key_usages,
+            sct_policy: cert.sct_policy(&config.acme),
+            dry_run: args.dry_run,
+        };
 
-        debug!("Executing global exec_extra hooks");
-        execute_hooks(&config.system.exec_extra, args.dry_run)?;
+        let mut attempt = 0;
+        let result = loop {
+            throttle.wait();
+            match acme::request(persist.clone(), &mut challenge, &mut dns, &req) {
+                Ok(()) => break Ok(()),
+                Err(err) if attempt < MAX_RETRIES && acme::is_transient(&err) => {
+                    let delay = backoff(attempt);
+                    warn!(
+                        "{:?}: acme server returned a temporary error, retrying in {}s: {:#}",
+                        cert.name,
+                        delay.as_secs(),
+                        err
+                    );
+                    if let Err(err) = cleanup_challenges(&mut challenge, &mut dns) {
+                        error!("{:?}: {:#}", cert.name, err);
+                    }
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        // remove the challenges even if the order failed
+        let cleanup = cleanup_challenges(&mut challenge, &mut dns);
+        if let (Err(_), Err(err)) = (&result, &cleanup) {
+            error!("{:?}: {:#}", cert.name, err);
+        }
+        result.with_context(|| anyhow!("Fail to get certificate {:?}", cert.name))?;
+        cleanup?;
     }
 
-    Ok(())
+    Ok(true)
 }
 
 fn cleanup_certs(persist: &FilePersist, dry_run: bool) -> Result<()> {
@@ -138,17 +642,610 @@ fn cleanup_certs(persist: &FilePersist, dry_run: bool) -> Result<()> {
     Ok(())
 }
 
-pub fn run(config: Config, mut args: RenewArgs) -> Result<()> {
-    let persist = FilePersist::new(&config);
+/// The result of renewing a single cert, with when it was started and how
+/// long it took.
+struct Attempt {
+    result: Result<bool>,
+    started: String,
+    duration: Duration,
+}
+
+/// Renew the given certs with up to `max_concurrent` threads, the results are
+/// returned in the same order as the certs.
+fn renew_all(args: &RenewArgs, config: &Config, certs: &[&CertConfig]) -> Vec<Attempt> {
+    let throttle = Throttle::new(Duration::from_secs(args.delay_between));
+    let queue = Mutex::new(certs.iter().enumerate());
+    let results = Mutex::new(certs.iter().map(|_| None).collect::<Vec<_>>());
 
-    let filter = args.certs.drain(..).collect::<HashSet<_>>();
-    for cert in config.filter_certs(&filter) {
-        if let Err(err) = renew_cert(&args, &config, &persist, &cert) {
-            error!("Failed to renew ({:?}): {:#}", cert.name, err);
+    thread::scope(|s| {
+        for _ in 0..args.max_concurrent.min(certs.len()) {
+            s.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (idx, cert) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let started = report::timestamp();
+                let start = Instant::now();
+                let result = renew_cert_with(args, config, cert, &throttle);
+                let attempt = Attempt {
+                    result,
+                    started,
+                    duration: start.elapsed(),
+                };
+                results.lock().unwrap()[idx] = Some(attempt);
+            });
         }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every cert has been processed"))
+        .collect()
+}
+
+pub fn run(config: Config, args: RenewArgs) -> Result<()> {
+    if args.max_concurrent == 0 {
+        bail!("--max-concurrent needs to be at least 1");
     }
 
-    cleanup_certs(&persist, args.dry_run).context("Failed to cleanup old certs")?;
+    chall::cleanup_on_signal().context("Failed to setup signal handler")?;
+    let start = Instant::now();
+    let mut report = Report::new(args.dry_run);
+    let result = renew_certs(&config, &args, &mut report);
+    if let Some(dest) = &args.report_json {
+        report.finish(start.elapsed(), &result);
+        report.write(dest)?;
+    }
+    result?;
 
+    if report.counts.failed > 0 && !args.exit_zero_on_failure {
+        bail!("Failed to renew {} certificate(s)", report.counts.failed);
+    }
     Ok(())
 }
+
+/// The report of a single cert, the expiry is read from the new certificate.
+fn cert_report(
+    config: &Config,
+    args: &RenewArgs,
+    cert: &CertConfig,
+    attempt: &Attempt,
+) -> CertReport {
+    let mut not_after = None;
+    let mut errors = Vec::new();
+    let action = match &attempt.result {
+        Ok(false) => Action::SkippedNotDue,
+        Ok(true) if args.dry_run => Action::DryRun,
+        Ok(true) if args.hooks_only => Action::HooksOnly,
+        Ok(true) => {
+            let persist = FilePersist::for_cert(config, cert);
+            not_after = persist
+                .load_cert_info(&cert.name)
+                .unwrap_or(None)
+                .map(|info| info.expires.rfc3339().to_string());
+            Action::Renewed
+        }
+        Err(err) => {
+            errors = report::error_chain(err);
+            Action::Failed
+        }
+    };
+    CertReport {
+        name: cert.name.clone(),
+        action,
+        started: attempt.started.clone(),
+        duration_ms: report::duration_ms(attempt.duration),
+        not_after,
+        errors,
+    }
+}
+
+/// A full renew run over all certs of the config, including hooks and the
+/// cleanup afterwards. What happened is recorded in `report`. The data
+/// directory needs to be locked and the signal handler of
+/// [`chall::cleanup_on_signal`] set up by the caller.
+pub fn renew_certs(config: &Config, args: &RenewArgs, report: &mut Report) -> Result<()> {
+    if !args.dry_run {
+        // proofs of renews that were killed
+        let max_age = Duration::from_secs(config.system.chall_max_age);
+        match chall::sweep(&config.system.chall_dir, max_age, false) {
+            Ok(deleted) => {
+                for path in deleted {
+                    info!("deleted stale challenge proof {:?}", path);
+                }
+            }
+            Err(err) => warn!("Failed to delete stale challenge proofs: {:#}", err),
+        }
+    }
+
+    if !args.skip_restarts {
+        report.add_hooks(hook_queue::retry(config, args.dry_run)?);
+    }
+
+    let certs = config.filter_managed_certs(&args.certs);
+    if !certs.is_empty() && !args.hooks_only {
+        clock::check_renew(config, args.ignore_clock_skew)?;
+    }
+
+    let mut renewed = Vec::new();
+    let mut skipped = 0;
+    let mut failed = 0;
+    for (cert, attempt) in certs.iter().zip(renew_all(args, config, &certs)) {
+        report.add_cert(cert_report(config, args, cert, &attempt));
+        match attempt.result {
+            Ok(true) => renewed.push(cert.name.as_str()),
+            Ok(false) => skipped += 1,
+            Err(err) => {
+                error!("Failed to renew ({:?}): {:#}", cert.name, err);
+                failed += 1;
+                if config.notify.is_enabled() && !args.dry_run {
+                    let info = FilePersist::for_cert(config, cert)
+                        .load_cert_info(&cert.name)
+                        .unwrap_or(None);
+                    let event = Event::renew_failed(&cert.name, &err, info.as_ref());
+                    notify::send(&config.notify, &event);
+                }
+            }
+        }
+    }
+    info!(
+        "Renewed {} certificate(s), skipped {}, failed {}",
+        renewed.len(),
+        skipped,
+        failed
+    );
+
+    if !args.dry_run {
+        permissions::fix_permissions(config);
+    }
+
+    let mut inconsistent = Vec::new();
+    let mut undeployed = Vec::new();
+    if !args.skip_restarts {
+        // a dry run doesn't execute hooks
+        if !args.dry_run {
+            inconsistent = inconsistent_certs(config, &certs, &renewed, args.force_exec);
+        }
+        let certs = certs
+            .iter()
+            .copied()
+            .filter(|c| !inconsistent.contains(&c.name.as_str()))
+            .collect::<Vec<_>>();
+        renewed.retain(|name| !inconsistent.contains(name));
+        let hooks = execute_planned_hooks(config, &certs, &renewed, args.force_exec, args.dry_run)?;
+        report.add_hooks(hooks.clone());
+        check_hooks(config, &hooks)?;
+
+        if !args.dry_run {
+            for cert in certs
+                .iter()
+                .filter(|c| c.verify_deployment && renewed.contains(&c.name.as_str()))
+            {
+                match deployment::verify_renewed(config, cert) {
+                    Ok(true) => (),
+                    Ok(false) => undeployed.push(cert.name.as_str()),
+                    Err(err) => {
+                        error!("Deployment check failed ({:?}): {:#}", cert.name, err);
+                        undeployed.push(cert.name.as_str());
+                    }
+                }
+            }
+        }
+    }
+
+    if !args.dry_run {
+        for cert in certs.iter().filter(|c| renewed.contains(&c.name.as_str())) {
+            if let Err(err) = prune::prune_cert(config, cert, false) {
+                error!("Failed to prune old versions ({:?}): {:#}", cert.name, err);
+            }
+        }
+    }
+
+    for staging in &[false, true] {
+        let persist = FilePersist::with_staging(config, *staging);
+        cleanup_certs(&persist, args.dry_run).context("Failed to cleanup old certs")?;
+    }
+
+    if !inconsistent.is_empty() {
+        bail!(
+            "The live files of {} certificate(s) don't belong together: {}",
+            inconsistent.len(),
+            inconsistent.join(", ")
+        );
+    }
+    if !undeployed.is_empty() {
+        bail!(
+            "The renewed certificate isn't served for {} certificate(s): {}",
+            undeployed.len(),
+            undeployed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AcmeConfig, DaemonConfig, SystemConfig};
+    use std::path::PathBuf;
+
+    fn cert(name: &str, exec: &[&str]) -> CertConfig {
+        CertConfig {
+            name: name.to_string(),
+            dns_names: vec![name.to_string()],
+            exec: exec.iter().map(|s| Exec::from(*s)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            certs: vec![
+                cert("a.example.com", &["reload a"]),
+                cert("b.example.com", &[]),
+                cert("c.example.com", &[]),
+            ],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig::default(),
+            system: SystemConfig {
+                data_dir: PathBuf::from("/var/lib/acme-redirect"),
+                exec: vec![Exec::from("reload default")],
+                exec_extra: vec![Exec::from("reload extra")],
+                ..Default::default()
+            },
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        }
+    }
+
+    type Env = Vec<(&'static str, String)>;
+
+    fn planned(renewed: &[&str], force: bool) -> Vec<(Vec<String>, Env)> {
+        planned_with(&config(), renewed, force)
+    }
+
+    fn planned_with(config: &Config, renewed: &[&str], force: bool) -> Vec<(Vec<String>, Env)> {
+        let certs = config.certs.iter().collect::<Vec<_>>();
+        plan_hooks(config, &certs, renewed, force)
+            .into_iter()
+            .map(|run| {
+                let env = run
+                    .env
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into_string().unwrap()))
+                    .collect();
+                (run.hooks.iter().map(Exec::to_string).collect(), env)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(5));
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(3), Duration::from_secs(40));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Duration::from_millis(50));
+        let start = Instant::now();
+        throttle.wait();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        throttle.wait();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_no_hooks_if_nothing_renewed() {
+        assert_eq!(planned(&[], false), vec![]);
+    }
+
+    #[test]
+    fn test_hooks_for_renewed_cert() {
+        assert_eq!(
+            planned(&["a.example.com"], false),
+            vec![
+                (
+                    vec!["reload a".to_string()],
+                    vec![
+                        ("ACME_CERT_NAME", "a.example.com".to_string()),
+                        (
+                            "ACME_LIVE_DIR",
+                            "/var/lib/acme-redirect/live/a.example.com".to_string()
+                        ),
+                        ("ACME_RENEWED_CERTS", "a.example.com".to_string()),
+                    ]
+                ),
+                (
+                    vec!["reload extra".to_string()],
+                    vec![("ACME_RENEWED_CERTS", "a.example.com".to_string())]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_hooks_run_once() {
+        assert_eq!(
+            planned(&["b.example.com", "c.example.com"], false),
+            vec![
+                (
+                    vec!["reload default".to_string()],
+                    vec![(
+                        "ACME_RENEWED_CERTS",
+                        "b.example.com c.example.com".to_string()
+                    )]
+                ),
+                (
+                    vec!["reload extra".to_string()],
+                    vec![(
+                        "ACME_RENEWED_CERTS",
+                        "b.example.com c.example.com".to_string()
+                    )]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_staging_hooks() {
+        let mut config = config();
+        config.certs[0].staging = true;
+        config.certs[1].staging = true;
+        assert_eq!(
+            planned_with(&config, &["a.example.com", "b.example.com"], false),
+            vec![
+                (
+                    vec!["reload a".to_string()],
+                    vec![
+                        ("ACME_CERT_NAME", "a.example.com".to_string()),
+                        (
+                            "ACME_LIVE_DIR",
+                            "/var/lib/acme-redirect/staging/live/a.example.com".to_string()
+                        ),
+                        (
+                            "ACME_RENEWED_CERTS",
+                            "a.example.com b.example.com".to_string()
+                        ),
+                        ("ACME_STAGING", "1".to_string()),
+                    ]
+                ),
+                (
+                    vec!["reload default".to_string()],
+                    vec![
+                        (
+                            "ACME_RENEWED_CERTS",
+                            "a.example.com b.example.com".to_string()
+                        ),
+                        ("ACME_STAGING", "1".to_string()),
+                    ]
+                ),
+                (
+                    vec!["reload extra".to_string()],
+                    vec![
+                        (
+                            "ACME_RENEWED_CERTS",
+                            "a.example.com b.example.com".to_string()
+                        ),
+                        ("ACME_STAGING", "1".to_string()),
+                    ]
+                ),
+            ]
+        );
+
+        // a production cert was renewed too
+        let hooks = planned_with(&config, &["a.example.com", "c.example.com"], false);
+        assert!(hooks[1].1.iter().all(|(k, _)| *k != "ACME_STAGING"));
+        assert!(hooks[2].1.iter().all(|(k, _)| *k != "ACME_STAGING"));
+    }
+
+    #[test]
+    fn test_force_exec() {
+        let hooks = planned(&[], true)
+            .into_iter()
+            .map(|(hooks, _)| hooks)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hooks,
+            vec![
+                vec!["reload a".to_string()],
+                vec!["reload default".to_string()],
+                vec!["reload extra".to_string()],
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config();
+        config.system.data_dir = dir.path().to_path_buf();
+        config.system.exec = vec![Exec::from("exit 1"), Exec::from("true")];
+        config.system.exec_extra = vec![Exec::Args(vec!["true".to_string()])];
+        let certs = config.certs[1..].iter().collect::<Vec<_>>();
+        run_hooks(&config, &certs, &["b.example.com"], false, false).unwrap();
+
+        config.system.fail_on_hook_error = true;
+        let err = run_hooks(&config, &certs, &["b.example.com"], false, false).unwrap_err();
+        assert_eq!(err.to_string(), "1 hook(s) failed");
+        // nothing is executed in a dry run
+        run_hooks(&config, &certs, &["b.example.com"], false, true).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config();
+        config.system.data_dir = dir.path().to_path_buf();
+        config.certs[0].exec = vec![Exec::from("exit 3")];
+        config.system.exec_extra = vec![Exec::from("true")];
+        let certs = config.certs[..1].iter().collect::<Vec<_>>();
+        let hooks =
+            execute_planned_hooks(&config, &certs, &["a.example.com"], false, false).unwrap();
+        assert_eq!(
+            hooks,
+            vec![
+                HookReport {
+                    cert: Some("a.example.com".to_string()),
+                    command: "exit 3".to_string(),
+                    exit_code: Some(3),
+                    error: Some("Hook exited with exit status: 3".to_string()),
+                },
+                HookReport {
+                    cert: None,
+                    command: "true".to_string(),
+                    exit_code: Some(0),
+                    error: None,
+                },
+            ]
+        );
+        assert!(
+            execute_planned_hooks(&config, &certs, &["a.example.com"], false, true)
+                .unwrap()
+                .is_empty()
+        );
+
+        // the failed hook is queued with its environment
+        let queue = hook_queue::load(&config);
+        assert_eq!(queue.hooks.len(), 1);
+        assert_eq!(queue.hooks[0].exec, Exec::from("exit 3"));
+        assert!(queue.hooks[0]
+            .env
+            .contains(&("ACME_CERT_NAME".to_string(), "a.example.com".to_string())));
+        assert_eq!(queue.pending("a.example.com").len(), 1);
+    }
+
+    fn self_signed(pkey: &PKey<Private>, name: &str) -> String {
+        self_signed_valid(pkey, name, 0, 90)
+    }
+
+    /// A certificate valid from `from` to `to` days relative to now.
+    fn self_signed_valid(pkey: &PKey<Private>, name: &str, from: i64, to: i64) -> String {
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::X509;
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(pkey).unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(name)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        let now = time::get_time().sec;
+        builder
+            .set_not_before(&Asn1Time::from_unix(now + from * 24 * 60 * 60).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(now + to * 24 * 60 * 60).unwrap())
+            .unwrap();
+        builder.sign(pkey, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_renew_at_lifetime_fraction() {
+        let mut config = config();
+        config.acme.renew_if_days_left = 30;
+        let pkey = acme::csr::create_private_key(config.acme.key_type).unwrap();
+        // a six day certificate with two days left
+        let short = self_signed_valid(&pkey, "a.example.com", -4, 2);
+        let short = CertInfo::from_pem(short.as_bytes()).unwrap();
+        let long = self_signed(&pkey, "a.example.com");
+        let long = CertInfo::from_pem(long.as_bytes()).unwrap();
+        let reason = |config: &Config, cert: &CertConfig, existing: &CertInfo| {
+            renewal_reason(config, cert, existing, None, false, false)
+        };
+
+        let mut cert = config.certs[0].clone();
+        assert!(reason(&config, &cert, &short).is_some());
+        assert!(reason(&config, &cert, &long).is_none());
+
+        cert.renew_at_lifetime_fraction = Some(0.7);
+        assert!(reason(&config, &cert, &short).is_none());
+        cert.renew_at_lifetime_fraction = Some(0.6);
+        assert!(reason(&config, &cert, &short).is_some());
+        assert!(reason(&config, &cert, &long).is_none());
+
+        cert.renew_at_lifetime_fraction = None;
+        config.acme.renew_at_lifetime_fraction = Some(0.7);
+        assert!(reason(&config, &cert, &short).is_none());
+        cert.renew_if_days_left = Some(3);
+        assert!(reason(&config, &cert, &short).is_some());
+    }
+
+    #[test]
+    fn test_reusable_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config();
+        config.system.data_dir = dir.path().to_path_buf();
+        let persist = FilePersist::new(&config);
+        let name = "a.example.com";
+        assert!(reusable_key(&persist, name, KeyType::EcP256).is_none());
+
+        let pkey = acme::csr::create_private_key(KeyType::EcP256).unwrap();
+        let fullcert = acme::Certificate {
+            private_key: String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            fullchain: self_signed(&pkey, name),
+        };
+        persist.store_cert(name, &fullcert).unwrap();
+        let reused = reusable_key(&persist, name, KeyType::EcP256).unwrap();
+        assert!(reused.public_eq(&pkey));
+        // the key type has been changed in the config
+        assert!(reusable_key(&persist, name, KeyType::EcP384).is_none());
+
+        let broken = acme::Certificate {
+            private_key: "broken\n".to_string(),
+            ..fullcert
+        };
+        persist.store_cert(name, &broken).unwrap();
+        assert!(reusable_key(&persist, name, KeyType::EcP256).is_none());
+    }
+
+    #[test]
+    fn test_inconsistent_certs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config();
+        config.system.data_dir = dir.path().to_path_buf();
+        let persist = FilePersist::new(&config);
+        let certs = config.certs.iter().collect::<Vec<_>>();
+        let key = || acme::csr::create_private_key(KeyType::EcP256).unwrap();
+        let pem = |pkey: &PKey<Private>| {
+            String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap()
+        };
+
+        let pkey = key();
+        let fullcert = acme::Certificate {
+            private_key: pem(&pkey),
+            fullchain: self_signed(&pkey, "a.example.com"),
+        };
+        persist.store_cert("a.example.com", &fullcert).unwrap();
+        // the privkey of another renewal
+        let fullcert = acme::Certificate {
+            private_key: pem(&key()),
+            fullchain: self_signed(&key(), "b.example.com"),
+        };
+        persist.store_cert("b.example.com", &fullcert).unwrap();
+
+        let all = ["a.example.com", "b.example.com", "c.example.com"];
+        assert_eq!(
+            inconsistent_certs(&config, &certs, &all, false),
+            &["b.example.com"]
+        );
+        assert!(inconsistent_certs(&config, &certs, &["a.example.com"], false).is_empty());
+        assert_eq!(
+            inconsistent_certs(&config, &certs, &[], true),
+            &["b.example.com"]
+        );
+    }
+}
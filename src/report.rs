@@ -0,0 +1,212 @@
+//! The json report of `renew --report-json`, for tools that need to know
+//! what happened to every certificate without parsing the logs. Fields are
+//! only added in a compatible way, anything else bumps [`REPORT_VERSION`].
+use crate::errors::*;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// The live certificate isn't due for renewal yet
+    SkippedNotDue,
+    Renewed,
+    Failed,
+    /// The certificate would have been renewed
+    DryRun,
+    /// The certificate is due, but only the hooks were executed
+    HooksOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CertReport {
+    pub name: String,
+    pub action: Action,
+    pub started: String,
+    pub duration_ms: u64,
+    /// The expiry of the new certificate if it has been renewed
+    pub not_after: Option<String>,
+    /// The error and its causes, outermost first
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HookReport {
+    /// The certificate the hook belongs to, None for the global hooks
+    pub cert: Option<String>,
+    pub command: String,
+    /// None if the hook couldn't be started or was killed after the timeout
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl HookReport {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Counts {
+    pub renewed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub hooks_failed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub report_version: u32,
+    pub started: String,
+    pub duration_ms: u64,
+    pub dry_run: bool,
+    pub certs: Vec<CertReport>,
+    pub hooks: Vec<HookReport>,
+    /// Set if the run was aborted, eg. because of the clock skew check
+    pub error: Option<String>,
+    pub counts: Counts,
+}
+
+/// The current time in the format used for timestamps in the report.
+pub fn timestamp() -> String {
+    time::now_utc().rfc3339().to_string()
+}
+
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+pub fn error_chain(err: &Error) -> Vec<String> {
+    err.chain().map(|cause| cause.to_string()).collect()
+}
+
+impl Report {
+    pub fn new(dry_run: bool) -> Report {
+        Report {
+            report_version: REPORT_VERSION,
+            started: timestamp(),
+            duration_ms: 0,
+            dry_run,
+            certs: Vec::new(),
+            hooks: Vec::new(),
+            error: None,
+            counts: Counts::default(),
+        }
+    }
+
+    pub fn add_cert(&mut self, cert: CertReport) {
+        match cert.action {
+            Action::Renewed | Action::DryRun | Action::HooksOnly => self.counts.renewed += 1,
+            Action::SkippedNotDue => self.counts.skipped += 1,
+            Action::Failed => self.counts.failed += 1,
+        }
+        self.certs.push(cert);
+    }
+
+    pub fn add_hooks(&mut self, hooks: Vec<HookReport>) {
+        self.counts.hooks_failed += hooks.iter().filter(|h| !h.is_success()).count();
+        self.hooks.extend(hooks);
+    }
+
+    /// Record the error that aborted the run and how long it took.
+    pub fn finish(&mut self, duration: Duration, result: &Result<()>) {
+        self.duration_ms = duration_ms(duration);
+        if let Err(err) = result {
+            self.error = Some(format!("{:#}", err));
+        }
+    }
+
+    /// Write the report to a file, or to stdout if `dest` is `-`.
+    pub fn write(&self, dest: &str) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        if dest == "-" {
+            let mut stdout = io::stdout();
+            stdout.write_all(json.as_bytes())?;
+            stdout.flush()?;
+        } else {
+            fs::write(dest, json).with_context(|| anyhow!("Failed to write report {:?}", dest))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(name: &str, action: Action) -> CertReport {
+        CertReport {
+            name: name.to_string(),
+            action,
+            started: "2020-04-28T23:31:27Z".to_string(),
+            duration_ms: 1200,
+            not_after: None,
+            errors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut report = Report::new(false);
+        report.started = "2020-04-28T23:31:27Z".to_string();
+        report.add_cert(CertReport {
+            not_after: Some("2020-07-27T23:31:27Z".to_string()),
+            ..cert("a.example.com", Action::Renewed)
+        });
+        let err = anyhow!("connection refused").context("Fail to get certificate");
+        report.add_cert(CertReport {
+            errors: error_chain(&err),
+            ..cert("b.example.com", Action::Failed)
+        });
+        report.add_cert(cert("c.example.com", Action::SkippedNotDue));
+        report.add_hooks(vec![HookReport {
+            cert: Some("a.example.com".to_string()),
+            command: "systemctl reload nginx".to_string(),
+            exit_code: Some(1),
+            error: Some("Hook exited with exit status: 1".to_string()),
+        }]);
+        report.finish(Duration::from_millis(2500), &Ok(()));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["report_version"], 1);
+        assert_eq!(json["duration_ms"], 2500);
+        assert_eq!(json["certs"][0]["action"], "renewed");
+        assert_eq!(json["certs"][0]["not_after"], "2020-07-27T23:31:27Z");
+        assert_eq!(json["certs"][1]["action"], "failed");
+        assert_eq!(
+            json["certs"][1]["errors"],
+            serde_json::json!(["Fail to get certificate", "connection refused"])
+        );
+        assert_eq!(json["certs"][2]["action"], "skipped-not-due");
+        assert_eq!(json["hooks"][0]["exit_code"], 1);
+        assert_eq!(json["error"], serde_json::Value::Null);
+        assert_eq!(
+            json["counts"],
+            serde_json::json!({"renewed": 1, "skipped": 1, "failed": 1, "hooks_failed": 1})
+        );
+    }
+
+    #[test]
+    fn test_aborted_run() {
+        let mut report = Report::new(true);
+        report.finish(Duration::from_secs(1), &Err(anyhow!("clock is off")));
+        assert_eq!(report.error.as_deref(), Some("clock is off"));
+        assert!(report.dry_run);
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        let report = Report::new(false);
+        report.write(path.to_str().unwrap()).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.ends_with("}\n"));
+        assert!(json.contains("\"report_version\": 1"));
+    }
+}
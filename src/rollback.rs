@@ -0,0 +1,25 @@
+use crate::args::RollbackArgs;
+use crate::config::Config;
+use crate::errors::*;
+use crate::persist::FilePersist;
+use crate::renew;
+
+pub fn run(config: Config, args: RollbackArgs) -> Result<()> {
+    let cert = config
+        .certs
+        .iter()
+        .find(|cert| cert.name == args.cert)
+        .ok_or_else(|| anyhow!("Certificate {:?} is not configured", args.cert))?;
+
+    let persist = FilePersist::for_cert(&config, cert);
+    let version = persist
+        .rollback(&cert.name)
+        .with_context(|| anyhow!("Failed to roll back {:?}", cert.name))?;
+    info!("{:?} is now using {:?}", cert.name, version);
+
+    if !args.skip_restarts {
+        renew::run_hooks(&config, &[cert], &[&cert.name], false, false)?;
+    }
+
+    Ok(())
+}
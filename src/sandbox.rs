@@ -1,12 +1,20 @@
+//! Dropping privileges of the daemon. This is only supported on unix, on
+//! other platforms the daemon keeps running as the user that started it.
 use crate::args::DaemonArgs;
 use crate::errors::*;
+#[cfg(target_os = "linux")]
 use caps::CapSet;
+#[cfg(unix)]
 use nix::unistd::{Gid, Uid};
+#[cfg(unix)]
 use std::env;
+#[cfg(unix)]
 use std::fs;
+#[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
+#[cfg(unix)]
 fn chroot(path: &Path) -> Result<()> {
     let metadata = fs::metadata(path)?;
 
@@ -27,6 +35,7 @@ fn chroot(path: &Path) -> Result<()> {
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
 fn drop_caps() -> Result<()> {
     debug!("Permanently clearing capability sets");
     caps::clear(None, CapSet::Effective)
@@ -36,7 +45,8 @@ fn drop_caps() -> Result<()> {
     Ok(())
 }
 
-pub fn init(args: &DaemonArgs) -> Result<()> {
+#[cfg(unix)]
+pub fn init(args: &DaemonArgs, chroot_dir: &Path) -> Result<()> {
     let user = if let Some(name) = &args.user {
         debug!("Resolving uid for {:?}", name);
         let user = users::get_user_by_name(&name)
@@ -50,9 +60,8 @@ pub fn init(args: &DaemonArgs) -> Result<()> {
     };
 
     if args.chroot {
-        let path = env::current_dir().context("Failed to determine current directory")?;
-        debug!("Chrooting into {:?}", path);
-        chroot(&path).context("Failed to chroot")?;
+        debug!("Chrooting into {:?}", chroot_dir);
+        chroot(chroot_dir).context("Failed to chroot")?;
     }
 
     if let Some((uid, gid)) = user {
@@ -62,7 +71,22 @@ pub fn init(args: &DaemonArgs) -> Result<()> {
         nix::unistd::setuid(uid).context("Failed to drop uid")?;
     }
 
+    #[cfg(target_os = "linux")]
     drop_caps()?;
 
     Ok(())
 }
+
+#[cfg(not(unix))]
+pub fn init(args: &DaemonArgs, _chroot_dir: &Path) -> Result<()> {
+    if args.chroot {
+        warn!("Chrooting is not supported on this platform, ignoring --chroot");
+    }
+    if let Some(name) = &args.user {
+        warn!(
+            "Dropping privileges is not supported on this platform, not switching to {:?}",
+            name
+        );
+    }
+    Ok(())
+}
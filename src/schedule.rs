@@ -0,0 +1,212 @@
+//! Renew certificates on an interval from the daemon, instead of running
+//! `renew` from a timer. On unix the renewals run in a helper process that's
+//! forked before the daemon drops its privileges and chroots, so certificates
+//! are written and hooks are executed like by a standalone renew.
+use crate::args::{DaemonArgs, RenewArgs};
+use crate::chall;
+use crate::config::{self, Config, ConfigDir};
+use crate::errors::*;
+use crate::lock;
+use crate::renew;
+use crate::report::Report;
+#[cfg(unix)]
+use nix::fcntl::OFlag;
+#[cfg(unix)]
+use nix::sys::wait;
+#[cfg(unix)]
+use nix::unistd::{self, ForkResult, Pid};
+use rand::Rng;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    interval: Duration,
+    jitter: Duration,
+    lock_wait: Option<Duration>,
+}
+
+impl Schedule {
+    pub fn from_args(args: &DaemonArgs) -> Option<Schedule> {
+        args.renew_interval.map(|interval| Schedule {
+            interval,
+            jitter: args.renew_jitter.unwrap_or_default(),
+            lock_wait: args.lock_wait,
+        })
+    }
+
+    /// How long to wait before the next run. The first run only waits for
+    /// the jitter, certificates that expired while the daemon wasn't running
+    /// are renewed right away.
+    fn delay<R: Rng>(&self, rng: &mut R, first: bool) -> Duration {
+        let jitter = Duration::from_secs(rng.gen_range(0..=self.jitter.as_secs()));
+        if first {
+            jitter
+        } else {
+            self.interval + jitter
+        }
+    }
+}
+
+/// Stops the renewals when it's dropped, a renew that's in progress is
+/// finished first.
+pub struct RenewHelper {
+    #[cfg(unix)]
+    pipe: Option<File>,
+    #[cfg(unix)]
+    child: Pid,
+    #[cfg(not(unix))]
+    _stop: mpsc::Sender<()>,
+}
+
+#[cfg(unix)]
+impl Drop for RenewHelper {
+    fn drop(&mut self) {
+        self.pipe.take();
+        debug!("Waiting for renew helper {} to exit", self.child);
+        wait::waitpid(self.child, None).ok();
+    }
+}
+
+/// Pick up certificates that were configured since the daemon started, the
+/// old configs are kept if the new ones are invalid.
+fn reload_certs(config: &mut Config) -> Result<()> {
+    let certs = ConfigDir::open(&config.config_dir)?
+        .with_settings(config)
+        .load_certs()?;
+    config::validate_certs(&certs)?;
+    config.certs = certs;
+    Ok(())
+}
+
+fn renew_once(config: &Config, lock_wait: Option<Duration>) -> Result<()> {
+    let _lock = lock::acquire(config, lock_wait)?;
+    let args = RenewArgs {
+        max_concurrent: 1,
+        ..Default::default()
+    };
+    renew::renew_certs(config, &args, &mut Report::new(false))
+}
+
+/// Renew on the schedule until `stop` is closed.
+fn run(mut config: Config, schedule: Schedule, stop: mpsc::Receiver<()>) -> Result<()> {
+    chall::cleanup_on_signal().context("Failed to setup signal handler")?;
+    let mut rng = rand::thread_rng();
+    let mut first = true;
+    loop {
+        let delay = schedule.delay(&mut rng, first);
+        first = false;
+        debug!("Next renew run in {}s", delay.as_secs());
+        match stop.recv_timeout(delay) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => {
+                debug!("Daemon stopped, not renewing anymore");
+                return Ok(());
+            }
+        }
+
+        if let Err(err) = reload_certs(&mut config) {
+            warn!("Failed to reload cert configs, keeping old ones: {:#}", err);
+        }
+        info!("Starting scheduled renew run");
+        if let Err(err) = renew_once(&config, schedule.lock_wait) {
+            error!("Scheduled renew run failed: {:#}", err);
+        }
+    }
+}
+
+/// Fork the renew helper, it's stopped when the pipe to the daemon is closed,
+/// even if the daemon gets killed.
+#[cfg(unix)]
+pub fn spawn(config: Config, schedule: Schedule) -> Result<RenewHelper> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+    // there are no other threads yet that could hold locks
+    match unsafe { unistd::fork() }.context("Failed to fork renew helper")? {
+        ForkResult::Child => {
+            unistd::close(write).ok();
+            let mut pipe = unsafe { File::from_raw_fd(read) };
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                // the daemon never writes, this returns once it's gone
+                let mut buf = [0];
+                pipe.read_exact(&mut buf).ok();
+                drop(tx);
+            });
+            let code = match run(config, schedule, rx) {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("Renew helper failed: {:#}", err);
+                    1
+                }
+            };
+            process::exit(code);
+        }
+        ForkResult::Parent { child } => {
+            unistd::close(read).ok();
+            info!(
+                "Renewing certificates every {}s in helper process {}",
+                schedule.interval.as_secs(),
+                child
+            );
+            let pipe = unsafe { File::from_raw_fd(write) };
+            Ok(RenewHelper {
+                pipe: Some(pipe),
+                child,
+            })
+        }
+    }
+}
+
+/// The daemon doesn't drop privileges on this platform, the renewals run in
+/// a thread of the daemon.
+#[cfg(not(unix))]
+pub fn spawn(config: Config, schedule: Schedule) -> Result<RenewHelper> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(err) = run(config, schedule, rx) {
+            error!("Renew helper failed: {:#}", err);
+        }
+    });
+    info!(
+        "Renewing certificates every {}s",
+        schedule.interval.as_secs()
+    );
+    Ok(RenewHelper { _stop: tx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let schedule = Schedule {
+            interval: Duration::from_secs(12 * 60 * 60),
+            jitter: Duration::from_secs(0),
+            lock_wait: None,
+        };
+        let mut rng = rand::thread_rng();
+        assert_eq!(schedule.delay(&mut rng, true), Duration::from_secs(0));
+        assert_eq!(schedule.delay(&mut rng, false), schedule.interval);
+
+        let schedule = Schedule {
+            jitter: Duration::from_secs(60 * 60),
+            ..schedule
+        };
+        for _ in 0..100 {
+            let delay = schedule.delay(&mut rng, false);
+            assert!(delay >= schedule.interval);
+            assert!(delay <= schedule.interval + schedule.jitter);
+            assert!(schedule.delay(&mut rng, true) <= schedule.jitter);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! The outcome of the latest renewals of each certificate, kept in
+//! `state/<name>.json` in the data directory so `status` can show why a
+//! certificate keeps failing to renew. The state is informational only, a
+//! state file that can't be read or parsed is treated as absent, except for
+//! the rate limit of the acme server that's respected by later renew runs.
+use crate::acme;
+use crate::errors::*;
+use crate::persist::FilePersist;
+use serde::{Deserialize, Serialize};
+
+/// How long a rate limited certificate is skipped if the acme server didn't
+/// send a Retry-After.
+pub const RATE_LIMIT_BACKOFF: i64 = 60 * 60;
+/// Certificates that failed this many times in a row are only retried after
+/// a back-off, see [`CertState::backoff_until`].
+pub const DEFAULT_BACKOFF_AFTER_FAILURES: u32 = 3;
+/// How long to wait after the latest failure, the last one is used for all
+/// further failures.
+const BACKOFF: &[i64] = &[60 * 60, 4 * 60 * 60, 24 * 60 * 60];
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertState {
+    /// Unix timestamp of the latest renewal attempt
+    pub last_attempt: Option<i64>,
+    /// Unix timestamp of the latest successful renewal
+    pub last_success: Option<i64>,
+    /// The error of the latest attempt, if it failed
+    pub last_error: Option<String>,
+    /// Failed validations since the latest successful renewal
+    pub consecutive_failures: u32,
+    /// Unix timestamp until which the acme server rejects new orders because
+    /// of a rate limit
+    pub retry_after: Option<i64>,
+}
+
+impl CertState {
+    pub fn record_success(&mut self, now: i64) {
+        self.last_attempt = Some(now);
+        self.last_success = Some(now);
+        self.last_error = None;
+        self.consecutive_failures = 0;
+        self.retry_after = None;
+    }
+
+    /// Every error is kept as the latest one, but only failed validations are
+    /// counted for the back-off. A network outage or a problem of the acme
+    /// server doesn't make later runs wait.
+    pub fn record_failure(&mut self, now: i64, err: &Error) {
+        self.last_attempt = Some(now);
+        self.last_error = Some(format!("{:#}", err));
+        if acme::is_validation_failure(err) {
+            self.consecutive_failures += 1;
+        }
+        self.retry_after = acme::rate_limit(err).map(|problem| {
+            let backoff = problem
+                .retry_after
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(RATE_LIMIT_BACKOFF);
+            now + backoff
+        });
+    }
+
+    /// The end of the rate limit window if it hasn't passed yet.
+    pub fn rate_limited_until(&self, now: i64) -> Option<i64> {
+        self.retry_after.filter(|until| *until > now)
+    }
+
+    /// When the next attempt is allowed after `after` or more consecutive
+    /// failures, None if the certificate isn't backed off.
+    pub fn backoff_until(&self, now: i64, after: u32) -> Option<i64> {
+        if after == 0 || self.consecutive_failures < after {
+            return None;
+        }
+        let step = ((self.consecutive_failures - after) as usize).min(BACKOFF.len() - 1);
+        let until = self.last_attempt? + BACKOFF[step];
+        Some(until).filter(|until| *until > now)
+    }
+
+    /// A line like `last attempt 2h ago: failed (...)` if the latest attempt
+    /// failed.
+    pub fn failure_summary(&self, now: i64, backoff_after: u32) -> Option<String> {
+        let error = self.last_error.as_ref()?;
+        let attempt = match self.last_attempt {
+            Some(attempt) => format!("last attempt {} ago", ago(now - attempt)),
+            None => "last attempt".to_string(),
+        };
+        let times = if self.consecutive_failures > 1 {
+            format!(" {} times", self.consecutive_failures)
+        } else {
+            String::new()
+        };
+        let error = error.lines().next().unwrap_or_default();
+        let retry = match (
+            self.rate_limited_until(now),
+            self.backoff_until(now, backoff_after),
+        ) {
+            (Some(until), _) => format!(", rate limited for another {}", ago(until - now)),
+            (None, Some(until)) => format!(", backing off for another {}", ago(until - now)),
+            (None, None) => String::new(),
+        };
+        Some(format!("{}: failed{} ({}){}", attempt, times, error, retry))
+    }
+}
+
+/// A short, rounded down duration like `5m` or `2h`.
+fn ago(secs: i64) -> String {
+    let secs = secs.max(0);
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h", secs / 3600)
+    } else {
+        format!("{}d", secs / 86400)
+    }
+}
+
+pub fn load(persist: &FilePersist, name: &str) -> Option<CertState> {
+    let json = match persist.load_state(name) {
+        Ok(json) => json?,
+        Err(err) => {
+            debug!("{:?}: Failed to read renewal state: {:#}", name, err);
+            return None;
+        }
+    };
+    match serde_json::from_str(&json) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            debug!("{:?}: Ignoring invalid renewal state: {}", name, err);
+            None
+        }
+    }
+}
+
+/// Until when the certificate is skipped because the acme server rate limited
+/// the latest attempt.
+pub fn rate_limited_until(persist: &FilePersist, name: &str, now: i64) -> Option<i64> {
+    load(persist, name)?.rate_limited_until(now)
+}
+
+/// Until when the certificate is skipped because its latest attempts failed,
+/// with the number of consecutive failures.
+pub fn backoff_until(
+    persist: &FilePersist,
+    name: &str,
+    now: i64,
+    after: u32,
+) -> Option<(u32, i64)> {
+    let state = load(persist, name)?;
+    let until = state.backoff_until(now, after)?;
+    Some((state.consecutive_failures, until))
+}
+
+/// Update the state of a certificate after a renewal attempt that failed
+/// with `error` or succeeded. Failing to write the state is logged, it never
+/// fails the renewal.
+pub fn record(persist: &FilePersist, name: &str, error: Option<&Error>) {
+    let now = time::get_time().sec;
+    let mut state = load(persist, name).unwrap_or_default();
+    match error {
+        Some(err) => state.record_failure(now, err),
+        None => state.record_success(now),
+    }
+    let stored = serde_json::to_string(&state)
+        .map_err(Error::from)
+        .and_then(|json| persist.store_state(name, &json));
+    if let Err(err) = stored {
+        warn!("{:?}: Failed to write renewal state: {:#}", name, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, SystemConfig};
+    use std::path::PathBuf;
+
+    fn validation_failure(detail: &str) -> Error {
+        Error::from(acme::api::Problem {
+            kind: "urn:ietf:params:acme:error:dns".to_string(),
+            detail: Some(detail.to_string()),
+            subproblems: vec![],
+            identifier: None,
+            retry_after: None,
+        })
+        .context("Validation of \"example.com\" failed (Invalid)")
+    }
+
+    #[test]
+    fn test_record_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: Default::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: Default::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::new(&config);
+        assert!(load(&persist, "example.com").is_none());
+
+        for _ in 0..2 {
+            record(
+                &persist,
+                "example.com",
+                Some(&validation_failure("NXDOMAIN")),
+            );
+        }
+        // only failed validations are counted
+        record(
+            &persist,
+            "example.com",
+            Some(&anyhow!("Connection refused")),
+        );
+        let state = load(&persist, "example.com").unwrap();
+        assert_eq!(state.last_success, None);
+        assert_eq!(state.last_error.as_deref(), Some("Connection refused"));
+        assert_eq!(state.consecutive_failures, 2);
+        let now = state.last_attempt.unwrap() + 7300;
+        assert_eq!(
+            state.failure_summary(now, 3).unwrap(),
+            "last attempt 2h ago: failed 2 times (Connection refused)"
+        );
+
+        record(&persist, "example.com", None);
+        let state = load(&persist, "example.com").unwrap();
+        assert_eq!(state.last_success, state.last_attempt);
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.failure_summary(now, 3), None);
+
+        persist
+            .store_state("example.com", "{\"last_attempt\":")
+            .unwrap();
+        assert!(load(&persist, "example.com").is_none());
+        // a corrupted state is replaced
+        record(
+            &persist,
+            "example.com",
+            Some(&validation_failure("timeout")),
+        );
+        assert_eq!(
+            load(&persist, "example.com").unwrap().consecutive_failures,
+            1
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_state() {
+        let mut problem = serde_json::from_str::<acme::api::Problem>(
+            r#"{
+  "type": "urn:ietf:params:acme:error:rateLimited",
+  "detail": "too many certificates already issued"
+}"#,
+        )
+        .unwrap();
+        let now = 1_600_000_000;
+        let mut state = CertState::default();
+        state.record_failure(
+            now,
+            &Error::from(problem.clone()).context("Fail to get certificate"),
+        );
+        assert_eq!(state.retry_after, Some(now + RATE_LIMIT_BACKOFF));
+        assert_eq!(
+            state.rate_limited_until(now + 60),
+            Some(now + RATE_LIMIT_BACKOFF)
+        );
+        assert_eq!(state.rate_limited_until(now + RATE_LIMIT_BACKOFF), None);
+        assert_eq!(
+            state.failure_summary(now + 60, 3).unwrap(),
+            "last attempt 1m ago: failed (Fail to get certificate: urn:ietf:params:acme:error:rateLimited: too many certificates already issued), rate limited for another 59m"
+        );
+
+        problem.retry_after = Some(std::time::Duration::from_secs(3 * 86400));
+        state.record_failure(now, &problem.clone().into());
+        assert_eq!(state.rate_limited_until(now), Some(now + 3 * 86400));
+
+        // other errors don't make later runs wait, neither of them counts
+        // towards the back-off
+        state.record_failure(now, &anyhow!("Connection refused"));
+        assert_eq!(state.rate_limited_until(now), None);
+        assert_eq!(state.consecutive_failures, 0);
+
+        state.record_failure(now, &Error::from(problem));
+        state.record_success(now);
+        assert_eq!(state.retry_after, None);
+
+        // state files written before the field existed
+        let state = serde_json::from_str::<CertState>(
+            r#"{"last_attempt":1,"last_success":1,"last_error":null,"consecutive_failures":0}"#,
+        )
+        .unwrap();
+        assert_eq!(state.retry_after, None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let now = 1_600_000_000;
+        let mut state = CertState::default();
+        for _ in 0..2 {
+            state.record_failure(now, &validation_failure("NXDOMAIN"));
+        }
+        assert_eq!(state.backoff_until(now, 3), None);
+
+        state.record_failure(now, &validation_failure("NXDOMAIN"));
+        assert_eq!(state.backoff_until(now, 3), Some(now + 3600));
+        assert_eq!(state.backoff_until(now + 3600, 3), None);
+        assert_eq!(
+            state.failure_summary(now + 60, 3).unwrap(),
+            "last attempt 1m ago: failed 3 times (Validation of \"example.com\" failed (Invalid): urn:ietf:params:acme:error:dns: NXDOMAIN), backing off for another 59m"
+        );
+        assert_eq!(state.backoff_until(now, 4), None);
+        state.record_failure(now, &anyhow!("Connection refused"));
+        assert_eq!(state.consecutive_failures, 3);
+        assert_eq!(state.backoff_until(now, 0), None);
+
+        state.record_failure(now, &validation_failure("NXDOMAIN"));
+        assert_eq!(state.backoff_until(now, 3), Some(now + 4 * 3600));
+        for _ in 0..5 {
+            state.record_failure(now, &validation_failure("NXDOMAIN"));
+        }
+        assert_eq!(state.backoff_until(now, 3), Some(now + 24 * 3600));
+
+        state.record_success(now);
+        assert_eq!(state.backoff_until(now, 3), None);
+    }
+
+    #[test]
+    fn test_ago() {
+        assert_eq!(ago(-3), "0s");
+        assert_eq!(ago(59), "59s");
+        assert_eq!(ago(125), "2m");
+        assert_eq!(ago(86399), "23h");
+        assert_eq!(ago(3 * 86400 + 5), "3d");
+    }
+}
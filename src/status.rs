@@ -1,35 +1,1640 @@
-use crate::config::Config;
+use crate::args::{StatusArgs, StatusFormat, StatusSort};
+use crate::ari::{self, Window};
+use crate::cert::{self, CertDetails, CertInfo, DnsNameChanges};
+use crate::clock;
+use crate::config::{CertConfig, Config, KeyType, RenewThreshold};
 use crate::errors::*;
-use crate::persist::FilePersist;
-use colored::Colorize;
+use crate::hook_queue::{self, QueuedHook};
+use crate::notify;
+use crate::persist::{FilePersist, LiveFile};
+use crate::remove;
+use crate::renew;
+use crate::state::{self, CertState};
+use colored::{ColoredString, Colorize};
+#[cfg(unix)]
 use nix::unistd::AccessFlags;
-use std::path::Path;
-
-pub fn run(config: Config) -> Result<()> {
-    let persist = FilePersist::new(&config);
-
-    let data_dir = Path::new(&config.system.data_dir);
-    nix::unistd::access(data_dir, AccessFlags::X_OK)
-        .with_context(|| anyhow!("Detected insufficient permissions to access {:?}", data_dir))?;
-
-    for cert in config.certs {
-        let name = cert.name;
-        // TODO: also show alt names?
-        if let Some(cert) = persist.load_cert_info(&name)? {
-            let days_left = cert.days_left();
-            let status = format!("{} days left", days_left);
-            let status = if days_left > config.acme.renew_if_days_left {
-                status.green()
-            } else if days_left > 0 {
-                status.yellow()
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The folder the live symlink points to and how many older versions are
+/// still around for `rollback`. With `reuse_private_key`, the date the
+/// private key has been used since.
+#[derive(Debug, Default)]
+struct Versions {
+    live: Option<String>,
+    older: usize,
+    privkey_since: Option<time::Tm>,
+}
+
+impl Versions {
+    fn load(persist: &FilePersist, cert: &CertConfig) -> Result<Versions> {
+        let name = &cert.name;
+        let live = persist.live_version(name)?;
+        let versions = persist.list_versions(name)?;
+        let older = live
+            .as_ref()
+            .and_then(|live| versions.iter().position(|v| v == live))
+            .unwrap_or(0);
+        // the keys are usually only readable by root
+        let privkey_since = if cert.reuse_private_key {
+            persist.privkey_since(name).unwrap_or_else(|err| {
+                debug!(
+                    "{:?}: Failed to check the age of the private key: {:#}",
+                    name, err
+                );
+                None
+            })
+        } else {
+            None
+        };
+        Ok(Versions {
+            live,
+            older,
+            privkey_since,
+        })
+    }
+}
+
+/// The fullchain of a monitor_only certificate with watch_cert, None if it
+/// doesn't exist (yet).
+fn load_watched<T>(path: &Path, parse: fn(&[u8]) -> Result<T>) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(buf) => parse(&buf)
+            .map(Some)
+            .with_context(|| anyhow!("Failed to parse watched certificate {:?}", path)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| anyhow!("Failed to read watched certificate {:?}", path))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CertStatus<'a> {
+    name: &'a str,
+    /// The config the certificate was split from
+    group: Option<&'a str>,
+    enabled: bool,
+    monitor_only: bool,
+    watch_cert: Option<&'a Path>,
+    staging: bool,
+    dns_names: &'a [String],
+    key_type: Option<KeyType>,
+    not_before: Option<String>,
+    not_after: Option<String>,
+    days_left: Option<i64>,
+    must_staple: bool,
+    must_staple_present: Option<bool>,
+    dns_names_added: Option<Vec<String>>,
+    dns_names_removed: Option<Vec<String>>,
+    acme_url: &'a str,
+    issuer: Option<String>,
+    chain_issuer: Option<String>,
+    renew_if_days_left: i64,
+    renew_at_lifetime_fraction: Option<f64>,
+    renewal_window: Option<Window>,
+    renewal_due: bool,
+    fullchain: Option<PathBuf>,
+    privkey: Option<PathBuf>,
+    version: Option<String>,
+    older_versions: usize,
+    private_key_since: Option<String>,
+    last_attempt: Option<String>,
+    last_success: Option<String>,
+    last_error: Option<String>,
+    consecutive_failures: u32,
+    rate_limited_until: Option<String>,
+    /// The next attempt after repeated failures
+    backoff_until: Option<String>,
+    /// Failed exec hooks that are retried by the next renew, the web server
+    /// might not use the live certificate yet
+    pending_hooks: Vec<String>,
+    /// Seconds the system clock is ahead of the acme server, negative if
+    /// it's behind
+    clock_skew: Option<i64>,
+    /// Installed files that don't belong together
+    mismatches: Vec<String>,
+    /// Shown without the certificate configs, see [`run_offline`]
+    offline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<CertDetails>,
+}
+
+fn rfc3339(timestamp: i64) -> String {
+    time::at_utc(time::Timespec::new(timestamp, 0))
+        .rfc3339()
+        .to_string()
+}
+
+impl<'a> CertStatus<'a> {
+    fn new(
+        config: &'a Config,
+        persist: &FilePersist,
+        cert: &'a CertConfig,
+        info: Option<&CertInfo>,
+        window: Option<Window>,
+        versions: Versions,
+        state: CertState,
+    ) -> CertStatus<'a> {
+        let changes = info.map(|i| DnsNameChanges::new(cert, i));
+        let fullchain = match &cert.watch_cert {
+            Some(path) => info.map(|_| path.clone()),
+            None => info.map(|_| persist.live_file(&cert.name, LiveFile::Fullchain)),
+        };
+        let privkey = match &cert.watch_cert {
+            Some(_) => None,
+            None => info.map(|_| persist.live_file(&cert.name, LiveFile::Privkey)),
+        };
+        CertStatus {
+            name: &cert.name,
+            group: cert.group.as_deref(),
+            enabled: cert.is_enabled(),
+            monitor_only: cert.monitor_only,
+            watch_cert: cert.watch_cert.as_deref(),
+            staging: cert.staging(&config.acme),
+            dns_names: &cert.dns_names,
+            key_type: info.and_then(|i| i.key_type),
+            not_before: info.map(|i| i.not_before.rfc3339().to_string()),
+            not_after: info.map(|i| i.expires.rfc3339().to_string()),
+            days_left: info.map(CertInfo::days_left),
+            must_staple: cert.must_staple,
+            must_staple_present: info.map(|i| i.must_staple),
+            dns_names_added: changes.as_ref().map(|c| c.added.clone()),
+            dns_names_removed: changes.map(|c| c.removed),
+            acme_url: cert.acme_url(&config.acme),
+            issuer: info.and_then(|i| i.issuer.clone()),
+            chain_issuer: info.and_then(|i| i.chain_issuer.clone()),
+            renew_if_days_left: cert.renew_if_days_left(&config.acme),
+            renew_at_lifetime_fraction: match cert.renew_threshold(&config.acme) {
+                RenewThreshold::LifetimeFraction(fraction) => Some(fraction),
+                RenewThreshold::DaysLeft(_) => None,
+            },
+            renewal_due: cert.is_managed()
+                && info
+                    .map(|i| {
+                        renew::renewal_reason(config, cert, i, window.as_ref(), true, false)
+                            .is_some()
+                    })
+                    .unwrap_or(true),
+            renewal_window: window,
+            fullchain,
+            privkey,
+            version: versions.live,
+            older_versions: versions.older,
+            private_key_since: versions
+                .privkey_since
+                .map(|since| since.rfc3339().to_string()),
+            last_attempt: state.last_attempt.map(rfc3339),
+            last_success: state.last_success.map(rfc3339),
+            rate_limited_until: state.rate_limited_until(time::get_time().sec).map(rfc3339),
+            backoff_until: state
+                .backoff_until(time::get_time().sec, config.acme.backoff_after_failures())
+                .map(rfc3339),
+            last_error: state.last_error,
+            consecutive_failures: state.consecutive_failures,
+            pending_hooks: Vec::new(),
+            clock_skew: None,
+            mismatches: Vec::new(),
+            offline: false,
+            details: None,
+        }
+    }
+}
+
+/// Problems with the installed files of a certificate, see
+/// [`cert::verify_installed`]. The key of a watched certificate isn't known.
+fn verify_files(persist: &FilePersist, cert: &CertConfig) -> Result<Vec<String>> {
+    match &cert.watch_cert {
+        Some(path) => {
+            let buf = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+            cert::verify_installed(&buf, None, &cert.ascii_dns_names()?)
+        }
+        None => Ok(persist.verify_live(cert)?.unwrap_or_default()),
+    }
+}
+
+/// The output of `--format plain`, one line per certificate.
+fn print_plain(
+    config: &Config,
+    cert: &CertConfig,
+    info: Option<&CertInfo>,
+    window: Option<&Window>,
+    versions: &Versions,
+    state: &CertState,
+    mismatches: &[String],
+) {
+    let mut name = cert.name.clone();
+    if let Some(group) = &cert.group {
+        name.push_str(&format!(" (part of {})", group));
+    }
+    if cert.staging(&config.acme) {
+        name.push_str(" (staging)");
+    }
+    if !cert.is_enabled() {
+        name.push_str(" (disabled)");
+    } else if cert.monitor_only {
+        name.push_str(" (monitor only)");
+    }
+    let name = name.bold();
+    let threshold = cert.renew_threshold(&config.acme);
+    let must_staple = cert.must_staple;
+    let changes = info.map(|info| DnsNameChanges::new(cert, info));
+    let managed = cert.is_managed();
+    // TODO: also show alt names?
+    if let Some(cert) = info {
+        let seconds_left = cert.seconds_left();
+        let status = format!("{} left", time_left(seconds_left));
+        let status = if !threshold.is_due(cert) {
+            status.green()
+        } else if seconds_left > 0 {
+            status.yellow()
+        } else {
+            status.red()
+        };
+        let key_type = cert
+            .key_type
+            .map(|k| k.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let staple = if must_staple && !cert.must_staple {
+            format!(" {}", "missing must-staple".yellow())
+        } else {
+            String::new()
+        };
+        let changes = match &changes {
+            Some(changes) if !changes.is_empty() => {
+                format!(" {}", format!("dns_names changed: {}", changes).yellow())
+            }
+            _ => String::new(),
+        };
+        let issuer = match &cert.issuer {
+            Some(issuer) => format!(" issued by {:?}", issuer),
+            None => String::new(),
+        };
+        let chain = match &cert.chain_issuer {
+            Some(issuer) => format!(" via {:?}", issuer),
+            None => String::new(),
+        };
+        let version = match &versions.live {
+            Some(live) => format!(" {} ({} older)", live, versions.older),
+            None => String::new(),
+        };
+        let key_age = match versions.privkey_since {
+            Some(since) => format!(" key {} days old", (time::now_utc() - since).num_days()),
+            None => String::new(),
+        };
+        let renew_at = match window {
+            _ if !managed => "not renewed".to_string(),
+            Some(window) => format!("renew from {}", window.start),
+            None => format!("renew at {}", threshold),
+        };
+        println!(
+            "{:50} {:10} {:20} ({}){}{}{}{}{}{}",
+            name, key_type, status, renew_at, issuer, chain, version, key_age, staple, changes
+        );
+    } else if managed {
+        println!("{:50} {:10} {:20} (renew at {})", name, "-", "-", threshold);
+    } else {
+        println!("{:50} {:10} {:20} (not renewed)", name, "-", "-");
+    }
+    for mismatch in mismatches {
+        println!("    {}", format!("BROKEN: {}", mismatch).red().bold());
+    }
+    let backoff_after = config.acme.backoff_after_failures();
+    if let Some(failure) = state.failure_summary(time::get_time().sec, backoff_after) {
+        println!("    {}", failure.red());
+    }
+}
+
+/// The note about a hook that's retried by the next renew.
+fn pending_hook_note(hook: &QueuedHook) -> String {
+    format!(
+        "hook `{}` failed {} time(s), the live certificate might not be in use yet",
+        hook.exec, hook.attempts
+    )
+}
+
+/// The details of a live certificate with `--verbose`.
+fn detail_lines(details: &CertDetails) -> Vec<String> {
+    let mut lines = details
+        .fields()
+        .into_iter()
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect::<Vec<_>>();
+    for sct in &details.sct_list {
+        lines.push(format!("sct: {} {}", sct.log_id, sct.timestamp));
+    }
+    lines
+}
+
+/// The width of the name column, longer names are truncated.
+const NAME_WIDTH: usize = 40;
+
+/// The state column of the table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RowState {
+    Ok,
+    /// Below the renew threshold
+    Due,
+    Expired,
+    /// Not issued yet
+    Missing,
+    /// The live files are broken or the latest renewal failed
+    Error,
+    Disabled,
+    /// Still in the data directory, but not configured anymore
+    Orphaned,
+}
+
+impl RowState {
+    fn as_str(self) -> &'static str {
+        match self {
+            RowState::Ok => "OK",
+            RowState::Due => "due",
+            RowState::Expired => "expired",
+            RowState::Missing => "missing",
+            RowState::Error => "error",
+            RowState::Disabled => "disabled",
+            RowState::Orphaned => "orphaned",
+        }
+    }
+
+    fn colored(self) -> ColoredString {
+        let state = self.as_str();
+        match self {
+            RowState::Ok => state.green(),
+            RowState::Due | RowState::Missing | RowState::Orphaned => state.yellow(),
+            RowState::Expired | RowState::Error => state.red().bold(),
+            RowState::Disabled => state.normal(),
+        }
+    }
+}
+
+/// A line of the table, the rows are printed once all certificates are
+/// loaded so they can be sorted.
+#[derive(Debug)]
+struct TableRow {
+    name: String,
+    dns_names: Option<usize>,
+    expires: Option<time::Tm>,
+    seconds_left: Option<i64>,
+    /// The certificate is below its renew threshold
+    due: bool,
+    state: RowState,
+    /// Printed indented below the row
+    notes: Vec<ColoredString>,
+}
+
+impl TableRow {
+    fn new(
+        config: &Config,
+        cert: &CertConfig,
+        info: Option<&CertInfo>,
+        window: Option<&Window>,
+        state: &CertState,
+        mismatches: &[String],
+    ) -> TableRow {
+        let due = match info {
+            Some(info) if cert.is_managed() => {
+                renew::renewal_reason(config, cert, info, window, true, false).is_some()
+            }
+            Some(info) => cert.renew_threshold(&config.acme).is_due(info),
+            None => false,
+        };
+        let seconds_left = info.map(CertInfo::seconds_left);
+        let now = time::get_time().sec;
+        let row_state = if !cert.is_enabled() {
+            RowState::Disabled
+        } else if info.is_none() {
+            RowState::Missing
+        } else if seconds_left.map(|left| left <= 0).unwrap_or(false) {
+            RowState::Expired
+        } else if !mismatches.is_empty() || state.last_error.is_some() {
+            RowState::Error
+        } else if due {
+            RowState::Due
+        } else {
+            RowState::Ok
+        };
+        let mut notes = mismatches
+            .iter()
+            .map(|mismatch| format!("BROKEN: {}", mismatch).red().bold())
+            .collect::<Vec<_>>();
+        if let Some(failure) = state.failure_summary(now, config.acme.backoff_after_failures()) {
+            notes.push(failure.red());
+        }
+        TableRow {
+            name: cert.name.clone(),
+            dns_names: Some(cert.dns_names.len()),
+            expires: info.map(|info| info.expires),
+            seconds_left,
+            due,
+            state: row_state,
+            notes,
+        }
+    }
+
+    fn orphaned(name: String) -> TableRow {
+        TableRow {
+            name,
+            dns_names: None,
+            expires: None,
+            seconds_left: None,
+            due: false,
+            state: RowState::Orphaned,
+            notes: vec!["not configured anymore".yellow()],
+        }
+    }
+
+    fn print(&self) {
+        let expires = self
+            .expires
+            .map(|expires| expires.strftime("%Y-%m-%d").unwrap().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let left = match self.seconds_left {
+            Some(seconds) if !self.due => time_left(seconds).green(),
+            Some(seconds) if seconds > 0 => time_left(seconds).yellow(),
+            Some(seconds) => time_left(seconds).red(),
+            None => "-".normal(),
+        };
+        let dns_names = self
+            .dns_names
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:name_width$} {:>5} {:10} {:>9} {}",
+            truncate(&self.name, NAME_WIDTH),
+            dns_names,
+            expires,
+            left,
+            self.state.colored(),
+            name_width = NAME_WIDTH
+        );
+        for note in &self.notes {
+            println!("    {}", note);
+        }
+    }
+}
+
+/// Shorten a name to `width` characters with an ellipsis.
+fn truncate(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        name.to_string()
+    } else {
+        let mut name = name.chars().take(width - 1).collect::<String>();
+        name.push('…');
+        name
+    }
+}
+
+fn sort_rows(rows: &mut [TableRow], sort: StatusSort) {
+    match sort {
+        StatusSort::Name => rows.sort_by(|a, b| a.name.cmp(&b.name)),
+        // certificates without an expiry come first, they need attention too
+        StatusSort::DaysLeft => rows.sort_by_key(|row| row.seconds_left),
+        StatusSort::Expiry => {
+            rows.sort_by_key(|row| row.expires.map(|expires| expires.to_timespec()))
+        }
+    }
+}
+
+fn print_rows(rows: &[TableRow]) {
+    let header = format!(
+        "{:name_width$} {:>5} {:10} {:>9} {}",
+        "NAME",
+        "NAMES",
+        "EXPIRES",
+        "LEFT",
+        "STATE",
+        name_width = NAME_WIDTH
+    );
+    println!("{}", header.bold());
+    for row in rows {
+        row.print();
+    }
+}
+
+/// The result of `status --check`, the exit codes follow the conventions of
+/// nagios plugins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Health {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+/// The exit code if the health couldn't be determined.
+const UNKNOWN: i32 = 3;
+
+impl Health {
+    fn as_str(self) -> &'static str {
+        match self {
+            Health::Ok => "OK",
+            Health::Warning => "WARNING",
+            Health::Critical => "CRITICAL",
+        }
+    }
+}
+
+/// What's in the data directory for a certificate.
+#[derive(Debug)]
+enum LiveCert {
+    /// The certificate was never issued
+    Missing,
+    /// The live certificate exists but its files can't be used
+    Broken(String),
+    Present(CertInfo),
+}
+
+impl LiveCert {
+    /// A present certificate is broken if its files don't belong together.
+    fn verify(self, persist: &FilePersist, cert: &CertConfig) -> LiveCert {
+        match self {
+            LiveCert::Present(info) => match verify_files(persist, cert) {
+                Ok(problems) if problems.is_empty() => LiveCert::Present(info),
+                Ok(problems) => LiveCert::Broken(problems.join("; ")),
+                Err(err) => LiveCert::Broken(format!("{:#}", err)),
+            },
+            live => live,
+        }
+    }
+
+    fn watched(path: &Path) -> LiveCert {
+        match load_watched(path, CertInfo::from_pem) {
+            Ok(Some(info)) => LiveCert::Present(info),
+            Ok(None) => LiveCert::Missing,
+            Err(err) => LiveCert::Broken(format!("{:#}", err)),
+        }
+    }
+
+    fn load(persist: &FilePersist, name: &str) -> LiveCert {
+        let version = match persist.live_version(name) {
+            Ok(Some(version)) => version,
+            Ok(None) => return LiveCert::Missing,
+            Err(err) => return LiveCert::Broken(format!("{:#}", err)),
+        };
+        let info = match persist.load_cert_info(name) {
+            Ok(Some(info)) => info,
+            Ok(None) => return LiveCert::Broken(format!("certificate {} is missing", version)),
+            Err(err) => return LiveCert::Broken(format!("certificate can't be read: {:#}", err)),
+        };
+        // the key is usually only readable by root, so don't try to open it
+        let privkey = persist.live_file(name, LiveFile::Privkey);
+        if let Err(err) = fs::symlink_metadata(&privkey) {
+            return LiveCert::Broken(format!("private key can't be found: {}", err));
+        }
+        LiveCert::Present(info)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct CertHealth {
+    health: Health,
+    days_left: Option<i64>,
+    message: String,
+}
+
+fn days(n: i64) -> String {
+    if n == 1 {
+        "1 day".to_string()
+    } else {
+        format!("{} days", n)
+    }
+}
+
+/// Short-lived certificates are shown with hours once less than two days
+/// are left.
+fn time_left(seconds: i64) -> String {
+    let hours = seconds / 3600;
+    if !(0..48).contains(&hours) {
+        days(seconds / (24 * 3600))
+    } else if hours == 1 {
+        "1 hour".to_string()
+    } else {
+        format!("{} hours", hours)
+    }
+}
+
+fn cert_health(
+    name: &str,
+    live: &LiveCert,
+    now: time::Tm,
+    warn_days: i64,
+    crit_days: Option<i64>,
+) -> CertHealth {
+    let (health, days_left, message) = match live {
+        LiveCert::Missing => (
+            Health::Warning,
+            None,
+            format!("{} has no certificate yet", name),
+        ),
+        LiveCert::Broken(err) => (Health::Critical, None, format!("{}: {}", name, err)),
+        LiveCert::Present(info) if info.expires <= now => {
+            let ago = (now - info.expires).num_days();
+            let message = if ago == 0 {
+                format!("{} expired less than a day ago", name)
+            } else {
+                format!("{} expired {} ago", name, days(ago))
+            };
+            (Health::Critical, Some(-ago), message)
+        }
+        LiveCert::Present(info) => {
+            let left = info.expires - now;
+            let days_left = left.num_days();
+            let health = if crit_days.map(|crit| days_left <= crit).unwrap_or(false) {
+                Health::Critical
+            } else if days_left <= warn_days {
+                Health::Warning
             } else {
-                status.red()
+                Health::Ok
             };
-            println!("{:50} {}", name.bold(), status);
+            let message = format!("{} expires in {}", name, time_left(left.num_seconds()));
+            (health, Some(days_left), message)
+        }
+    };
+    CertHealth {
+        health,
+        days_left,
+        message,
+    }
+}
+
+/// A single line about the worst certificate and the overall health.
+fn summarize(results: &[CertHealth]) -> (Health, String) {
+    // certificates without an expiry date are the most urgent
+    let worst = results
+        .iter()
+        .min_by_key(|r| (std::cmp::Reverse(r.health), r.days_left));
+    let worst = match worst {
+        Some(worst) => worst,
+        None => return (Health::Ok, "no certificates configured".to_string()),
+    };
+    if worst.health == Health::Ok {
+        let summary = format!(
+            "{} certificate(s) valid, {} first",
+            results.len(),
+            worst.message
+        );
+        return (Health::Ok, summary);
+    }
+    let others = results.iter().filter(|r| r.health != Health::Ok).count() - 1;
+    let summary = if others > 0 {
+        format!("{} ({} more problem(s))", worst.message, others)
+    } else {
+        worst.message.clone()
+    };
+    (worst.health, summary)
+}
+
+fn check(config: &Config, args: &StatusArgs) -> Result<(Health, String)> {
+    check_access(&config.system.data_dir)?;
+    let now = time::now_utc();
+    let results = config
+        .filter_certs(&args.certs)
+        .into_iter()
+        .filter(|cert| cert.is_enabled())
+        .map(|cert| {
+            let persist = FilePersist::for_cert(config, cert);
+            let live = match &cert.watch_cert {
+                Some(path) => LiveCert::watched(path),
+                None => LiveCert::load(&persist, &cert.name),
+            }
+            .verify(&persist, cert);
+            let threshold = cert.renew_threshold(&config.acme);
+            let warn_days = args.warn_days.unwrap_or_else(|| match (&live, threshold) {
+                (_, RenewThreshold::DaysLeft(days)) => days,
+                (LiveCert::Present(info), threshold) => {
+                    (threshold.remaining(info.lifetime()).as_secs() / (24 * 3600)) as i64
+                }
+                _ => cert.renew_if_days_left(&config.acme),
+            });
+            cert_health(&cert.name, &live, now, warn_days, args.crit_days)
+        })
+        .collect::<Vec<_>>();
+    Ok(summarize(&results))
+}
+
+fn check_access(data_dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    let access = nix::unistd::access(data_dir, AccessFlags::X_OK).map_err(Error::from);
+    #[cfg(not(unix))]
+    let access = std::fs::read_dir(data_dir).map(|_| ()).map_err(Error::from);
+    access.with_context(|| anyhow!("Detected insufficient permissions to access {:?}", data_dir))
+}
+
+/// The live certificates of the data directory as if they were configured
+/// with the dns names they're valid for.
+fn live_certs(config: &Config) -> Result<Vec<CertConfig>> {
+    let mut certs = Vec::new();
+    for (name, staging) in remove::orphaned_certs(config)? {
+        let persist = FilePersist::with_staging(config, staging);
+        let dns_names = match persist.load_cert_info(&name) {
+            Ok(info) => info.map(|info| info.dns_names).unwrap_or_default(),
+            Err(err) => {
+                warn!("{:?}: Failed to read the live certificate: {:#}", name, err);
+                Vec::new()
+            }
+        };
+        certs.push(CertConfig {
+            name,
+            dns_names,
+            staging,
+            ..Default::default()
+        });
+    }
+    Ok(certs)
+}
+
+pub fn run(config: Config, args: StatusArgs) -> Result<()> {
+    show(config, args, false)
+}
+
+/// Show the live certificates of a data directory without the certificate
+/// configs, eg. of a backup on another host. Nothing is sent to the acme
+/// server and checks that need the config are skipped.
+pub fn run_offline(mut config: Config, args: StatusArgs) -> Result<()> {
+    info!(
+        "Config directory {:?} doesn't exist, showing the live certificates in {:?}",
+        config.config_dir, config.system.data_dir
+    );
+    config.certs = live_certs(&config)?;
+    let args = StatusArgs {
+        skip_ari: true,
+        notify: false,
+        ..args
+    };
+    show(config, args, true)
+}
+
+fn show(config: Config, args: StatusArgs, offline: bool) -> Result<()> {
+    if args.check {
+        let code = match check(&config, &args) {
+            Ok((health, summary)) => {
+                println!("{}: {}", health.as_str(), summary);
+                health as i32
+            }
+            Err(err) => {
+                println!("UNKNOWN: {:#}", err);
+                UNKNOWN
+            }
+        };
+        io::stdout().flush()?;
+        process::exit(code);
+    }
+
+    check_access(&config.system.data_dir)?;
+    if args.no_color {
+        colored::control::set_override(false);
+    }
+    if offline && args.format() != StatusFormat::Json {
+        println!(
+            "{}",
+            "Offline: no certificate configs, the dns names are taken from the live certificates and the checks of the config are skipped".yellow()
+        );
+    }
+
+    let mut status = Vec::new();
+    let mut rows = Vec::new();
+    let mut skews = HashMap::new();
+    let hook_queue = hook_queue::load(&config);
+    for cert in config.filter_certs(&args.certs) {
+        let clock_skew = if args.skip_ari {
+            None
+        } else {
+            let url = cert.acme_url(&config.acme);
+            *skews.entry(url).or_insert_with(|| {
+                let skew = clock::measure(&config, url).unwrap_or_else(|err| {
+                    debug!("Failed to check the clock against {:?}: {:#}", url, err);
+                    None
+                });
+                match skew {
+                    Some(skew) if clock::is_excessive(&config, skew) => {
+                        let warning = format!(
+                            "WARNING: {} ({:?}), days left are wrong and renew refuses to run",
+                            clock::describe(skew),
+                            url
+                        );
+                        if args.format() != StatusFormat::Json {
+                            println!("{}", warning.red().bold());
+                        } else {
+                            warn!("{}", warning);
+                        }
+                    }
+                    _ => (),
+                }
+                skew
+            })
+        };
+        let persist = FilePersist::for_cert(&config, cert);
+        let (info, versions, details) = match &cert.watch_cert {
+            Some(path) => (
+                load_watched(path, CertInfo::from_pem)?,
+                Versions::default(),
+                if args.verbose {
+                    load_watched(path, CertDetails::from_pem)?
+                } else {
+                    None
+                },
+            ),
+            None => (
+                persist.load_cert_info(&cert.name)?,
+                Versions::load(&persist, cert)?,
+                if args.verbose {
+                    persist.load_cert_details(&cert.name)?
+                } else {
+                    None
+                },
+            ),
+        };
+        let state = state::load(&persist, &cert.name).unwrap_or_default();
+        let pending_hooks = hook_queue.pending(&cert.name);
+        let mismatches = if info.is_some() {
+            verify_files(&persist, cert).unwrap_or_else(|err| vec![format!("{:#}", err)])
+        } else {
+            Vec::new()
+        };
+        let window = if info.is_none() || args.skip_ari || !cert.is_managed() {
+            None
         } else {
-            println!("{:50} -", name.bold());
+            ari::suggested_window(&config, cert, &persist)
+        };
+        if args.notify && cert.is_enabled() {
+            if let Some(event) = info
+                .as_ref()
+                .and_then(|info| notify::expiry_warning(&config.notify, &cert.name, info))
+            {
+                notify::send(&config.notify, &event);
+            }
+        }
+        match args.format() {
+            StatusFormat::Table => {
+                let mut row = TableRow::new(
+                    &config,
+                    cert,
+                    info.as_ref(),
+                    window.as_ref(),
+                    &state,
+                    &mismatches,
+                );
+                row.notes.extend(
+                    pending_hooks
+                        .iter()
+                        .map(|hook| pending_hook_note(hook).yellow()),
+                );
+                if let Some(details) = &details {
+                    row.notes
+                        .extend(detail_lines(details).into_iter().map(|line| line.normal()));
+                }
+                rows.push(row);
+            }
+            StatusFormat::Plain => {
+                print_plain(
+                    &config,
+                    cert,
+                    info.as_ref(),
+                    window.as_ref(),
+                    &versions,
+                    &state,
+                    &mismatches,
+                );
+                for hook in &pending_hooks {
+                    println!("    {}", pending_hook_note(hook).yellow());
+                }
+                if let Some(details) = &details {
+                    for line in detail_lines(details) {
+                        println!("    {}", line);
+                    }
+                }
+            }
+            StatusFormat::Json => {
+                let mut cert_status = CertStatus::new(
+                    &config,
+                    &persist,
+                    cert,
+                    info.as_ref(),
+                    window,
+                    versions,
+                    state,
+                );
+                cert_status.clock_skew = clock_skew;
+                if offline {
+                    cert_status.offline = true;
+                    cert_status.dns_names_added = None;
+                    cert_status.dns_names_removed = None;
+                }
+                cert_status.mismatches = mismatches;
+                cert_status.pending_hooks = pending_hooks
+                    .iter()
+                    .map(|hook| hook.exec.to_string())
+                    .collect();
+                cert_status.details = details;
+                status.push(cert_status);
+            }
+        }
+    }
+
+    if args.format() == StatusFormat::Json {
+        serde_json::to_writer_pretty(io::stdout(), &status)?;
+        println!();
+    } else if args.format() == StatusFormat::Table {
+        if args.certs.is_empty() {
+            for (name, staging) in remove::orphaned_certs(&config)? {
+                let name = if staging {
+                    format!("{} (staging)", name)
+                } else {
+                    name
+                };
+                rows.push(TableRow::orphaned(name));
+            }
+        }
+        if let Some(sort) = args.sort {
+            sort_rows(&mut rows, sort);
+        }
+        print_rows(&rows);
+    } else if args.certs.is_empty() {
+        for (name, staging) in remove::orphaned_certs(&config)? {
+            let name = if staging {
+                format!("{} {}", name, "(staging)").bold()
+            } else {
+                name.bold()
+            };
+            println!(
+                "{:50} {}",
+                name,
+                "orphaned, not configured anymore".yellow()
+            );
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AcmeConfig, DaemonConfig, SystemConfig};
+
+    #[test]
+    fn test_json_status() {
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig {
+                acme_url: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+                renew_if_days_left: 30,
+                ..Default::default()
+            },
+            system: SystemConfig {
+                data_dir: PathBuf::from("/var/lib/acme-redirect"),
+                ..Default::default()
+            },
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::new(&config);
+        let info = CertInfo {
+            not_before: time::strptime("2020-04-28 23:31:27", "%Y-%m-%d %H:%M:%S").unwrap(),
+            expires: time::strptime("2020-07-27 23:31:27", "%Y-%m-%d %H:%M:%S").unwrap(),
+            key_type: Some(KeyType::EcP384),
+            must_staple: false,
+            issuer: Some("R3".to_string()),
+            chain_issuer: Some("ISRG Root X1".to_string()),
+            dns_names: vec!["example.com".to_string()],
+        };
+
+        let versions = Versions {
+            live: Some("20200428-example.com".to_string()),
+            older: 2,
+            privkey_since: Some(
+                time::strptime("2020-01-29 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+            ),
+        };
+        let state = CertState {
+            last_attempt: Some(1595892687),
+            last_success: Some(1588116687),
+            last_error: Some("DNS problem: NXDOMAIN looking up A for example.com".to_string()),
+            consecutive_failures: 3,
+            retry_after: None,
+        };
+        let status = CertStatus::new(&config, &persist, &cert, Some(&info), None, versions, state);
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "example.com",
+                "group": null,
+                "enabled": true,
+                "monitor_only": false,
+                "watch_cert": null,
+                "staging": false,
+                "dns_names": ["example.com"],
+                "key_type": "ec-p384",
+                "not_before": "2020-04-28T23:31:27Z",
+                "not_after": "2020-07-27T23:31:27Z",
+                "days_left": info.days_left(),
+                "must_staple": false,
+                "must_staple_present": false,
+                "dns_names_added": [],
+                "dns_names_removed": [],
+                "acme_url": "https://acme-v02.api.letsencrypt.org/directory",
+                "issuer": "R3",
+                "chain_issuer": "ISRG Root X1",
+                "renew_if_days_left": 30,
+                "renew_at_lifetime_fraction": null,
+                "renewal_window": null,
+                "renewal_due": true,
+                "fullchain": "/var/lib/acme-redirect/live/example.com/fullchain",
+                "privkey": "/var/lib/acme-redirect/live/example.com/privkey",
+                "version": "20200428-example.com",
+                "older_versions": 2,
+                "private_key_since": "2020-01-29T00:00:00Z",
+                "last_attempt": "2020-07-27T23:31:27Z",
+                "last_success": "2020-04-28T23:31:27Z",
+                "last_error": "DNS problem: NXDOMAIN looking up A for example.com",
+                "consecutive_failures": 3,
+                "rate_limited_until": null,
+                "backoff_until": null,
+                "pending_hooks": [],
+                "clock_skew": null,
+                "mismatches": [],
+                "offline": false,
+            })
+        );
+
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            None,
+            None,
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["not_after"], serde_json::Value::Null);
+        assert_eq!(json["must_staple_present"], serde_json::Value::Null);
+        assert_eq!(json["dns_names_added"], serde_json::Value::Null);
+        assert_eq!(json["renewal_due"], true);
+        assert_eq!(json["last_attempt"], serde_json::Value::Null);
+        assert_eq!(json["consecutive_failures"], 0);
+
+        let internal = CertConfig {
+            acme_url: Some("https://ca.internal/acme/directory".to_string()),
+            ..cert.clone()
+        };
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &internal,
+            None,
+            None,
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["acme_url"], "https://ca.internal/acme/directory");
+
+        let cert = CertConfig {
+            staging: true,
+            ..cert
+        };
+        let persist = FilePersist::for_cert(&config, &cert);
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info),
+            None,
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["staging"], true);
+        assert_eq!(
+            json["fullchain"],
+            "/var/lib/acme-redirect/staging/live/example.com/fullchain"
+        );
+    }
+
+    #[test]
+    fn test_unmanaged_status() {
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string()],
+            enabled: Some(false),
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig {
+                renew_if_days_left: 30,
+                ..Default::default()
+            },
+            system: SystemConfig::default(),
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::new(&config);
+        let now = time::now_utc();
+        let info = CertInfo {
+            not_before: now - time::Duration::days(80),
+            expires: now + time::Duration::days(10),
+            key_type: None,
+            must_staple: false,
+            issuer: None,
+            chain_issuer: None,
+            dns_names: vec!["example.com".to_string()],
+        };
+
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info),
+            None,
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["enabled"], false);
+        assert_eq!(json["renewal_due"], false);
+
+        let cert = CertConfig {
+            enabled: None,
+            monitor_only: true,
+            watch_cert: Some(PathBuf::from("/etc/ssl/example.com/fullchain.pem")),
+            ..cert
+        };
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info),
+            None,
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["enabled"], true);
+        assert_eq!(json["monitor_only"], true);
+        assert_eq!(json["renewal_due"], false);
+        assert_eq!(json["watch_cert"], "/etc/ssl/example.com/fullchain.pem");
+        assert_eq!(json["fullchain"], "/etc/ssl/example.com/fullchain.pem");
+        assert_eq!(json["privkey"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_watched_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fullchain.pem");
+        assert!(matches!(LiveCert::watched(&path), LiveCert::Missing));
+
+        fs::write(&path, "not a certificate").unwrap();
+        assert!(matches!(LiveCert::watched(&path), LiveCert::Broken(_)));
+    }
+
+    fn self_signed(
+        pkey: &openssl::pkey::PKey<openssl::pkey::Private>,
+        dns_names: &[&str],
+    ) -> String {
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::X509;
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(pkey).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        for name in dns_names {
+            san.dns(name);
+        }
+        let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(pkey, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_live_certs() {
+        use crate::acme::{csr, Certificate};
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            certs: vec![],
+            config_dir: PathBuf::from("/etc/acme-redirect.d"),
+            acme: AcmeConfig::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let pkey = csr::create_private_key(KeyType::EcP256).unwrap();
+        let fullcert = Certificate {
+            private_key: String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            fullchain: self_signed(&pkey, &["example.com", "www.example.com"]),
+        };
+        FilePersist::new(&config)
+            .store_cert("example.com", &fullcert)
+            .unwrap();
+        FilePersist::with_staging(&config, true)
+            .store_cert("staging.example.com", &fullcert)
+            .unwrap();
+
+        let certs = live_certs(&config).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].name, "example.com");
+        assert_eq!(certs[0].dns_names, &["example.com", "www.example.com"]);
+        assert!(!certs[0].staging);
+        assert_eq!(certs[1].name, "staging.example.com");
+        assert!(certs[1].staging);
+
+        // the synthesized configs find the live files again
+        let persist = FilePersist::for_cert(&config, &certs[1]);
+        assert!(persist
+            .load_cert_info("staging.example.com")
+            .unwrap()
+            .is_some());
+        assert!(verify_files(&persist, &certs[1]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_mismatched_key() {
+        use crate::acme::{csr, Certificate};
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig::default(),
+            system: SystemConfig {
+                data_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            },
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::new(&config);
+        let pkey = csr::create_private_key(KeyType::EcP256).unwrap();
+        let fullcert = Certificate {
+            private_key: String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+            fullchain: self_signed(&pkey, &["example.com"]),
+        };
+        persist.store_cert("example.com", &fullcert).unwrap();
+
+        let now = time::now_utc();
+        let live = LiveCert::load(&persist, "example.com").verify(&persist, &cert);
+        assert_eq!(
+            cert_health("example.com", &live, now, 30, None).health,
+            Health::Ok
+        );
+        assert!(verify_files(&persist, &cert).unwrap().is_empty());
+
+        let other = csr::create_private_key(KeyType::EcP256).unwrap();
+        fs::write(
+            persist.live_file("example.com", LiveFile::Privkey),
+            other.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let live = LiveCert::load(&persist, "example.com").verify(&persist, &cert);
+        assert_eq!(
+            cert_health("example.com", &live, now, 30, None),
+            CertHealth {
+                health: Health::Critical,
+                days_left: None,
+                message: "example.com: the private key doesn't belong to the certificate"
+                    .to_string(),
+            }
+        );
+
+        // the names are checked after the config changed
+        let cert = CertConfig {
+            dns_names: vec!["example.com".to_string(), "www.example.com".to_string()],
+            ..cert
+        };
+        assert_eq!(
+            verify_files(&persist, &cert).unwrap(),
+            &[
+                "the private key doesn't belong to the certificate",
+                "the certificate isn't valid for \"www.example.com\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_renewal_window() {
+        let cert = CertConfig {
+            name: "example.com".to_string(),
+            dns_names: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let config = Config {
+            certs: vec![cert.clone()],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig {
+                renew_if_days_left: 30,
+                ..Default::default()
+            },
+            system: SystemConfig::default(),
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let persist = FilePersist::new(&config);
+        let now = time::now_utc();
+        let info = |days| CertInfo {
+            not_before: now - time::Duration::days(90 - days),
+            expires: now + time::Duration::days(days),
+            key_type: Some(config.acme.key_type),
+            must_staple: false,
+            issuer: None,
+            chain_issuer: None,
+            dns_names: vec!["example.com".to_string()],
+        };
+        let window = |start: &str| Window {
+            start: start.to_string(),
+            end: "2999-01-07T00:00:00Z".to_string(),
+            explanation_url: Some("https://example.com/incident".to_string()),
+        };
+
+        // the window started, even though the threshold isn't reached yet
+        let started = window("2021-01-03T00:00:00Z");
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info(60)),
+            Some(started),
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["renewal_due"], true);
+        assert_eq!(
+            json["renewal_window"],
+            serde_json::json!({
+                "start": "2021-01-03T00:00:00Z",
+                "end": "2999-01-07T00:00:00Z",
+                "explanation_url": "https://example.com/incident",
+            })
+        );
+
+        // the CA doesn't want the cert to be renewed yet
+        let future = window("2999-01-03T00:00:00Z");
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info(10)),
+            Some(future),
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["renewal_due"], false);
+
+        // changed dns names don't wait for the window
+        let cert = CertConfig {
+            dns_names: vec!["example.com".to_string(), "api.example.com".to_string()],
+            ..cert
+        };
+        let future = window("2999-01-03T00:00:00Z");
+        let status = CertStatus::new(
+            &config,
+            &persist,
+            &cert,
+            Some(&info(60)),
+            Some(future),
+            Versions::default(),
+            CertState::default(),
+        );
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["renewal_due"], true);
+        assert_eq!(
+            json["dns_names_added"],
+            serde_json::json!(["api.example.com"])
+        );
+        assert_eq!(json["dns_names_removed"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_check_health() {
+        let now = time::now_utc();
+        let live = |days| {
+            LiveCert::Present(CertInfo {
+                not_before: now - time::Duration::days(90 - days),
+                expires: now + time::Duration::days(days) + time::Duration::hours(1),
+                key_type: None,
+                must_staple: false,
+                issuer: None,
+                chain_issuer: None,
+                dns_names: vec!["example.com".to_string()],
+            })
+        };
+        let health = |live: &LiveCert, crit| cert_health("example.com", live, now, 30, crit);
+
+        assert_eq!(health(&live(31), None).health, Health::Ok);
+        assert_eq!(
+            health(&live(30), None),
+            CertHealth {
+                health: Health::Warning,
+                days_left: Some(30),
+                message: "example.com expires in 30 days".to_string(),
+            }
+        );
+        assert_eq!(health(&live(0), None).health, Health::Warning);
+        assert_eq!(
+            health(&live(0), None).message,
+            "example.com expires in 1 hour"
+        );
+        assert_eq!(health(&live(7), Some(7)).health, Health::Critical);
+        assert_eq!(
+            health(&live(-4), None),
+            CertHealth {
+                health: Health::Critical,
+                days_left: Some(-3),
+                message: "example.com expired 3 days ago".to_string(),
+            }
+        );
+        assert_eq!(
+            health(&LiveCert::Missing, Some(7)).message,
+            "example.com has no certificate yet"
+        );
+        assert_eq!(health(&LiveCert::Missing, Some(7)).health, Health::Warning);
+        let broken = LiveCert::Broken("private key can't be found".to_string());
+        assert_eq!(health(&broken, None).health, Health::Critical);
+    }
+
+    #[test]
+    fn test_table_rows() {
+        let config = Config {
+            certs: vec![],
+            config_dir: PathBuf::new(),
+            acme: AcmeConfig {
+                renew_if_days_left: 30,
+                ..Default::default()
+            },
+            system: SystemConfig::default(),
+            daemon: DaemonConfig::default(),
+            notify: Default::default(),
+        };
+        let cert = |name: &str| CertConfig {
+            name: name.to_string(),
+            dns_names: vec![name.to_string(), format!("www.{}", name)],
+            ..Default::default()
+        };
+        let now = time::now_utc();
+        let info = |days| CertInfo {
+            not_before: now - time::Duration::days(90 - days),
+            expires: now + time::Duration::days(days) + time::Duration::hours(1),
+            key_type: Some(config.acme.key_type),
+            must_staple: false,
+            issuer: None,
+            chain_issuer: None,
+            dns_names: vec!["example.com".to_string(), "www.example.com".to_string()],
+        };
+        let row = |info: Option<&CertInfo>, state: &CertState, mismatches: &[String]| {
+            TableRow::new(&config, &cert("example.com"), info, None, state, mismatches)
+        };
+        let ok = CertState::default();
+
+        let valid = row(Some(&info(60)), &ok, &[]);
+        assert_eq!(valid.state, RowState::Ok);
+        assert_eq!(valid.dns_names, Some(2));
+        assert!(!valid.due);
+        assert!(valid.notes.is_empty());
+        assert_eq!(row(Some(&info(10)), &ok, &[]).state, RowState::Due);
+        assert_eq!(row(Some(&info(-3)), &ok, &[]).state, RowState::Expired);
+        assert_eq!(row(None, &ok, &[]).state, RowState::Missing);
+        let broken = row(
+            Some(&info(60)),
+            &ok,
+            &["the private key doesn't belong to the certificate".to_string()],
+        );
+        assert_eq!(broken.state, RowState::Error);
+        assert_eq!(broken.notes.len(), 1);
+        let failed = CertState {
+            last_attempt: Some(time::get_time().sec),
+            last_error: Some("DNS problem: NXDOMAIN".to_string()),
+            consecutive_failures: 1,
+            ..Default::default()
+        };
+        assert_eq!(row(Some(&info(10)), &failed, &[]).state, RowState::Error);
+        let disabled = CertConfig {
+            enabled: Some(false),
+            ..cert("example.com")
+        };
+        assert_eq!(
+            TableRow::new(&config, &disabled, None, None, &ok, &[]).state,
+            RowState::Disabled
+        );
+
+        let mut rows = vec![
+            TableRow {
+                name: "b.example.com".to_string(),
+                ..row(Some(&info(60)), &ok, &[])
+            },
+            TableRow::orphaned("c.example.com".to_string()),
+            TableRow {
+                name: "a.example.com".to_string(),
+                ..row(Some(&info(10)), &ok, &[])
+            },
+        ];
+        let names = |rows: &[TableRow]| rows.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+        sort_rows(&mut rows, StatusSort::Name);
+        assert_eq!(
+            names(&rows),
+            &["a.example.com", "b.example.com", "c.example.com"]
+        );
+        sort_rows(&mut rows, StatusSort::DaysLeft);
+        assert_eq!(
+            names(&rows),
+            &["c.example.com", "a.example.com", "b.example.com"]
+        );
+        sort_rows(&mut rows, StatusSort::Expiry);
+        assert_eq!(
+            names(&rows),
+            &["c.example.com", "a.example.com", "b.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("example.com", 11), "example.com");
+        assert_eq!(truncate("www.example.com", 11), "www.exampl…");
+        assert_eq!(truncate("bücher.example", 6), "büche…");
+    }
+
+    #[test]
+    fn test_time_left() {
+        assert_eq!(time_left(3 * 24 * 3600 + 60), "3 days");
+        assert_eq!(time_left(2 * 24 * 3600), "2 days");
+        assert_eq!(time_left(47 * 3600 + 3599), "47 hours");
+        assert_eq!(time_left(3600), "1 hour");
+        assert_eq!(time_left(59), "0 hours");
+        assert_eq!(time_left(-2 * 24 * 3600), "-2 days");
+    }
+
+    #[test]
+    fn test_check_summary() {
+        let result = |health, days_left: Option<i64>, name: &str| CertHealth {
+            health,
+            days_left,
+            message: format!("{} {:?}", name, days_left),
+        };
+        assert_eq!(
+            summarize(&[]),
+            (Health::Ok, "no certificates configured".to_string())
+        );
+        assert_eq!(
+            summarize(&[
+                result(Health::Ok, Some(60), "a"),
+                result(Health::Ok, Some(45), "b")
+            ]),
+            (
+                Health::Ok,
+                "2 certificate(s) valid, b Some(45) first".to_string()
+            )
+        );
+        assert_eq!(
+            summarize(&[
+                result(Health::Warning, Some(20), "a"),
+                result(Health::Ok, Some(60), "b"),
+                result(Health::Warning, Some(10), "c"),
+            ]),
+            (
+                Health::Warning,
+                "c Some(10) (1 more problem(s))".to_string()
+            )
+        );
+        assert_eq!(
+            summarize(&[
+                result(Health::Warning, None, "a"),
+                result(Health::Critical, Some(-3), "b"),
+                result(Health::Critical, None, "c"),
+            ]),
+            (Health::Critical, "c None (2 more problem(s))".to_string())
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Socket activation and service notifications for systemd, see sd_listen_fds(3)
+//! and sd_notify(3). On other platforms there are never any sockets passed
+//! and notifications aren't sent.
+use crate::errors::*;
+#[cfg(target_os = "linux")]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{self, SockType};
+use std::env;
+use std::net::TcpListener;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{self as unix, UnixDatagram};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket that was passed to us by the service manager.
+pub enum ListenFd {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(target_os = "linux")]
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<usize> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+    let listen_pid = listen_pid
+        .parse::<u32>()
+        .context("LISTEN_PID is not a valid pid")?;
+    if listen_pid != pid {
+        // the variables were meant for a different process
+        return Ok(0);
+    }
+    listen_fds
+        .parse::<usize>()
+        .context("LISTEN_FDS is not a valid number")
+}
+
+#[cfg(target_os = "linux")]
+fn listen_fd(fd: RawFd) -> Result<ListenFd> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    if socket::getsockopt(fd, socket::sockopt::SockType)? != SockType::Stream {
+        bail!("Socket is not a stream socket");
+    }
+    if !socket::getsockopt(fd, socket::sockopt::AcceptConn)? {
+        bail!("Socket is not listening");
+    }
+    // std refuses to return the local address if the address family doesn't match
+    let socket = unsafe { TcpListener::from_raw_fd(fd) };
+    if socket.local_addr().is_ok() {
+        return Ok(ListenFd::Tcp(socket));
+    }
+    let socket = unsafe { UnixListener::from_raw_fd(socket.into_raw_fd()) };
+    socket
+        .local_addr()
+        .map_err(|_| anyhow!("Unsupported address family"))?;
+    Ok(ListenFd::Unix(socket))
+}
+
+/// Take the sockets passed with LISTEN_FDS, if any. The variables are
+/// removed from the environment so they aren't inherited by hooks.
+#[cfg(target_os = "linux")]
+pub fn listen_fds() -> Result<Vec<ListenFd>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let n = parse_listen_fds(
+        listen_pid.as_deref(),
+        listen_fds.as_deref(),
+        std::process::id(),
+    )?;
+    (LISTEN_FDS_START..)
+        .take(n)
+        .map(|fd| listen_fd(fd).with_context(|| anyhow!("Invalid socket passed as fd {}", fd)))
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen_fds() -> Result<Vec<ListenFd>> {
+    Ok(Vec::new())
+}
+
+/// Connection to the notification socket of the service manager.
+#[derive(Debug)]
+pub struct Notifier {
+    #[cfg(target_os = "linux")]
+    socket: UnixDatagram,
+}
+
+impl Notifier {
+    /// Connect to NOTIFY_SOCKET if it's set. This needs to happen before
+    /// chrooting, the socket is usually outside of the chroot.
+    #[cfg(target_os = "linux")]
+    pub fn open() -> Result<Option<Notifier>> {
+        let path = match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        env::remove_var("NOTIFY_SOCKET");
+        let notifier = Notifier::connect(Path::new(&path))
+            .with_context(|| anyhow!("Failed to connect to notify socket: {:?}", path))?;
+        Ok(Some(notifier))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open() -> Result<Option<Notifier>> {
+        Ok(None)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn connect(path: &Path) -> Result<Notifier> {
+        let addr = match path.as_os_str().as_bytes().strip_prefix(b"@") {
+            Some(name) => unix::SocketAddr::from_abstract_name(name)?,
+            None => unix::SocketAddr::from_pathname(path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&addr)?;
+        Ok(Notifier { socket })
+    }
+
+    pub fn notify(&self, state: &str) {
+        debug!("Sending service notification: {:?}", state);
+        #[cfg(target_os = "linux")]
+        if let Err(err) = self.socket.send(state.as_bytes()) {
+            warn!("Failed to send service notification: {}", err);
+        }
+    }
+}
+
+/// The interval for WATCHDOG=1 notifications if the watchdog is enabled for
+/// this process, half of the configured timeout.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(None, None, 1234).unwrap(), 0);
+        assert_eq!(parse_listen_fds(Some("1234"), None, 1234).unwrap(), 0);
+        assert_eq!(parse_listen_fds(Some("1234"), Some("2"), 1234).unwrap(), 2);
+        assert_eq!(parse_listen_fds(Some("4321"), Some("2"), 1234).unwrap(), 0);
+        assert!(parse_listen_fds(Some("1234"), Some("x"), 1234).is_err());
+        assert!(parse_listen_fds(Some("x"), Some("2"), 1234).is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::connect(&path).unwrap();
+        notifier.notify("READY=1");
+
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
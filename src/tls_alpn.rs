@@ -0,0 +1,259 @@
+//! tls-alpn-01 challenges (RFC 8737). The proof is a self signed certificate
+//! for the dns name that is presented during a tls handshake on port 443 with
+//! the `acme-tls/1` protocol. The pem of the certificate and its key are
+//! stored like http-01 proofs, under a token derived from the dns name since
+//! the handshake only tells us the SNI name.
+use crate::acme::jws;
+use crate::chall::Token;
+use crate::errors::*;
+use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{self, AlpnError, NameType, SniError, SslAcceptor, SslMethod, SslRef};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Extension, X509Name, X509};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The protocol name in wire format, prefixed with its length.
+const ACME_TLS_ALPN: &[u8] = b"\x0aacme-tls/1";
+/// id-pe-acmeIdentifier
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+/// Proofs of tls-alpn-01 can't be confused with acme tokens, this prefix
+/// isn't base64 of a sha256 hash.
+const TOKEN_PREFIX: &str = "tls-alpn-01-";
+/// The challenge certificate only needs to be valid during the validation.
+const CERT_DAYS: u32 = 7;
+/// Connections beyond this are closed right away.
+const MAX_CONNECTIONS: usize = 32;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The token the challenge certificate of a dns name is stored under.
+pub fn token(dns_name: &str) -> Token {
+    let hash = openssl::sha::sha256(dns_name.to_ascii_lowercase().as_bytes());
+    let token = format!("{}{}", TOKEN_PREFIX, jws::base64url(hash));
+    Token::parse(&token).expect("tls-alpn-01 token is always valid")
+}
+
+/// The http-01 handler must not hand out the key of challenge certificates.
+pub fn is_token(token: &Token) -> bool {
+    token.as_str().starts_with(TOKEN_PREFIX)
+}
+
+/// Create the challenge certificate for a dns name, it's returned as pem of
+/// the private key followed by the certificate.
+pub fn challenge_cert(dns_name: &str, key_authorization: &str) -> Result<String> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_text("CN", dns_name)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(127, openssl::bn::MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = Asn1Integer::from_bn(&serial)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERT_DAYS)?;
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+
+    let san = SubjectAlternativeName::new()
+        .dns(dns_name)
+        .build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(san)?;
+
+    // the extension value is a DER encoded OCTET STRING of the hash
+    let hash = openssl::sha::sha256(key_authorization.as_bytes());
+    let mut value = vec![0x04, hash.len() as u8];
+    value.extend_from_slice(&hash);
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID)?;
+    let value = Asn1OctetString::new_from_bytes(&value)?;
+    cert.append_extension(X509Extension::new_from_der(&oid, true, &value)?)?;
+
+    cert.sign(&key, MessageDigest::sha256())?;
+    let cert = cert.build();
+
+    let mut pem = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+    pem.push_str(&String::from_utf8(cert.to_pem()?)?);
+    Ok(pem)
+}
+
+/// Use the challenge certificate of the SNI name, handshakes for names
+/// without a challenge fail since there's no other certificate.
+fn select_cert<F>(ssl: &mut SslRef, lookup: &F) -> Result<(), SniError>
+where
+    F: Fn(&Token) -> Option<Vec<u8>>,
+{
+    let dns_name = ssl
+        .servername(NameType::HOST_NAME)
+        .ok_or(SniError::ALERT_FATAL)?
+        .to_string();
+    info!("tls-alpn-01: {:?}", dns_name);
+
+    let pem = match lookup(&token(&dns_name)) {
+        Some(pem) => pem,
+        None => {
+            debug!("No tls-alpn-01 challenge for {:?}", dns_name);
+            return Err(SniError::ALERT_FATAL);
+        }
+    };
+    let loaded = PKey::private_key_from_pem(&pem)
+        .and_then(|key| ssl.set_private_key(&key))
+        .and_then(|_| X509::from_pem(&pem))
+        .and_then(|cert| ssl.set_certificate(&cert));
+    if let Err(err) = loaded {
+        warn!("Invalid tls-alpn-01 challenge for {:?}: {}", dns_name, err);
+        return Err(SniError::ALERT_FATAL);
+    }
+    Ok(())
+}
+
+/// Normal https handshakes are rejected, only acme-tls/1 is spoken here.
+fn select_alpn(client: &[u8]) -> Result<&[u8], AlpnError> {
+    ssl::select_next_proto(ACME_TLS_ALPN, client).ok_or_else(|| {
+        debug!("Rejecting tls handshake without acme-tls/1");
+        AlpnError::ALERT_FATAL
+    })
+}
+
+fn handshake(acceptor: &SslAcceptor, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut stream = acceptor.accept(stream)?;
+    // the validation is done after the handshake
+    stream.shutdown().ok();
+    Ok(())
+}
+
+/// Answer tls-alpn-01 challenges on a socket in a background thread, the
+/// challenge certificates are read with `lookup`.
+pub fn spawn<F>(socket: TcpListener, lookup: F) -> Result<()>
+where
+    F: Fn(&Token) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_servername_callback(move |ssl, _alert| select_cert(ssl, &lookup));
+    acceptor.set_alpn_select_callback(|_ssl, client| select_alpn(client));
+    let acceptor = Arc::new(acceptor.build());
+    let active = Arc::new(AtomicUsize::new(0));
+
+    thread::Builder::new()
+        .name("tls-alpn-01".to_string())
+        .spawn(move || {
+            for stream in socket.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept tls-alpn-01 connection: {}", err);
+                        continue;
+                    }
+                };
+                if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    debug!("Too many tls-alpn-01 connections, closing");
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let acceptor = acceptor.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handshake(&acceptor, stream) {
+                        debug!("tls-alpn-01 handshake failed: {:#}", err);
+                    }
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use std::collections::HashMap;
+
+    const KEY_AUTH: &str =
+        "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA.9jg46WB3rR_AHD-EBXdN7cBkH1WOu0tA3M9fm21mqTI";
+
+    fn acme_identifier(cert: &X509) -> Option<Vec<u8>> {
+        let der = cert.to_der().unwrap();
+        // 06 08 <oid> 01 01 ff 04 22 04 20 <hash>
+        let oid = [0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+        let pos = der.windows(oid.len()).position(|w| w == oid)?;
+        let rest = &der[pos + oid.len()..];
+        assert_eq!(&rest[..7], &[0x01, 0x01, 0xff, 0x04, 0x22, 0x04, 0x20]);
+        Some(rest[7..39].to_vec())
+    }
+
+    fn connect(port: u16, name: &str, alpn: &[u8]) -> Result<X509> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(alpn)?;
+        let connector = connector.build();
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        let stream = connector.connect(name, stream)?;
+        assert_eq!(
+            stream.ssl().selected_alpn_protocol(),
+            Some(&b"acme-tls/1"[..])
+        );
+        Ok(stream.ssl().peer_certificate().unwrap())
+    }
+
+    #[test]
+    fn test_token() {
+        let token = token("Example.com");
+        assert!(is_token(&token));
+        assert_eq!(token, super::token("example.com"));
+        assert_ne!(token, super::token("example.org"));
+        assert!(!is_token(&Token::parse(&"a".repeat(43)).unwrap()));
+    }
+
+    #[test]
+    fn test_challenge_cert() {
+        let pem = challenge_cert("example.com", KEY_AUTH).unwrap();
+        assert!((pem.len() as u64) < crate::chall::MAX_PROOF_SIZE);
+        PKey::private_key_from_pem(pem.as_bytes()).unwrap();
+        let cert = X509::from_pem(pem.as_bytes()).unwrap();
+        let names = cert.subject_alt_names().unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.get(0).unwrap().dnsname(), Some("example.com"));
+        assert_eq!(
+            acme_identifier(&cert).unwrap(),
+            openssl::sha::sha256(KEY_AUTH.as_bytes()).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_listener() {
+        let mut challs = HashMap::new();
+        let pem = challenge_cert("example.com", KEY_AUTH).unwrap();
+        challs.insert(token("example.com").as_str().to_string(), pem.into_bytes());
+
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        spawn(socket, move |token| challs.get(token.as_str()).cloned()).unwrap();
+
+        let cert = connect(port, "example.com", ACME_TLS_ALPN).unwrap();
+        assert!(acme_identifier(&cert).is_some());
+
+        // no challenge for this name
+        assert!(connect(port, "example.org", ACME_TLS_ALPN).is_err());
+        // normal https
+        assert!(connect(port, "example.com", b"\x08http/1.1").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Find the keys of a config file that are silently ignored while loading
+//! it, like typos of option names. The document is deserialized into the
+//! config type once more and every value the type skips is recorded.
+use crate::errors::*;
+use serde::de::value::Error;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::cell::RefCell;
+use toml::Value;
+
+struct ValueDeserializer<'a> {
+    value: &'a Value,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'a> ValueDeserializer<'a> {
+    fn child(&self, value: &'a Value, path: String) -> ValueDeserializer<'a> {
+        ValueDeserializer {
+            value,
+            path,
+            unknown: self.unknown,
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(s) => visitor.visit_str(s),
+            Value::Integer(i) => visitor.visit_i64(*i),
+            Value::Float(f) => visitor.visit_f64(*f),
+            Value::Boolean(b) => visitor.visit_bool(*b),
+            Value::Datetime(dt) => visitor.visit_string(dt.to_string()),
+            Value::Array(values) => visitor.visit_seq(SeqAccess {
+                parent: &self,
+                iter: values.iter().enumerate(),
+            }),
+            Value::Table(table) => visitor.visit_map(MapAccess {
+                parent: &self,
+                iter: table.iter(),
+                next: None,
+            }),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    // the derived code skips values of unknown fields with this
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.unknown.borrow_mut().push(self.path);
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier
+    }
+}
+
+struct SeqAccess<'a, 'b> {
+    parent: &'b ValueDeserializer<'a>,
+    iter: std::iter::Enumerate<std::slice::Iter<'a, Value>>,
+}
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for SeqAccess<'a, 'b> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some((idx, value)) => {
+                let path = format!("{}[{}]", self.parent.path, idx);
+                seed.deserialize(self.parent.child(value, path)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+struct MapAccess<'a, 'b> {
+    parent: &'b ValueDeserializer<'a>,
+    iter: toml::map::Iter<'a>,
+    next: Option<(&'a String, &'a Value)>,
+}
+
+impl<'de, 'a, 'b> de::MapAccess<'de> for MapAccess<'a, 'b> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.next = Some((key, value));
+                seed.deserialize(key.as_str().into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self
+            .next
+            .take()
+            .ok_or_else(|| de::Error::custom("value is missing"))?;
+        let path = if self.parent.path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", self.parent.path, key)
+        };
+        seed.deserialize(self.parent.child(value, path))
+    }
+}
+
+/// The dotted paths of all keys in `value` that `T` ignores. Keys are
+/// compared case sensitively. Values that can't be deserialized are
+/// reported by the regular loading, keys after them aren't checked.
+pub fn find<T: DeserializeOwned>(value: &Value) -> Vec<String> {
+    let unknown = RefCell::new(Vec::new());
+    let deserializer = ValueDeserializer {
+        value,
+        path: String::new(),
+        unknown: &unknown,
+    };
+    if let Err(err) = T::deserialize(deserializer) {
+        debug!("Stopped looking for unknown config keys: {}", err);
+    }
+    unknown.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CertConfigFile, ConfigFile};
+
+    fn unknown<T: DeserializeOwned>(s: &str) -> Vec<String> {
+        find::<T>(&s.parse::<Value>().unwrap())
+    }
+
+    #[test]
+    fn test_no_unknown_keys() {
+        assert!(unknown::<ConfigFile>(
+            r#"
+            [acme]
+            acme_email = "certs@example.com"
+            key_type = "rsa2048"
+            order_timeout = 300
+
+            [system]
+            chall_dirs = ["/var/lib/acme-redirect/chall"]
+            keep_versions = 3
+
+            [daemon]
+            redirect_code = 301
+            headers = { Strict-Transport-Security = "max-age=31536000" }
+        "#
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_nested_unknown_keys() {
+        assert_eq!(
+            unknown::<ConfigFile>(
+                r#"
+                verbose = true
+
+                [acme]
+                renew_if_days_leftt = 10
+
+                [system]
+                exec = ["systemctl reload nginx"]
+                exec_extraa = []
+
+                [daemon]
+                redirect_code = 301
+                foo = { bar = 1 }
+
+                [acmee]
+                acme_email = "certs@example.com"
+            "#
+            ),
+            &[
+                "acme.renew_if_days_leftt",
+                "acmee",
+                "daemon.foo",
+                "system.exec_extraa",
+                "verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_case_sensitive_keys() {
+        assert_eq!(
+            unknown::<CertConfigFile>(
+                r#"
+                [cert]
+                name = "example.com"
+                dns_names = ["example.com"]
+                Renew_If_Days_Left = 10
+                exec = ["systemctl reload nginx"]
+            "#
+            ),
+            &["cert.Renew_If_Days_Left"]
+        );
+        assert_eq!(
+            unknown::<CertConfigFile>("[Cert]\nname = \"example.com\"\n"),
+            &["Cert"]
+        );
+    }
+}
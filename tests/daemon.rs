@@ -0,0 +1,130 @@
+//! Run the daemon and talk to it like a misbehaving client.
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn free_addr() -> String {
+    let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().to_string()
+}
+
+fn start(dir: &Path, args: &[&str]) -> (Daemon, String) {
+    let chall_dir = dir.join("chall");
+    let config_dir = dir.join("certs.d");
+    fs::create_dir_all(&chall_dir).unwrap();
+    fs::create_dir_all(&config_dir).unwrap();
+    let config = dir.join("acme-redirect.conf");
+    fs::write(
+        &config,
+        format!(
+            "[acme]\nacme_email = \"nobody@example.com\"\n[system]\ndata_dir = {:?}\nchall_dir = {:?}\n",
+            dir, chall_dir
+        ),
+    )
+    .unwrap();
+
+    let addr = free_addr();
+    let daemon = Command::new(env!("CARGO_BIN_EXE_acme-redirect"))
+        .arg("-c")
+        .arg(&config)
+        .arg("--config-dir")
+        .arg(&config_dir)
+        .args(["daemon", "-B", &addr])
+        .args(args)
+        .spawn()
+        .unwrap();
+    let daemon = Daemon(daemon);
+
+    let start = Instant::now();
+    while TcpStream::connect(&addr).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "daemon didn't start listening on {}",
+            addr
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    (daemon, addr)
+}
+
+/// Send `req` and read until the daemon closes the connection.
+fn send(addr: &str, req: &[u8]) -> (String, Duration) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let start = Instant::now();
+    stream.write_all(req).unwrap();
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).unwrap();
+    (String::from_utf8_lossy(&res).into_owned(), start.elapsed())
+}
+
+#[test]
+fn test_slow_client_disconnected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_daemon, addr) = start(
+        dir.path(),
+        &["--client-request-timeout", "1", "--keep-alive", "1"],
+    );
+
+    // nothing is sent at all
+    let (res, elapsed) = send(&addr, b"");
+    assert!(res.starts_with("HTTP/1.1 408"), "{:?}", res);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+    // the request is never finished
+    let (res, elapsed) = send(&addr, b"GET / HTTP/1.1\r\nHost: exa");
+    assert!(res.starts_with("HTTP/1.1 408"), "{:?}", res);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+}
+
+#[test]
+fn test_slow_client_without_keep_alive() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_daemon, addr) = start(
+        dir.path(),
+        &["--client-request-timeout", "1", "--keep-alive", "0"],
+    );
+
+    let (res, elapsed) = send(&addr, b"GET / HTTP/1.1\r\nHost: exa");
+    assert!(res.starts_with("HTTP/1.1 408"), "{:?}", res);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+    // the connection is closed after the response
+    let (res, elapsed) = send(&addr, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+    assert!(res.starts_with("HTTP/1.1 301"), "{:?}", res);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+}
+
+#[test]
+fn test_large_headers_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_daemon, addr) = start(
+        dir.path(),
+        &["--max-header-size", "1024", "--keep-alive", "0"],
+    );
+
+    let (res, _) = send(&addr, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+    assert!(res.starts_with("HTTP/1.1 301"), "{:?}", res);
+
+    let req = format!(
+        "GET / HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\n\r\n",
+        "a".repeat(2048)
+    );
+    let (res, _) = send(&addr, req.as_bytes());
+    assert!(res.starts_with("HTTP/1.1 431"), "{:?}", res);
+}
@@ -0,0 +1,119 @@
+//! Issue a certificate from a pebble test server, with the daemon answering
+//! the http-01 challenge. This only runs if PEBBLE_URL is set:
+//!
+//! ```text
+//! docker run -d --net=host ghcr.io/letsencrypt/pebble-challtestsrv -defaultIPv4 127.0.0.1
+//! docker run -d --net=host -e PEBBLE_VA_NOSLEEP=1 ghcr.io/letsencrypt/pebble -dnsserver 127.0.0.1:8053
+//! PEBBLE_URL=https://localhost:14000/dir cargo test --test pebble
+//! ```
+//!
+//! PEBBLE_CA_CERT can point to pebble's https certificate, otherwise its
+//! certificate isn't verified. PEBBLE_HTTP_PORT is the port pebble connects
+//! to for http-01 (5002 by default) and PEBBLE_DNS_NAME the name that's
+//! requested (pebble.test by default).
+#![cfg(unix)]
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+fn wait_for(addr: &str) {
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "daemon didn't start listening on {}",
+            addr
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_pebble() {
+    let acme_url = match env::var("PEBBLE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("PEBBLE_URL is not set, skipping");
+            return;
+        }
+    };
+    let http_port = env::var("PEBBLE_HTTP_PORT").unwrap_or_else(|_| "5002".to_string());
+    let dns_name = env::var("PEBBLE_DNS_NAME").unwrap_or_else(|_| "pebble.test".to_string());
+    let tls = match env::var("PEBBLE_CA_CERT") {
+        Ok(path) => format!("acme_ca_cert = {:?}\n", path),
+        Err(_) => "accept_invalid_certs = true\n".to_string(),
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    let chall_dir = dir.path().join("chall");
+    let config_dir = dir.path().join("certs.d");
+    fs::create_dir(&data_dir).unwrap();
+    fs::create_dir(&chall_dir).unwrap();
+    fs::create_dir(&config_dir).unwrap();
+
+    let config = dir.path().join("acme-redirect.conf");
+    fs::write(
+        &config,
+        format!(
+            "[acme]\nacme_email = \"nobody@example.com\"\nacme_url = {:?}\n{}\n[system]\ndata_dir = {:?}\nchall_dir = {:?}\n",
+            acme_url, tls, data_dir, chall_dir
+        ),
+    )
+    .unwrap();
+    fs::write(
+        config_dir.join("pebble.conf"),
+        format!(
+            "[cert]\nname = {:?}\ndns_names = [{:?}]\n",
+            dns_name, dns_name
+        ),
+    )
+    .unwrap();
+
+    let cmd = || {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_acme-redirect"));
+        cmd.arg("-c")
+            .arg(&config)
+            .arg("--config-dir")
+            .arg(&config_dir);
+        cmd
+    };
+
+    let bind_addr = format!("127.0.0.1:{}", http_port);
+    let _daemon = Daemon(cmd().args(["daemon", "-B", &bind_addr]).spawn().unwrap());
+    wait_for(&bind_addr);
+
+    let status = cmd().arg("renew").status().unwrap();
+    assert!(status.success());
+
+    let live = data_dir.join("live").join(&dns_name);
+    for file in &["privkey", "bundle"] {
+        assert_eq!(mode(&live.join(file)), 0o640, "{}", file);
+    }
+    for file in &["fullchain", "chain", "cert"] {
+        assert_eq!(mode(&live.join(file)), 0o644, "{}", file);
+    }
+    assert_eq!(mode(&data_dir.join("acc.key")), 0o600);
+
+    // the second run doesn't need a new certificate
+    let status = cmd().arg("renew").status().unwrap();
+    assert!(status.success());
+}
@@ -0,0 +1,319 @@
+//! Issue a certificate through the library api against a mocked acme server.
+use acme_redirect::config::{AcmeConfig, SystemConfig};
+use acme_redirect::{renew_cert, CertConfig, Config, PersistState, RenewOutcome};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Name, X509Req, X509};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const TOKEN: &str = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+
+struct Ca {
+    key: PKey<Private>,
+    cert: X509,
+}
+
+impl Ca {
+    fn new() -> Ca {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "mock acme ca").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(365).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        Ca {
+            key,
+            cert: cert.build(),
+        }
+    }
+
+    fn sign(&self, csr: &[u8], dns_names: &[String]) -> String {
+        let csr = X509Req::from_der(csr).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(csr.subject_name()).unwrap();
+        cert.set_issuer_name(self.cert.subject_name()).unwrap();
+        cert.set_pubkey(&csr.public_key().unwrap()).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        for name in dns_names {
+            san.dns(name);
+        }
+        let san = san.build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&self.key, MessageDigest::sha256()).unwrap();
+
+        let leaf = cert.build().to_pem().unwrap();
+        let ca = self.cert.to_pem().unwrap();
+        String::from_utf8([leaf, ca].concat()).unwrap()
+    }
+}
+
+struct State {
+    chall_dir: PathBuf,
+    ca: Ca,
+    dns_names: Vec<String>,
+    orders: usize,
+    validated: bool,
+    fullchain: Option<String>,
+}
+
+fn response(status: &str, headers: &[(&str, String)], body: &str) -> String {
+    let mut res = format!("HTTP/1.1 {}\r\nReplay-Nonce: nonce\r\n", status);
+    for (key, value) in headers {
+        res.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    format!(
+        "{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        res,
+        body.len(),
+        body
+    )
+}
+
+fn decode(data: &Value) -> Vec<u8> {
+    base64::decode_config(data.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+}
+
+fn handle(state: &Mutex<State>, url: &str, method: &str, path: &str, body: &str) -> String {
+    let mut state = state.lock().unwrap();
+    let payload = if method == "POST" {
+        let jws = serde_json::from_str::<Value>(body).unwrap();
+        let payload = decode(&jws["payload"]);
+        if payload.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&payload).unwrap()
+        }
+    } else {
+        Value::Null
+    };
+
+    let order = |state: &State, status: &str| {
+        json!({
+            "status": status,
+            "identifiers": state.dns_names.iter()
+                .map(|name| json!({"type": "dns", "value": name}))
+                .collect::<Vec<_>>(),
+            "authorizations": [format!("{}/authz/1", url)],
+            "finalize": format!("{}/finalize/1", url),
+            "certificate": format!("{}/cert/1", url),
+        })
+        .to_string()
+    };
+
+    match (method, path) {
+        ("GET", "/directory") => response(
+            "200 OK",
+            &[],
+            &json!({
+                "newNonce": format!("{}/nonce", url),
+                "newAccount": format!("{}/account", url),
+                "newOrder": format!("{}/order", url),
+                "revokeCert": format!("{}/revoke", url),
+                "keyChange": format!("{}/key-change", url),
+            })
+            .to_string(),
+        ),
+        ("HEAD", "/nonce") => response("200 OK", &[], ""),
+        ("POST", "/account") => response(
+            "201 Created",
+            &[("Location", format!("{}/account/1", url))],
+            r#"{"status":"valid"}"#,
+        ),
+        ("POST", "/order") => {
+            state.orders += 1;
+            state.validated = false;
+            state.dns_names = payload["identifiers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|id| id["value"].as_str().unwrap().to_string())
+                .collect();
+            response(
+                "201 Created",
+                &[("Location", format!("{}/order/1", url))],
+                &order(&state, "pending"),
+            )
+        }
+        ("POST", "/order/1") => {
+            let status = if state.validated { "ready" } else { "pending" };
+            response("200 OK", &[], &order(&state, status))
+        }
+        ("POST", "/authz/1") => response(
+            "200 OK",
+            &[],
+            &json!({
+                "status": if state.validated { "valid" } else { "pending" },
+                "identifier": {"type": "dns", "value": state.dns_names[0]},
+                "challenges": [{
+                    "type": "http-01",
+                    "url": format!("{}/chall/1", url),
+                    "status": "pending",
+                    "token": TOKEN,
+                }],
+            })
+            .to_string(),
+        ),
+        ("POST", "/chall/1") => {
+            // the proof has to be in place before the challenge is triggered
+            let proof = fs::read_to_string(state.chall_dir.join("challs").join(TOKEN)).unwrap();
+            assert!(proof.starts_with(&format!("{}.", TOKEN)));
+            state.validated = true;
+            response("200 OK", &[], "{}")
+        }
+        ("POST", "/finalize/1") => {
+            let csr = decode(&payload["csr"]);
+            let fullchain = state.ca.sign(&csr, &state.dns_names);
+            state.fullchain = Some(fullchain);
+            response("200 OK", &[], &order(&state, "valid"))
+        }
+        ("POST", "/cert/1") => {
+            let fullchain = state.fullchain.clone().unwrap();
+            format!(
+                "HTTP/1.1 200 OK\r\nReplay-Nonce: nonce\r\nContent-Type: application/pem-certificate-chain\r\nContent-Length: {}\r\n\r\n{}",
+                fullchain.len(),
+                fullchain
+            )
+        }
+        _ => response("404 Not Found", &[], "{}"),
+    }
+}
+
+fn serve(state: Arc<Mutex<State>>, url: String, stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_string();
+        let path = parts.next().unwrap().to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((key, value)) = header.split_once(':') {
+                if key.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+
+        let res = handle(&state, &url, &method, &path, &body);
+        stream.write_all(res.as_bytes()).unwrap();
+    }
+}
+
+fn mock_server(chall_dir: PathBuf) -> (String, Arc<Mutex<State>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let state = Arc::new(Mutex::new(State {
+        chall_dir,
+        ca: Ca::new(),
+        dns_names: vec![],
+        orders: 0,
+        validated: false,
+        fullchain: None,
+    }));
+    {
+        let url = url.clone();
+        let state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = state.clone();
+                let url = url.clone();
+                thread::spawn(move || serve(state, url, stream.unwrap()));
+            }
+        });
+    }
+    (url, state)
+}
+
+#[test]
+fn test_renew_cert() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let chall_dir = tempfile::tempdir().unwrap();
+    fs::create_dir(chall_dir.path().join("challs")).unwrap();
+    let (url, state) = mock_server(chall_dir.path().to_path_buf());
+
+    let cert = CertConfig {
+        name: "example.com".to_string(),
+        dns_names: vec!["example.com".to_string(), "www.example.com".to_string()],
+        ..Default::default()
+    };
+    let config = Config {
+        certs: vec![cert.clone()],
+        config_dir: PathBuf::new(),
+        acme: AcmeConfig {
+            acme_url: format!("{}/directory", url),
+            renew_if_days_left: 30,
+            ..Default::default()
+        },
+        system: SystemConfig {
+            data_dir: data_dir.path().to_path_buf(),
+            chall_dir: chall_dir.path().to_path_buf(),
+            ..Default::default()
+        },
+        daemon: Default::default(),
+        notify: Default::default(),
+    };
+
+    assert_eq!(renew_cert(&config, &cert).unwrap(), RenewOutcome::Renewed);
+    assert_eq!(state.lock().unwrap().orders, 1);
+    // the proof is removed after the order
+    assert_eq!(
+        fs::read_dir(chall_dir.path().join("challs"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    let persist = PersistState::for_cert(&config, &cert);
+    let info = persist.load_cert_info("example.com").unwrap().unwrap();
+    assert!(info.days_left() > 30);
+    let fullchain = fs::read_to_string(persist.live_path("example.com").join("fullchain")).unwrap();
+    assert_eq!(Some(fullchain), state.lock().unwrap().fullchain);
+    assert!(persist.load_acc_privkey().unwrap().is_some());
+
+    // the new cert isn't due for renewal
+    assert_eq!(renew_cert(&config, &cert).unwrap(), RenewOutcome::NotDue);
+    assert_eq!(state.lock().unwrap().orders, 1);
+}
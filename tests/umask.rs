@@ -0,0 +1,118 @@
+//! Files in the data directory get their modes independent of the umask. The
+//! umask is process wide, so this runs in its own test binary.
+#![cfg(unix)]
+use acme_redirect::acme::Certificate;
+use acme_redirect::chall::Challenge;
+use acme_redirect::config::{Config, SystemConfig};
+use acme_redirect::PersistState;
+use nix::sys::stat::{umask, Mode};
+use nix::unistd;
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::{X509Name, X509};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+fn certificate() -> Certificate {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_text("CN", "example.com").unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(90).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    Certificate {
+        private_key: String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+        fullchain: String::from_utf8(cert.build().to_pem().unwrap()).unwrap(),
+    }
+}
+
+fn config(dir: &Path, group: Option<String>) -> Config {
+    Config {
+        certs: vec![],
+        config_dir: PathBuf::new(),
+        acme: Default::default(),
+        system: SystemConfig {
+            data_dir: dir.join("data"),
+            chall_dir: dir.join("chall"),
+            group,
+            ..Default::default()
+        },
+        daemon: Default::default(),
+        notify: Default::default(),
+    }
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::symlink_metadata(path).unwrap().mode() & 0o7777
+}
+
+#[test]
+fn test_modes_independent_of_umask() {
+    // the primary group of the current user, so this works without root
+    let gid = unistd::getegid();
+    let group = users::get_group_by_gid(gid.as_raw())
+        .unwrap()
+        .name()
+        .to_string_lossy()
+        .into_owned();
+    let cert = certificate();
+
+    for mask in &[0o077, 0o002, 0o022, 0o777] {
+        for group in &[None, Some(group.clone())] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = config(dir.path(), group.clone());
+            fs::create_dir_all(config.system.chall_dir.join("challs")).unwrap();
+
+            let old = umask(Mode::from_bits_truncate(*mask));
+            let persist = PersistState::new(&config);
+            persist.store_cert("example.com", &cert).unwrap();
+            persist.store_acc_privkey("acc key").unwrap();
+            persist.store_state("example.com", "{}").unwrap();
+            let mut chall = Challenge::new(&config).unwrap();
+            let token = chall.random().unwrap();
+            umask(old);
+
+            let ctx = format!("umask {:04o}, group {:?}", mask, group);
+            let data_dir = &config.system.data_dir;
+            let live = persist.live_path("example.com");
+            let dir_mode = if group.is_some() { 0o750 } else { 0o755 };
+            for path in &[data_dir.join("certs"), data_dir.join("live")] {
+                assert_eq!(mode(path), dir_mode, "{:?}, {}", path, ctx);
+            }
+            assert_eq!(mode(&fs::canonicalize(&live).unwrap()), dir_mode, "{}", ctx);
+            for file in &["privkey", "bundle"] {
+                assert_eq!(mode(&live.join(file)), 0o640, "{}, {}", file, ctx);
+            }
+            for file in &["fullchain", "chain", "cert"] {
+                assert_eq!(mode(&live.join(file)), 0o644, "{}, {}", file, ctx);
+            }
+            if group.is_some() {
+                let md = fs::metadata(live.join("privkey")).unwrap();
+                assert_eq!(md.gid(), gid.as_raw(), "{}", ctx);
+            }
+            assert_eq!(mode(&data_dir.join("acc.key")), 0o600, "{}", ctx);
+            assert_eq!(
+                mode(&data_dir.join("state/example.com.json")),
+                0o644,
+                "{}",
+                ctx
+            );
+            let proof = config.system.chall_dir.join("challs").join(&token);
+            assert_eq!(mode(&proof), 0o644, "{}", ctx);
+            chall.cleanup().unwrap();
+        }
+    }
+}